@@ -0,0 +1,228 @@
+//! Measures each kernel family across shapes and compiler options on the local adapter.
+//!
+//! Criterion writes its estimates for every benchmark as JSON under `target/criterion`, keyed by
+//! `<family>/<variant>/<shape>`, which is what regression tracking and tuning read.
+
+use std::collections::HashMap;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use momentum::{
+    builder::{Add, Attention, Concat, MatMul, Mul, Random},
+    compiler::{Compiler, Runner},
+    graph::{ElemwiseOp, ExprId, Graph, Op, OpKind, ReduceOp},
+    tensor::{Layout, Tensor},
+    wgpu::{
+        compiler::{Accumulation, WgpuCompiler},
+        runner::WgpuRunner,
+    },
+};
+
+fn tensor(dims: &[usize]) -> Tensor {
+    let layout = Layout::from(dims);
+
+    Tensor::from_parts(
+        (0..layout.elements())
+            .map(|index| (index as f32 * 0.37).sin())
+            .collect(),
+        layout,
+    )
+}
+
+struct Case {
+    graph: Graph,
+    inputs: Vec<Tensor>,
+}
+
+impl Case {
+    fn new(inputs: &[&[usize]], build: impl FnOnce(&mut Graph, &[ExprId]) -> ExprId) -> Self {
+        let mut graph = Graph::new();
+        let ids = inputs
+            .iter()
+            .map(|dims| graph.add_input(Layout::from(*dims)))
+            .collect::<Vec<_>>();
+        let output = build(&mut graph, &ids);
+
+        graph.add_output(output);
+
+        Self {
+            inputs: inputs.iter().map(|dims| tensor(dims)).collect(),
+            graph,
+        }
+    }
+}
+
+fn variants() -> Vec<(&'static str, WgpuCompiler)> {
+    let base = WgpuCompiler {
+        assertions: false,
+        ..Default::default()
+    };
+
+    vec![
+        ("default", base.clone()),
+        (
+            "workgroup-64",
+            WgpuCompiler {
+                workgroup_size_x: 64,
+                ..base.clone()
+            },
+        ),
+        (
+            "workgroup-128",
+            WgpuCompiler {
+                workgroup_size_x: 128,
+                workgroup_overrides: HashMap::from([(OpKind::Movement, [16, 8, 1])]),
+                ..base.clone()
+            },
+        ),
+        (
+            "two-float",
+            WgpuCompiler {
+                mean_accumulation: Accumulation::TwoFloat,
+                sum_accumulation: Accumulation::TwoFloat,
+                ..base.clone()
+            },
+        ),
+        (
+            "no-in-place",
+            WgpuCompiler {
+                in_place: false,
+                ..base
+            },
+        ),
+    ]
+}
+
+fn families() -> Vec<(&'static str, Vec<(String, Case)>)> {
+    let square = [64, 256, 512];
+
+    vec![
+        (
+            "elemwise",
+            square
+                .iter()
+                .map(|&size| {
+                    let case = Case::new(&[&[size, size], &[size, size]], |graph, ids| {
+                        let product = Mul::new(ids[0], ids[1]).build(graph);
+                        let sine = graph.add_op(Op::Elemwise(ElemwiseOp::Sin), &[product]);
+
+                        Add::new(sine, ids[0]).build(graph)
+                    });
+
+                    (format!("{size}x{size}"), case)
+                })
+                .collect(),
+        ),
+        (
+            "reduce",
+            square
+                .iter()
+                .map(|&size| {
+                    let case = Case::new(&[&[size, size]], |graph, ids| {
+                        graph.add_op(
+                            Op::Reduce {
+                                op: ReduceOp::Mean,
+                                dims: vec![1],
+                            },
+                            &[ids[0]],
+                        )
+                    });
+
+                    (format!("{size}x{size}"), case)
+                })
+                .collect(),
+        ),
+        (
+            "matmul",
+            square
+                .iter()
+                .map(|&size| {
+                    let case = Case::new(&[&[size, size], &[size, size]], |graph, ids| {
+                        MatMul::new(ids[0], ids[1]).build(graph)
+                    });
+
+                    (format!("{size}x{size}"), case)
+                })
+                .collect(),
+        ),
+        (
+            "attention",
+            [(1, 64, 32), (4, 128, 64), (8, 256, 64)]
+                .iter()
+                .map(|&(heads, tokens, dim)| {
+                    let dims = [heads, tokens, dim];
+                    let case = Case::new(&[&dims, &dims, &dims], |graph, ids| {
+                        Attention::new(ids[0], ids[1], ids[2]).build(graph)
+                    });
+
+                    (format!("{heads}x{tokens}x{dim}"), case)
+                })
+                .collect(),
+        ),
+        (
+            "concat",
+            square
+                .iter()
+                .map(|&size| {
+                    let case = Case::new(&[&[size, size], &[size, size]], |graph, ids| {
+                        let sine = graph.add_op(Op::Elemwise(ElemwiseOp::Sin), &[ids[0]]);
+
+                        Concat::new(&[sine, ids[1]], 1).build(graph)
+                    });
+
+                    (format!("{size}x{size}"), case)
+                })
+                .collect(),
+        ),
+        (
+            "random",
+            square
+                .iter()
+                .map(|&size| {
+                    let case = Case::new(&[], |graph, _| {
+                        Random::normal([size, size], 0.0, 1.0).build(graph)
+                    });
+
+                    (format!("{size}x{size}"), case)
+                })
+                .collect(),
+        ),
+    ]
+}
+
+fn kernels(c: &mut Criterion) {
+    let mut runner = WgpuRunner::new();
+
+    for (family, cases) in families() {
+        let mut group = c.benchmark_group(family);
+
+        group.sample_size(20);
+
+        for (shape, case) in &cases {
+            for (variant, compiler) in variants() {
+                let plan = compiler.compile(case.graph.clone());
+                let elements = plan
+                    .outputs()
+                    .map(|(_, layout, _)| layout.elements())
+                    .sum::<usize>();
+                let plan = runner.preprocess(plan);
+
+                group.throughput(Throughput::Elements(elements as u64));
+
+                group.bench_with_input(
+                    BenchmarkId::new(variant, shape),
+                    &case.inputs,
+                    |b, inputs| {
+                        let inputs = inputs.iter().map(Tensor::view).collect::<Vec<_>>();
+
+                        b.iter(|| runner.run(plan.clone(), &inputs))
+                    },
+                );
+            }
+        }
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, kernels);
+criterion_main!(benches);
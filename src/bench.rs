@@ -0,0 +1,146 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+use crate::{
+    compiler::Runner,
+    energy::{self, EnergySampler},
+    tensor::Tensor,
+};
+
+/// Untimed runs before measuring, which compile pipelines and fill the allocator's pools.
+pub const WARMUP_ITERS: usize = 3;
+
+/// The latencies of repeated runs of one plan.
+#[derive(Clone, Debug)]
+pub struct BenchmarkReport {
+    pub iters: usize,
+    pub mean: Duration,
+    pub median: Duration,
+    pub p95: Duration,
+    pub min: Duration,
+    pub max: Duration,
+    /// The bytes of the inputs and outputs of a single run.
+    pub bytes: usize,
+    /// The energy all the timed runs took together, when sampled.
+    pub joules: Option<f64>,
+}
+
+impl BenchmarkReport {
+    /// Runs per second, at the mean latency.
+    pub fn throughput(&self) -> f64 {
+        1.0 / self.mean.as_secs_f64()
+    }
+
+    /// Input and output bytes moved per second, at the mean latency.
+    pub fn bandwidth(&self) -> f64 {
+        self.bytes as f64 * self.throughput()
+    }
+
+    pub fn joules_per_iter(&self) -> Option<f64> {
+        self.joules.map(|joules| joules / self.iters as f64)
+    }
+}
+
+impl Display for BenchmarkReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} iters: mean {:?}, median {:?}, p95 {:?}, min {:?}, max {:?}, {:.1} runs/s, {:.1} MB/s",
+            self.iters,
+            self.mean,
+            self.median,
+            self.p95,
+            self.min,
+            self.max,
+            self.throughput(),
+            self.bandwidth() / 1e6
+        )?;
+
+        if let Some(joules) = self.joules {
+            write!(
+                f,
+                ", {joules:.3} J, {:.3} J/iter",
+                joules / self.iters as f64
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs `plan` on `inputs` `iters` times after a warmup, timing each run. A run only returns once
+/// its outputs are read back, so each latency covers the upload, the kernels, and the readback.
+/// With a sampler, the energy of the timed runs is measured too, and left out if sampling fails.
+pub fn benchmark<R: Runner>(
+    runner: &mut R,
+    plan: &R::Runnable,
+    inputs: &[Tensor],
+    iters: usize,
+    energy: Option<&mut dyn EnergySampler>,
+) -> BenchmarkReport
+where
+    R::Runnable: Clone,
+{
+    assert!(iters > 0, "cannot benchmark zero iterations");
+
+    let views = inputs.iter().map(Tensor::view).collect::<Vec<_>>();
+    let mut bytes = 0;
+
+    for _ in 0..WARMUP_ITERS {
+        let outputs = runner.run(plan.clone(), &views);
+
+        bytes = inputs
+            .iter()
+            .chain(&outputs)
+            .map(|tensor| tensor.layout.elements() * size_of::<f32>())
+            .sum();
+    }
+
+    let mut time = || {
+        (0..iters)
+            .map(|_| {
+                let plan = plan.clone();
+                let start = Instant::now();
+
+                runner.run(plan, &views);
+
+                start.elapsed()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let (mut latencies, joules) = match energy {
+        Some(sampler) => {
+            let (latencies, joules) = energy::measure(sampler, time);
+
+            (
+                latencies,
+                joules
+                    .inspect_err(|error| warn!("could not sample energy: {error}"))
+                    .ok(),
+            )
+        }
+        None => (time(), None),
+    };
+
+    latencies.sort();
+
+    // Nearest rank, so the p95 of few runs is the slowest one rather than an interpolation.
+    let percentile =
+        |fraction: f64| latencies[((fraction * iters as f64).ceil() as usize).clamp(1, iters) - 1];
+
+    BenchmarkReport {
+        iters,
+        mean: latencies.iter().sum::<Duration>() / iters as u32,
+        median: percentile(0.5),
+        p95: percentile(0.95),
+        min: latencies[0],
+        max: latencies[iters - 1],
+        bytes,
+        joules,
+    }
+}
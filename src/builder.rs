@@ -1,4 +1,11 @@
-use crate::graph::{ElemwiseOp, ExprId, Graph, Op};
+use std::{iter, sync::Arc};
+
+use crate::{
+    graph::{
+        Distribution, ElemwiseOp, ExprId, Graph, MatMulMask, MovementOp, Op, Predicate, ReduceOp,
+    },
+    tensor::{DimId, Layout, Shape, Tensor},
+};
 
 pub struct Add {
     left: ExprId,
@@ -29,3 +36,644 @@ impl Mul {
         graph.add_op(Op::Elemwise(ElemwiseOp::Mul), &[self.left, self.right])
     }
 }
+
+pub struct MatMul {
+    left: ExprId,
+    right: ExprId,
+    mask: Option<MatMulMask>,
+}
+
+impl MatMul {
+    pub fn new(left: ExprId, right: ExprId) -> Self {
+        Self {
+            left,
+            right,
+            mask: None,
+        }
+    }
+
+    /// Only computes the output elements `mask` keeps, leaving the rest zero.
+    pub fn mask(mut self, mask: MatMulMask) -> Self {
+        self.mask = Some(mask);
+        self
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        let op = match &self.mask {
+            Some(mask) => Op::MaskedMatMul { mask: mask.clone() },
+            None => Op::MatMul,
+        };
+
+        graph.add_op(op, &[self.left, self.right])
+    }
+}
+
+pub struct Outer {
+    left: ExprId,
+    right: ExprId,
+}
+
+impl Outer {
+    /// The outer product of the last dimensions of `left` and `right`, batched over the others.
+    pub fn new(left: ExprId, right: ExprId) -> Self {
+        Self { left, right }
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        let (left, right) = (&graph[self.left].layout, &graph[self.right].layout);
+
+        assert_eq!(
+            left.dims()[..left.rank() - 1],
+            right.dims()[..right.rank() - 1],
+            "outer product batch dimensions do not match"
+        );
+
+        let rank = left.rank();
+        let left = unsqueeze(graph, self.left, rank);
+        let right = unsqueeze(graph, self.right, rank - 1);
+
+        Mul::new(left, right).build(graph)
+    }
+}
+
+// Adds a dimension of size one at `dim`, for broadcasting against it.
+fn unsqueeze(graph: &mut Graph, input: ExprId, dim: DimId) -> ExprId {
+    graph.add_op(Op::Movement(MovementOp::Unsqueeze(dim)), &[input])
+}
+
+/// Ones at the positions `indices` hold, along a new last dimension of `classes` elements, and
+/// zeros elsewhere. Indices are floats holding whole numbers, like the ones `ArgMax` gives.
+pub struct OneHot {
+    indices: ExprId,
+    classes: usize,
+}
+
+impl OneHot {
+    pub fn new(indices: ExprId, classes: usize) -> Self {
+        Self { indices, classes }
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        let rank = graph[self.indices].layout.rank();
+        let indices = unsqueeze(graph, self.indices, rank);
+
+        let negated_classes = graph.add_const(Tensor::from_parts(
+            (0..self.classes).map(|class| -(class as f32)).collect(),
+            Layout::from([vec![1; rank], vec![self.classes]].concat()),
+        ));
+        let minus_one = broadcast_scalar(graph, -1.0, rank + 1);
+        let one = broadcast_scalar(graph, 1.0, rank + 1);
+
+        // Without comparisons, the distance to each class is clamped and squared, which is one
+        // everywhere but at the index's own class, where it is zero.
+        let distance = Add::new(indices, negated_classes).build(graph);
+        let clamped = graph.add_op(Op::Elemwise(ElemwiseOp::Clamp), &[distance, minus_one, one]);
+        let squared = Mul::new(clamped, clamped).build(graph);
+        let negated = Mul::new(squared, minus_one).build(graph);
+
+        Add::new(negated, one).build(graph)
+    }
+}
+
+/// The rows of `table` that `indices` pick, along a new last dimension. There is no gather, so
+/// lookups multiply the indices' one-hot encoding by the table, which also gives the table its
+/// gradients.
+pub struct Embedding {
+    indices: ExprId,
+    table: ExprId,
+}
+
+impl Embedding {
+    pub fn new(indices: ExprId, table: ExprId) -> Self {
+        Self { indices, table }
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        let table = &graph[self.table].layout;
+
+        assert_eq!(table.rank(), 2, "embedding tables must be matrices");
+
+        let one_hot = OneHot::new(self.indices, table.dims()[0]).build(graph);
+
+        MatMul::new(one_hot, self.table).build(graph)
+    }
+}
+
+pub struct Attention {
+    query: ExprId,
+    key: ExprId,
+    value: ExprId,
+    scale: Option<f32>,
+}
+
+impl Attention {
+    pub fn new(query: ExprId, key: ExprId, value: ExprId) -> Self {
+        Self {
+            query,
+            key,
+            value,
+            scale: None,
+        }
+    }
+
+    pub fn scale(mut self, scale: f32) -> Self {
+        self.scale = Some(scale);
+        self
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        let scale = self.scale.unwrap_or_else(|| {
+            let dims = graph[self.query].layout.dims();
+
+            1.0 / (dims[dims.len() - 1] as f32).sqrt()
+        });
+
+        graph.add_op(Op::Attention { scale }, &[self.query, self.key, self.value])
+    }
+}
+
+/// The softmax over the last dimension. The compiler fuses softmaxes built this way, taken of
+/// scaled query-key products and multiplied by values, into attention.
+pub struct Softmax {
+    input: ExprId,
+}
+
+impl Softmax {
+    pub fn new(input: ExprId) -> Self {
+        Self { input }
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        let rank = graph[self.input].layout.rank();
+
+        let max = Max::new(self.input, &[rank - 1]).build(graph);
+        let minus_one = broadcast_scalar(graph, -1.0, rank);
+        let negated_max = Mul::new(max, minus_one).build(graph);
+        let shifted = Add::new(self.input, negated_max).build(graph);
+        let exp = graph.add_op(Op::Elemwise(ElemwiseOp::Exp), &[shifted]);
+
+        // There is no division, so the sum is inverted as the square of its inverse square root.
+        let sum = Sum::new(exp, &[rank - 1]).build(graph);
+        let inverse_root = graph.add_op(Op::Elemwise(ElemwiseOp::Rsqrt), &[sum]);
+        let inverse = Mul::new(inverse_root, inverse_root).build(graph);
+
+        Mul::new(exp, inverse).build(graph)
+    }
+}
+
+pub struct Random {
+    shape: Shape,
+    distribution: Distribution,
+    seed: u64,
+}
+
+impl Random {
+    pub fn uniform(shape: impl Into<Shape>, low: f32, high: f32) -> Self {
+        Self {
+            shape: shape.into(),
+            distribution: Distribution::Uniform { low, high },
+            seed: 0,
+        }
+    }
+
+    pub fn normal(shape: impl Into<Shape>, mean: f32, std: f32) -> Self {
+        Self {
+            shape: shape.into(),
+            distribution: Distribution::Normal { mean, std },
+            seed: 0,
+        }
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        graph.add_op(
+            Op::Random {
+                distribution: self.distribution,
+                seed: self.seed,
+                shape: self.shape.clone(),
+            },
+            &[],
+        )
+    }
+}
+
+pub struct Dropout {
+    input: ExprId,
+    probability: f32,
+    seed: u64,
+}
+
+impl Dropout {
+    pub fn new(input: ExprId, probability: f32) -> Self {
+        assert!(
+            (0.0..1.0).contains(&probability),
+            "dropout probability must be in [0, 1)"
+        );
+
+        Self {
+            input,
+            probability,
+            seed: 0,
+        }
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        graph.add_op(
+            Op::Dropout {
+                probability: self.probability,
+                seed: self.seed,
+            },
+            &[self.input],
+        )
+    }
+}
+
+pub struct Repeat {
+    input: ExprId,
+    repeats: Vec<usize>,
+}
+
+impl Repeat {
+    pub fn new(input: ExprId, repeats: &[usize]) -> Self {
+        Self {
+            input,
+            repeats: repeats.to_owned(),
+        }
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        graph.add_op(
+            Op::Repeat {
+                repeats: self.repeats.clone(),
+            },
+            &[self.input],
+        )
+    }
+}
+
+pub struct Flip {
+    input: ExprId,
+    dims: Vec<DimId>,
+}
+
+impl Flip {
+    pub fn new(input: ExprId, dims: &[DimId]) -> Self {
+        Self {
+            input,
+            dims: dims.to_owned(),
+        }
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        graph.add_op(
+            Op::Movement(MovementOp::Flip(self.dims.clone())),
+            &[self.input],
+        )
+    }
+}
+
+pub struct Diagonal {
+    input: ExprId,
+    offset: isize,
+}
+
+impl Diagonal {
+    pub fn new(input: ExprId) -> Self {
+        Self { input, offset: 0 }
+    }
+
+    pub fn offset(mut self, offset: isize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        graph.add_op(
+            Op::Diagonal {
+                offset: self.offset,
+            },
+            &[self.input],
+        )
+    }
+}
+
+pub struct Trace {
+    input: ExprId,
+}
+
+impl Trace {
+    pub fn new(input: ExprId) -> Self {
+        Self { input }
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        graph.add_op(Op::Trace, &[self.input])
+    }
+}
+
+pub struct If {
+    condition: ExprId,
+    predicate: Predicate,
+    inputs: Vec<ExprId>,
+    then_graph: Arc<Graph>,
+    else_graph: Arc<Graph>,
+}
+
+impl If {
+    /// Picks `then_graph` when `predicate` holds for the scalar `condition`. Both branches take
+    /// `inputs` and have a single output of the same shape.
+    pub fn new(
+        condition: ExprId,
+        predicate: Predicate,
+        inputs: &[ExprId],
+        then_graph: Graph,
+        else_graph: Graph,
+    ) -> Self {
+        Self {
+            condition,
+            predicate,
+            inputs: inputs.to_owned(),
+            then_graph: Arc::new(then_graph),
+            else_graph: Arc::new(else_graph),
+        }
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        graph.add_op(
+            Op::If {
+                predicate: self.predicate,
+                then_graph: self.then_graph.clone(),
+                else_graph: self.else_graph.clone(),
+            },
+            &iter::once(self.condition)
+                .chain(self.inputs.iter().copied())
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+pub struct Scan {
+    state: ExprId,
+    sequence: ExprId,
+    dim: DimId,
+    body: Arc<Graph>,
+}
+
+impl Scan {
+    /// Folds `body` over `sequence` along `dim`, starting from `state`. The body takes the state
+    /// and the sequence at one position, with `dim` kept at size one, and returns the next state.
+    pub fn new(state: ExprId, sequence: ExprId, dim: DimId, body: Graph) -> Self {
+        Self {
+            state,
+            sequence,
+            dim,
+            body: Arc::new(body),
+        }
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        graph.add_op(
+            Op::Scan {
+                body: self.body.clone(),
+                dim: self.dim,
+            },
+            &[self.state, self.sequence],
+        )
+    }
+}
+
+pub struct Concat {
+    inputs: Vec<ExprId>,
+    dim: DimId,
+}
+
+impl Concat {
+    pub fn new(inputs: &[ExprId], dim: DimId) -> Self {
+        Self {
+            inputs: inputs.to_owned(),
+            dim,
+        }
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        graph.add_op(Op::Concat { dim: self.dim }, &self.inputs)
+    }
+}
+
+// Reduces over `dims`, keeping them with size one, or over every dimension into a scalar.
+fn reduce(graph: &mut Graph, op: ReduceOp, input: ExprId, dims: Option<&[DimId]>) -> ExprId {
+    let Some(dims) = dims else {
+        let dims = (0..graph[input].layout.rank()).collect::<Vec<_>>();
+        let reduced = reduce(graph, op, input, Some(&dims));
+
+        return graph.add_op(Op::Movement(MovementOp::Squeeze(None)), &[reduced]);
+    };
+
+    graph.add_op(
+        Op::Reduce {
+            op,
+            dims: dims.to_owned(),
+        },
+        &[input],
+    )
+}
+
+pub struct Sum {
+    input: ExprId,
+    // Every dimension when unset.
+    dims: Option<Vec<DimId>>,
+}
+
+impl Sum {
+    pub fn new(input: ExprId, dims: &[DimId]) -> Self {
+        Self {
+            input,
+            dims: Some(dims.to_owned()),
+        }
+    }
+
+    /// The sum of every element, as a scalar whatever the rank of the input.
+    pub fn all(input: ExprId) -> Self {
+        Self { input, dims: None }
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        reduce(graph, ReduceOp::Sum, self.input, self.dims.as_deref())
+    }
+}
+
+pub struct Max {
+    input: ExprId,
+    dims: Option<Vec<DimId>>,
+}
+
+impl Max {
+    pub fn new(input: ExprId, dims: &[DimId]) -> Self {
+        Self {
+            input,
+            dims: Some(dims.to_owned()),
+        }
+    }
+
+    /// The largest element, as a scalar whatever the rank of the input.
+    pub fn all(input: ExprId) -> Self {
+        Self { input, dims: None }
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        reduce(graph, ReduceOp::Max, self.input, self.dims.as_deref())
+    }
+}
+
+/// The position of the first largest element along a dimension, which stays with size one. The
+/// position is a float, like every element.
+pub struct ArgMax {
+    input: ExprId,
+    dim: DimId,
+}
+
+impl ArgMax {
+    pub fn new(input: ExprId, dim: DimId) -> Self {
+        Self { input, dim }
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        reduce(graph, ReduceOp::ArgMax, self.input, Some(&[self.dim]))
+    }
+}
+
+pub struct ArgMin {
+    input: ExprId,
+    dim: DimId,
+}
+
+impl ArgMin {
+    pub fn new(input: ExprId, dim: DimId) -> Self {
+        Self { input, dim }
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        reduce(graph, ReduceOp::ArgMin, self.input, Some(&[self.dim]))
+    }
+}
+
+fn broadcast_scalar(graph: &mut Graph, value: f32, rank: usize) -> ExprId {
+    graph.add_const(Tensor::from_parts(
+        Box::new([value]),
+        Layout::from(vec![1; rank]),
+    ))
+}
+
+fn normalize(
+    graph: &mut Graph,
+    input: ExprId,
+    dims: &[DimId],
+    eps: f32,
+    affine: Option<(ExprId, ExprId)>,
+) -> ExprId {
+    let rank = graph[input].layout.rank();
+
+    let mean = graph.add_op(
+        Op::Reduce {
+            op: ReduceOp::Mean,
+            dims: dims.to_owned(),
+        },
+        &[input],
+    );
+    let minus_one = broadcast_scalar(graph, -1.0, rank);
+    let negated_mean = Mul::new(mean, minus_one).build(graph);
+    let centered = Add::new(input, negated_mean).build(graph);
+
+    let squared = Mul::new(centered, centered).build(graph);
+    let variance = graph.add_op(
+        Op::Reduce {
+            op: ReduceOp::Mean,
+            dims: dims.to_owned(),
+        },
+        &[squared],
+    );
+    let eps = broadcast_scalar(graph, eps, rank);
+    let variance = Add::new(variance, eps).build(graph);
+    let inverse_std = graph.add_op(Op::Elemwise(ElemwiseOp::Rsqrt), &[variance]);
+
+    let normalized = Mul::new(centered, inverse_std).build(graph);
+
+    match affine {
+        Some((scale, shift)) => {
+            let scaled = Mul::new(normalized, scale).build(graph);
+
+            Add::new(scaled, shift).build(graph)
+        }
+        None => normalized,
+    }
+}
+
+pub struct LayerNorm {
+    input: ExprId,
+    dims: Vec<DimId>,
+    eps: f32,
+    affine: Option<(ExprId, ExprId)>,
+}
+
+impl LayerNorm {
+    pub fn new(input: ExprId, dims: &[DimId], eps: f32) -> Self {
+        Self {
+            input,
+            dims: dims.to_owned(),
+            eps,
+            affine: None,
+        }
+    }
+
+    pub fn affine(mut self, scale: ExprId, shift: ExprId) -> Self {
+        self.affine = Some((scale, shift));
+        self
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        normalize(graph, self.input, &self.dims, self.eps, self.affine)
+    }
+}
+
+pub struct BatchNorm {
+    input: ExprId,
+    eps: f32,
+    affine: Option<(ExprId, ExprId)>,
+}
+
+impl BatchNorm {
+    pub fn new(input: ExprId, eps: f32) -> Self {
+        Self {
+            input,
+            eps,
+            affine: None,
+        }
+    }
+
+    pub fn affine(mut self, scale: ExprId, shift: ExprId) -> Self {
+        self.affine = Some((scale, shift));
+        self
+    }
+
+    pub fn build(&self, graph: &mut Graph) -> ExprId {
+        let rank = graph[self.input].layout.rank();
+
+        assert!(
+            rank >= 2,
+            "batch norm input must have batch and channel dimensions"
+        );
+
+        let dims = iter::once(0).chain(2..rank).collect::<Vec<_>>();
+
+        normalize(graph, self.input, &dims, self.eps, self.affine)
+    }
+}
@@ -1,4 +1,7 @@
-use crate::{graph::Graph, tensor::Tensor};
+use crate::{
+    graph::Graph,
+    tensor::{Tensor, TensorView},
+};
 
 pub trait Runner {
     type Compiler: Compiler;
@@ -8,7 +11,8 @@ pub trait Runner {
     fn preprocess(&mut self, result: <Self::Compiler as Compiler>::CompileResult)
         -> Self::Runnable;
 
-    fn run(&mut self, runnable: Self::Runnable, inputs: Vec<Tensor>) -> Vec<Tensor>;
+    /// Runs `runnable` on borrowed inputs, which are only read while the call lasts.
+    fn run(&mut self, runnable: Self::Runnable, inputs: &[TensorView]) -> Vec<Tensor>;
 }
 
 pub trait Compiler {
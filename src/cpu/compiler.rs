@@ -0,0 +1,44 @@
+use std::sync::Arc;
+
+use crate::{
+    compiler::Compiler,
+    graph::{ExprId, Graph},
+    ir::{self, Program},
+};
+
+// Values are separate allocations on the CPU, so their offsets only need to be distinct.
+const ALIGNMENT: usize = size_of::<f32>();
+
+/// Compiles graphs for [`CpuRunner`](super::runner::CpuRunner), lowering them to kernels that
+/// fuse chains of elementwise ops.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CpuCompiler;
+
+#[derive(Clone, Debug)]
+pub struct CpuPlan {
+    pub(crate) graph: Arc<Graph>,
+    pub(crate) program: Program,
+    // The values no longer needed once each kernel is done.
+    pub(crate) frees: Vec<Vec<ExprId>>,
+}
+
+impl Compiler for CpuCompiler {
+    type CompileResult = CpuPlan;
+
+    fn compile(&self, graph: Graph) -> CpuPlan {
+        let program = ir::lower(&graph, ALIGNMENT);
+        let mut frees = vec![Vec::new(); program.kernels.len()];
+
+        for buffer in &program.buffers {
+            if !graph.outputs.contains(&buffer.id) && buffer.lifetime.end <= frees.len() {
+                frees[buffer.lifetime.end - 1].push(buffer.id);
+            }
+        }
+
+        CpuPlan {
+            graph: Arc::new(graph),
+            program,
+            frees,
+        }
+    }
+}
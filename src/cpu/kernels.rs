@@ -0,0 +1,340 @@
+use rayon::prelude::*;
+use wide::f32x8;
+
+use crate::{
+    graph::{ElemwiseOp, ExprBody, Graph, Mode, Op, ReduceOp},
+    interp,
+    ir::Kernel,
+    tensor::{DimId, Layout, Tensor},
+};
+
+const LANES: usize = 8;
+
+// Elements per parallel task, enough to amortize handing the task to another thread.
+const CHUNK: usize = 1 << 14;
+
+#[derive(Clone, Copy)]
+enum Operand<'a> {
+    Slice(&'a [f32]),
+    Splat(f32),
+}
+
+impl<'a> Operand<'a> {
+    // Only operands read in the order of the output, or broadcast whole, are vectorized.
+    fn new(tensor: &'a Tensor, layout: &Layout) -> Option<Self> {
+        if tensor.layout.elements() == 1 {
+            Some(Operand::Splat(tensor.data[0]))
+        } else if tensor.layout.dims() == layout.dims() && tensor.layout.is_contiguous() {
+            Some(Operand::Slice(&tensor.data[..layout.elements()]))
+        } else {
+            None
+        }
+    }
+
+    // The operand for `len` elements of the output from `start` on.
+    fn slice(self, start: usize, len: usize) -> Self {
+        match self {
+            Operand::Slice(data) => Operand::Slice(&data[start..start + len]),
+            splat => splat,
+        }
+    }
+
+    fn get(self, index: usize) -> f32 {
+        match self {
+            Operand::Slice(data) => data[index],
+            Operand::Splat(value) => value,
+        }
+    }
+
+    fn lanes(self, start: usize) -> f32x8 {
+        match self {
+            Operand::Slice(data) => load(&data[start..start + LANES]),
+            Operand::Splat(value) => f32x8::splat(value),
+        }
+    }
+}
+
+fn load(lane: &[f32]) -> f32x8 {
+    f32x8::new(lane.try_into().unwrap())
+}
+
+fn elemwise_lanes(op: ElemwiseOp, [a, b, c]: [f32x8; 3]) -> f32x8 {
+    match op {
+        ElemwiseOp::Add => a + b,
+        ElemwiseOp::Mul => a * b,
+        ElemwiseOp::Fma => a.mul_add(b, c),
+        ElemwiseOp::Floor => a.floor(),
+        ElemwiseOp::Ceil => a.ceil(),
+        ElemwiseOp::Clamp => a.max(b).min(c),
+        // Transcendental functions go through `std`, so they match the reference evaluator.
+        _ => f32x8::new(a.to_array().map(|value| interp::elemwise(op, &[value]))),
+    }
+}
+
+// Operands are aligned with the output.
+fn elemwise_into(op: ElemwiseOp, operands: [Operand; 3], output: &mut [f32]) {
+    let vectorized = output.len() - output.len() % LANES;
+    let (lanes, remainder) = output.split_at_mut(vectorized);
+
+    for (index, lane) in lanes.chunks_exact_mut(LANES).enumerate() {
+        let start = index * LANES;
+
+        lane.copy_from_slice(
+            &elemwise_lanes(op, operands.map(|operand| operand.lanes(start))).to_array(),
+        );
+    }
+
+    for (offset, value) in remainder.iter_mut().enumerate() {
+        let index = vectorized + offset;
+
+        *value = interp::elemwise(op, &operands.map(|operand| operand.get(index)));
+    }
+}
+
+fn elemwise(op: ElemwiseOp, layout: &Layout, children: &[&Tensor]) -> Option<Tensor> {
+    let mut operands = [Operand::Splat(0.0); 3];
+
+    for (operand, child) in operands.iter_mut().zip(children) {
+        *operand = Operand::new(child, layout)?;
+    }
+
+    let mut data = vec![0.0; layout.elements()];
+
+    data.par_chunks_mut(CHUNK)
+        .enumerate()
+        .for_each(|(chunk, output)| {
+            let len = output.len();
+
+            elemwise_into(
+                op,
+                operands.map(|operand| operand.slice(chunk * CHUNK, len)),
+                output,
+            );
+        });
+
+    Some(Tensor::from_parts(data.into(), layout.contiguous()))
+}
+
+#[derive(Clone, Copy)]
+enum Source<'a> {
+    Memory(Operand<'a>),
+    // The value of an earlier expression in the same kernel.
+    Fused(usize),
+}
+
+// Runs a kernel's chain of elementwise ops a chunk at a time, keeping the values in between in
+// chunk-sized scratch buffers.
+fn fused_elemwise(graph: &Graph, kernel: &Kernel, values: &[Option<Tensor>]) -> Option<Tensor> {
+    let layout = &graph[kernel.output].layout;
+
+    let exprs = kernel
+        .fused
+        .iter()
+        .map(|&id| {
+            let ExprBody::Op {
+                op: Op::Elemwise(op),
+                children,
+            } = &graph[id].body
+            else {
+                return None;
+            };
+
+            let mut sources = [Source::Memory(Operand::Splat(0.0)); 3];
+
+            for (source, child) in sources.iter_mut().zip(children) {
+                *source = match kernel.fused.iter().position(|id| id == child) {
+                    Some(position) => Source::Fused(position),
+                    None => {
+                        Source::Memory(Operand::new(values[child.0].as_ref().unwrap(), layout)?)
+                    }
+                };
+            }
+
+            Some((*op, sources))
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    let mut data = vec![0.0; layout.elements()];
+
+    data.par_chunks_mut(CHUNK)
+        .enumerate()
+        .for_each(|(chunk, output)| {
+            let len = output.len();
+            let mut scratch = vec![vec![0.0; len]; exprs.len() - 1];
+
+            for (position, (op, sources)) in exprs.iter().enumerate() {
+                let (done, rest) = scratch.split_at_mut(position.min(exprs.len() - 1));
+                let operands = sources.map(|source| match source {
+                    Source::Memory(operand) => operand.slice(chunk * CHUNK, len),
+                    Source::Fused(position) => Operand::Slice(&done[position]),
+                });
+
+                match rest.first_mut() {
+                    Some(target) => elemwise_into(*op, operands, target),
+                    None => elemwise_into(*op, operands, output),
+                }
+            }
+        });
+
+    Some(Tensor::from_parts(data.into(), layout.contiguous()))
+}
+
+fn reduce_row(op: ReduceOp, row: &[f32]) -> f32 {
+    let lanes = row.chunks_exact(LANES);
+    let remainder = lanes.remainder();
+
+    match op {
+        ReduceOp::Sum | ReduceOp::Mean => {
+            let sum = lanes
+                .fold(f32x8::splat(0.0), |sum, lane| sum + load(lane))
+                .reduce_add()
+                + remainder.iter().sum::<f32>();
+
+            match op {
+                ReduceOp::Mean => sum / row.len() as f32,
+                _ => sum,
+            }
+        }
+        ReduceOp::Max => lanes
+            .fold(f32x8::splat(f32::NEG_INFINITY), |max, lane| {
+                max.max(load(lane))
+            })
+            .to_array()
+            .into_iter()
+            .chain(remainder.iter().copied())
+            .fold(f32::NEG_INFINITY, f32::max),
+        ReduceOp::ArgMax | ReduceOp::ArgMin => {
+            unreachable!("positions are found like `interp` does")
+        }
+    }
+}
+
+// Reductions over the trailing dimensions of a contiguous tensor, one row per task.
+fn reduce(op: ReduceOp, dims: &[DimId], layout: &Layout, input: &Tensor) -> Option<Tensor> {
+    let rank = input.layout.rank();
+    let mut dims = dims.to_vec();
+
+    dims.sort_unstable();
+    dims.dedup();
+
+    // Positions count the reduced dimensions in the order they are given, which sorting loses.
+    if matches!(op, ReduceOp::ArgMax | ReduceOp::ArgMin) {
+        return None;
+    }
+
+    if !input.layout.is_contiguous() || dims != (rank - dims.len()..rank).collect::<Vec<_>>() {
+        return None;
+    }
+
+    let row = input.layout.dims()[rank - dims.len()..]
+        .iter()
+        .product::<usize>();
+
+    if row == 0 {
+        return None;
+    }
+
+    let data = input.data[..input.layout.elements()]
+        .par_chunks(row)
+        .map(|row| reduce_row(op, row))
+        .collect::<Vec<_>>();
+
+    Some(Tensor::from_parts(data.into(), layout.contiguous()))
+}
+
+// Matmuls of contiguous matrices with the same batch dimensions, one output row per task. Each
+// row accumulates scaled rows of the right hand side, which reads both sides in order.
+fn matmul(layout: &Layout, lhs: &Tensor, rhs: &Tensor) -> Option<Tensor> {
+    let (lhs_dims, rhs_dims) = (lhs.layout.dims(), rhs.layout.dims());
+    let rank = lhs_dims.len();
+
+    if rank < 2
+        || rhs_dims.len() != rank
+        || lhs_dims[..rank - 2] != rhs_dims[..rank - 2]
+        || !lhs.layout.is_contiguous()
+        || !rhs.layout.is_contiguous()
+        || layout.elements() == 0
+    {
+        return None;
+    }
+
+    let (m, k, n) = (lhs_dims[rank - 2], lhs_dims[rank - 1], rhs_dims[rank - 1]);
+    let mut data = vec![0.0; layout.elements()];
+
+    data.par_chunks_mut(n)
+        .enumerate()
+        .for_each(|(row, output)| {
+            let batch = row / m;
+            let lhs_row = &lhs.data[row * k..(row + 1) * k];
+            let rhs_matrix = &rhs.data[batch * k * n..(batch + 1) * k * n];
+
+            for (&scale, rhs_row) in lhs_row.iter().zip(rhs_matrix.chunks_exact(n)) {
+                let vectorized = n - n % LANES;
+                let (lanes, remainder) = output.split_at_mut(vectorized);
+                let scale_lanes = f32x8::splat(scale);
+
+                for (lane, rhs_lane) in lanes
+                    .chunks_exact_mut(LANES)
+                    .zip(rhs_row.chunks_exact(LANES))
+                {
+                    lane.copy_from_slice(&(load(lane) + load(rhs_lane) * scale_lanes).to_array());
+                }
+
+                for (value, &rhs_value) in remainder.iter_mut().zip(&rhs_row[vectorized..]) {
+                    *value += rhs_value * scale;
+                }
+            }
+        });
+
+    Some(Tensor::from_parts(data.into(), layout.contiguous()))
+}
+
+// Evaluates an op with a vectorized, parallel kernel when there is one for its operands, and like
+// the reference evaluator otherwise.
+fn eval_op(op: &Op, mode: Mode, layout: &Layout, children: &[&Tensor]) -> Tensor {
+    let result = match op {
+        Op::Elemwise(elemwise_op) => elemwise(*elemwise_op, layout, children),
+        Op::Reduce { op, dims } => reduce(*op, dims, layout, children[0]),
+        Op::MatMul => matmul(layout, children[0], children[1]),
+        _ => None,
+    };
+
+    result.unwrap_or_else(|| interp::eval_op(op, mode, layout, children))
+}
+
+/// Evaluates a kernel from the values of its inputs.
+pub(super) fn eval_kernel(graph: &Graph, kernel: &Kernel, values: &[Option<Tensor>]) -> Tensor {
+    if kernel.fused.len() > 1 {
+        if let Some(tensor) = fused_elemwise(graph, kernel, values) {
+            return tensor;
+        }
+    }
+
+    // Expressions the fused path cannot take are evaluated one at a time.
+    let mut fused = Vec::<Tensor>::with_capacity(kernel.fused.len());
+
+    for &id in &kernel.fused {
+        let ExprBody::Op { op, children } = &graph[id].body else {
+            unreachable!("kernels only compute ops");
+        };
+
+        let tensor = eval_op(
+            op,
+            graph.mode,
+            &graph[id].layout,
+            &children
+                .iter()
+                .map(
+                    |child| match kernel.fused.iter().position(|id| id == child) {
+                        Some(position) => &fused[position],
+                        None => values[child.0].as_ref().unwrap(),
+                    },
+                )
+                .collect::<Vec<_>>(),
+        );
+
+        fused.push(tensor);
+    }
+
+    fused.pop().unwrap()
+}
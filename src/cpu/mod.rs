@@ -0,0 +1,3 @@
+pub mod compiler;
+mod kernels;
+pub mod runner;
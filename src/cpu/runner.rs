@@ -0,0 +1,112 @@
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::{
+    compiler::Runner,
+    graph::ExprBody,
+    tensor::{Tensor, TensorView},
+};
+
+use super::{
+    compiler::{CpuCompiler, CpuPlan},
+    kernels,
+};
+
+/// Runs graphs on the CPU, splitting elementwise ops, reductions and matmuls across threads and
+/// vectorizing their inner loops. Fused elementwise ops are computed a chunk at a time, without
+/// materializing the values in between. Ops without such a kernel are evaluated like `interp`
+/// does.
+#[derive(Default)]
+pub struct CpuRunner {
+    // Rayon's global pool when unset.
+    pool: Option<ThreadPool>,
+}
+
+impl CpuRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A runner with its own pool of `threads` threads.
+    pub fn with_threads(threads: usize) -> Self {
+        Self {
+            pool: Some(
+                ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build()
+                    .expect("could not create thread pool"),
+            ),
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.pool
+            .as_ref()
+            .map_or_else(rayon::current_num_threads, ThreadPool::current_num_threads)
+    }
+}
+
+impl Runner for CpuRunner {
+    type Compiler = CpuCompiler;
+
+    type Runnable = CpuPlan;
+
+    fn preprocess(&mut self, plan: CpuPlan) -> CpuPlan {
+        plan
+    }
+
+    fn run(&mut self, plan: CpuPlan, inputs: &[TensorView]) -> Vec<Tensor> {
+        let graph = &plan.graph;
+
+        assert_eq!(
+            graph.inputs.len(),
+            inputs.len(),
+            "wrong number of inputs for graph"
+        );
+
+        let mut values = vec![None; graph.exprs.len()];
+
+        for (index, (&id, input)) in graph.inputs.iter().zip(inputs).enumerate() {
+            assert_eq!(
+                graph[id].layout.dims(),
+                input.layout.dims(),
+                "input {index} has the wrong layout"
+            );
+
+            let input = input.rebased();
+
+            // Ops index their operands forwards from the start of their data.
+            values[id.0] = Some(if input.layout.is_forward() {
+                input.to_tensor()
+            } else {
+                input.to_contiguous()
+            });
+        }
+
+        for buffer in &plan.program.buffers {
+            if let ExprBody::Const(tensor) = &graph[buffer.id].body {
+                values[buffer.id.0] = Some(tensor.clone());
+            }
+        }
+
+        let mut evaluate = || {
+            for (kernel, frees) in plan.program.kernels.iter().zip(&plan.frees) {
+                values[kernel.output.0] = Some(kernels::eval_kernel(graph, kernel, &values));
+
+                for free in frees {
+                    values[free.0] = None;
+                }
+            }
+        };
+
+        match &self.pool {
+            Some(pool) => pool.install(evaluate),
+            None => evaluate(),
+        }
+
+        graph
+            .outputs
+            .iter()
+            .map(|output| values[output.0].clone().unwrap())
+            .collect()
+    }
+}
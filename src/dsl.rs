@@ -0,0 +1,365 @@
+use std::{
+    cell::RefCell,
+    ops::{Add, Mul, Neg, Sub},
+    ptr,
+};
+
+use crate::{
+    graph::{ComplexOp, ElemwiseOp, ExprId, Graph, MovementOp, Op, ReduceOp},
+    tensor::{DimId, Layout, Shape, Tensor},
+};
+
+/// Builds a graph from an expression over its inputs, such as
+/// `graph! { (a: [3, 4], b: [3, 4]) -> { sin(a * b) + a } }`. Returns the graph, the ids of the
+/// inputs in declaration order, and the ids of the outputs. The body may evaluate to a single
+/// expression or a tuple of them.
+#[macro_export]
+macro_rules! graph {
+    (($($input:ident : [$($dim:expr),* $(,)?]),* $(,)?) -> { $($body:tt)* }) => {{
+        let graph = ::std::cell::RefCell::new($crate::graph::Graph::new());
+
+        $(
+            let $input = $crate::dsl::Var::input(
+                &graph,
+                $crate::tensor::Layout::from([$($dim),*]),
+            );
+        )*
+
+        let outputs = {
+            #[allow(unused_imports)]
+            use $crate::dsl::*;
+
+            $crate::dsl::Outputs::add_outputs({ $($body)* })
+        };
+        let inputs = ($($input.id(),)*);
+
+        (graph.into_inner(), inputs, outputs)
+    }};
+}
+
+#[derive(Copy, Clone)]
+pub struct Var<'a> {
+    graph: &'a RefCell<Graph>,
+    id: ExprId,
+}
+
+impl<'a> Var<'a> {
+    pub fn input(graph: &'a RefCell<Graph>, layout: Layout) -> Self {
+        let id = graph.borrow_mut().add_input(layout);
+
+        Self { graph, id }
+    }
+
+    pub fn constant(graph: &'a RefCell<Graph>, tensor: Tensor) -> Self {
+        let id = graph.borrow_mut().add_const(tensor);
+
+        Self { graph, id }
+    }
+
+    pub fn id(self) -> ExprId {
+        self.id
+    }
+
+    pub fn layout(self) -> Layout {
+        (*self.graph.borrow()[self.id].layout).clone()
+    }
+
+    fn op(self, op: Op, others: &[Var<'a>]) -> Self {
+        assert!(
+            others.iter().all(|other| ptr::eq(self.graph, other.graph)),
+            "expressions belong to different graphs"
+        );
+
+        let children = [self.id]
+            .into_iter()
+            .chain(others.iter().map(|other| other.id))
+            .collect::<Vec<_>>();
+        let id = self.graph.borrow_mut().add_op(op, &children);
+
+        Self {
+            graph: self.graph,
+            id,
+        }
+    }
+
+    // A constant that broadcasts against `self`.
+    fn scalar(self, value: f32) -> Self {
+        Var::constant(
+            self.graph,
+            Tensor::from_parts(
+                Box::new([value]),
+                Layout::from(vec![1; self.layout().rank()]),
+            ),
+        )
+    }
+}
+
+impl<'a> Add for Var<'a> {
+    type Output = Var<'a>;
+
+    fn add(self, rhs: Var<'a>) -> Var<'a> {
+        self.op(Op::Elemwise(ElemwiseOp::Add), &[rhs])
+    }
+}
+
+impl<'a> Add<f32> for Var<'a> {
+    type Output = Var<'a>;
+
+    fn add(self, rhs: f32) -> Var<'a> {
+        self + self.scalar(rhs)
+    }
+}
+
+impl<'a> Add<Var<'a>> for f32 {
+    type Output = Var<'a>;
+
+    fn add(self, rhs: Var<'a>) -> Var<'a> {
+        rhs + self
+    }
+}
+
+impl<'a> Mul for Var<'a> {
+    type Output = Var<'a>;
+
+    fn mul(self, rhs: Var<'a>) -> Var<'a> {
+        self.op(Op::Elemwise(ElemwiseOp::Mul), &[rhs])
+    }
+}
+
+impl<'a> Mul<f32> for Var<'a> {
+    type Output = Var<'a>;
+
+    fn mul(self, rhs: f32) -> Var<'a> {
+        self * self.scalar(rhs)
+    }
+}
+
+impl<'a> Mul<Var<'a>> for f32 {
+    type Output = Var<'a>;
+
+    fn mul(self, rhs: Var<'a>) -> Var<'a> {
+        rhs * self
+    }
+}
+
+impl<'a> Neg for Var<'a> {
+    type Output = Var<'a>;
+
+    fn neg(self) -> Var<'a> {
+        self * -1.0
+    }
+}
+
+impl<'a> Sub for Var<'a> {
+    type Output = Var<'a>;
+
+    fn sub(self, rhs: Var<'a>) -> Var<'a> {
+        self + -rhs
+    }
+}
+
+impl<'a> Sub<f32> for Var<'a> {
+    type Output = Var<'a>;
+
+    fn sub(self, rhs: f32) -> Var<'a> {
+        self + -rhs
+    }
+}
+
+impl<'a> Sub<Var<'a>> for f32 {
+    type Output = Var<'a>;
+
+    fn sub(self, rhs: Var<'a>) -> Var<'a> {
+        -rhs + self
+    }
+}
+
+pub fn sin(input: Var) -> Var {
+    input.op(Op::Elemwise(ElemwiseOp::Sin), &[])
+}
+
+pub fn rsqrt(input: Var) -> Var {
+    input.op(Op::Elemwise(ElemwiseOp::Rsqrt), &[])
+}
+
+pub fn exp(input: Var) -> Var {
+    input.op(Op::Elemwise(ElemwiseOp::Exp), &[])
+}
+
+pub fn log(input: Var) -> Var {
+    input.op(Op::Elemwise(ElemwiseOp::Log), &[])
+}
+
+pub fn tanh(input: Var) -> Var {
+    input.op(Op::Elemwise(ElemwiseOp::Tanh), &[])
+}
+
+pub fn floor(input: Var) -> Var {
+    input.op(Op::Elemwise(ElemwiseOp::Floor), &[])
+}
+
+pub fn ceil(input: Var) -> Var {
+    input.op(Op::Elemwise(ElemwiseOp::Ceil), &[])
+}
+
+/// `a * b + c` with a single rounding.
+pub fn fma<'a>(a: Var<'a>, b: Var<'a>, c: Var<'a>) -> Var<'a> {
+    a.op(Op::Elemwise(ElemwiseOp::Fma), &[b, c])
+}
+
+pub fn clamp<'a>(input: Var<'a>, low: Var<'a>, high: Var<'a>) -> Var<'a> {
+    input.op(Op::Elemwise(ElemwiseOp::Clamp), &[low, high])
+}
+
+pub fn matmul<'a>(left: Var<'a>, right: Var<'a>) -> Var<'a> {
+    left.op(Op::MatMul, &[right])
+}
+
+pub fn transpose(input: Var) -> Var {
+    input.op(Op::Movement(MovementOp::Transpose), &[])
+}
+
+pub fn reshape<'a>(input: Var<'a>, shape: impl Into<Shape>) -> Var<'a> {
+    input.op(Op::Movement(MovementOp::Reshape(shape.into())), &[])
+}
+
+pub fn squeeze(input: Var, dim: Option<DimId>) -> Var {
+    input.op(Op::Movement(MovementOp::Squeeze(dim)), &[])
+}
+
+pub fn unsqueeze(input: Var, dim: DimId) -> Var {
+    input.op(Op::Movement(MovementOp::Unsqueeze(dim)), &[])
+}
+
+pub fn unfold(input: Var, dim: DimId, size: usize, step: usize) -> Var {
+    input.op(Op::Movement(MovementOp::Unfold { dim, size, step }), &[])
+}
+
+pub fn flip<'a>(input: Var<'a>, dims: &[DimId]) -> Var<'a> {
+    input.op(Op::Movement(MovementOp::Flip(dims.to_vec())), &[])
+}
+
+pub fn repeat<'a>(input: Var<'a>, repeats: &[usize]) -> Var<'a> {
+    input.op(
+        Op::Repeat {
+            repeats: repeats.to_vec(),
+        },
+        &[],
+    )
+}
+
+pub fn diagonal(input: Var, offset: isize) -> Var {
+    input.op(Op::Diagonal { offset }, &[])
+}
+
+pub fn trace(input: Var) -> Var {
+    input.op(Op::Trace, &[])
+}
+
+pub fn complex_add<'a>(lhs: Var<'a>, rhs: Var<'a>) -> Var<'a> {
+    lhs.op(Op::Complex(ComplexOp::Add), &[rhs])
+}
+
+pub fn complex_mul<'a>(lhs: Var<'a>, rhs: Var<'a>) -> Var<'a> {
+    lhs.op(Op::Complex(ComplexOp::Mul), &[rhs])
+}
+
+pub fn conj(input: Var) -> Var {
+    input.op(Op::Complex(ComplexOp::Conj), &[])
+}
+
+pub fn nonzero(input: Var) -> Var {
+    input.op(Op::Nonzero, &[])
+}
+
+pub fn masked_select<'a>(input: Var<'a>, mask: Var<'a>) -> Var<'a> {
+    input.op(Op::MaskedSelect, &[mask])
+}
+
+pub fn sum<'a>(input: Var<'a>, dims: &[DimId]) -> Var<'a> {
+    input.op(
+        Op::Reduce {
+            op: ReduceOp::Sum,
+            dims: dims.to_vec(),
+        },
+        &[],
+    )
+}
+
+pub fn mean<'a>(input: Var<'a>, dims: &[DimId]) -> Var<'a> {
+    input.op(
+        Op::Reduce {
+            op: ReduceOp::Mean,
+            dims: dims.to_vec(),
+        },
+        &[],
+    )
+}
+
+pub fn max<'a>(input: Var<'a>, dims: &[DimId]) -> Var<'a> {
+    input.op(
+        Op::Reduce {
+            op: ReduceOp::Max,
+            dims: dims.to_vec(),
+        },
+        &[],
+    )
+}
+
+/// The position of the largest element along `dim`, as a float.
+pub fn argmax(input: Var, dim: DimId) -> Var {
+    input.op(
+        Op::Reduce {
+            op: ReduceOp::ArgMax,
+            dims: vec![dim],
+        },
+        &[],
+    )
+}
+
+/// The position of the smallest element along `dim`, as a float.
+pub fn argmin(input: Var, dim: DimId) -> Var {
+    input.op(
+        Op::Reduce {
+            op: ReduceOp::ArgMin,
+            dims: vec![dim],
+        },
+        &[],
+    )
+}
+
+pub trait Outputs {
+    type Ids;
+
+    fn add_outputs(self) -> Self::Ids;
+}
+
+impl Outputs for Var<'_> {
+    type Ids = ExprId;
+
+    fn add_outputs(self) -> ExprId {
+        self.graph.borrow_mut().add_output(self.id);
+
+        self.id
+    }
+}
+
+macro_rules! impl_outputs {
+    ($($var:ident),*) => {
+        impl<$($var: Outputs),*> Outputs for ($($var,)*) {
+            type Ids = ($($var::Ids,)*);
+
+            #[allow(non_snake_case)]
+            fn add_outputs(self) -> Self::Ids {
+                let ($($var,)*) = self;
+
+                ($($var.add_outputs(),)*)
+            }
+        }
+    };
+}
+
+impl_outputs!(A);
+impl_outputs!(A, B);
+impl_outputs!(A, B, C);
+impl_outputs!(A, B, C, D);
@@ -0,0 +1,69 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// A source of energy readings, which `bench::benchmark` samples around its timed runs.
+pub trait EnergySampler {
+    /// Returns a monotonically increasing energy counter, in joules.
+    fn sample(&mut self) -> io::Result<f64>;
+}
+
+pub struct RaplSampler {
+    energy_path: PathBuf,
+    max_energy_uj: u64,
+    last_uj: Option<u64>,
+    wraps: u64,
+}
+
+impl RaplSampler {
+    pub fn new(domain: impl AsRef<Path>) -> io::Result<Self> {
+        let domain = domain.as_ref();
+
+        Ok(Self {
+            energy_path: domain.join("energy_uj"),
+            max_energy_uj: read_counter(&domain.join("max_energy_range_uj"))?,
+            last_uj: None,
+            wraps: 0,
+        })
+    }
+
+    pub fn detect() -> io::Result<Self> {
+        Self::new("/sys/class/powercap/intel-rapl:0")
+    }
+}
+
+impl EnergySampler for RaplSampler {
+    fn sample(&mut self) -> io::Result<f64> {
+        let energy_uj = read_counter(&self.energy_path)?;
+
+        if self.last_uj.is_some_and(|last_uj| energy_uj < last_uj) {
+            self.wraps += 1;
+        }
+
+        self.last_uj = Some(energy_uj);
+
+        // The counter goes from its maximum back to zero, so each wrap skips one more than it.
+        let total_uj = energy_uj + self.wraps * (self.max_energy_uj + 1);
+
+        Ok(total_uj as f64 / 1e6)
+    }
+}
+
+fn read_counter(path: &Path) -> io::Result<u64> {
+    fs::read_to_string(path)?.trim().parse().map_err(|error| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a counter: {error}", path.display()),
+        )
+    })
+}
+
+/// The energy `f` takes, from samples before and after it. `f` always runs, even if sampling fails.
+pub fn measure<T>(sampler: &mut dyn EnergySampler, f: impl FnOnce() -> T) -> (T, io::Result<f64>) {
+    let start = sampler.sample();
+    let result = f();
+    let end = sampler.sample();
+
+    (result, start.and_then(|start| Ok(end? - start)))
+}
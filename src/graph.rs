@@ -1,26 +1,50 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::f32::consts::TAU;
 use std::fmt;
 use std::fmt::{Debug, Display, Formatter};
 use std::hash::Hash;
+use std::mem;
 use std::ops::{Index, IndexMut};
+use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
+use tracing::trace;
 
-use crate::tensor::{DimId, Layout, Shape, Tensor};
+use crate::rewrite::{Rewrite, Rewriter};
+use crate::tensor::{DType, DimId, Layout, ReshapeError, Shape, SparseTensor, Tensor};
 
 #[derive(Copy, Clone, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct ExprId(pub(crate) usize);
 
+impl ExprId {
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
 impl Debug for ExprId {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "@{}", self.0)
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ElemwiseOp {
     Add,
     Mul,
     Sin,
+    Rsqrt,
+    /// `a * b + c`, rounded once.
+    Fma,
+    Exp,
+    Log,
+    Tanh,
+    Floor,
+    Ceil,
+    /// Clamps the first child between the second and third.
+    Clamp,
 }
 
 impl Display for ElemwiseOp {
@@ -29,14 +53,49 @@ impl Display for ElemwiseOp {
             ElemwiseOp::Add => "add",
             ElemwiseOp::Mul => "mul",
             ElemwiseOp::Sin => "sin",
+            ElemwiseOp::Rsqrt => "rsqrt",
+            ElemwiseOp::Fma => "fma",
+            ElemwiseOp::Exp => "exp",
+            ElemwiseOp::Log => "log",
+            ElemwiseOp::Tanh => "tanh",
+            ElemwiseOp::Floor => "floor",
+            ElemwiseOp::Ceil => "ceil",
+            ElemwiseOp::Clamp => "clamp",
+        })
+    }
+}
+
+/// Elementwise ops on complex tensors, whose elements are stored along a last dimension of two
+/// like `DType::Complex64`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum ComplexOp {
+    Add,
+    Mul,
+    /// The complex conjugate.
+    Conj,
+}
+
+impl Display for ComplexOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ComplexOp::Add => "complex_add",
+            ComplexOp::Mul => "complex_mul",
+            ComplexOp::Conj => "conj",
         })
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ReduceOp {
     Sum,
     Max,
+    Mean,
+    /// The position of the first largest element, counted in row-major order over the reduced
+    /// dimensions, in the order they are given. Tensors only hold floats, which store positions
+    /// exactly up to 2^24.
+    ArgMax,
+    /// Like `ArgMax`, but for the first smallest element.
+    ArgMin,
 }
 
 impl Display for ReduceOp {
@@ -44,6 +103,9 @@ impl Display for ReduceOp {
         f.write_str(match self {
             ReduceOp::Sum => "sum",
             ReduceOp::Max => "max",
+            ReduceOp::Mean => "mean",
+            ReduceOp::ArgMax => "argmax",
+            ReduceOp::ArgMin => "argmin",
         })
     }
 }
@@ -54,11 +116,21 @@ impl Debug for ReduceOp {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum MovementOp {
     Reshape(Shape),
     Transpose,
-    Squeeze,
+    /// Drops a dimension of size one, or all of them if none is given.
+    Squeeze(Option<DimId>),
+    /// Inserts a dimension of size one before the given one.
+    Unsqueeze(DimId),
+    Unfold {
+        dim: DimId,
+        size: usize,
+        step: usize,
+    },
+    /// Reverses the order of the elements along each of the dimensions.
+    Flip(Vec<DimId>),
 }
 
 impl Display for MovementOp {
@@ -66,22 +138,507 @@ impl Display for MovementOp {
         f.write_str(match self {
             MovementOp::Reshape(_) => "reshape",
             MovementOp::Transpose => "transpose",
-            MovementOp::Squeeze => "squeeze",
+            MovementOp::Squeeze(_) => "squeeze",
+            MovementOp::Unsqueeze(_) => "unsqueeze",
+            MovementOp::Unfold { .. } => "unfold",
+            MovementOp::Flip(_) => "flip",
         })
     }
 }
 
-#[derive(Clone)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum Predicate {
+    Finite,
+    InRange { min: f32, max: f32 },
+}
+
+impl Predicate {
+    pub(crate) fn holds(&self, value: f32) -> bool {
+        match *self {
+            Predicate::Finite => value.is_finite(),
+            Predicate::InRange { min, max } => (min..=max).contains(&value),
+        }
+    }
+}
+
+/// The elements of a matmul's output that are computed. The rest are zero.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum MatMulMask {
+    /// Elements on or below the diagonal.
+    Lower,
+    /// Elements on or above the diagonal.
+    Upper,
+    /// The `size` by `size` tiles of the output, in row-major order, whose entry in `tiles` is
+    /// set. Tiles on the last row and column may be cut short.
+    Blocks { size: usize, tiles: Vec<bool> },
+}
+
+impl MatMulMask {
+    pub(crate) fn keeps(&self, row: usize, column: usize, columns: usize) -> bool {
+        match self {
+            MatMulMask::Lower => column <= row,
+            MatMulMask::Upper => column >= row,
+            MatMulMask::Blocks { size, tiles } => {
+                tiles[row / size * columns.div_ceil(*size) + column / size]
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum Distribution {
+    Uniform { low: f32, high: f32 },
+    Normal { mean: f32, std: f32 },
+}
+
+impl Distribution {
+    // Element `index` of a random tensor is drawn from the Philox4x32-10 block at counter `index`
+    // under the key `seed`, so the CPU and the GPU kernel agree bit for bit on the raw bits.
+    pub(crate) fn sample(&self, seed: u64, index: u32) -> f32 {
+        let [first, second, ..] = philox([index, 0, 0, 0], [seed as u32, (seed >> 32) as u32]);
+        let (first, second) = (
+            (first >> 8) as f32 / (1 << 24) as f32,
+            (second >> 8) as f32 / (1 << 24) as f32,
+        );
+
+        match *self {
+            Distribution::Uniform { low, high } => low + (high - low) * first,
+            Distribution::Normal { mean, std } => {
+                mean + std * (-2.0 * (1.0 - first).ln()).sqrt() * (TAU * second).cos()
+            }
+        }
+    }
+}
+
+// The factor element `index` of a dropout's input is multiplied by in training: zero for dropped
+// elements, and the inverse keep probability for the rest so the expected value is unchanged.
+pub(crate) fn dropout_scale(probability: f32, seed: u64, index: u32) -> f32 {
+    let uniform = Distribution::Uniform {
+        low: 0.0,
+        high: 1.0,
+    };
+
+    if uniform.sample(seed, index) >= probability {
+        1.0 / (1.0 - probability)
+    } else {
+        0.0
+    }
+}
+
+pub(crate) const PHILOX_MULTIPLIERS: [u32; 2] = [0xd2511f53, 0xcd9e8d57];
+pub(crate) const PHILOX_WEYL: [u32; 2] = [0x9e3779b9, 0xbb67ae85];
+
+fn philox(mut counter: [u32; 4], mut key: [u32; 2]) -> [u32; 4] {
+    for round in 0..10 {
+        if round > 0 {
+            key[0] = key[0].wrapping_add(PHILOX_WEYL[0]);
+            key[1] = key[1].wrapping_add(PHILOX_WEYL[1]);
+        }
+
+        let first = u64::from(PHILOX_MULTIPLIERS[0]) * u64::from(counter[0]);
+        let second = u64::from(PHILOX_MULTIPLIERS[1]) * u64::from(counter[2]);
+
+        counter = [
+            (second >> 32) as u32 ^ counter[1] ^ key[0],
+            second as u32,
+            (first >> 32) as u32 ^ counter[3] ^ key[1],
+            first as u32,
+        ];
+    }
+
+    counter
+}
+
+pub(crate) struct MatMulGeometry {
+    pub(crate) batch: Vec<usize>,
+    pub(crate) m: usize,
+    pub(crate) k: usize,
+    pub(crate) n: usize,
+    pub(crate) lhs_strides: Vec<usize>,
+    pub(crate) rhs_strides: Vec<usize>,
+    pub(crate) output_dims: Vec<usize>,
+}
+
+impl MatMulGeometry {
+    pub(crate) fn new(lhs: &Layout, rhs: &Layout) -> Self {
+        assert!(
+            lhs.rank() >= 1 && rhs.rank() >= 1,
+            "matmul operands must have at least one dimension"
+        );
+
+        let (mut lhs_dims, mut lhs_strides) = (lhs.dims().to_vec(), lhs.forward_strides());
+        let (mut rhs_dims, mut rhs_strides) = (rhs.dims().to_vec(), rhs.forward_strides());
+
+        if lhs.rank() == 1 {
+            lhs_dims.insert(0, 1);
+            lhs_strides.insert(0, 0);
+        }
+
+        if rhs.rank() == 1 {
+            rhs_dims.push(1);
+            rhs_strides.push(0);
+        }
+
+        let (lhs_batch, &[m, k]) = lhs_dims.split_at(lhs_dims.len() - 2) else {
+            unreachable!()
+        };
+        let (rhs_batch, &[rhs_k, n]) = rhs_dims.split_at(rhs_dims.len() - 2) else {
+            unreachable!()
+        };
+
+        assert_eq!(k, rhs_k, "matmul inner dimensions differ");
+
+        let rank = lhs_batch.len().max(rhs_batch.len());
+
+        let broadcast = |dims: &[usize], strides: &[usize]| {
+            (0..rank)
+                .map(move |dim| {
+                    (dim + dims.len())
+                        .checked_sub(rank)
+                        .map_or((1, 0), |dim| (dims[dim], strides[dim]))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let lhs_batch = broadcast(lhs_batch, &lhs_strides);
+        let rhs_batch = broadcast(rhs_batch, &rhs_strides);
+
+        let batch = lhs_batch
+            .iter()
+            .zip(rhs_batch.iter())
+            .map(|(&(lhs, _), &(rhs, _))| {
+                assert!(
+                    lhs == rhs || lhs == 1 || rhs == 1,
+                    "matmul batch dimensions {lhs} and {rhs} cannot be broadcast"
+                );
+
+                lhs.max(rhs)
+            })
+            .collect::<Vec<_>>();
+
+        let batch_strides = |dims: &[(usize, usize)]| {
+            dims.iter()
+                .map(|&(dim, stride)| if dim == 1 { 0 } else { stride })
+                .collect::<Vec<_>>()
+        };
+
+        let mut output_dims = batch.clone();
+
+        if lhs.rank() > 1 {
+            output_dims.push(m);
+        }
+
+        if rhs.rank() > 1 {
+            output_dims.push(n);
+        }
+
+        Self {
+            m,
+            k,
+            n,
+            lhs_strides: [
+                batch_strides(&lhs_batch),
+                lhs_strides[lhs_strides.len() - 2..].to_vec(),
+            ]
+            .concat(),
+            rhs_strides: [
+                batch_strides(&rhs_batch),
+                rhs_strides[rhs_strides.len() - 2..].to_vec(),
+            ]
+            .concat(),
+            batch,
+            output_dims,
+        }
+    }
+
+    pub(crate) fn bases(&self, mut index: usize) -> (usize, usize) {
+        let n = index % self.n;
+        index /= self.n;
+
+        let m = index % self.m;
+        index /= self.m;
+
+        let rank = self.batch.len();
+        let mut lhs = m * self.lhs_strides[rank];
+        let mut rhs = n * self.rhs_strides[rank + 1];
+
+        for dim in (0..rank).rev() {
+            lhs += (index % self.batch[dim]) * self.lhs_strides[dim];
+            rhs += (index % self.batch[dim]) * self.rhs_strides[dim];
+            index /= self.batch[dim];
+        }
+
+        (lhs, rhs)
+    }
+}
+
+pub(crate) struct AttentionGeometry {
+    pub(crate) batch: Vec<usize>,
+    pub(crate) queries: usize,
+    pub(crate) keys: usize,
+    pub(crate) head_dim: usize,
+    pub(crate) value_dim: usize,
+    pub(crate) query_strides: Vec<usize>,
+    pub(crate) key_strides: Vec<usize>,
+    pub(crate) value_strides: Vec<usize>,
+    pub(crate) output_dims: Vec<usize>,
+}
+
+impl AttentionGeometry {
+    pub(crate) fn new(query: &Layout, key: &Layout, value: &Layout) -> Self {
+        assert!(
+            query.rank() >= 2 && query.rank() == key.rank() && query.rank() == value.rank(),
+            "attention operands must have the same rank of at least two"
+        );
+
+        let rank = query.rank() - 2;
+        let batch = query.dims()[..rank].to_vec();
+
+        assert!(
+            key.dims()[..rank] == batch[..] && value.dims()[..rank] == batch[..],
+            "attention batch dimensions differ"
+        );
+
+        let &[queries, head_dim] = &query.dims()[rank..] else {
+            unreachable!()
+        };
+        let &[keys, key_dim] = &key.dims()[rank..] else {
+            unreachable!()
+        };
+        let &[values, value_dim] = &value.dims()[rank..] else {
+            unreachable!()
+        };
+
+        assert_eq!(
+            head_dim, key_dim,
+            "attention query and key dimensions differ"
+        );
+        assert_eq!(keys, values, "attention key and value counts differ");
+
+        Self {
+            output_dims: [&batch[..], &[queries, value_dim]].concat(),
+            batch,
+            queries,
+            keys,
+            head_dim,
+            value_dim,
+            query_strides: query.forward_strides(),
+            key_strides: key.forward_strides(),
+            value_strides: value.forward_strides(),
+        }
+    }
+
+    pub(crate) fn bases(&self, mut index: usize) -> (usize, usize, usize) {
+        let (mut query, mut key, mut value) = (0, 0, 0);
+
+        for dim in (0..self.batch.len()).rev() {
+            let position = index % self.batch[dim];
+
+            query += position * self.query_strides[dim];
+            key += position * self.key_strides[dim];
+            value += position * self.value_strides[dim];
+
+            index /= self.batch[dim];
+        }
+
+        (query, key, value)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum OpKind {
+    Elemwise,
+    Complex,
+    Reduce,
+    Movement,
+    Concat,
+    MatMul,
+    Attention,
+    Random,
+    Dropout,
+    Repeat,
+    Diagonal,
+    Trace,
+    Assert,
+    If,
+    Scan,
+    Nonzero,
+    MaskedSelect,
+    SpMM,
+    SpMV,
+    Fft,
+    Custom,
+}
+
+pub trait CustomOp: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn infer_layout(&self, children: &[&Layout]) -> Layout;
+
+    /// Returns a complete compute shader. The output is bound at binding 0 and each input `i` at
+    /// binding `i + 1`, all in group 0.
+    fn wgsl(&self, output: &Layout, inputs: &[&Layout]) -> String;
+
+    fn workgroups(&self, output: &Layout) -> [u32; 3];
+
+    /// A compute shader counting the workgroups to dispatch `wgsl` with, for ops whose work
+    /// depends on the data. It writes the three counts as `u32`s at binding 0, reads the inputs
+    /// like `wgsl` does, and runs as a single workgroup. `workgroups` is ignored when given.
+    fn indirect_wgsl(&self, _output: &Layout, _inputs: &[&Layout]) -> Option<String> {
+        None
+    }
+
+    /// Whether the shader gives bit-identical results on every run, which deterministic plans
+    /// require. Shaders that accumulate through float atomics or racing writes must not claim it.
+    fn deterministic(&self) -> bool {
+        false
+    }
+
+    fn eval(&self, _output: &Layout, _children: &[&Tensor]) -> Option<Tensor> {
+        None
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Op {
     Elemwise(ElemwiseOp),
-    Reduce { op: ReduceOp, dims: Vec<DimId> },
+    Complex(ComplexOp),
+    Reduce {
+        op: ReduceOp,
+        dims: Vec<DimId>,
+    },
     Movement(MovementOp),
+    Concat {
+        dim: DimId,
+    },
+    MatMul,
+    /// A matmul that only computes the elements of each output matrix that `mask` keeps.
+    MaskedMatMul {
+        mask: MatMulMask,
+    },
+    Attention {
+        scale: f32,
+    },
+    Random {
+        distribution: Distribution,
+        seed: u64,
+        shape: Shape,
+    },
+    Dropout {
+        probability: f32,
+        seed: u64,
+    },
+    /// Tiles the input `repeats[dim]` times along each dimension.
+    Repeat {
+        repeats: Vec<usize>,
+    },
+    /// The diagonal of the last two dimensions, indexed by a new last dimension. Positive offsets
+    /// take a diagonal right of the main one, negative ones below it.
+    Diagonal {
+        offset: isize,
+    },
+    /// The sum of the main diagonal of the last two dimensions, which are removed.
+    Trace,
+    Assert {
+        predicate: Predicate,
+        message: String,
+    },
+    /// Runs `then_graph` on the inputs if the predicate holds for the scalar first child, and
+    /// `else_graph` otherwise. The rest of the children are the inputs of both branches.
+    If {
+        predicate: Predicate,
+        then_graph: Arc<Graph>,
+        else_graph: Arc<Graph>,
+    },
+    /// Runs `body` once per position along `dim` of the sequence, the second child. The body takes
+    /// the state, starting from the first child, and the sequence at that position with `dim` kept
+    /// at size one, and returns the next state. All the states are stacked along a new first
+    /// dimension.
+    Scan {
+        body: Arc<Graph>,
+        dim: DimId,
+    },
+    /// The flat positions of the nonzero elements, in order, as floats. The output has as many
+    /// elements as the input, so the positions are followed by -1 once they run out.
+    Nonzero,
+    /// The elements of the first child where the mask, the second child of the same shape, is
+    /// nonzero, in order and followed by zeros up to the input's element count.
+    MaskedSelect,
+    /// The product of a sparse matrix, given by its values, columns and row offsets like
+    /// `SparseTensor::parts`, and a dense matrix, the fourth child.
+    SpMM,
+    /// Like `SpMM`, but with a dense vector.
+    SpMV,
+    /// The discrete Fourier transform of a complex tensor along `dim`, whose size must be a power
+    /// of two. The inverse transform is scaled by one over that size, so it undoes the forward one.
+    Fft {
+        dim: DimId,
+        inverse: bool,
+    },
+    #[serde(skip)]
+    Custom(Arc<dyn CustomOp>),
 }
 
 impl Op {
+    pub fn kind(&self) -> OpKind {
+        match self {
+            Op::Elemwise(_) => OpKind::Elemwise,
+            Op::Complex(_) => OpKind::Complex,
+            Op::Reduce { .. } => OpKind::Reduce,
+            Op::Movement(_) => OpKind::Movement,
+            Op::Concat { .. } => OpKind::Concat,
+            Op::MatMul | Op::MaskedMatMul { .. } => OpKind::MatMul,
+            Op::Attention { .. } => OpKind::Attention,
+            Op::Random { .. } => OpKind::Random,
+            Op::Dropout { .. } => OpKind::Dropout,
+            Op::Repeat { .. } => OpKind::Repeat,
+            Op::Diagonal { .. } => OpKind::Diagonal,
+            Op::Trace => OpKind::Trace,
+            Op::Assert { .. } => OpKind::Assert,
+            Op::If { .. } => OpKind::If,
+            Op::Scan { .. } => OpKind::Scan,
+            Op::Nonzero => OpKind::Nonzero,
+            Op::MaskedSelect => OpKind::MaskedSelect,
+            Op::SpMM => OpKind::SpMM,
+            Op::SpMV => OpKind::SpMV,
+            Op::Fft { .. } => OpKind::Fft,
+            Op::Custom(_) => OpKind::Custom,
+        }
+    }
+
     pub(crate) fn infer_layout(&self, children: &[&Layout]) -> Layout {
         match self {
-            Op::Elemwise(_) => children[0].clone(),
+            Op::Elemwise(_) => Layout::from(
+                (0..children[0].rank())
+                    .map(|dim| {
+                        let size = children
+                            .iter()
+                            .map(|child| child.dims()[dim])
+                            .max()
+                            .unwrap();
+
+                        assert!(
+                            children
+                                .iter()
+                                .all(|child| [1, size].contains(&child.dims()[dim])),
+                            "elementwise operands cannot be broadcast together"
+                        );
+
+                        size
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+            Op::Complex(_) => {
+                assert!(
+                    children
+                        .iter()
+                        .all(|child| child.dims().last() == Some(&DType::Complex64.components())),
+                    "complex operands must hold pairs along their last dimension"
+                );
+
+                Op::Elemwise(ElemwiseOp::Add).infer_layout(children)
+            }
+            Op::Assert { .. } => children[0].clone(),
             Op::Reduce {
                 dims: reduce_dims, ..
             } => {
@@ -94,40 +651,192 @@ impl Op {
                 Layout::from(dims)
             }
             Op::Movement(op) => match op {
-                MovementOp::Reshape(shape) => children[0].reshape(shape.clone()),
+                // Reshapes the strides can't express are copied into a contiguous buffer.
+                MovementOp::Reshape(shape) => match children[0].reshape(shape.clone()) {
+                    Ok(layout) => layout,
+                    Err(ReshapeError::NeedsCopy { .. }) => Layout::from(shape.dims()),
+                    Err(error) => panic!("{error}"),
+                },
                 MovementOp::Transpose => {
                     let rank = children[0].shape().rank();
 
-                    let mut dims = children[0].dims().to_vec();
-                    dims.swap(rank - 2, rank - 1);
+                    let mut order = (0..rank).collect::<Vec<_>>();
+                    order.swap(rank - 2, rank - 1);
 
-                    let mut strides = children[0].strides().to_vec();
-                    strides.swap(rank - 2, rank - 1);
+                    children[0].permute(&order)
+                }
+                MovementOp::Squeeze(dim) => children[0].squeeze(*dim),
+                MovementOp::Unsqueeze(dim) => children[0].unsqueeze(*dim),
+                MovementOp::Unfold { dim, size, step } => children[0].unfold(*dim, *size, *step),
+                // Buffers on the device can't be walked backwards, so flips are copied.
+                MovementOp::Flip(dims) => children[0].flip(dims).contiguous(),
+            },
+            Op::Concat { dim } => {
+                let mut dims = children[0].dims().to_vec();
 
-                    Layout {
-                        shape: Shape {
-                            dims: dims.into_boxed_slice(),
-                            strides: strides.into_boxed_slice(),
-                        },
-                    }
+                dims[*dim] = children.iter().map(|layout| layout.dims()[*dim]).sum();
+
+                Layout::from(dims)
+            }
+            Op::MatMul => Layout::from(MatMulGeometry::new(children[0], children[1]).output_dims),
+            Op::MaskedMatMul { mask } => {
+                let geometry = MatMulGeometry::new(children[0], children[1]);
+
+                if let MatMulMask::Blocks { size, tiles } = mask {
+                    assert!(*size > 0, "matmul mask tiles must be non-empty");
+                    assert_eq!(
+                        tiles.len(),
+                        geometry.m.div_ceil(*size) * geometry.n.div_ceil(*size),
+                        "matmul mask does not cover the output"
+                    );
                 }
-                MovementOp::Squeeze => {
-                    let (dims, strides): (Vec<_>, Vec<_>) = children[0]
-                        .dims()
-                        .iter()
-                        .copied()
-                        .zip(children[0].strides().iter().copied())
-                        .filter(|&(dim, _)| dim != 1)
-                        .unzip();
-
-                    Layout {
-                        shape: Shape {
-                            dims: dims.into_boxed_slice(),
-                            strides: strides.into_boxed_slice(),
-                        },
+
+                Layout::from(geometry.output_dims)
+            }
+            Op::Attention { .. } => Layout::from(
+                AttentionGeometry::new(children[0], children[1], children[2]).output_dims,
+            ),
+            Op::Random { shape, .. } => Layout::from(shape.dims()),
+            Op::Dropout { .. } => Layout::from(children[0].dims()),
+            Op::Repeat { repeats } => children[0].repeat(repeats),
+            Op::Diagonal { offset } => children[0].diagonal(*offset),
+            Op::Trace => {
+                let diagonal = children[0].diagonal_view(0);
+
+                Layout::from(&diagonal.dims()[..diagonal.rank() - 1])
+            }
+            Op::If {
+                then_graph,
+                else_graph,
+                ..
+            } => {
+                assert_eq!(children[0].elements(), 1, "if condition must be a scalar");
+
+                let output_dims = |branch: &Graph| {
+                    assert_eq!(
+                        branch.inputs.len(),
+                        children.len() - 1,
+                        "wrong number of inputs for if branch"
+                    );
+                    assert!(
+                        branch
+                            .inputs
+                            .iter()
+                            .zip(&children[1..])
+                            .all(|(input, child)| branch[*input].layout.dims() == child.dims()),
+                        "if branch inputs do not match the given inputs"
+                    );
+                    assert_eq!(
+                        branch.outputs.len(),
+                        1,
+                        "if branches must have exactly one output"
+                    );
+
+                    branch[branch.outputs[0]].layout.dims().to_vec()
+                };
+
+                let dims = output_dims(then_graph);
+
+                assert_eq!(
+                    dims,
+                    output_dims(else_graph),
+                    "if branches have different output shapes"
+                );
+
+                Layout::from(dims)
+            }
+            Op::Scan { body, dim } => {
+                let (state, sequence) = (children[0], children[1]);
+                let mut slice = sequence.dims().to_vec();
+
+                slice[*dim] = 1;
+
+                assert!(
+                    body.inputs.len() == 2 && body.outputs.len() == 1,
+                    "scan bodies take a state and a slice and return the next state"
+                );
+                assert_eq!(
+                    body[body.inputs[0]].layout.dims(),
+                    state.dims(),
+                    "scan body state does not match the initial state"
+                );
+                assert_eq!(
+                    body[body.inputs[1]].layout.dims(),
+                    slice,
+                    "scan body slice does not match the sequence"
+                );
+                assert_eq!(
+                    body[body.outputs[0]].layout.dims(),
+                    state.dims(),
+                    "scan body changes the shape of the state"
+                );
+
+                Layout::from([&[sequence.dims()[*dim]], state.dims()].concat())
+            }
+            Op::Nonzero => Layout::from([children[0].elements()]),
+            Op::MaskedSelect => {
+                assert_eq!(
+                    children[0].dims(),
+                    children[1].dims(),
+                    "masked select mask does not match the input"
+                );
+
+                Layout::from([children[0].elements()])
+            }
+            Op::SpMM | Op::SpMV => {
+                let (values, columns, offsets, dense) =
+                    (children[0], children[1], children[2], children[3]);
+
+                assert!(
+                    values.rank() == 1 && columns.rank() == 1 && offsets.rank() == 1,
+                    "sparse matrix parts must be one dimensional"
+                );
+                assert_eq!(
+                    values.elements(),
+                    columns.elements(),
+                    "sparse matrix has a different number of values and columns"
+                );
+                assert!(
+                    offsets.elements() > 0,
+                    "sparse matrix row offsets must include the end of the last row"
+                );
+
+                let rows = offsets.elements() - 1;
+
+                match self {
+                    Op::SpMM => {
+                        assert_eq!(dense.rank(), 2, "sparse matmul takes a dense matrix");
+
+                        Layout::from([rows, dense.dims()[1]])
+                    }
+                    _ => {
+                        assert_eq!(dense.rank(), 1, "sparse matvec takes a dense vector");
+
+                        Layout::from([rows])
                     }
                 }
-            },
+            }
+            Op::Fft { dim, .. } => {
+                let input = children[0];
+
+                assert_eq!(
+                    input.dims().last(),
+                    Some(&DType::Complex64.components()),
+                    "fft input must hold complex pairs along its last dimension"
+                );
+                assert!(
+                    *dim < input.rank() - 1,
+                    "fft dim {dim} is not a complex dimension of {input}"
+                );
+                assert!(
+                    input.dims()[*dim].is_power_of_two(),
+                    "fft size {} is not a power of two",
+                    input.dims()[*dim]
+                );
+
+                Layout::from(input.dims())
+            }
+            Op::Custom(op) => op.infer_layout(children),
         }
     }
 }
@@ -137,6 +846,50 @@ impl Op {
         match self {
             Op::Reduce { dims, .. } => vec![("dims", Box::new(dims))],
             Op::Movement(MovementOp::Reshape(shape)) => vec![("shape", Box::new(shape))],
+            Op::Movement(MovementOp::Flip(dims)) => vec![("dims", Box::new(dims))],
+            Op::Movement(MovementOp::Squeeze(Some(dim)) | MovementOp::Unsqueeze(dim)) => {
+                vec![("dim", Box::new(dim))]
+            }
+            Op::Movement(MovementOp::Unfold { dim, size, step }) => vec![
+                ("dim", Box::new(dim)),
+                ("size", Box::new(size)),
+                ("step", Box::new(step)),
+            ],
+            Op::Concat { dim } => vec![("dim", Box::new(dim))],
+            Op::Attention { scale } => vec![("scale", Box::new(scale))],
+            Op::Random {
+                distribution,
+                seed,
+                shape,
+            } => vec![
+                ("distribution", Box::new(distribution)),
+                ("seed", Box::new(seed)),
+                ("shape", Box::new(shape)),
+            ],
+            Op::Dropout { probability, seed } => vec![
+                ("probability", Box::new(probability)),
+                ("seed", Box::new(seed)),
+            ],
+            Op::Repeat { repeats } => vec![("repeats", Box::new(repeats))],
+            Op::Diagonal { offset } => vec![("offset", Box::new(offset))],
+            Op::MaskedMatMul { mask } => vec![("mask", Box::new(mask))],
+            Op::Assert { predicate, message } => vec![
+                ("predicate", Box::new(predicate)),
+                ("message", Box::new(message)),
+            ],
+            Op::If {
+                predicate,
+                then_graph,
+                else_graph,
+            } => vec![
+                ("predicate", Box::new(predicate)),
+                ("then", Box::new(then_graph)),
+                ("else", Box::new(else_graph)),
+            ],
+            Op::Scan { dim, body } => vec![("dim", Box::new(dim)), ("body", Box::new(body))],
+            Op::Fft { dim, inverse } => {
+                vec![("dim", Box::new(dim)), ("inverse", Box::new(inverse))]
+            }
             _ => vec![],
         }
     }
@@ -146,8 +899,27 @@ impl Debug for Op {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.write_str(&match self {
             Op::Elemwise(op) => op.to_string(),
+            Op::Complex(op) => op.to_string(),
             Op::Reduce { op, .. } => op.to_string(),
             Op::Movement(op) => op.to_string(),
+            Op::Concat { .. } => String::from("concat"),
+            Op::MatMul => String::from("matmul"),
+            Op::MaskedMatMul { .. } => String::from("masked_matmul"),
+            Op::Attention { .. } => String::from("attention"),
+            Op::Random { .. } => String::from("random"),
+            Op::Dropout { .. } => String::from("dropout"),
+            Op::Repeat { .. } => String::from("repeat"),
+            Op::Diagonal { .. } => String::from("diagonal"),
+            Op::Trace => String::from("trace"),
+            Op::Assert { .. } => String::from("assert"),
+            Op::If { .. } => String::from("if"),
+            Op::Scan { .. } => String::from("scan"),
+            Op::Nonzero => String::from("nonzero"),
+            Op::MaskedSelect => String::from("masked_select"),
+            Op::SpMM => String::from("spmm"),
+            Op::SpMV => String::from("spmv"),
+            Op::Fft { .. } => String::from("fft"),
+            Op::Custom(op) => op.name().to_owned(),
         })?;
 
         let parameters = self.parameters();
@@ -168,13 +940,30 @@ impl Debug for Op {
     }
 }
 
-#[derive(Clone)]
+const MAX_PRINTED_CONST: usize = 16;
+
+// Almost every op has at most three children, so they are stored inline.
+pub(crate) type Children = SmallVec<[ExprId; 3]>;
+
+#[derive(Clone, Serialize, Deserialize)]
 pub(crate) enum ExprBody {
-    Op { op: Op, children: Vec<ExprId> },
+    Op { op: Op, children: Children },
     Input(Layout),
     Const(Tensor),
 }
 
+impl ExprBody {
+    fn infer_layout<'a>(&self, layout_of: impl Fn(ExprId) -> &'a Layout) -> Layout {
+        match self {
+            ExprBody::Op { op, children } => {
+                op.infer_layout(&children.iter().copied().map(layout_of).collect::<Vec<_>>())
+            }
+            ExprBody::Input(layout) => layout.clone(),
+            ExprBody::Const(tensor) => tensor.layout.clone(),
+        }
+    }
+}
+
 impl Debug for ExprBody {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
@@ -188,23 +977,141 @@ impl Debug for ExprBody {
                 result.finish()
             }
             ExprBody::Input(_) => f.write_str("?"),
+            // Small consts are spelled out, so that the text of most hand-written graphs parses
+            // back into them.
+            ExprBody::Const(tensor)
+                if tensor.layout.is_contiguous()
+                    && tensor.layout.elements() <= MAX_PRINTED_CONST =>
+            {
+                write!(f, "{:?}", tensor.data)
+            }
             ExprBody::Const(_) => f.write_str(".."),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExprInfo {
     pub(crate) body: ExprBody,
-    pub(crate) layout: Layout,
+    pub(crate) layout: Arc<Layout>,
     pub(crate) last_usage: ExprId,
 }
 
-#[derive(Default)]
+const GRAPH_FORMAT_VERSION: u32 = 5;
+
+#[derive(Debug)]
+pub enum GraphFormatError {
+    UnsupportedVersion(u32),
+    Decode(bincode::Error),
+    /// Custom ops hold code, so graphs using them cannot be encoded. Holds the op's name.
+    CustomOp(String),
+}
+
+impl Display for GraphFormatError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            GraphFormatError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported graph format version {version} (expected {GRAPH_FORMAT_VERSION})"
+            ),
+            GraphFormatError::Decode(error) => write!(f, "could not decode graph: {error}"),
+            GraphFormatError::CustomOp(name) => {
+                write!(f, "cannot encode graph with custom op {name}")
+            }
+        }
+    }
+}
+
+impl Error for GraphFormatError {}
+
+impl From<bincode::Error> for GraphFormatError {
+    fn from(value: bincode::Error) -> Self {
+        Self::Decode(value)
+    }
+}
+
+/// A summary of a graph's size. Branch and scan bodies count as the single op that runs them,
+/// except for their FLOPs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GraphStats {
+    pub ops: HashMap<OpKind, usize>,
+    /// The elements of every const.
+    pub parameters: usize,
+    pub flops: usize,
+    /// The most ops on a path from an input or const to any expression.
+    pub depth: usize,
+    /// The most ops at the same depth, which could all run at once.
+    pub width: usize,
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
 pub struct Graph {
     pub(crate) inputs: Vec<ExprId>,
     pub(crate) exprs: Vec<ExprInfo>,
     pub(crate) outputs: Vec<ExprId>,
+    pub(crate) mode: Mode,
+    pub(crate) keep_f32: BTreeSet<ExprId>,
+    pub(crate) names: BTreeMap<ExprId, String>,
+    #[serde(skip)]
+    scope: Vec<String>,
+    #[serde(skip)]
+    layouts: Layouts,
+}
+
+/// The expressions holding the parts of a sparse matrix.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SparseExpr {
+    pub values: ExprId,
+    pub columns: ExprId,
+    pub offsets: ExprId,
+}
+
+impl SparseExpr {
+    /// The product with a dense matrix or vector, through `SpMM` or `SpMV` by its rank.
+    pub fn matmul(self, graph: &mut Graph, dense: ExprId) -> ExprId {
+        let op = match graph[dense].layout.rank() {
+            1 => Op::SpMV,
+            _ => Op::SpMM,
+        };
+
+        graph.add_op(op, &[self.values, self.columns, self.offsets, dense])
+    }
+}
+
+/// Whether training-only ops such as dropout take effect when the graph is evaluated or compiled.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Mode {
+    #[default]
+    Training,
+    Inference,
+}
+
+// Expressions share one allocation per distinct layout, since most graphs only have a handful.
+#[derive(Default, Clone)]
+struct Layouts(HashSet<Arc<Layout>>);
+
+impl Layouts {
+    fn intern(&mut self, layout: Layout) -> Arc<Layout> {
+        if let Some(layout) = self.0.get(&layout) {
+            return layout.clone();
+        }
+
+        let layout = Arc::new(layout);
+
+        self.0.insert(layout.clone());
+
+        layout
+    }
+
+    fn reintern(&mut self, layout: Arc<Layout>) -> Arc<Layout> {
+        if let Some(layout) = self.0.get(&layout) {
+            return layout.clone();
+        }
+
+        self.0.insert(layout.clone());
+
+        layout
+    }
 }
 
 impl Index<ExprId> for Graph {
@@ -226,31 +1133,190 @@ impl Graph {
         Self::default()
     }
 
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    /// Marks `exprs` as sensitive to precision, such as a softmax or a loss, so passes that lower
+    /// precision leave them in f32.
+    pub fn keep_f32(&mut self, exprs: &[ExprId]) {
+        self.keep_f32.extend(exprs.iter().copied());
+    }
+
+    pub fn keeps_f32(&self, expr: ExprId) -> bool {
+        self.keep_f32.contains(&expr)
+    }
+
+    /// Names the expressions `build` adds after `name`, nested under any enclosing scopes as in
+    /// `encoder.layer1`. Names show up when the graph is printed and label the GPU work computing
+    /// the expressions, so that captures in graphics debuggers are easier to navigate.
+    pub fn scope<T>(&mut self, name: &str, build: impl FnOnce(&mut Self) -> T) -> T {
+        self.scope.push(name.to_owned());
+
+        let result = build(self);
+
+        self.scope.pop();
+
+        result
+    }
+
+    pub fn name(&self, expr: ExprId) -> Option<&str> {
+        self.names.get(&expr).map(String::as_str)
+    }
+
+    // The name of the innermost scope, followed by `name` if there is one.
+    fn scoped_name(&self, name: Option<&str>) -> Option<String> {
+        let path = self
+            .scope
+            .iter()
+            .map(String::as_str)
+            .chain(name)
+            .collect::<Vec<_>>();
+
+        (!path.is_empty()).then(|| path.join("."))
+    }
+
+    // The id of an expression as printed, followed by its name if it has one.
+    fn label(&self, id: ExprId) -> String {
+        match self.name(id) {
+            Some(name) => format!("{id:?} {name:?}"),
+            None => format!("{id:?}"),
+        }
+    }
+
+    /// The expressions `outputs` are computed from without going through `inputs`, including
+    /// `outputs` themselves but not `inputs`. Useful for marking a whole subgraph.
+    pub fn region(&self, inputs: &[ExprId], outputs: &[ExprId]) -> Vec<ExprId> {
+        let mut region = BTreeSet::new();
+        let mut pending = outputs.to_vec();
+
+        while let Some(id) = pending.pop() {
+            if inputs.contains(&id) || !region.insert(id) {
+                continue;
+            }
+
+            if let ExprBody::Op { children, .. } = &self[id].body {
+                pending.extend(children.iter().copied());
+            }
+        }
+
+        region.into_iter().collect()
+    }
+
     pub(crate) fn last_usages(&self) -> Vec<ExprId> {
         self.exprs.iter().map(|expr| expr.last_usage).collect()
     }
 
-    fn add_expr(&mut self, expr: ExprBody) -> ExprId {
-        let id = ExprId(self.exprs.len());
-
-        let layout = match &expr {
-            ExprBody::Op { op, children } => {
-                for expr in children.iter().copied() {
-                    self[expr].last_usage = id;
-                }
+    pub fn stats(&self) -> GraphStats {
+        let mut stats = GraphStats::default();
+        let mut depths = vec![0; self.exprs.len()];
+        let mut layers = HashMap::new();
 
-                op.infer_layout(
-                    &children
+        for (index, expr) in self.exprs.iter().enumerate() {
+            match &expr.body {
+                ExprBody::Op { op, children } => {
+                    depths[index] = 1 + children
                         .iter()
-                        .copied()
-                        .map(|expr| &self[expr].layout)
-                        .collect::<Vec<_>>(),
-                )
+                        .map(|child| depths[child.0])
+                        .max()
+                        .unwrap_or(0);
+
+                    *stats.ops.entry(op.kind()).or_default() += 1;
+                    *layers.entry(depths[index]).or_default() += 1;
+                    stats.flops += self.flops(ExprId(index));
+                }
+                ExprBody::Const(tensor) => stats.parameters += tensor.layout.elements(),
+                ExprBody::Input(_) => {}
             }
-            ExprBody::Input(layout) => layout.clone(),
-            ExprBody::Const(tensor) => tensor.layout.clone(),
+        }
+
+        stats.depth = depths.into_iter().max().unwrap_or(0);
+        stats.width = layers.into_values().max().unwrap_or(0);
+
+        stats
+    }
+
+    /// A rough count of the floating point operations computing `id` takes, for cost estimates.
+    /// Data movement counts as none, and branches as the more expensive of the two.
+    pub(crate) fn flops(&self, id: ExprId) -> usize {
+        let ExprBody::Op { op, children } = &self[id].body else {
+            return 0;
+        };
+
+        let elements = self[id].layout.elements();
+        let input = |index: usize| &self[children[index]].layout;
+        let total = |graph: &Graph| {
+            (0..graph.exprs.len())
+                .map(|id| graph.flops(ExprId(id)))
+                .sum::<usize>()
         };
 
+        match op {
+            Op::Elemwise(_) | Op::Random { .. } | Op::Dropout { .. } => elements,
+            // Each complex product takes four multiplies and two adds, over two stored elements.
+            Op::Complex(ComplexOp::Mul) => 3 * elements,
+            Op::Complex(_) => elements,
+            Op::Reduce { .. } => input(0).elements(),
+            Op::Trace => {
+                let dims = input(0).dims();
+
+                elements * dims[dims.len() - 2].min(dims[dims.len() - 1])
+            }
+            Op::MatMul | Op::MaskedMatMul { .. } => {
+                2 * elements * MatMulGeometry::new(input(0), input(1)).k
+            }
+            Op::Attention { .. } => {
+                let geometry = AttentionGeometry::new(input(0), input(1), input(2));
+
+                // Both products, plus a handful of operations per score for the softmax.
+                geometry.batch.iter().product::<usize>()
+                    * geometry.queries
+                    * geometry.keys
+                    * (2 * geometry.head_dim + 2 * geometry.value_dim + 5)
+            }
+            Op::If {
+                then_graph,
+                else_graph,
+                ..
+            } => total(then_graph).max(total(else_graph)),
+            Op::Scan { body, dim } => input(1).dims()[*dim] * total(body),
+            Op::Movement(_)
+            | Op::Concat { .. }
+            | Op::Repeat { .. }
+            | Op::Diagonal { .. }
+            | Op::Assert { .. }
+            | Op::Custom(_) => 0,
+            Op::Nonzero | Op::MaskedSelect => input(0).elements(),
+            // Each radix-2 step takes a complex multiply and two adds per pair of elements.
+            Op::Fft { dim, .. } => 5 * elements / 2 * input(0).dims()[*dim].ilog2() as usize,
+            Op::SpMM | Op::SpMV => {
+                2 * input(0).elements() * self[id].layout.dims()[1..].iter().product::<usize>()
+            }
+        }
+    }
+
+    pub(crate) fn add_expr(&mut self, expr: ExprBody) -> ExprId {
+        let id = ExprId(self.exprs.len());
+
+        let layout = expr.infer_layout(|child| &self[child].layout);
+        let layout = self.layouts.intern(layout);
+
+        if let ExprBody::Op { children, .. } = &expr {
+            for expr in children.iter().copied() {
+                self[expr].last_usage = id;
+            }
+        }
+
+        trace!(?id, layout = %layout, "added expression");
+
+        if let Some(name) = self.scoped_name(None) {
+            self.names.insert(id, name);
+        }
+
         self.exprs.push(ExprInfo {
             body: expr,
             layout,
@@ -261,6 +1327,11 @@ impl Graph {
     }
 
     pub fn add_input(&mut self, layout: Layout) -> ExprId {
+        assert!(
+            layout.is_forward(),
+            "graph inputs must walk forwards from the start of their buffer"
+        );
+
         let id = self.add_expr(ExprBody::Input(layout));
 
         self.inputs.push(id);
@@ -268,20 +1339,424 @@ impl Graph {
         id
     }
 
+    // Like inputs, consts walk forwards from the start of their data.
     pub fn add_const(&mut self, tensor: Tensor) -> ExprId {
+        let tensor = if tensor.layout.is_forward() {
+            tensor
+        } else {
+            tensor.view().to_contiguous()
+        };
+
         self.add_expr(ExprBody::Const(tensor))
     }
 
+    /// Adds the parts of a sparse matrix with `rows` rows and `nnz` entries as three inputs, which
+    /// are given like `SparseTensor::parts`.
+    pub fn add_sparse_input(&mut self, rows: usize, nnz: usize) -> SparseExpr {
+        SparseExpr {
+            values: self.add_input(Layout::from([nnz])),
+            columns: self.add_input(Layout::from([nnz])),
+            offsets: self.add_input(Layout::from([rows + 1])),
+        }
+    }
+
+    pub fn add_sparse_const(&mut self, tensor: &SparseTensor) -> SparseExpr {
+        let [values, columns, offsets] = tensor.parts().map(|part| self.add_const(part));
+
+        SparseExpr {
+            values,
+            columns,
+            offsets,
+        }
+    }
+
     pub fn add_op(&mut self, op: Op, children: &[ExprId]) -> ExprId {
         self.add_expr(ExprBody::Op {
             op,
-            children: children.to_owned(),
+            children: Children::from_slice(children),
         })
     }
 
     pub fn add_output(&mut self, expr: ExprId) {
         self.outputs.push(expr);
     }
+
+    /// Starts a shard of expressions built on top of the graph as it is now. Shards only borrow
+    /// the graph, so several can be built at once on different threads and merged afterwards.
+    pub fn shard(&self) -> GraphShard<'_> {
+        GraphShard {
+            graph: self,
+            fragment: Fragment {
+                base: self.exprs.len(),
+                exprs: Vec::new(),
+                inputs: Vec::new(),
+                outputs: Vec::new(),
+                layouts: Layouts::default(),
+            },
+        }
+    }
+
+    /// Appends a finished shard's expressions, inputs and outputs to the graph. The returned
+    /// remap translates the shard's ids into ids in the graph.
+    pub fn merge(&mut self, fragment: Fragment) -> Remap {
+        assert!(
+            fragment.base <= self.exprs.len(),
+            "fragment was sharded from a different graph"
+        );
+
+        let remap = Remap {
+            base: fragment.base,
+            offset: self.exprs.len(),
+        };
+
+        for mut expr in fragment.exprs {
+            let id = ExprId(self.exprs.len());
+
+            if let ExprBody::Op { children, .. } = &mut expr.body {
+                for child in children {
+                    *child = remap.get(*child);
+                    self[*child].last_usage = id;
+                }
+            }
+
+            expr.last_usage = id;
+            expr.layout = self.layouts.reintern(expr.layout);
+            self.exprs.push(expr);
+        }
+
+        self.inputs
+            .extend(fragment.inputs.into_iter().map(|id| remap.get(id)));
+        self.outputs
+            .extend(fragment.outputs.into_iter().map(|id| remap.get(id)));
+
+        remap
+    }
+
+    /// Instantiates `graph` inside this one, with `inputs` standing in for its inputs. Returns the
+    /// ids its outputs end up at.
+    pub fn inline(&mut self, graph: &Graph, inputs: &[ExprId]) -> Vec<ExprId> {
+        assert_eq!(
+            graph.inputs.len(),
+            inputs.len(),
+            "wrong number of inputs for inlined graph"
+        );
+
+        let mut ids = vec![None; graph.exprs.len()];
+
+        for (&input, &id) in graph.inputs.iter().zip(inputs) {
+            assert_eq!(
+                graph[input].layout.dims(),
+                self[id].layout.dims(),
+                "{id:?} does not have the shape of the inlined graph's input {input:?}"
+            );
+
+            ids[input.0] = Some(id);
+        }
+
+        for (index, expr) in graph.exprs.iter().enumerate() {
+            if ids[index].is_some() {
+                continue;
+            }
+
+            ids[index] = Some(
+                self.add_expr(match &expr.body {
+                    ExprBody::Op { op, children } => ExprBody::Op {
+                        op: op.clone(),
+                        children: children
+                            .iter()
+                            .map(|child| ids[child.0].expect("children precede the expression"))
+                            .collect(),
+                    },
+                    ExprBody::Input(_) => {
+                        panic!("inlined graph has an input outside its input list")
+                    }
+                    ExprBody::Const(tensor) => ExprBody::Const(tensor.clone()),
+                }),
+            );
+        }
+
+        for &id in &graph.keep_f32 {
+            if !graph.inputs.contains(&id) {
+                self.keep_f32.insert(ids[id.0].unwrap());
+            }
+        }
+
+        for (&id, name) in &graph.names {
+            if !graph.inputs.contains(&id) {
+                let name = self.scoped_name(Some(name)).unwrap();
+
+                self.names.insert(ids[id.0].unwrap(), name);
+            }
+        }
+
+        graph
+            .outputs
+            .iter()
+            .map(|output| ids[output.0].unwrap())
+            .collect()
+    }
+
+    /// A copy of the graph that can be shared without leaking its contents: const data is replaced
+    /// by random values of the same layout, and assertion messages by generic ones. Structure,
+    /// layouts and ops are kept, though failures that depend on specific values may not reproduce.
+    /// Names are dropped.
+    pub fn anonymize(&self, seed: u64) -> Graph {
+        let mut graph = self.clone();
+        let mut assertions = 0;
+
+        graph.names.clear();
+
+        for (id, expr) in graph.exprs.iter_mut().enumerate() {
+            match &mut expr.body {
+                ExprBody::Const(tensor) => {
+                    let distribution = Distribution::Uniform {
+                        low: -1.0,
+                        high: 1.0,
+                    };
+
+                    tensor.data = (0..tensor.data.len() as u32)
+                        .map(|index| distribution.sample(seed.wrapping_add(id as u64), index))
+                        .collect();
+                }
+                ExprBody::Op {
+                    op: Op::Assert { message, .. },
+                    ..
+                } => {
+                    *message = format!("assertion {assertions}");
+                    assertions += 1;
+                }
+                ExprBody::Op {
+                    op:
+                        Op::If {
+                            then_graph,
+                            else_graph,
+                            ..
+                        },
+                    ..
+                } => {
+                    let seed = seed.wrapping_add(id as u64);
+
+                    *then_graph = Arc::new(then_graph.anonymize(seed));
+                    *else_graph = Arc::new(else_graph.anonymize(!seed));
+                }
+                ExprBody::Op {
+                    op: Op::Scan { body, .. },
+                    ..
+                } => *body = Arc::new(body.anonymize(seed.wrapping_add(id as u64))),
+                _ => {}
+            }
+        }
+
+        graph
+    }
+
+    // A copy for laying out again with other inputs, which only keeps the layouts of its consts of
+    // more than one element. Those are left as inputs missing from the input list, which
+    // `with_input_layouts` turns back into consts.
+    pub(crate) fn without_const_data(&self) -> Graph {
+        Graph {
+            inputs: self.inputs.clone(),
+            exprs: self
+                .exprs
+                .iter()
+                .map(|expr| match &expr.body {
+                    ExprBody::Const(tensor) if tensor.layout.elements() > 1 => ExprInfo {
+                        body: ExprBody::Input(tensor.layout.clone()),
+                        layout: expr.layout.clone(),
+                        last_usage: expr.last_usage,
+                    },
+                    _ => expr.clone(),
+                })
+                .collect(),
+            outputs: self.outputs.clone(),
+            mode: self.mode,
+            keep_f32: self.keep_f32.clone(),
+            names: self.names.clone(),
+            ..Graph::default()
+        }
+    }
+
+    // The graph with inputs of `layouts`, in the order of its input list, and every expression
+    // laid out again to match. Consts left without data by `without_const_data` are zeros, whose
+    // pages the allocator only maps once they are written.
+    pub(crate) fn with_input_layouts(&self, layouts: &[Layout]) -> Graph {
+        let inputs = self
+            .inputs
+            .iter()
+            .zip(layouts)
+            .map(|(&id, layout)| (id, layout))
+            .collect::<HashMap<_, _>>();
+        let mut graph = Graph::new();
+
+        for (id, expr) in (0..).map(ExprId).zip(&self.exprs) {
+            graph.add_expr(match (&expr.body, inputs.get(&id)) {
+                (ExprBody::Input(_), Some(&layout)) => ExprBody::Input(layout.clone()),
+                (ExprBody::Input(layout), None) => ExprBody::Const(Tensor::from_parts(
+                    vec![0.0; layout.size() / mem::size_of::<f32>()].into(),
+                    layout.clone(),
+                )),
+                (body, _) => body.clone(),
+            });
+        }
+
+        graph.inputs = self.inputs.clone();
+        graph.outputs = self.outputs.clone();
+        graph.mode = self.mode;
+        graph.keep_f32 = self.keep_f32.clone();
+        graph.names = self.names.clone();
+
+        graph
+    }
+
+    /// A copy of the graph with random ops and training dropout replaced by consts drawn ahead of
+    /// time, so every run of it computes exactly the same thing. Each op's seed is xored with
+    /// `seed`, so zero freezes the values the graph would draw on the device.
+    pub fn freeze_rng(&self, seed: u64) -> Graph {
+        Rewriter::new()
+            .rule(Rewrite::freeze_random(seed))
+            .rule(Rewrite::freeze_dropout(seed))
+            .rewrite(self)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, GraphFormatError> {
+        if let Some(name) = self.custom_op() {
+            return Err(GraphFormatError::CustomOp(name.to_owned()));
+        }
+
+        Ok(bincode::serialize(&(GRAPH_FORMAT_VERSION, self))
+            .expect("graphs without custom ops always encode"))
+    }
+
+    // The name of a custom op in the graph or any of its branch and scan bodies, if there is one.
+    fn custom_op(&self) -> Option<&str> {
+        self.exprs.iter().find_map(|expr| match &expr.body {
+            ExprBody::Op { op, .. } => match op {
+                Op::Custom(op) => Some(op.name()),
+                Op::If {
+                    then_graph,
+                    else_graph,
+                    ..
+                } => then_graph.custom_op().or_else(|| else_graph.custom_op()),
+                Op::Scan { body, .. } => body.custom_op(),
+                _ => None,
+            },
+            _ => None,
+        })
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, GraphFormatError> {
+        let version: u32 = bincode::deserialize(bytes)?;
+
+        if version != GRAPH_FORMAT_VERSION {
+            return Err(GraphFormatError::UnsupportedVersion(version));
+        }
+
+        let (_, mut graph): (u32, Graph) = bincode::deserialize(bytes)?;
+
+        for expr in &mut graph.exprs {
+            expr.layout = graph.layouts.reintern(expr.layout.clone());
+        }
+
+        Ok(graph)
+    }
+}
+
+/// Expressions built against a snapshot of a graph. Ids below the snapshot's size refer to the
+/// graph's expressions, and the rest to the shard's own.
+pub struct GraphShard<'a> {
+    graph: &'a Graph,
+    fragment: Fragment,
+}
+
+impl Index<ExprId> for GraphShard<'_> {
+    type Output = ExprInfo;
+
+    fn index(&self, index: ExprId) -> &Self::Output {
+        match index.0.checked_sub(self.fragment.base) {
+            Some(index) => &self.fragment.exprs[index],
+            None => &self.graph[index],
+        }
+    }
+}
+
+impl GraphShard<'_> {
+    fn add_expr(&mut self, expr: ExprBody) -> ExprId {
+        let id = ExprId(self.fragment.base + self.fragment.exprs.len());
+        let layout = expr.infer_layout(|child| &self[child].layout);
+        let layout = self.fragment.layouts.intern(layout);
+
+        // Usages are only known once the shard is merged.
+        self.fragment.exprs.push(ExprInfo {
+            body: expr,
+            layout,
+            last_usage: id,
+        });
+
+        id
+    }
+
+    pub fn add_input(&mut self, layout: Layout) -> ExprId {
+        assert!(
+            layout.is_forward(),
+            "graph inputs must walk forwards from the start of their buffer"
+        );
+
+        let id = self.add_expr(ExprBody::Input(layout));
+
+        self.fragment.inputs.push(id);
+
+        id
+    }
+
+    // Like inputs, consts walk forwards from the start of their data.
+    pub fn add_const(&mut self, tensor: Tensor) -> ExprId {
+        let tensor = if tensor.layout.is_forward() {
+            tensor
+        } else {
+            tensor.view().to_contiguous()
+        };
+
+        self.add_expr(ExprBody::Const(tensor))
+    }
+
+    pub fn add_op(&mut self, op: Op, children: &[ExprId]) -> ExprId {
+        self.add_expr(ExprBody::Op {
+            op,
+            children: Children::from_slice(children),
+        })
+    }
+
+    pub fn add_output(&mut self, expr: ExprId) {
+        self.fragment.outputs.push(expr);
+    }
+
+    /// Releases the borrow of the graph so the shard can be merged into it.
+    pub fn finish(self) -> Fragment {
+        self.fragment
+    }
+}
+
+pub struct Fragment {
+    base: usize,
+    exprs: Vec<ExprInfo>,
+    inputs: Vec<ExprId>,
+    outputs: Vec<ExprId>,
+    layouts: Layouts,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Remap {
+    base: usize,
+    offset: usize,
+}
+
+impl Remap {
+    pub fn get(&self, id: ExprId) -> ExprId {
+        match id.0.checked_sub(self.base) {
+            Some(index) => ExprId(self.offset + index),
+            None => id,
+        }
+    }
 }
 
 impl Debug for Graph {
@@ -291,7 +1766,7 @@ impl Debug for Graph {
             "({}) -> ({}) {{",
             self.inputs
                 .iter()
-                .map(|input| format!("{input:?}: {}", self[*input].layout))
+                .map(|input| format!("{}: {}", self.label(*input), self[*input].layout))
                 .collect::<Vec<_>>()
                 .join(", "),
             self.outputs
@@ -315,8 +1790,9 @@ impl Debug for Graph {
                 .filter(|(info, _)| !matches!(info.body, ExprBody::Input(..)))
                 .map(|(node, id)| {
                     format!(
-                        "{}{id:?}: {} = {:?};",
+                        "{}{}: {} = {:?};",
                         if f.alternate() { "    " } else { "" },
+                        self.label(id),
                         node.layout,
                         node.body
                     )
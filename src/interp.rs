@@ -0,0 +1,1100 @@
+use std::{f64::consts::TAU, iter};
+
+use crate::{
+    graph::{
+        dropout_scale, AttentionGeometry, ComplexOp, ElemwiseOp, ExprBody, ExprId, Graph,
+        MatMulGeometry, Mode, MovementOp, Op, ReduceOp,
+    },
+    tensor::{DimId, Layout, Shape, Tensor},
+};
+
+fn indices(dims: &[usize]) -> impl Iterator<Item = Vec<usize>> + '_ {
+    let elements = dims.iter().product::<usize>();
+
+    (0..elements).map(move |mut flat| {
+        let mut index = vec![0; dims.len()];
+
+        for (dim, size) in dims.iter().enumerate().rev() {
+            index[dim] = flat % size;
+            flat /= size;
+        }
+
+        index
+    })
+}
+
+// Dimensions of size one are broadcast, whatever their index.
+fn element(layout: &Layout, index: &[usize]) -> usize {
+    layout.dims().iter().zip(index).enumerate().fold(
+        layout.offset(),
+        |element, (dim, (&size, &index))| {
+            if size == 1 {
+                element
+            } else {
+                layout.step(element, dim, index)
+            }
+        },
+    )
+}
+
+fn get(tensor: &Tensor, index: &[usize]) -> f32 {
+    tensor.data[element(&tensor.layout, index)]
+}
+
+// The complex element of `tensor` at `index`, which leaves out the last dimension its parts are
+// stored along.
+fn get_complex(tensor: &Tensor, index: &[usize]) -> (f32, f32) {
+    (
+        get(tensor, &[index, &[0]].concat()),
+        get(tensor, &[index, &[1]].concat()),
+    )
+}
+
+fn accumulate_complex(grad: &mut Tensor, index: &[usize], (re, im): (f32, f32)) {
+    accumulate(grad, &[index, &[0]].concat(), re);
+    accumulate(grad, &[index, &[1]].concat(), im);
+}
+
+fn from_fn(layout: &Layout, f: impl Fn(&[usize]) -> f32) -> Tensor {
+    let layout = layout.contiguous();
+
+    Tensor::from_parts(
+        indices(layout.dims()).map(|index| f(&index)).collect(),
+        layout,
+    )
+}
+
+pub(crate) fn contiguous(tensor: &Tensor) -> Tensor {
+    from_fn(&tensor.layout, |index| get(tensor, index))
+}
+
+pub(crate) fn elemwise(op: ElemwiseOp, args: &[f32]) -> f32 {
+    match op {
+        ElemwiseOp::Add => args[0] + args[1],
+        ElemwiseOp::Mul => args[0] * args[1],
+        ElemwiseOp::Sin => args[0].sin(),
+        ElemwiseOp::Rsqrt => 1.0 / args[0].sqrt(),
+        ElemwiseOp::Fma => args[0].mul_add(args[1], args[2]),
+        ElemwiseOp::Exp => args[0].exp(),
+        ElemwiseOp::Log => args[0].ln(),
+        ElemwiseOp::Tanh => args[0].tanh(),
+        ElemwiseOp::Floor => args[0].floor(),
+        ElemwiseOp::Ceil => args[0].ceil(),
+        // Matches WGSL rather than `f32::clamp`, which panics when the bounds are out of order.
+        ElemwiseOp::Clamp => args[0].max(args[1]).min(args[2]),
+    }
+}
+
+fn reduce(op: ReduceOp, values: impl Iterator<Item = f32>) -> f32 {
+    match op {
+        // NaNs never compare as better, so they are only picked when they come first.
+        ReduceOp::ArgMax | ReduceOp::ArgMin => {
+            let better = |value: f32, best: f32| match op {
+                ReduceOp::ArgMax => value > best,
+                _ => value < best,
+            };
+
+            values
+                .enumerate()
+                .fold(None, |best, (position, value)| match best {
+                    Some((_, best_value)) if !better(value, best_value) => best,
+                    _ => Some((position, value)),
+                })
+                .map_or(0.0, |(position, _)| position as f32)
+        }
+        ReduceOp::Sum => values.sum(),
+        ReduceOp::Max => values.fold(f32::NEG_INFINITY, f32::max),
+        ReduceOp::Mean => {
+            let (sum, count) =
+                values.fold((0.0, 0), |(sum, count), value| (sum + value, count + 1));
+
+            sum / count as f32
+        }
+    }
+}
+
+fn attention_probabilities(
+    geometry: &AttentionGeometry,
+    scale: f32,
+    query: &Tensor,
+    key: &Tensor,
+    (query_base, key_base): (usize, usize),
+    row: usize,
+) -> Vec<f32> {
+    let rank = geometry.batch.len();
+    let (query_strides, key_strides) = (
+        &geometry.query_strides[rank..],
+        &geometry.key_strides[rank..],
+    );
+
+    let scores = (0..geometry.keys)
+        .map(|column| {
+            scale
+                * (0..geometry.head_dim)
+                    .map(|dim| {
+                        query.data[query_base + row * query_strides[0] + dim * query_strides[1]]
+                            * key.data[key_base + column * key_strides[0] + dim * key_strides[1]]
+                    })
+                    .sum::<f32>()
+        })
+        .collect::<Vec<_>>();
+
+    let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let weights = scores
+        .iter()
+        .map(|score| (score - max).exp())
+        .collect::<Vec<_>>();
+    let total = weights.iter().sum::<f32>();
+
+    weights.into_iter().map(|weight| weight / total).collect()
+}
+
+fn view(tensor: &Tensor, layout: Layout) -> Tensor {
+    Tensor::from_parts(tensor.data.clone(), layout)
+}
+
+// Whether element `index` of a matmul's output is computed rather than masked to zero.
+fn keeps(op: &Op, geometry: &MatMulGeometry, index: usize) -> bool {
+    match op {
+        Op::MaskedMatMul { mask } => mask.keeps(
+            index / geometry.n % geometry.m,
+            index % geometry.n,
+            geometry.n,
+        ),
+        _ => true,
+    }
+}
+
+// The index into a matrix batch of element `index` of its diagonal at `offset`.
+fn diagonal_index(index: &[usize], offset: isize) -> Vec<usize> {
+    let (position, batch) = index.split_last().unwrap();
+    let (row, column) = if offset >= 0 {
+        (0, offset.unsigned_abs())
+    } else {
+        (offset.unsigned_abs(), 0)
+    };
+
+    [batch, &[position + row, position + column]].concat()
+}
+
+// The entries of a row of the sparse matrix in the first three children, with their columns.
+fn row_entries<'a>(
+    children: &'a [&Tensor],
+    row: usize,
+) -> impl Iterator<Item = (usize, usize)> + 'a {
+    let (columns, offsets) = (children[1], children[2]);
+
+    (get(offsets, &[row]) as usize..get(offsets, &[row + 1]) as usize)
+        .map(move |entry| (entry, get(columns, &[entry]) as usize))
+}
+
+// The unscaled discrete Fourier transform of a complex tensor along `dim`, with the positive
+// exponent of the inverse transform when `inverse` is set.
+fn dft(input: &Tensor, dim: DimId, inverse: bool) -> Tensor {
+    let size = input.layout.dims()[dim];
+    let sign = if inverse { 1.0 } else { -1.0 };
+
+    from_fn(&input.layout, |index| {
+        let (&part, index) = index.split_last().unwrap();
+        let mut position = index.to_vec();
+        let (mut re, mut im) = (0.0, 0.0);
+
+        for source in 0..size {
+            position[dim] = source;
+
+            let (a, b) = get_complex(input, &position);
+            let angle = sign * TAU * ((index[dim] * source) % size) as f64 / size as f64;
+            let (sin, cos) = angle.sin_cos();
+
+            re += a as f64 * cos - b as f64 * sin;
+            im += a as f64 * sin + b as f64 * cos;
+        }
+
+        [re, im][part] as f32
+    })
+}
+
+fn dropout_mask(probability: f32, seed: u64, layout: &Layout) -> Tensor {
+    Tensor::from_parts(
+        (0..layout.elements() as u32)
+            .map(|index| dropout_scale(probability, seed, index))
+            .collect(),
+        layout.contiguous(),
+    )
+}
+
+fn scan_slice(sequence: &Tensor, dim: DimId, position: usize) -> Tensor {
+    let mut dims = sequence.layout.dims().to_vec();
+
+    dims[dim] = 1;
+
+    from_fn(&Layout::from(dims), |index| {
+        let mut index = index.to_vec();
+
+        index[dim] = position;
+
+        get(sequence, &index)
+    })
+}
+
+// The initial state followed by the state after each position, all contiguous.
+fn scan_states(
+    body: &Graph,
+    dim: DimId,
+    mode: Mode,
+    state: &Tensor,
+    sequence: &Tensor,
+) -> Vec<Tensor> {
+    let mut states = vec![contiguous(state)];
+
+    for position in 0..sequence.layout.dims()[dim] {
+        let values = eval_exprs(
+            body,
+            mode,
+            vec![
+                states.last().unwrap().clone(),
+                scan_slice(sequence, dim, position),
+            ],
+        );
+
+        states.push(contiguous(values[body.outputs[0].0].as_ref().unwrap()));
+    }
+
+    states
+}
+
+pub(crate) fn eval_op(op: &Op, mode: Mode, layout: &Layout, children: &[&Tensor]) -> Tensor {
+    match op {
+        Op::Elemwise(op) => from_fn(layout, |index| {
+            elemwise(
+                *op,
+                &children
+                    .iter()
+                    .map(|child| get(child, index))
+                    .collect::<Vec<_>>(),
+            )
+        }),
+        Op::Complex(op) => from_fn(layout, |index| {
+            let (&part, index) = index.split_last().unwrap();
+            let (re, im) = match op {
+                ComplexOp::Add => {
+                    let ((a, b), (c, d)) = (
+                        get_complex(children[0], index),
+                        get_complex(children[1], index),
+                    );
+
+                    (a + c, b + d)
+                }
+                ComplexOp::Mul => {
+                    let ((a, b), (c, d)) = (
+                        get_complex(children[0], index),
+                        get_complex(children[1], index),
+                    );
+
+                    (a * c - b * d, a * d + b * c)
+                }
+                ComplexOp::Conj => {
+                    let (a, b) = get_complex(children[0], index);
+
+                    (a, -b)
+                }
+            };
+
+            [re, im][part]
+        }),
+        Op::Reduce { op, dims } => {
+            let input = children[0];
+
+            from_fn(layout, |index| {
+                let reduced_dims = dims.iter().map(|&dim| input.layout.dims()[dim]);
+
+                reduce(
+                    *op,
+                    indices(&reduced_dims.collect::<Vec<_>>()).map(|reduced_index| {
+                        let mut index = index.to_vec();
+
+                        for (&dim, &reduced) in dims.iter().zip(&reduced_index) {
+                            index[dim] = reduced;
+                        }
+
+                        get(input, &index)
+                    }),
+                )
+            })
+        }
+        Op::Movement(op) => {
+            let input = children[0];
+
+            match op {
+                MovementOp::Reshape(shape) => input
+                    .clone()
+                    .reshape(shape.clone())
+                    .unwrap_or_else(|error| panic!("{error}")),
+                MovementOp::Transpose => {
+                    let rank = input.layout.rank();
+
+                    let mut order = (0..rank).collect::<Vec<_>>();
+                    order.swap(rank - 2, rank - 1);
+
+                    view(input, input.layout.permute(&order))
+                }
+                MovementOp::Squeeze(dim) => view(input, input.layout.squeeze(*dim)),
+                MovementOp::Unsqueeze(dim) => view(input, input.layout.unsqueeze(*dim)),
+                MovementOp::Unfold { dim, size, step } => {
+                    view(input, input.layout.unfold(*dim, *size, *step))
+                }
+                MovementOp::Flip(dims) => contiguous(&view(input, input.layout.flip(dims))),
+            }
+        }
+        Op::Concat { dim } => from_fn(layout, |index| {
+            let mut index = index.to_vec();
+
+            for child in children {
+                let size = child.layout.dims()[*dim];
+
+                if index[*dim] < size {
+                    return get(child, &index);
+                }
+
+                index[*dim] -= size;
+            }
+
+            unreachable!("concat index out of bounds")
+        }),
+        Op::MatMul | Op::MaskedMatMul { .. } => {
+            let (lhs, rhs) = (children[0], children[1]);
+            let geometry = MatMulGeometry::new(&lhs.layout, &rhs.layout);
+
+            let lhs_k = geometry.lhs_strides[geometry.lhs_strides.len() - 1];
+            let rhs_k = geometry.rhs_strides[geometry.batch.len()];
+
+            Tensor::from_parts(
+                (0..layout.elements())
+                    .map(|index| {
+                        if !keeps(op, &geometry, index) {
+                            return 0.0;
+                        }
+
+                        let (lhs_base, rhs_base) = geometry.bases(index);
+
+                        (0..geometry.k)
+                            .map(|k| {
+                                lhs.data[lhs_base + k * lhs_k] * rhs.data[rhs_base + k * rhs_k]
+                            })
+                            .sum()
+                    })
+                    .collect(),
+                layout.contiguous(),
+            )
+        }
+        Op::Attention { scale } => {
+            let (query, key, value) = (children[0], children[1], children[2]);
+            let geometry = AttentionGeometry::new(&query.layout, &key.layout, &value.layout);
+            let value_strides = &geometry.value_strides[geometry.batch.len()..];
+
+            let mut data = Vec::with_capacity(layout.elements());
+
+            for batch in 0..geometry.batch.iter().product() {
+                let (query_base, key_base, value_base) = geometry.bases(batch);
+
+                for row in 0..geometry.queries {
+                    let probabilities = attention_probabilities(
+                        &geometry,
+                        *scale,
+                        query,
+                        key,
+                        (query_base, key_base),
+                        row,
+                    );
+
+                    data.extend((0..geometry.value_dim).map(|column| {
+                        probabilities
+                            .iter()
+                            .enumerate()
+                            .map(|(key, probability)| {
+                                probability
+                                    * value.data[value_base
+                                        + key * value_strides[0]
+                                        + column * value_strides[1]]
+                            })
+                            .sum::<f32>()
+                    }));
+                }
+            }
+
+            Tensor::from_parts(data.into_boxed_slice(), layout.contiguous())
+        }
+        Op::Random {
+            distribution, seed, ..
+        } => Tensor::from_parts(
+            (0..layout.elements() as u32)
+                .map(|index| distribution.sample(*seed, index))
+                .collect(),
+            layout.clone(),
+        ),
+        Op::Dropout { probability, seed } => match mode {
+            Mode::Training => {
+                let mask = dropout_mask(*probability, *seed, layout);
+
+                from_fn(layout, |index| get(children[0], index) * get(&mask, index))
+            }
+            Mode::Inference => contiguous(children[0]),
+        },
+        Op::Repeat { repeats } => {
+            let input = children[0];
+
+            if input.layout.can_expand(repeats) {
+                view(input, input.layout.repeat(repeats))
+            } else {
+                from_fn(layout, |index| {
+                    get(
+                        input,
+                        &index
+                            .iter()
+                            .zip(input.layout.dims())
+                            .map(|(index, dim)| index % dim)
+                            .collect::<Vec<_>>(),
+                    )
+                })
+            }
+        }
+        Op::Diagonal { offset } => {
+            let input = children[0];
+
+            from_fn(layout, |index| get(input, &diagonal_index(index, *offset)))
+        }
+        Op::Trace => {
+            let input = children[0];
+            let diagonal = input.layout.diagonal_view(0);
+            let length = diagonal.dims()[diagonal.rank() - 1];
+
+            from_fn(layout, |index| {
+                (0..length)
+                    .map(|position| get(input, &diagonal_index(&[index, &[position]].concat(), 0)))
+                    .sum()
+            })
+        }
+        Op::Assert { predicate, message } => {
+            let input = contiguous(children[0]);
+
+            let failures = input
+                .data
+                .iter()
+                .filter(|&&value| !predicate.holds(value))
+                .count();
+
+            assert!(
+                failures == 0,
+                "assertion failed: {message} ({failures} elements)"
+            );
+
+            input
+        }
+        Op::If {
+            predicate,
+            then_graph,
+            else_graph,
+        } => {
+            let branch = if predicate.holds(children[0].data[0]) {
+                then_graph
+            } else {
+                else_graph
+            };
+
+            let values = eval_exprs(
+                branch,
+                mode,
+                children[1..].iter().map(|&child| child.clone()).collect(),
+            );
+
+            contiguous(values[branch.outputs[0].0].as_ref().unwrap())
+        }
+        Op::Scan { body, dim } => {
+            let states = scan_states(body, *dim, mode, children[0], children[1]);
+
+            Tensor::from_parts(
+                states[1..]
+                    .iter()
+                    .flat_map(|state| state.data.iter().copied())
+                    .collect(),
+                layout.clone(),
+            )
+        }
+        Op::Nonzero => {
+            let input = contiguous(children[0]);
+            let positions = input
+                .data
+                .iter()
+                .enumerate()
+                .filter(|&(_, &value)| value != 0.0)
+                .map(|(position, _)| position as f32);
+
+            Tensor::from_parts(
+                positions
+                    .chain(iter::repeat(-1.0))
+                    .take(layout.elements())
+                    .collect(),
+                layout.clone(),
+            )
+        }
+        Op::MaskedSelect => {
+            let (input, mask) = (contiguous(children[0]), contiguous(children[1]));
+            let selected = input
+                .data
+                .iter()
+                .zip(mask.data.iter())
+                .filter(|&(_, &mask)| mask != 0.0)
+                .map(|(&value, _)| value);
+
+            Tensor::from_parts(
+                selected
+                    .chain(iter::repeat(0.0))
+                    .take(layout.elements())
+                    .collect(),
+                layout.clone(),
+            )
+        }
+        Op::Fft { dim, inverse } => {
+            let scale = if *inverse {
+                1.0 / layout.dims()[*dim] as f32
+            } else {
+                1.0
+            };
+
+            dft(children[0], *dim, *inverse).map(|value| value * scale)
+        }
+        Op::SpMM | Op::SpMV => {
+            let (values, dense) = (children[0], children[3]);
+
+            from_fn(layout, |index| {
+                row_entries(children, index[0])
+                    .map(|(entry, column)| {
+                        get(values, &[entry]) * get(dense, &[&[column], &index[1..]].concat())
+                    })
+                    .sum()
+            })
+        }
+        Op::Custom(op) => op
+            .eval(layout, children)
+            .unwrap_or_else(|| panic!("custom op {} has no CPU implementation", op.name())),
+    }
+}
+
+// Branches of an `if` are evaluated in the mode of the graph containing them.
+fn eval_exprs(graph: &Graph, mode: Mode, inputs: Vec<Tensor>) -> Vec<Option<Tensor>> {
+    assert_eq!(
+        graph.inputs.len(),
+        inputs.len(),
+        "wrong number of inputs for graph"
+    );
+
+    let mut values = vec![None; graph.exprs.len()];
+
+    // Ops index their operands forwards from the start of their data.
+    for (id, input) in graph.inputs.iter().zip(inputs) {
+        values[id.0] = Some(if input.layout.is_forward() {
+            input
+        } else {
+            input.view().to_contiguous()
+        });
+    }
+
+    for &output in graph.outputs.iter() {
+        eval_expr(graph, mode, output, &mut values);
+    }
+
+    values
+}
+
+fn eval_expr(graph: &Graph, mode: Mode, id: ExprId, values: &mut Vec<Option<Tensor>>) -> Tensor {
+    if let Some(value) = &values[id.0] {
+        return value.clone();
+    }
+
+    let value = match &graph[id].body {
+        ExprBody::Op { op, children } => {
+            let children = children
+                .iter()
+                .map(|&child| eval_expr(graph, mode, child, values))
+                .collect::<Vec<_>>();
+
+            eval_op(
+                op,
+                mode,
+                &graph[id].layout,
+                &children.iter().collect::<Vec<_>>(),
+            )
+        }
+        ExprBody::Input(_) => panic!("missing value for input {id:?}"),
+        ExprBody::Const(tensor) => tensor.clone(),
+    };
+
+    values[id.0] = Some(value.clone());
+
+    value
+}
+
+pub fn eval(graph: &Graph, inputs: Vec<Tensor>) -> Vec<Tensor> {
+    let values = eval_exprs(graph, graph.mode, inputs);
+
+    graph
+        .outputs
+        .iter()
+        .map(|output| values[output.0].clone().unwrap())
+        .collect()
+}
+
+fn accumulate(grad: &mut Tensor, index: &[usize], value: f32) {
+    let element = element(&grad.layout, index);
+
+    grad.data[element] += value;
+}
+
+fn backward_op(
+    op: &Op,
+    mode: Mode,
+    grad: &Tensor,
+    children: &[&Tensor],
+    output: &Tensor,
+) -> Vec<Tensor> {
+    let mut grads = children
+        .iter()
+        .map(|child| from_fn(&child.layout, |_| 0.0))
+        .collect::<Vec<_>>();
+
+    match op {
+        Op::Elemwise(op) => {
+            for index in indices(grad.layout.dims()) {
+                let grad_value = get(grad, &index);
+                let args = children
+                    .iter()
+                    .map(|child| get(child, &index))
+                    .collect::<Vec<_>>();
+
+                let partials = match op {
+                    ElemwiseOp::Add => vec![1.0, 1.0],
+                    ElemwiseOp::Mul => vec![args[1], args[0]],
+                    ElemwiseOp::Sin => vec![args[0].cos()],
+                    ElemwiseOp::Rsqrt => vec![-0.5 / (args[0] * args[0].sqrt())],
+                    ElemwiseOp::Fma => vec![args[1], args[0], 1.0],
+                    ElemwiseOp::Exp => vec![args[0].exp()],
+                    ElemwiseOp::Log => vec![1.0 / args[0]],
+                    ElemwiseOp::Tanh => vec![1.0 - args[0].tanh().powi(2)],
+                    ElemwiseOp::Floor | ElemwiseOp::Ceil => vec![0.0],
+                    // The gradient flows to whichever child the result was taken from.
+                    ElemwiseOp::Clamp if args[0].max(args[1]) > args[2] => vec![0.0, 0.0, 1.0],
+                    ElemwiseOp::Clamp if args[0] >= args[1] => vec![1.0, 0.0, 0.0],
+                    ElemwiseOp::Clamp => vec![0.0, 1.0, 0.0],
+                };
+
+                for (child_grad, partial) in grads.iter_mut().zip(partials) {
+                    accumulate(child_grad, &index, grad_value * partial);
+                }
+            }
+        }
+        // Gradients of real losses with respect to complex values, so a product passes the
+        // gradient times the conjugate of the other operand.
+        Op::Complex(op) => {
+            let dims = grad.layout.dims();
+
+            for index in indices(&dims[..dims.len() - 1]) {
+                let (re, im) = get_complex(grad, &index);
+
+                match op {
+                    ComplexOp::Add => {
+                        accumulate_complex(&mut grads[0], &index, (re, im));
+                        accumulate_complex(&mut grads[1], &index, (re, im));
+                    }
+                    ComplexOp::Mul => {
+                        let ((a, b), (c, d)) = (
+                            get_complex(children[0], &index),
+                            get_complex(children[1], &index),
+                        );
+
+                        accumulate_complex(
+                            &mut grads[0],
+                            &index,
+                            (re * c + im * d, im * c - re * d),
+                        );
+                        accumulate_complex(
+                            &mut grads[1],
+                            &index,
+                            (re * a + im * b, im * a - re * b),
+                        );
+                    }
+                    ComplexOp::Conj => accumulate_complex(&mut grads[0], &index, (re, -im)),
+                }
+            }
+        }
+        Op::Reduce { op, .. } => {
+            let count = children[0].layout.elements() / output.layout.elements();
+
+            for index in indices(children[0].layout.dims()) {
+                let partial = match op {
+                    ReduceOp::Sum => 1.0,
+                    ReduceOp::Max if get(children[0], &index) == get(output, &index) => 1.0,
+                    ReduceOp::Max => 0.0,
+                    ReduceOp::Mean => 1.0 / count as f32,
+                    // Positions only change in jumps, so they have no gradient.
+                    ReduceOp::ArgMax | ReduceOp::ArgMin => 0.0,
+                };
+
+                accumulate(&mut grads[0], &index, get(grad, &index) * partial);
+            }
+        }
+        Op::Movement(op) => {
+            let child_dims = children[0].layout.dims();
+
+            grads[0] = match op {
+                MovementOp::Reshape(_) | MovementOp::Squeeze(_) | MovementOp::Unsqueeze(_) => grad
+                    .clone()
+                    .reshape(Shape::from(child_dims))
+                    .unwrap_or_else(|error| panic!("{error}")),
+                MovementOp::Transpose => from_fn(&children[0].layout, |index| {
+                    let mut index = index.to_vec();
+                    let rank = index.len();
+
+                    index.swap(rank - 2, rank - 1);
+
+                    get(grad, &index)
+                }),
+                MovementOp::Unfold { dim, step, .. } => {
+                    let mut child_grad = from_fn(&children[0].layout, |_| 0.0);
+
+                    for index in indices(grad.layout.dims()) {
+                        let mut child_index = index[..index.len() - 1].to_vec();
+
+                        child_index[*dim] = index[*dim] * step + index[index.len() - 1];
+
+                        accumulate(&mut child_grad, &child_index, get(grad, &index));
+                    }
+
+                    child_grad
+                }
+                MovementOp::Flip(dims) => contiguous(&view(grad, grad.layout.flip(dims))),
+            };
+        }
+        Op::Concat { dim } => {
+            let mut offset = 0;
+
+            for (child, child_grad) in children.iter().zip(grads.iter_mut()) {
+                *child_grad = from_fn(&child.layout, |index| {
+                    let mut index = index.to_vec();
+
+                    index[*dim] += offset;
+
+                    get(grad, &index)
+                });
+
+                offset += child.layout.dims()[*dim];
+            }
+        }
+        Op::MatMul | Op::MaskedMatMul { .. } => {
+            let (lhs, rhs) = (contiguous(children[0]), contiguous(children[1]));
+            let geometry = MatMulGeometry::new(&lhs.layout, &rhs.layout);
+            let grad = contiguous(grad);
+
+            let lhs_k = geometry.lhs_strides[geometry.lhs_strides.len() - 1];
+            let rhs_k = geometry.rhs_strides[geometry.batch.len()];
+
+            for (index, &grad_value) in grad.data.iter().enumerate() {
+                if !keeps(op, &geometry, index) {
+                    continue;
+                }
+
+                let (lhs_base, rhs_base) = geometry.bases(index);
+
+                for k in 0..geometry.k {
+                    grads[0].data[lhs_base + k * lhs_k] +=
+                        grad_value * rhs.data[rhs_base + k * rhs_k];
+                    grads[1].data[rhs_base + k * rhs_k] +=
+                        grad_value * lhs.data[lhs_base + k * lhs_k];
+                }
+            }
+        }
+        Op::Attention { scale } => {
+            let (query, key, value) = (
+                contiguous(children[0]),
+                contiguous(children[1]),
+                contiguous(children[2]),
+            );
+            let geometry = AttentionGeometry::new(&query.layout, &key.layout, &value.layout);
+            let grad = contiguous(grad);
+
+            let rank = geometry.batch.len();
+            let query_strides = &geometry.query_strides[rank..];
+            let key_strides = &geometry.key_strides[rank..];
+            let value_strides = &geometry.value_strides[rank..];
+
+            for batch in 0..geometry.batch.iter().product() {
+                let (query_base, key_base, value_base) = geometry.bases(batch);
+
+                for row in 0..geometry.queries {
+                    let probabilities = attention_probabilities(
+                        &geometry,
+                        *scale,
+                        &query,
+                        &key,
+                        (query_base, key_base),
+                        row,
+                    );
+                    let grad_base = (batch * geometry.queries + row) * geometry.value_dim;
+                    let query_row = query_base + row * query_strides[0];
+
+                    let grad_probabilities = (0..geometry.keys)
+                        .map(|column| {
+                            (0..geometry.value_dim)
+                                .map(|dim| {
+                                    grad.data[grad_base + dim]
+                                        * value.data[value_base
+                                            + column * value_strides[0]
+                                            + dim * value_strides[1]]
+                                })
+                                .sum::<f32>()
+                        })
+                        .collect::<Vec<_>>();
+                    let expected = probabilities
+                        .iter()
+                        .zip(grad_probabilities.iter())
+                        .map(|(probability, grad)| probability * grad)
+                        .sum::<f32>();
+
+                    for column in 0..geometry.keys {
+                        let key_row = key_base + column * key_strides[0];
+                        let value_row = value_base + column * value_strides[0];
+
+                        for dim in 0..geometry.value_dim {
+                            grads[2].data[value_row + dim * value_strides[1]] +=
+                                probabilities[column] * grad.data[grad_base + dim];
+                        }
+
+                        let grad_score =
+                            scale * probabilities[column] * (grad_probabilities[column] - expected);
+
+                        for dim in 0..geometry.head_dim {
+                            grads[0].data[query_row + dim * query_strides[1]] +=
+                                grad_score * key.data[key_row + dim * key_strides[1]];
+                            grads[1].data[key_row + dim * key_strides[1]] +=
+                                grad_score * query.data[query_row + dim * query_strides[1]];
+                        }
+                    }
+                }
+            }
+        }
+        Op::Random { .. } => {}
+        Op::Dropout { probability, seed } => {
+            grads[0] = match mode {
+                Mode::Training => {
+                    let mask = dropout_mask(*probability, *seed, &grad.layout);
+
+                    from_fn(&grad.layout, |index| get(grad, index) * get(&mask, index))
+                }
+                Mode::Inference => grad.clone(),
+            }
+        }
+        Op::Repeat { .. } => {
+            let child_dims = children[0].layout.dims();
+
+            for index in indices(grad.layout.dims()) {
+                let child_index = index
+                    .iter()
+                    .zip(child_dims)
+                    .map(|(index, dim)| index % dim)
+                    .collect::<Vec<_>>();
+
+                accumulate(&mut grads[0], &child_index, get(grad, &index));
+            }
+        }
+        Op::Diagonal { offset } => {
+            for index in indices(grad.layout.dims()) {
+                accumulate(
+                    &mut grads[0],
+                    &diagonal_index(&index, *offset),
+                    get(grad, &index),
+                );
+            }
+        }
+        Op::Trace => {
+            let diagonal = children[0].layout.diagonal_view(0);
+
+            for index in indices(diagonal.dims()) {
+                let (_, batch) = index.split_last().unwrap();
+
+                accumulate(&mut grads[0], &diagonal_index(&index, 0), get(grad, batch));
+            }
+        }
+        Op::Assert { .. } => grads[0] = grad.clone(),
+        // Positions have no gradient.
+        Op::Nonzero => {}
+        Op::MaskedSelect => {
+            let mask = children[1];
+            let selected = indices(mask.layout.dims()).filter(|index| get(mask, index) != 0.0);
+
+            for (index, grad_value) in selected.zip(contiguous(grad).data.iter()) {
+                accumulate(&mut grads[0], &index, *grad_value);
+            }
+        }
+        // The transform is unitary up to its scale, so the gradient goes through the opposite
+        // transform with the same scale.
+        Op::Fft { dim, inverse } => {
+            let scale = if *inverse {
+                1.0 / grad.layout.dims()[*dim] as f32
+            } else {
+                1.0
+            };
+
+            grads[0] = dft(grad, *dim, !*inverse).map(|value| value * scale);
+        }
+        // The sparsity pattern has no gradient, only the stored values.
+        Op::SpMM | Op::SpMV => {
+            let (values, dense) = (children[0], children[3]);
+
+            for index in indices(grad.layout.dims()) {
+                let grad_value = get(grad, &index);
+
+                for (entry, column) in row_entries(children, index[0]) {
+                    let dense_index = [&[column], &index[1..]].concat();
+
+                    accumulate(
+                        &mut grads[0],
+                        &[entry],
+                        grad_value * get(dense, &dense_index),
+                    );
+                    accumulate(
+                        &mut grads[3],
+                        &dense_index,
+                        grad_value * get(values, &[entry]),
+                    );
+                }
+            }
+        }
+        Op::If {
+            predicate,
+            then_graph,
+            else_graph,
+        } => {
+            let branch = if predicate.holds(children[0].data[0]) {
+                then_graph
+            } else {
+                else_graph
+            };
+
+            let branch_grads = backward(
+                branch,
+                mode,
+                children[1..].iter().map(|&child| child.clone()).collect(),
+                vec![grad.clone()],
+            );
+
+            for (child_grad, branch_grad) in grads[1..].iter_mut().zip(branch_grads) {
+                *child_grad = branch_grad;
+            }
+        }
+        Op::Scan { body, dim } => {
+            let states = scan_states(body, *dim, mode, children[0], children[1]);
+            let grad = contiguous(grad);
+            let elements = states[0].layout.elements();
+
+            let mut state_grad = from_fn(&states[0].layout, |_| 0.0);
+
+            for position in (0..states.len() - 1).rev() {
+                let output_grad = &grad.data[position * elements..][..elements];
+
+                for (state_grad, output_grad) in state_grad.data.iter_mut().zip(output_grad) {
+                    *state_grad += output_grad;
+                }
+
+                let body_grads = backward(
+                    body,
+                    mode,
+                    vec![
+                        states[position].clone(),
+                        scan_slice(children[1], *dim, position),
+                    ],
+                    vec![state_grad],
+                );
+                let [previous_grad, slice_grad] = <[Tensor; 2]>::try_from(body_grads).unwrap();
+
+                for index in indices(slice_grad.layout.dims()) {
+                    let mut sequence_index = index.clone();
+
+                    sequence_index[*dim] = position;
+
+                    accumulate(&mut grads[1], &sequence_index, get(&slice_grad, &index));
+                }
+
+                state_grad = previous_grad;
+            }
+
+            grads[0] = state_grad;
+        }
+        Op::Custom(op) => panic!("custom op {} has no gradient", op.name()),
+    }
+
+    grads
+}
+
+pub fn gradients(graph: &Graph, inputs: Vec<Tensor>) -> Vec<Tensor> {
+    let seeds = graph
+        .outputs
+        .iter()
+        .map(|output| from_fn(&graph[*output].layout, |_| 1.0))
+        .collect();
+
+    backward(graph, graph.mode, inputs, seeds)
+}
+
+// The gradients of the inputs given the gradients of the outputs.
+fn backward(graph: &Graph, mode: Mode, inputs: Vec<Tensor>, seeds: Vec<Tensor>) -> Vec<Tensor> {
+    let values = eval_exprs(graph, mode, inputs);
+    let mut grads: Vec<Option<Tensor>> = vec![None; graph.exprs.len()];
+
+    let add_grad = |grads: &mut Vec<Option<Tensor>>, id: ExprId, grad: Tensor| {
+        grads[id.0] = Some(match grads[id.0].take() {
+            Some(existing) => from_fn(&existing.layout, |index| {
+                get(&existing, index) + get(&grad, index)
+            }),
+            None => grad,
+        });
+    };
+
+    for (&output, seed) in graph.outputs.iter().zip(seeds) {
+        add_grad(&mut grads, output, seed);
+    }
+
+    for index in (0..graph.exprs.len()).rev() {
+        let (ExprBody::Op { op, children }, Some(grad)) = (&graph.exprs[index].body, &grads[index])
+        else {
+            continue;
+        };
+
+        let child_values = children
+            .iter()
+            .map(|child| values[child.0].as_ref().expect("child was not evaluated"))
+            .collect::<Vec<_>>();
+
+        let child_grads = backward_op(
+            op,
+            mode,
+            grad,
+            &child_values,
+            values[index]
+                .as_ref()
+                .expect("expression was not evaluated"),
+        );
+
+        for (&child, child_grad) in children.iter().zip(child_grads) {
+            add_grad(&mut grads, child, child_grad);
+        }
+    }
+
+    graph
+        .inputs
+        .iter()
+        .map(|input| {
+            grads[input.0]
+                .take()
+                .unwrap_or_else(|| from_fn(&graph[*input].layout, |_| 0.0))
+        })
+        .collect()
+}
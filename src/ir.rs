@@ -0,0 +1,278 @@
+//! The mid-level form graphs are lowered to before a backend emits code for them: kernels, each a
+//! loop nest computing one output along with the elementwise expressions fused into it, and a
+//! plan placing every value the kernels pass through memory into one pool.
+//!
+//! Both backends fuse as these kernels group expressions: the CPU backend runs them as they are,
+//! and the wgpu compiler emits one shader per fused elementwise chain. The wgpu compiler still
+//! does its own in-place reuse and arena planning though, since it also packs concat inputs,
+//! stores values as f16, chunks oversized dispatches and lowers branch and scan bodies inline,
+//! all with buffers no expression of the graph has. It only shares `Pool`, for placing those in
+//! its arenas.
+
+use std::{collections::HashMap, ops::Range};
+
+use crate::graph::{ExprBody, ExprId, Graph, Op};
+
+/// The loops a kernel runs, outermost first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoopNest {
+    /// The loops over the output's dimensions.
+    pub dims: Vec<usize>,
+    /// How many of the outer loops are independent of each other, and so can be split across
+    /// threads or workgroups.
+    pub parallel: usize,
+    /// The loops inside each output element, which accumulate into it.
+    pub reduction: Vec<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Kernel {
+    pub output: ExprId,
+    /// The expressions computed inside the kernel without going through memory, in order and
+    /// ending with `output`.
+    pub fused: Vec<ExprId>,
+    /// The values the kernel reads from memory.
+    pub inputs: Vec<ExprId>,
+    pub loops: LoopNest,
+}
+
+#[derive(Clone, Debug)]
+pub struct Buffer {
+    pub id: ExprId,
+    pub size: usize,
+    /// Where the buffer starts in the program's pool.
+    pub offset: usize,
+    /// The kernels from the one writing the buffer to the last one reading it. Inputs and consts
+    /// are written before the first kernel.
+    pub lifetime: Range<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Program {
+    pub kernels: Vec<Kernel>,
+    pub buffers: Vec<Buffer>,
+    pub pool_size: usize,
+}
+
+/// First-fit placement of buffers into one pool of memory, as they are allocated and freed.
+pub(crate) struct Pool {
+    alignment: usize,
+    pub(crate) size: usize,
+    live: Vec<(usize, usize)>,
+}
+
+impl Pool {
+    pub(crate) fn new(alignment: usize) -> Self {
+        Self {
+            alignment,
+            size: 0,
+            live: Vec::new(),
+        }
+    }
+
+    pub(crate) fn place(&mut self, size: usize) -> usize {
+        // Empty buffers still take room, so that each live buffer has an offset of its own.
+        let size = size.max(1).next_multiple_of(self.alignment);
+
+        self.live.sort();
+
+        let mut offset = 0;
+
+        for &(start, end) in self.live.iter() {
+            if start >= offset + size {
+                break;
+            }
+
+            offset = offset.max(end);
+        }
+
+        self.live.push((offset, offset + size));
+        self.size = self.size.max(offset + size);
+
+        offset
+    }
+
+    pub(crate) fn free(&mut self, offset: usize) {
+        self.live.retain(|&(start, _)| start != offset);
+    }
+}
+
+fn is_elemwise(graph: &Graph, id: ExprId) -> bool {
+    matches!(
+        graph[id].body,
+        ExprBody::Op {
+            op: Op::Elemwise(_),
+            ..
+        }
+    )
+}
+
+fn loops(graph: &Graph, id: ExprId) -> LoopNest {
+    let dims = graph[id].layout.dims().to_vec();
+    let ExprBody::Op { op, children } = &graph[id].body else {
+        unreachable!("only ops are kernels");
+    };
+    let input = |index: usize| graph[children[index]].layout.dims();
+
+    let (parallel, reduction) = match op {
+        Op::Reduce {
+            dims: reduced_dims, ..
+        } => (
+            dims.len(),
+            reduced_dims.iter().map(|&dim| input(0)[dim]).collect(),
+        ),
+        Op::MatMul | Op::MaskedMatMul { .. } => (dims.len(), vec![*input(0).last().unwrap()]),
+        Op::Attention { .. } => (dims.len(), vec![input(1)[input(1).len() - 2]]),
+        Op::Trace => {
+            let rank = input(0).len();
+
+            (dims.len(), vec![input(0)[rank - 2].min(input(0)[rank - 1])])
+        }
+        // Each step of a scan depends on the previous one, branches run as a whole, and where a
+        // compacted element goes depends on every element before it.
+        Op::Scan { .. } | Op::If { .. } | Op::Nonzero | Op::MaskedSelect => (0, Vec::new()),
+        Op::Fft { dim, .. } => (dims.len(), vec![input(0)[*dim]]),
+        // Rows sum however many entries they store, so this takes the average.
+        Op::SpMM | Op::SpMV => (dims.len(), vec![input(0)[0].div_ceil(dims[0].max(1))]),
+        _ => (dims.len(), Vec::new()),
+    };
+
+    LoopNest {
+        dims,
+        parallel,
+        reduction,
+    }
+}
+
+/// Lowers the part of the graph its outputs depend on. An elementwise expression with a single
+/// use is fused into the elementwise expression using it, if both have the same dimensions.
+/// Buffers are placed at offsets that are multiples of `alignment`, reusing the memory of buffers
+/// no kernel reads anymore.
+pub fn lower(graph: &Graph, alignment: usize) -> Program {
+    let live = graph.region(&graph.inputs, &graph.outputs);
+    let mut uses = HashMap::new();
+
+    for &id in &live {
+        if let ExprBody::Op { children, .. } = &graph[id].body {
+            for &child in children {
+                *uses.entry(child).or_insert(0) += 1;
+            }
+        }
+    }
+
+    let mut fused_into = HashMap::new();
+
+    for &id in live.iter().filter(|&&id| is_elemwise(graph, id)) {
+        let ExprBody::Op { children, .. } = &graph[id].body else {
+            unreachable!("elementwise expressions are ops");
+        };
+
+        for &child in children {
+            if is_elemwise(graph, child)
+                && uses[&child] == 1
+                && !graph.outputs.contains(&child)
+                && graph[child].layout.dims() == graph[id].layout.dims()
+            {
+                fused_into.insert(child, id);
+            }
+        }
+    }
+
+    // Expressions are numbered in topological order, so each group ends with its root.
+    let mut groups = HashMap::<ExprId, Vec<ExprId>>::new();
+
+    for &id in live
+        .iter()
+        .filter(|&&id| matches!(graph[id].body, ExprBody::Op { .. }))
+    {
+        let mut root = id;
+
+        while let Some(&parent) = fused_into.get(&root) {
+            root = parent;
+        }
+
+        groups.entry(root).or_default().push(id);
+    }
+
+    let mut kernels = groups
+        .into_iter()
+        .map(|(output, fused)| {
+            let mut inputs = Vec::new();
+
+            for &id in &fused {
+                if let ExprBody::Op { children, .. } = &graph[id].body {
+                    for child in children {
+                        if !fused.contains(child) && !inputs.contains(child) {
+                            inputs.push(*child);
+                        }
+                    }
+                }
+            }
+
+            Kernel {
+                output,
+                loops: loops(graph, output),
+                fused,
+                inputs,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    kernels.sort_by_key(|kernel| kernel.output);
+
+    let mut lifetimes = HashMap::new();
+
+    for &id in graph.inputs.iter().chain(&live) {
+        if !matches!(graph[id].body, ExprBody::Op { .. }) {
+            lifetimes.insert(id, 0..1);
+        }
+    }
+
+    for (index, kernel) in kernels.iter().enumerate() {
+        lifetimes.insert(kernel.output, index..index + 1);
+
+        for input in &kernel.inputs {
+            lifetimes.get_mut(input).unwrap().end = index + 1;
+        }
+    }
+
+    for output in &graph.outputs {
+        lifetimes.get_mut(output).unwrap().end = kernels.len().max(1);
+    }
+
+    let mut buffers = lifetimes
+        .into_iter()
+        .map(|(id, lifetime)| Buffer {
+            id,
+            size: graph[id].layout.size(),
+            offset: 0,
+            lifetime,
+        })
+        .collect::<Vec<_>>();
+
+    buffers.sort_by_key(|buffer| (buffer.lifetime.start, buffer.id));
+
+    let mut pool = Pool::new(alignment);
+    let mut by_end = (0..buffers.len()).collect::<Vec<_>>();
+    let mut freed = 0;
+
+    by_end.sort_by_key(|&index| buffers[index].lifetime.end);
+
+    // Buffers no kernel reads anymore make room for the ones written after them.
+    for index in 0..buffers.len() {
+        while freed < by_end.len()
+            && buffers[by_end[freed]].lifetime.end <= buffers[index].lifetime.start
+        {
+            pool.free(buffers[by_end[freed]].offset);
+            freed += 1;
+        }
+
+        buffers[index].offset = pool.place(buffers[index].size);
+    }
+
+    Program {
+        kernels,
+        buffers,
+        pool_size: pool.size,
+    }
+}
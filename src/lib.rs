@@ -1,5 +1,17 @@
+pub mod bench;
 pub mod builder;
 pub mod compiler;
+pub mod cpu;
+pub mod dsl;
+pub mod energy;
 pub mod graph;
+pub mod interp;
+pub mod ir;
+pub mod nn;
+pub mod optim;
+pub mod parse;
+pub mod rewrite;
 pub mod tensor;
+pub mod testing;
+pub mod typed;
 pub mod wgpu;
@@ -1,3 +1,5 @@
+use std::env;
+
 use momentum::{
     builder,
     compiler::{Compiler, Runner},
@@ -6,7 +8,30 @@ use momentum::{
     wgpu::{compiler::WgpuCompiler, runner::WgpuRunner},
 };
 
+fn replay(path: &str) {
+    let mut runner = WgpuRunner::new();
+    let replay = runner.replay(path);
+
+    println!("recorded on: {}", replay.recorded_adapter.trim());
+    println!("replaying on: {:#?}", runner.adapter_info());
+
+    for difference in replay.differences.iter() {
+        println!(
+            "step {}: max difference {}",
+            difference.step, difference.max_difference
+        );
+    }
+
+    println!("{:#?}", replay.outputs);
+}
+
 fn main() {
+    if let [_, command, path] = &env::args().collect::<Vec<_>>()[..] {
+        if command == "replay" {
+            return replay(path);
+        }
+    }
+
     let mut graph = Graph::new();
 
     let a = graph.add_input(Layout::scalar());
@@ -28,10 +53,10 @@ fn main() {
         "{:#?}",
         runner.run(
             runnable,
-            vec![
-                Tensor::from_scalar(2.0),
-                Tensor::from_scalar(2.0),
-                Tensor::from_scalar(2.0)
+            &[
+                Tensor::from_scalar(2.0).view(),
+                Tensor::from_scalar(2.0).view(),
+                Tensor::from_scalar(2.0).view()
             ]
         )
     );
@@ -0,0 +1,301 @@
+use crate::{
+    builder,
+    graph::{ExprId, Graph, MovementOp, Op},
+    tensor::{Layout, Shape, Tensor},
+};
+
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub enum Registration {
+    #[default]
+    Const,
+    Input,
+}
+
+#[derive(Default)]
+pub struct Parameters {
+    registration: Registration,
+    parameters: Vec<(String, ExprId, Tensor)>,
+}
+
+impl Parameters {
+    pub fn new(registration: Registration) -> Self {
+        Self {
+            registration,
+            parameters: Vec::new(),
+        }
+    }
+
+    pub fn register(&mut self, graph: &mut Graph, name: &str, tensor: &Tensor) -> ExprId {
+        if let Some(id) = self.get(name) {
+            return id;
+        }
+
+        let id = match self.registration {
+            Registration::Const => graph.add_const(tensor.clone()),
+            Registration::Input => graph.add_input(tensor.layout.clone()),
+        };
+
+        self.parameters.push((name.to_owned(), id, tensor.clone()));
+
+        id
+    }
+
+    pub fn get(&self, name: &str) -> Option<ExprId> {
+        self.parameters
+            .iter()
+            .find(|(parameter, ..)| parameter == name)
+            .map(|&(_, id, _)| id)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.parameters.iter().map(|(name, ..)| name.as_str())
+    }
+
+    /// Interleaves the registered parameter tensors with `data`, in the order the graph expects
+    /// its inputs.
+    pub fn inputs(&self, graph: &Graph, data: Vec<Tensor>) -> Vec<Tensor> {
+        let mut data = data.into_iter();
+
+        let inputs = graph
+            .inputs
+            .iter()
+            .map(|&input| {
+                self.parameters
+                    .iter()
+                    .find(|&&(_, id, _)| id == input)
+                    .map(|(_, _, tensor)| tensor.clone())
+                    .unwrap_or_else(|| data.next().expect("not enough data inputs for graph"))
+            })
+            .collect();
+
+        assert!(data.next().is_none(), "too many data inputs for graph");
+
+        inputs
+    }
+}
+
+pub trait Module {
+    fn forward(&self, graph: &mut Graph, parameters: &mut Parameters, input: ExprId) -> ExprId;
+}
+
+fn reshape(graph: &mut Graph, input: ExprId, dims: Vec<usize>) -> ExprId {
+    graph.add_op(
+        Op::Movement(MovementOp::Reshape(Shape::from(dims))),
+        &[input],
+    )
+}
+
+fn transpose(graph: &mut Graph, input: ExprId) -> ExprId {
+    graph.add_op(Op::Movement(MovementOp::Transpose), &[input])
+}
+
+fn add_bias(graph: &mut Graph, input: ExprId, bias: ExprId, dim: usize) -> ExprId {
+    let rank = graph[input].layout.rank();
+    let mut dims = vec![1; rank];
+
+    dims[dim] = graph[bias].layout.elements();
+
+    let bias = reshape(graph, bias, dims);
+
+    builder::Add::new(input, bias).build(graph)
+}
+
+pub struct Linear {
+    name: String,
+    weight: Tensor,
+    bias: Option<Tensor>,
+}
+
+impl Linear {
+    pub fn new(name: &str, weight: Tensor, bias: Option<Tensor>) -> Self {
+        assert_eq!(weight.layout.rank(), 2, "linear weight must be a matrix");
+
+        Self {
+            name: name.to_owned(),
+            weight,
+            bias,
+        }
+    }
+}
+
+impl Module for Linear {
+    fn forward(&self, graph: &mut Graph, parameters: &mut Parameters, input: ExprId) -> ExprId {
+        let weight = parameters.register(graph, &format!("{}.weight", self.name), &self.weight);
+        let output = builder::MatMul::new(input, weight).build(graph);
+
+        match &self.bias {
+            Some(bias) => {
+                let bias = parameters.register(graph, &format!("{}.bias", self.name), bias);
+                let rank = graph[output].layout.rank();
+
+                add_bias(graph, output, bias, rank - 1)
+            }
+            None => output,
+        }
+    }
+}
+
+/// Looks up the rows of the embedding table that the input's indices pick, which are floats
+/// holding whole numbers.
+pub struct Embedding {
+    name: String,
+    weight: Tensor,
+}
+
+impl Embedding {
+    pub fn new(name: &str, weight: Tensor) -> Self {
+        assert_eq!(weight.layout.rank(), 2, "embedding table must be a matrix");
+
+        Self {
+            name: name.to_owned(),
+            weight,
+        }
+    }
+}
+
+impl Module for Embedding {
+    fn forward(&self, graph: &mut Graph, parameters: &mut Parameters, input: ExprId) -> ExprId {
+        let weight = parameters.register(graph, &format!("{}.weight", self.name), &self.weight);
+
+        builder::Embedding::new(input, weight).build(graph)
+    }
+}
+
+pub struct Conv2d {
+    name: String,
+    weight: Tensor,
+    bias: Option<Tensor>,
+    stride: usize,
+    padding: usize,
+}
+
+impl Conv2d {
+    pub fn new(name: &str, weight: Tensor, bias: Option<Tensor>) -> Self {
+        assert_eq!(
+            weight.layout.rank(),
+            4,
+            "convolution weight must have output channel, input channel, height and width dimensions"
+        );
+
+        Self {
+            name: name.to_owned(),
+            weight,
+            bias,
+            stride: 1,
+            padding: 0,
+        }
+    }
+
+    pub fn stride(mut self, stride: usize) -> Self {
+        self.stride = stride;
+        self
+    }
+
+    pub fn padding(mut self, padding: usize) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    fn output_size(&self, size: usize, kernel: usize) -> usize {
+        (size + 2 * self.padding)
+            .checked_sub(kernel)
+            .expect("convolution kernel is larger than its padded input")
+            / self.stride
+            + 1
+    }
+
+    // Surrounds the height and width dimensions of a channels-last input with zeros.
+    fn pad(&self, graph: &mut Graph, input: ExprId) -> ExprId {
+        if self.padding == 0 {
+            return input;
+        }
+
+        let mut padded = input;
+
+        for dim in [1, 2] {
+            let mut dims = graph[padded].layout.dims().to_vec();
+
+            dims[dim] = self.padding;
+
+            let zeros = graph.add_const(Tensor::from_parts(
+                vec![0.0; dims.iter().product()].into_boxed_slice(),
+                Layout::from(dims),
+            ));
+
+            padded = builder::Concat::new(&[zeros, padded, zeros], dim).build(graph);
+        }
+
+        padded
+    }
+}
+
+impl Module for Conv2d {
+    fn forward(&self, graph: &mut Graph, parameters: &mut Parameters, input: ExprId) -> ExprId {
+        let &[batch, channels, height, width] = graph[input].layout.dims() else {
+            panic!("convolution input must have batch, channel, height and width dimensions")
+        };
+        let &[out_channels, in_channels, kernel_height, kernel_width] = self.weight.layout.dims()
+        else {
+            unreachable!()
+        };
+
+        assert_eq!(channels, in_channels, "convolution channel counts differ");
+
+        let output_height = self.output_size(height, kernel_height);
+        let output_width = self.output_size(width, kernel_width);
+
+        // Channels go last, so that unfolding the height and width leaves the pixels under each
+        // kernel position in the last three dimensions, ready to be flattened into rows.
+        let pixels = reshape(graph, input, vec![batch, channels, height * width]);
+        let pixels = transpose(graph, pixels);
+        let pixels = reshape(graph, pixels, vec![batch, height, width, channels]);
+        let pixels = self.pad(graph, pixels);
+
+        let windows = [(1, kernel_height), (2, kernel_width)].into_iter().fold(
+            pixels,
+            |windows, (dim, size)| {
+                graph.add_op(
+                    Op::Movement(MovementOp::Unfold {
+                        dim,
+                        size,
+                        step: self.stride,
+                    }),
+                    &[windows],
+                )
+            },
+        );
+        let rows = reshape(
+            graph,
+            windows,
+            vec![
+                batch,
+                output_height * output_width,
+                channels * kernel_height * kernel_width,
+            ],
+        );
+
+        let weight = parameters.register(graph, &format!("{}.weight", self.name), &self.weight);
+        let weight = reshape(
+            graph,
+            weight,
+            vec![out_channels, in_channels * kernel_height * kernel_width],
+        );
+        let weight = transpose(graph, weight);
+
+        let output = builder::MatMul::new(rows, weight).build(graph);
+        let output = transpose(graph, output);
+        let mut output = reshape(
+            graph,
+            output,
+            vec![batch, out_channels, output_height, output_width],
+        );
+
+        if let Some(bias) = &self.bias {
+            let bias = parameters.register(graph, &format!("{}.bias", self.name), bias);
+
+            output = add_bias(graph, output, bias, 1);
+        }
+
+        output
+    }
+}
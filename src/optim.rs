@@ -0,0 +1,338 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    builder,
+    compiler::{Compiler, Runner},
+    graph::{ElemwiseOp, ExprId, Graph, MovementOp, Op},
+    tensor::{Layout, Shape, Tensor},
+    wgpu::{
+        compiler::WgpuCompiler,
+        runner::{ConcreteWgpuPlan, WgpuRunner},
+    },
+};
+
+pub trait Optimizer {
+    fn states(&self) -> usize;
+
+    fn scalars(&self, step: usize) -> Vec<f32>;
+
+    fn update(
+        &self,
+        graph: &mut Graph,
+        parameter: ExprId,
+        grad: ExprId,
+        states: &[ExprId],
+        scalars: &[ExprId],
+    ) -> (ExprId, Vec<ExprId>);
+}
+
+fn constant(graph: &mut Graph, value: f32, like: ExprId) -> ExprId {
+    let rank = graph[like].layout.rank();
+
+    graph.add_const(Tensor::from_parts(
+        Box::new([value]),
+        Layout::from(vec![1; rank]),
+    ))
+}
+
+fn broadcast(graph: &mut Graph, scalar: ExprId, like: ExprId) -> ExprId {
+    let rank = graph[like].layout.rank();
+
+    graph.add_op(
+        Op::Movement(MovementOp::Reshape(Shape::from(vec![1; rank]))),
+        &[scalar],
+    )
+}
+
+fn scale(graph: &mut Graph, value: f32, input: ExprId) -> ExprId {
+    let value = constant(graph, value, input);
+
+    builder::Mul::new(value, input).build(graph)
+}
+
+pub struct Sgd {
+    learning_rate: f32,
+    momentum: f32,
+}
+
+impl Sgd {
+    pub fn new(learning_rate: f32) -> Self {
+        Self {
+            learning_rate,
+            momentum: 0.0,
+        }
+    }
+
+    pub fn momentum(mut self, momentum: f32) -> Self {
+        self.momentum = momentum;
+        self
+    }
+}
+
+impl Optimizer for Sgd {
+    fn states(&self) -> usize {
+        usize::from(self.momentum != 0.0)
+    }
+
+    fn scalars(&self, _step: usize) -> Vec<f32> {
+        Vec::new()
+    }
+
+    fn update(
+        &self,
+        graph: &mut Graph,
+        parameter: ExprId,
+        grad: ExprId,
+        states: &[ExprId],
+        _scalars: &[ExprId],
+    ) -> (ExprId, Vec<ExprId>) {
+        let (direction, states) = match states {
+            &[velocity] => {
+                let velocity = scale(graph, self.momentum, velocity);
+                let velocity = builder::Add::new(velocity, grad).build(graph);
+
+                (velocity, vec![velocity])
+            }
+            _ => (grad, Vec::new()),
+        };
+
+        let step = scale(graph, -self.learning_rate, direction);
+
+        (builder::Add::new(parameter, step).build(graph), states)
+    }
+}
+
+/// Adam with epsilon added to the bias-corrected second moment inside the square root.
+pub struct Adam {
+    learning_rate: f32,
+    beta1: f32,
+    beta2: f32,
+    eps: f32,
+}
+
+impl Adam {
+    pub fn new(learning_rate: f32) -> Self {
+        Self {
+            learning_rate,
+            beta1: 0.9,
+            beta2: 0.999,
+            eps: 1e-8,
+        }
+    }
+
+    pub fn betas(mut self, beta1: f32, beta2: f32) -> Self {
+        self.beta1 = beta1;
+        self.beta2 = beta2;
+        self
+    }
+
+    pub fn eps(mut self, eps: f32) -> Self {
+        self.eps = eps;
+        self
+    }
+}
+
+impl Optimizer for Adam {
+    fn states(&self) -> usize {
+        2
+    }
+
+    fn scalars(&self, step: usize) -> Vec<f32> {
+        let step = step as i32 + 1;
+
+        vec![
+            -self.learning_rate / (1.0 - self.beta1.powi(step)),
+            1.0 / (1.0 - self.beta2.powi(step)),
+        ]
+    }
+
+    fn update(
+        &self,
+        graph: &mut Graph,
+        parameter: ExprId,
+        grad: ExprId,
+        states: &[ExprId],
+        scalars: &[ExprId],
+    ) -> (ExprId, Vec<ExprId>) {
+        let (first, second) = (states[0], states[1]);
+
+        let first = scale(graph, self.beta1, first);
+        let first_grad = scale(graph, 1.0 - self.beta1, grad);
+        let first = builder::Add::new(first, first_grad).build(graph);
+
+        let squared = builder::Mul::new(grad, grad).build(graph);
+        let second = scale(graph, self.beta2, second);
+        let second_grad = scale(graph, 1.0 - self.beta2, squared);
+        let second = builder::Add::new(second, second_grad).build(graph);
+
+        let step_size = broadcast(graph, scalars[0], parameter);
+        let correction = broadcast(graph, scalars[1], parameter);
+        let eps = constant(graph, self.eps, parameter);
+
+        let corrected = builder::Mul::new(second, correction).build(graph);
+        let corrected = builder::Add::new(corrected, eps).build(graph);
+        let inverse_norm = graph.add_op(Op::Elemwise(ElemwiseOp::Rsqrt), &[corrected]);
+
+        let step = builder::Mul::new(first, inverse_norm).build(graph);
+        let step = builder::Mul::new(step, step_size).build(graph);
+
+        (
+            builder::Add::new(parameter, step).build(graph),
+            vec![first, second],
+        )
+    }
+}
+
+/// Builds a graph whose inputs are, per parameter, the parameter, its gradient and its optimizer
+/// states, followed by the optimizer's scalars. Its outputs are, per parameter, the updated
+/// parameter followed by its updated states.
+pub fn update_graph(optimizer: &impl Optimizer, parameters: &[Layout]) -> Graph {
+    let mut graph = Graph::new();
+
+    let inputs = parameters
+        .iter()
+        .map(|layout| {
+            let parameter = graph.add_input(layout.clone());
+            let grad = graph.add_input(layout.clone());
+            let states = (0..optimizer.states())
+                .map(|_| graph.add_input(layout.clone()))
+                .collect::<Vec<_>>();
+
+            (parameter, grad, states)
+        })
+        .collect::<Vec<_>>();
+
+    let scalars = (0..optimizer.scalars(0).len())
+        .map(|_| graph.add_input(Layout::from(vec![1])))
+        .collect::<Vec<_>>();
+
+    for (parameter, grad, states) in inputs {
+        let (parameter, states) = optimizer.update(&mut graph, parameter, grad, &states, &scalars);
+
+        graph.add_output(parameter);
+
+        for state in states {
+            graph.add_output(state);
+        }
+    }
+
+    graph
+}
+
+// Distinguishes the persistent buffers of trainers sharing a runner.
+static TRAINERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs an optimizer's update graph on the device. Optimizer states stay in the runner's
+/// persistent buffers between steps, so only parameters and gradients cross to and from the host.
+pub struct Trainer<O> {
+    optimizer: O,
+    runnable: ConcreteWgpuPlan,
+    // The names of the persistent buffers holding each parameter's states, in order.
+    states: Vec<String>,
+    step: usize,
+}
+
+impl<O: Optimizer> Trainer<O> {
+    pub fn new(
+        optimizer: O,
+        parameters: &[Layout],
+        compiler: &WgpuCompiler,
+        runner: &mut WgpuRunner,
+    ) -> Self {
+        let graph = update_graph(&optimizer, parameters);
+        let trainer = TRAINERS.fetch_add(1, Ordering::Relaxed);
+        let count = optimizer.states();
+
+        let mut compiler = compiler.clone();
+        let mut states = Vec::new();
+
+        for (index, layout) in parameters.iter().enumerate() {
+            let stride = count + 2;
+            let outputs = count + 1;
+
+            for state in 0..count {
+                let name = format!("optim{trainer}.{index}.{state}");
+
+                compiler
+                    .persistent
+                    .insert(graph.inputs[index * stride + 2 + state], name.clone());
+                compiler
+                    .persistent
+                    .insert(graph.outputs[index * outputs + 1 + state], name.clone());
+                runner.persist(
+                    &name,
+                    &Tensor::from_parts(
+                        vec![0.0; layout.elements()].into_boxed_slice(),
+                        layout.contiguous(),
+                    ),
+                );
+
+                states.push(name);
+            }
+        }
+
+        let runnable = runner.preprocess(compiler.compile(graph));
+
+        Self {
+            optimizer,
+            runnable,
+            states,
+            step: 0,
+        }
+    }
+
+    /// Reads the optimizer states back from the device, in the order of the parameters.
+    pub fn states(&self, runner: &WgpuRunner) -> Vec<Tensor> {
+        self.states
+            .iter()
+            .map(|name| {
+                runner
+                    .read_persistent(name)
+                    .expect("optimizer state was removed from the runner")
+            })
+            .collect()
+    }
+
+    pub fn step(
+        &mut self,
+        runner: &mut WgpuRunner,
+        parameters: Vec<Tensor>,
+        grads: Vec<Tensor>,
+    ) -> Vec<Tensor> {
+        assert_eq!(
+            parameters.len(),
+            grads.len(),
+            "every parameter needs a gradient"
+        );
+
+        let mut inputs = Vec::new();
+
+        for (parameter, grad) in parameters.into_iter().zip(grads) {
+            inputs.push(parameter);
+            inputs.push(grad);
+        }
+
+        inputs.extend(
+            self.optimizer
+                .scalars(self.step)
+                .into_iter()
+                .map(|scalar| Tensor::from_parts(Box::new([scalar]), Layout::from(vec![1]))),
+        );
+
+        let parameters = runner.run(
+            self.runnable.clone(),
+            &inputs.iter().map(Tensor::view).collect::<Vec<_>>(),
+        );
+
+        self.step += 1;
+
+        parameters
+    }
+
+    /// Frees the states' persistent buffers.
+    pub fn release(self, runner: &WgpuRunner) {
+        for name in &self.states {
+            runner.remove_persistent(name);
+        }
+    }
+}
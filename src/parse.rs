@@ -0,0 +1,809 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+    sync::Arc,
+};
+
+use crate::{
+    graph::{
+        ComplexOp, Distribution, ElemwiseOp, ExprBody, Graph, MatMulMask, MovementOp, Op,
+        Predicate, ReduceOp,
+    },
+    tensor::{Layout, Shape, Tensor},
+};
+
+/// Where and why the text of a graph could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Display for GraphParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for GraphParseError {}
+
+// An op parameter, in the shape `Debug` prints it.
+enum Value {
+    // Numbers, booleans and unit variants.
+    Word(String),
+    Str(String),
+    List(Vec<Value>),
+    Struct(String, Vec<(String, Value)>),
+    Graph(Graph),
+}
+
+enum Body {
+    // `None` when the data was elided.
+    Const(Option<Vec<f32>>),
+    Op {
+        name: String,
+        parameters: Vec<(String, Value)>,
+        children: Vec<usize>,
+    },
+}
+
+struct Statement {
+    position: usize,
+    id: usize,
+    name: Option<String>,
+    layout: Layout,
+    // `None` for inputs.
+    body: Option<Body>,
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error_at(&self, position: usize, message: impl Into<String>) -> GraphParseError {
+        let before = &self.text[..position];
+
+        GraphParseError {
+            line: before.matches('\n').count() + 1,
+            column: before.len() - before.rfind('\n').map_or(0, |newline| newline + 1) + 1,
+            message: message.into(),
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> GraphParseError {
+        self.error_at(self.position, message)
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.position..]
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.position = self.text.len() - self.rest().trim_start().len();
+        self.rest().chars().next()
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.peek();
+
+        let found = self.rest().starts_with(token);
+
+        if found {
+            self.position += token.len();
+        }
+
+        found
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), GraphParseError> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected `{token}`")))
+        }
+    }
+
+    fn word(&mut self) -> Result<&'a str, GraphParseError> {
+        self.peek();
+
+        let rest = self.rest();
+        let length = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || "+-._".contains(c)))
+            .unwrap_or(rest.len());
+
+        if length == 0 {
+            return Err(self.error("expected a word or number"));
+        }
+
+        self.position += length;
+
+        Ok(&rest[..length])
+    }
+
+    fn id(&mut self) -> Result<usize, GraphParseError> {
+        self.expect("@")?;
+
+        let position = self.position;
+
+        self.word()?
+            .parse()
+            .map_err(|_| self.error_at(position, "expected an expression id"))
+    }
+
+    // An id, optionally followed by the expression's name as a string.
+    fn named_id(&mut self) -> Result<(usize, Option<String>), GraphParseError> {
+        let id = self.id()?;
+        let name = if self.peek() == Some('"') {
+            Some(self.string()?)
+        } else {
+            None
+        };
+
+        Ok((id, name))
+    }
+
+    fn ids(&mut self) -> Result<Vec<usize>, GraphParseError> {
+        let mut ids = Vec::new();
+
+        if self.eat(")") {
+            return Ok(ids);
+        }
+
+        loop {
+            ids.push(self.id()?);
+
+            if self.eat(")") {
+                return Ok(ids);
+            }
+
+            self.expect(",")?;
+        }
+    }
+
+    // Layouts are printed as their dims followed by the element type, as in `2x4f32`.
+    fn layout(&mut self) -> Result<Layout, GraphParseError> {
+        let position = self.position;
+        let word = self.word()?;
+        let error = || self.error_at(position, format!("`{word}` is not a layout"));
+        let dims = word.strip_suffix("f32").ok_or_else(error)?;
+
+        if dims.is_empty() {
+            return Ok(Layout::scalar());
+        }
+
+        dims.split('x')
+            .map(|dim| dim.parse())
+            .collect::<Result<Vec<usize>, _>>()
+            .map(Layout::from)
+            .map_err(|_| error())
+    }
+
+    fn string(&mut self) -> Result<String, GraphParseError> {
+        self.expect("\"")?;
+
+        let mut string = String::new();
+        let mut chars = self.rest().char_indices();
+
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.position += index + 1;
+
+                    return Ok(string);
+                }
+                '\\' => string.push(match chars.next().map(|(_, c)| c) {
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('0') => '\0',
+                    Some('u') => {
+                        let rest = chars.as_str();
+                        let end = rest.find('}').unwrap_or(0);
+                        let code = rest
+                            .get(1..end)
+                            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| self.error("invalid unicode escape"))?;
+
+                        // Indices restart after the escape, so the position is moved past it.
+                        chars = rest[end + 1..].char_indices();
+                        self.position = self.text.len() - rest.len() + end + 1;
+                        string.push(code);
+                        continue;
+                    }
+                    Some(c) => c,
+                    None => break,
+                }),
+                c => string.push(c),
+            }
+        }
+
+        Err(self.error("unterminated string"))
+    }
+
+    fn value(&mut self) -> Result<Value, GraphParseError> {
+        match self.peek() {
+            Some('"') => self.string().map(Value::Str),
+            Some('(') => self.graph().map(Value::Graph),
+            Some('[') => {
+                self.expect("[")?;
+
+                let mut values = Vec::new();
+
+                while !self.eat("]") {
+                    values.push(self.value()?);
+
+                    if !self.eat(",") {
+                        self.expect("]")?;
+                        break;
+                    }
+                }
+
+                Ok(Value::List(values))
+            }
+            _ => {
+                let word = self.word()?.to_owned();
+
+                if !self.eat("{") {
+                    return Ok(Value::Word(word));
+                }
+
+                let mut fields = Vec::new();
+
+                while !self.eat("}") {
+                    let field = self.word()?.to_owned();
+
+                    self.expect(":")?;
+                    fields.push((field, self.value()?));
+
+                    if !self.eat(",") {
+                        self.expect("}")?;
+                        break;
+                    }
+                }
+
+                Ok(Value::Struct(word, fields))
+            }
+        }
+    }
+
+    fn body(&mut self) -> Result<Body, GraphParseError> {
+        if self.eat("..") {
+            return Ok(Body::Const(None));
+        }
+
+        if self.peek() == Some('[') {
+            let position = self.position;
+            let Value::List(values) = self.value()? else {
+                unreachable!("lists start with a bracket");
+            };
+
+            return values
+                .iter()
+                .map(|value| match value {
+                    Value::Word(word) => word.parse().ok(),
+                    _ => None,
+                })
+                .collect::<Option<Vec<f32>>>()
+                .map(|data| Body::Const(Some(data)))
+                .ok_or_else(|| self.error_at(position, "const data must be numbers"));
+        }
+
+        let name = self.word()?.to_owned();
+        let mut parameters = Vec::new();
+        let mut children = Vec::new();
+
+        if self.eat("(") {
+            if matches!(self.peek(), Some('@' | ')')) {
+                children = self.ids()?;
+            } else {
+                loop {
+                    let parameter = self.word()?.to_owned();
+
+                    self.expect("=")?;
+                    parameters.push((parameter, self.value()?));
+
+                    if self.eat(")") {
+                        break;
+                    }
+
+                    self.expect(",")?;
+                }
+
+                if self.eat("(") {
+                    children = self.ids()?;
+                }
+            }
+        }
+
+        Ok(Body::Op {
+            name,
+            parameters,
+            children,
+        })
+    }
+
+    fn graph(&mut self) -> Result<Graph, GraphParseError> {
+        let mut statements = Vec::new();
+
+        self.expect("(")?;
+
+        if !self.eat(")") {
+            loop {
+                let position = self.position;
+                let (id, name) = self.named_id()?;
+
+                self.expect(":")?;
+                statements.push(Statement {
+                    position,
+                    id,
+                    name,
+                    layout: self.layout()?,
+                    body: None,
+                });
+
+                if self.eat(")") {
+                    break;
+                }
+
+                self.expect(",")?;
+            }
+        }
+
+        let inputs = statements
+            .iter()
+            .map(|statement| statement.id)
+            .collect::<Vec<_>>();
+
+        self.expect("->")?;
+        self.expect("(")?;
+
+        let outputs_position = self.position;
+        let outputs = self.ids()?;
+
+        self.expect("{")?;
+
+        while !self.eat("}") {
+            self.peek();
+
+            let position = self.position;
+            let (id, name) = self.named_id()?;
+
+            self.expect(":")?;
+
+            let layout = self.layout()?;
+
+            self.expect("=")?;
+
+            let body = self.body()?;
+
+            self.expect(";")?;
+            statements.push(Statement {
+                position,
+                id,
+                name,
+                layout,
+                body: Some(body),
+            });
+        }
+
+        // Ids give the order expressions were added in, which children must respect.
+        statements.sort_by_key(|statement| statement.id);
+
+        let mut graph = Graph::new();
+        let mut ids = HashMap::new();
+
+        for Statement {
+            position,
+            id,
+            name,
+            layout,
+            body,
+        } in statements
+        {
+            let error = |message: String| self.error_at(position, message);
+
+            if ids.contains_key(&id) {
+                return Err(error(format!("@{id} is defined twice")));
+            }
+
+            let expr = match body {
+                None => graph.add_expr(ExprBody::Input(layout.clone())),
+                Some(Body::Const(None)) => {
+                    return Err(error(format!("the data of const @{id} was elided")))
+                }
+                Some(Body::Const(Some(data))) => {
+                    if data.len() != layout.elements() {
+                        return Err(error(format!(
+                            "const @{id} has {} elements, but {layout} has {}",
+                            data.len(),
+                            layout.elements()
+                        )));
+                    }
+
+                    graph.add_const(Tensor::from_parts(data.into(), layout.clone()))
+                }
+                Some(Body::Op {
+                    name,
+                    parameters,
+                    children,
+                }) => {
+                    let op = op(&name, parameters).map_err(error)?;
+                    let children = children
+                        .iter()
+                        .map(|child| {
+                            ids.get(child).copied().ok_or_else(|| {
+                                error(format!("@{child} is used by @{id} before it is defined"))
+                            })
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                    graph.add_op(op, &children)
+                }
+            };
+
+            if graph[expr].layout.dims() != layout.dims() {
+                return Err(error(format!(
+                    "@{id} is declared as {layout}, but is {}",
+                    graph[expr].layout
+                )));
+            }
+
+            if let Some(name) = name {
+                graph.names.insert(expr, name);
+            }
+
+            ids.insert(id, expr);
+        }
+
+        graph.inputs = inputs.iter().map(|id| ids[id]).collect();
+
+        for output in outputs {
+            let id = ids.get(&output).copied().ok_or_else(|| {
+                self.error_at(
+                    outputs_position,
+                    format!("output @{output} is never defined"),
+                )
+            })?;
+
+            graph.add_output(id);
+        }
+
+        Ok(graph)
+    }
+}
+
+fn field(fields: &mut Vec<(String, Value)>, name: &str) -> Result<Value, String> {
+    let index = fields
+        .iter()
+        .position(|(field, _)| field == name)
+        .ok_or_else(|| format!("missing `{name}`"))?;
+
+    Ok(fields.remove(index).1)
+}
+
+fn number<T: FromStr>(value: Value) -> Result<T, String> {
+    match value {
+        Value::Word(word) => word
+            .parse()
+            .map_err(|_| format!("`{word}` is not a number")),
+        _ => Err(String::from("expected a number")),
+    }
+}
+
+fn list<T>(value: Value, item: impl Fn(Value) -> Result<T, String>) -> Result<Vec<T>, String> {
+    match value {
+        Value::List(values) => values.into_iter().map(item).collect(),
+        _ => Err(String::from("expected a list")),
+    }
+}
+
+fn variant(value: Value) -> Result<(String, Vec<(String, Value)>), String> {
+    match value {
+        Value::Word(word) => Ok((word, Vec::new())),
+        Value::Struct(name, fields) => Ok((name, fields)),
+        _ => Err(String::from("expected a variant")),
+    }
+}
+
+fn shape(value: Value) -> Result<Shape, String> {
+    let (name, mut fields) = variant(value)?;
+
+    if name != "Shape" {
+        return Err(format!("expected a shape, found `{name}`"));
+    }
+
+    Ok(Shape {
+        dims: list(field(&mut fields, "dims")?, number)?.into(),
+        strides: list(field(&mut fields, "strides")?, number)?.into(),
+    })
+}
+
+fn graph(value: Value) -> Result<Arc<Graph>, String> {
+    match value {
+        Value::Graph(graph) => Ok(Arc::new(graph)),
+        _ => Err(String::from("expected a graph")),
+    }
+}
+
+fn predicate(value: Value) -> Result<Predicate, String> {
+    match variant(value)? {
+        (name, _) if name == "Finite" => Ok(Predicate::Finite),
+        (name, mut fields) if name == "InRange" => Ok(Predicate::InRange {
+            min: number(field(&mut fields, "min")?)?,
+            max: number(field(&mut fields, "max")?)?,
+        }),
+        (name, _) => Err(format!("unknown predicate `{name}`")),
+    }
+}
+
+fn distribution(value: Value) -> Result<Distribution, String> {
+    match variant(value)? {
+        (name, mut fields) if name == "Uniform" => Ok(Distribution::Uniform {
+            low: number(field(&mut fields, "low")?)?,
+            high: number(field(&mut fields, "high")?)?,
+        }),
+        (name, mut fields) if name == "Normal" => Ok(Distribution::Normal {
+            mean: number(field(&mut fields, "mean")?)?,
+            std: number(field(&mut fields, "std")?)?,
+        }),
+        (name, _) => Err(format!("unknown distribution `{name}`")),
+    }
+}
+
+fn mask(value: Value) -> Result<MatMulMask, String> {
+    match variant(value)? {
+        (name, _) if name == "Lower" => Ok(MatMulMask::Lower),
+        (name, _) if name == "Upper" => Ok(MatMulMask::Upper),
+        (name, mut fields) if name == "Blocks" => Ok(MatMulMask::Blocks {
+            size: number(field(&mut fields, "size")?)?,
+            tiles: list(field(&mut fields, "tiles")?, number)?,
+        }),
+        (name, _) => Err(format!("unknown mask `{name}`")),
+    }
+}
+
+// The op printed as `name(parameters)`, the inverse of its `Debug` implementation.
+fn op(name: &str, mut parameters: Vec<(String, Value)>) -> Result<Op, String> {
+    let parameters = &mut parameters;
+    let reduce = |op, parameters: &mut Vec<_>| -> Result<Op, String> {
+        Ok(Op::Reduce {
+            op,
+            dims: list(field(parameters, "dims")?, number)?,
+        })
+    };
+
+    let op = match name {
+        "add" => Op::Elemwise(ElemwiseOp::Add),
+        "mul" => Op::Elemwise(ElemwiseOp::Mul),
+        "sin" => Op::Elemwise(ElemwiseOp::Sin),
+        "rsqrt" => Op::Elemwise(ElemwiseOp::Rsqrt),
+        "fma" => Op::Elemwise(ElemwiseOp::Fma),
+        "exp" => Op::Elemwise(ElemwiseOp::Exp),
+        "log" => Op::Elemwise(ElemwiseOp::Log),
+        "tanh" => Op::Elemwise(ElemwiseOp::Tanh),
+        "floor" => Op::Elemwise(ElemwiseOp::Floor),
+        "ceil" => Op::Elemwise(ElemwiseOp::Ceil),
+        "clamp" => Op::Elemwise(ElemwiseOp::Clamp),
+        "complex_add" => Op::Complex(ComplexOp::Add),
+        "complex_mul" => Op::Complex(ComplexOp::Mul),
+        "conj" => Op::Complex(ComplexOp::Conj),
+        "sum" => reduce(ReduceOp::Sum, parameters)?,
+        "max" => reduce(ReduceOp::Max, parameters)?,
+        "mean" => reduce(ReduceOp::Mean, parameters)?,
+        "argmax" => reduce(ReduceOp::ArgMax, parameters)?,
+        "argmin" => reduce(ReduceOp::ArgMin, parameters)?,
+        "reshape" => Op::Movement(MovementOp::Reshape(shape(field(parameters, "shape")?)?)),
+        "transpose" => Op::Movement(MovementOp::Transpose),
+        "squeeze" => Op::Movement(MovementOp::Squeeze(
+            field(parameters, "dim").ok().map(number).transpose()?,
+        )),
+        "unsqueeze" => Op::Movement(MovementOp::Unsqueeze(number(field(parameters, "dim")?)?)),
+        "flip" => Op::Movement(MovementOp::Flip(list(field(parameters, "dims")?, number)?)),
+        "unfold" => Op::Movement(MovementOp::Unfold {
+            dim: number(field(parameters, "dim")?)?,
+            size: number(field(parameters, "size")?)?,
+            step: number(field(parameters, "step")?)?,
+        }),
+        "concat" => Op::Concat {
+            dim: number(field(parameters, "dim")?)?,
+        },
+        "matmul" => Op::MatMul,
+        "masked_matmul" => Op::MaskedMatMul {
+            mask: mask(field(parameters, "mask")?)?,
+        },
+        "attention" => Op::Attention {
+            scale: number(field(parameters, "scale")?)?,
+        },
+        "random" => Op::Random {
+            distribution: distribution(field(parameters, "distribution")?)?,
+            seed: number(field(parameters, "seed")?)?,
+            shape: shape(field(parameters, "shape")?)?,
+        },
+        "dropout" => Op::Dropout {
+            probability: number(field(parameters, "probability")?)?,
+            seed: number(field(parameters, "seed")?)?,
+        },
+        "repeat" => Op::Repeat {
+            repeats: list(field(parameters, "repeats")?, number)?,
+        },
+        "diagonal" => Op::Diagonal {
+            offset: number(field(parameters, "offset")?)?,
+        },
+        "trace" => Op::Trace,
+        "nonzero" => Op::Nonzero,
+        "masked_select" => Op::MaskedSelect,
+        "spmm" => Op::SpMM,
+        "spmv" => Op::SpMV,
+        "assert" => Op::Assert {
+            predicate: predicate(field(parameters, "predicate")?)?,
+            message: match field(parameters, "message")? {
+                Value::Str(message) => message,
+                _ => return Err(String::from("expected a string message")),
+            },
+        },
+        "if" => Op::If {
+            predicate: predicate(field(parameters, "predicate")?)?,
+            then_graph: graph(field(parameters, "then")?)?,
+            else_graph: graph(field(parameters, "else")?)?,
+        },
+        "scan" => Op::Scan {
+            dim: number(field(parameters, "dim")?)?,
+            body: graph(field(parameters, "body")?)?,
+        },
+        "fft" => Op::Fft {
+            dim: number(field(parameters, "dim")?)?,
+            inverse: number(field(parameters, "inverse")?)?,
+        },
+        _ => return Err(format!("unknown op `{name}`")),
+    };
+
+    match parameters.first() {
+        Some((parameter, _)) => Err(format!("{name} has no parameter `{parameter}`")),
+        None => Ok(op),
+    }
+}
+
+impl Graph {
+    /// Parses the text `Debug` prints for a graph, such as
+    /// `(@0: 4f32) -> (@2) { @1: 1f32 = [2.0]; @2: 4f32 = mul(@0, @1); }`. Large consts are
+    /// printed as `..` and cannot be parsed back, and custom ops are unknown to the parser. Named
+    /// expressions have their name after the id, as in `@2 "scale": 4f32 = mul(@0, @1);`.
+    ///
+    /// Like building the graph directly, this panics if an op's children have incompatible
+    /// layouts.
+    pub fn parse(text: &str) -> Result<Graph, GraphParseError> {
+        let mut parser = Parser { text, position: 0 };
+        let graph = parser.graph()?;
+
+        if parser.peek().is_some() {
+            return Err(parser.error("unexpected text after the graph"));
+        }
+
+        Ok(graph)
+    }
+}
+
+impl FromStr for Graph {
+    type Err = GraphParseError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        Self::parse(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        graph::{Distribution, ElemwiseOp, Graph, MovementOp, Op, Predicate, ReduceOp},
+        tensor::{Layout, Shape},
+    };
+
+    fn assert_round_trips(graph: &Graph) {
+        let text = format!("{graph:?}");
+        let parsed = Graph::parse(&text).unwrap_or_else(|error| panic!("{error} in {text}"));
+
+        assert_eq!(format!("{parsed:?}"), text);
+    }
+
+    #[test]
+    fn round_trips_movement_and_random() {
+        let mut graph = Graph::new();
+        let input = graph.add_input(Layout::from([4, 6]));
+        let unfolded = graph.add_op(
+            Op::Movement(MovementOp::Unfold {
+                dim: 1,
+                size: 3,
+                step: 2,
+            }),
+            &[input],
+        );
+        let flipped = graph.add_op(Op::Movement(MovementOp::Flip(vec![0, 2])), &[unfolded]);
+        let noise = graph.add_op(
+            Op::Random {
+                distribution: Distribution::Uniform {
+                    low: -0.5,
+                    high: 0.5,
+                },
+                seed: 7,
+                shape: Shape::from([4, 2, 3]),
+            },
+            &[],
+        );
+        let sum = graph.add_op(Op::Elemwise(ElemwiseOp::Add), &[flipped, noise]);
+        let normal = graph.add_op(
+            Op::Random {
+                distribution: Distribution::Normal {
+                    mean: 1.0,
+                    std: 2.0,
+                },
+                seed: 3,
+                shape: Shape::from([4, 2, 3]),
+            },
+            &[],
+        );
+        let output = graph.add_op(Op::Elemwise(ElemwiseOp::Mul), &[sum, normal]);
+
+        graph.add_output(output);
+
+        assert_round_trips(&graph);
+    }
+
+    #[test]
+    fn round_trips_subgraphs() {
+        let mut then_graph = Graph::new();
+        let input = then_graph.add_input(Layout::from([3]));
+        let doubled = then_graph.add_op(Op::Elemwise(ElemwiseOp::Add), &[input, input]);
+        then_graph.add_output(doubled);
+
+        let mut else_graph = Graph::new();
+        let input = else_graph.add_input(Layout::from([3]));
+        let flipped = else_graph.add_op(Op::Movement(MovementOp::Flip(vec![0])), &[input]);
+        else_graph.add_output(flipped);
+
+        let mut body = Graph::new();
+        let state = body.add_input(Layout::from([3]));
+        let element = body.add_input(Layout::from([3, 1]));
+        let element = body.add_op(Op::Movement(MovementOp::Squeeze(Some(1))), &[element]);
+        let next = body.add_op(Op::Elemwise(ElemwiseOp::Mul), &[state, element]);
+        body.add_output(next);
+
+        let mut graph = Graph::new();
+        let condition = graph.add_input(Layout::from([1]));
+        let input = graph.add_input(Layout::from([3]));
+        let sequence = graph.add_input(Layout::from([3, 5]));
+        let branched = graph.add_op(
+            Op::If {
+                predicate: Predicate::InRange { min: 0.0, max: 1.0 },
+                then_graph: Arc::new(then_graph),
+                else_graph: Arc::new(else_graph),
+            },
+            &[condition, input],
+        );
+        let states = graph.add_op(
+            Op::Scan {
+                body: Arc::new(body),
+                dim: 1,
+            },
+            &[branched, sequence],
+        );
+        let total = graph.add_op(
+            Op::Reduce {
+                op: ReduceOp::Sum,
+                dims: vec![0],
+            },
+            &[states],
+        );
+
+        graph.add_output(total);
+
+        assert_round_trips(&graph);
+    }
+}
@@ -0,0 +1,666 @@
+use std::{collections::HashMap, fmt, ops::Index, sync::Arc};
+
+use tracing::debug;
+
+use crate::{
+    graph::{dropout_scale, ElemwiseOp, ExprBody, ExprId, Graph, Mode, MovementOp, Op, ReduceOp},
+    interp,
+    tensor::Tensor,
+};
+
+/// A tree of expressions to look for in a graph, with named holes that bind the expressions found
+/// there.
+#[derive(Clone)]
+pub enum Pattern {
+    /// Any expression. A name used twice must bind the same expression both times.
+    Var(String),
+    /// Any const.
+    Const(String),
+    /// A const whose elements all equal the value.
+    Splat(f32),
+    /// An elementwise op whose children match in order, or in either order for add and mul.
+    Elemwise(ElemwiseOp, Vec<Pattern>),
+    /// Any op the predicate accepts, with exactly as many children, matching in order.
+    Op(fn(&Op) -> bool, Vec<Pattern>),
+}
+
+impl Pattern {
+    pub fn var(name: impl Into<String>) -> Self {
+        Self::Var(name.into())
+    }
+
+    pub fn constant(name: impl Into<String>) -> Self {
+        Self::Const(name.into())
+    }
+
+    pub fn splat(value: f32) -> Self {
+        Self::Splat(value)
+    }
+
+    pub fn elemwise(op: ElemwiseOp, children: impl Into<Vec<Pattern>>) -> Self {
+        Self::Elemwise(op, children.into())
+    }
+
+    pub fn op(matches: fn(&Op) -> bool, children: impl Into<Vec<Pattern>>) -> Self {
+        Self::Op(matches, children.into())
+    }
+
+    fn matches(&self, graph: &Graph, id: ExprId, bindings: &mut HashMap<String, ExprId>) -> bool {
+        let body = &graph[id].body;
+
+        match self {
+            Pattern::Var(name) => *bindings.entry(name.clone()).or_insert(id) == id,
+            Pattern::Const(name) => {
+                matches!(body, ExprBody::Const(_))
+                    && *bindings.entry(name.clone()).or_insert(id) == id
+            }
+            Pattern::Splat(value) => match body {
+                ExprBody::Const(tensor) => tensor.data.iter().all(|element| element == value),
+                _ => false,
+            },
+            Pattern::Elemwise(op, patterns) => {
+                let ExprBody::Op {
+                    op: Op::Elemwise(actual),
+                    children,
+                } = body
+                else {
+                    return false;
+                };
+
+                if actual != op || children.len() != patterns.len() {
+                    return false;
+                }
+
+                let saved = bindings.clone();
+
+                if Self::matches_all(patterns, graph, children, bindings) {
+                    return true;
+                }
+
+                *bindings = saved;
+
+                let commutative = matches!(op, ElemwiseOp::Add | ElemwiseOp::Mul);
+
+                commutative
+                    && Self::matches_all(patterns, graph, &[children[1], children[0]], bindings)
+            }
+            Pattern::Op(matches, patterns) => match body {
+                ExprBody::Op { op, children } => {
+                    matches(op)
+                        && children.len() == patterns.len()
+                        && Self::matches_all(patterns, graph, children, bindings)
+                }
+                _ => false,
+            },
+        }
+    }
+
+    fn matches_all(
+        patterns: &[Pattern],
+        graph: &Graph,
+        children: &[ExprId],
+        bindings: &mut HashMap<String, ExprId>,
+    ) -> bool {
+        patterns
+            .iter()
+            .zip(children)
+            .all(|(pattern, &child)| pattern.matches(graph, child, bindings))
+    }
+}
+
+/// Where a pattern matched: the expression at its root, and what each of its names bound.
+#[derive(Clone, Debug)]
+pub struct Match {
+    root: ExprId,
+    bindings: HashMap<String, ExprId>,
+}
+
+impl Match {
+    pub fn root(&self) -> ExprId {
+        self.root
+    }
+
+    pub fn get(&self, name: &str) -> Option<ExprId> {
+        self.bindings.get(name).copied()
+    }
+}
+
+impl Index<&str> for Match {
+    type Output = ExprId;
+
+    fn index(&self, name: &str) -> &ExprId {
+        self.bindings
+            .get(name)
+            .unwrap_or_else(|| panic!("pattern has no variable named {name}"))
+    }
+}
+
+type Replace = dyn Fn(&mut Graph, &Match) -> Option<ExprId> + Send + Sync;
+
+/// A rule replacing the expressions a pattern matches. The replacement adds whatever it needs to
+/// the graph and returns the expression standing in for the root, or `None` to leave it be.
+#[derive(Clone)]
+pub struct Rewrite {
+    name: String,
+    pattern: Pattern,
+    replace: Arc<Replace>,
+}
+
+impl fmt::Debug for Rewrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Rewrite").field(&self.name).finish()
+    }
+}
+
+impl Rewrite {
+    pub fn new(
+        name: impl Into<String>,
+        pattern: Pattern,
+        replace: impl Fn(&mut Graph, &Match) -> Option<ExprId> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            pattern,
+            replace: Arc::new(replace),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Replaces the pattern with the expression bound to `name`.
+    pub fn to_var(name: impl Into<String>, pattern: Pattern, var: &'static str) -> Self {
+        Self::new(name, pattern, move |_, found| Some(found[var]))
+    }
+
+    /// Fuses `a * b + c` into an fma. Not part of the standard rules, as rounding once changes
+    /// results slightly.
+    pub fn fuse_fma() -> Self {
+        Self::new(
+            "a * b + c -> fma(a, b, c)",
+            Pattern::elemwise(
+                ElemwiseOp::Add,
+                [
+                    Pattern::elemwise(ElemwiseOp::Mul, [Pattern::var("a"), Pattern::var("b")]),
+                    Pattern::var("c"),
+                ],
+            ),
+            |graph, found| {
+                Some(graph.add_op(
+                    Op::Elemwise(ElemwiseOp::Fma),
+                    &[found["a"], found["b"], found["c"]],
+                ))
+            },
+        )
+    }
+
+    /// Evaluates elementwise ops whose `arity` children are all consts into a const.
+    pub fn fold_constants(arity: usize) -> Self {
+        Self::new(
+            format!("fold {arity}-ary constants"),
+            Pattern::op(
+                |op| matches!(op, Op::Elemwise(_)),
+                (0..arity)
+                    .map(|index| Pattern::constant(format!("c{index}")))
+                    .collect::<Vec<_>>(),
+            ),
+            |graph, found| {
+                let ExprBody::Op { op, children } = &graph[found.root()].body else {
+                    unreachable!("pattern only matches ops");
+                };
+
+                let tensors = children
+                    .iter()
+                    .map(|&child| match &graph[child].body {
+                        ExprBody::Const(tensor) => tensor,
+                        _ => unreachable!("pattern only matches consts"),
+                    })
+                    .collect::<Vec<_>>();
+
+                let tensor = interp::eval_op(op, graph.mode, &graph[found.root()].layout, &tensors);
+
+                Some(graph.add_const(tensor))
+            },
+        )
+    }
+    /// Fuses `softmax(q @ transpose(k) * scale) @ v`, with the softmax spelled like
+    /// `builder::Softmax` builds it, into an attention op. The query, key and value must have the
+    /// shapes attention takes, without broadcasting.
+    pub fn fuse_attention() -> Self {
+        Self::new(
+            "softmax(q @ k^T * scale) @ v -> attention(q, k, v)",
+            Pattern::op(
+                |op| matches!(op, Op::MatMul),
+                [Pattern::var("p"), Pattern::var("v")],
+            ),
+            |graph, found| {
+                let (query, key, value, scale) = attention_operands(graph, found.root())?;
+
+                Some(graph.add_op(Op::Attention { scale }, &[query, key, value]))
+            },
+        )
+    }
+
+    /// Replaces random ops with consts holding the values they would draw, with their seeds
+    /// xored with `seed`. A zero `seed` keeps the values the ops draw on the device.
+    pub fn freeze_random(seed: u64) -> Self {
+        Self::new(
+            "random -> const",
+            Pattern::op(|op| matches!(op, Op::Random { .. }), []),
+            move |graph, found| {
+                let ExprBody::Op {
+                    op:
+                        Op::Random {
+                            distribution,
+                            seed: own,
+                            shape,
+                        },
+                    ..
+                } = &graph[found.root()].body
+                else {
+                    unreachable!("pattern only matches random ops");
+                };
+
+                let op = Op::Random {
+                    distribution: *distribution,
+                    seed: own ^ seed,
+                    shape: shape.clone(),
+                };
+                let tensor = interp::eval_op(&op, graph.mode, &graph[found.root()].layout, &[]);
+
+                Some(graph.add_const(tensor))
+            },
+        )
+    }
+
+    /// Replaces dropout in training mode with a product by a const mask, drawn like
+    /// `freeze_random` draws its values. Dropout in inference mode draws nothing, so it is kept.
+    pub fn freeze_dropout(seed: u64) -> Self {
+        Self::new(
+            "dropout(x) -> x * mask",
+            Pattern::op(|op| matches!(op, Op::Dropout { .. }), [Pattern::var("x")]),
+            move |graph, found| {
+                let ExprBody::Op {
+                    op:
+                        Op::Dropout {
+                            probability,
+                            seed: own,
+                        },
+                    ..
+                } = graph[found.root()].body
+                else {
+                    unreachable!("pattern only matches dropout ops");
+                };
+
+                if graph.mode == Mode::Inference {
+                    return None;
+                }
+
+                let layout = graph[found.root()].layout.contiguous();
+                let mask = Tensor::from_parts(
+                    (0..layout.elements() as u32)
+                        .map(|index| dropout_scale(probability, own ^ seed, index))
+                        .collect(),
+                    layout,
+                );
+                let mask = graph.add_const(mask);
+
+                Some(graph.add_op(Op::Elemwise(ElemwiseOp::Mul), &[found["x"], mask]))
+            },
+        )
+    }
+}
+
+/// Applies rewrites to a graph until none of them match, or a pass limit is reached.
+#[derive(Clone, Debug)]
+pub struct Rewriter {
+    rules: Vec<Rewrite>,
+    max_passes: usize,
+}
+
+impl Default for Rewriter {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            max_passes: 16,
+        }
+    }
+}
+
+impl Rewriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Multiplying by one, adding zero, and folding elementwise ops on consts.
+    pub fn standard() -> Self {
+        Self::new()
+            .rule(Rewrite::to_var(
+                "x * 1 -> x",
+                Pattern::elemwise(ElemwiseOp::Mul, [Pattern::var("x"), Pattern::splat(1.0)]),
+                "x",
+            ))
+            .rule(Rewrite::to_var(
+                "x + 0 -> x",
+                Pattern::elemwise(ElemwiseOp::Add, [Pattern::var("x"), Pattern::splat(0.0)]),
+                "x",
+            ))
+            .rule(Rewrite::fold_constants(1))
+            .rule(Rewrite::fold_constants(2))
+            .rule(Rewrite::fold_constants(3))
+    }
+
+    pub fn rule(mut self, rewrite: Rewrite) -> Self {
+        self.rules.push(rewrite);
+        self
+    }
+
+    pub fn max_passes(mut self, max_passes: usize) -> Self {
+        self.max_passes = max_passes;
+        self
+    }
+
+    pub fn rules(&self) -> &[Rewrite] {
+        &self.rules
+    }
+
+    /// A rewritten copy of the graph, without the expressions that no longer contribute to its
+    /// outputs. Inputs and outputs keep their order, and branch and scan bodies are rewritten too.
+    pub fn rewrite(&self, graph: &Graph) -> Graph {
+        self.rewrite_mapped(graph).0
+    }
+
+    /// Like `rewrite`, but also gives the id each expression of `graph` ended up at, or what
+    /// replaced it, unless it was dropped.
+    pub fn rewrite_mapped(&self, graph: &Graph) -> (Graph, Vec<Option<ExprId>>) {
+        let mut graph = graph.clone();
+        let mut ids = (0..graph.exprs.len())
+            .map(|id| Some(ExprId(id)))
+            .collect::<Vec<_>>();
+
+        for _ in 0..self.max_passes {
+            let (rewritten, rewritten_ids, applied) = self.pass(&graph);
+
+            // Replaced expressions are dropped right away, so they cannot match again.
+            let (pruned, pruned_ids) = prune(&rewritten);
+
+            graph = pruned;
+            ids = compose(&ids, &rewritten_ids, &pruned_ids);
+
+            if applied == 0 {
+                break;
+            }
+        }
+
+        (graph, ids)
+    }
+
+    fn pass(&self, graph: &Graph) -> (Graph, Vec<Option<ExprId>>, usize) {
+        let mut applied = 0;
+
+        let (rewritten, ids) = rebuild(
+            graph,
+            |_| true,
+            |rewritten, id| {
+                if let ExprBody::Op { op, .. } = &mut rewritten[id].body {
+                    match op {
+                        Op::If {
+                            then_graph,
+                            else_graph,
+                            ..
+                        } => {
+                            *then_graph = Arc::new(self.rewrite(then_graph));
+                            *else_graph = Arc::new(self.rewrite(else_graph));
+                        }
+                        Op::Scan { body, .. } => *body = Arc::new(self.rewrite(body)),
+                        _ => {}
+                    }
+                }
+
+                match self.apply(rewritten, id) {
+                    Some(replacement) => {
+                        applied += 1;
+
+                        replacement
+                    }
+                    None => id,
+                }
+            },
+        );
+
+        (rewritten, ids, applied)
+    }
+
+    // The first rule whose pattern matches and whose replacement keeps the root's layout.
+    fn apply(&self, graph: &mut Graph, id: ExprId) -> Option<ExprId> {
+        for rule in &self.rules {
+            let mut bindings = HashMap::new();
+
+            if !rule.pattern.matches(graph, id, &mut bindings) {
+                continue;
+            }
+
+            let found = Match { root: id, bindings };
+
+            if let Some(replacement) = (rule.replace)(graph, &found) {
+                if graph[replacement].layout == graph[id].layout {
+                    debug!(rule = rule.name, ?id, ?replacement, "applied rewrite");
+
+                    return Some(replacement);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+// Copies the expressions `keep` accepts into a new graph, letting `map` redirect each copied
+// expression to another one in the new graph. Also gives the id each kept expression maps to.
+fn rebuild(
+    graph: &Graph,
+    keep: impl Fn(ExprId) -> bool,
+    mut map: impl FnMut(&mut Graph, ExprId) -> ExprId,
+) -> (Graph, Vec<Option<ExprId>>) {
+    let mut rebuilt = Graph::new();
+    let mut ids = vec![None; graph.exprs.len()];
+
+    rebuilt.mode = graph.mode;
+
+    for (index, expr) in graph.exprs.iter().enumerate() {
+        if !keep(ExprId(index)) {
+            continue;
+        }
+
+        let id = rebuilt.add_expr(match &expr.body {
+            ExprBody::Op { op, children } => ExprBody::Op {
+                op: op.clone(),
+                children: children
+                    .iter()
+                    .map(|child| ids[child.0].expect("kept expression has a pruned child"))
+                    .collect(),
+            },
+            body => body.clone(),
+        });
+
+        ids[index] = Some(map(&mut rebuilt, id));
+    }
+
+    let get = |id: &ExprId| ids[id.0].unwrap();
+
+    rebuilt.inputs = graph.inputs.iter().map(get).collect();
+    rebuilt.outputs = graph.outputs.iter().map(get).collect();
+    rebuilt.keep_f32 = graph
+        .keep_f32
+        .iter()
+        .filter(|id| ids[id.0].is_some())
+        .map(get)
+        .collect();
+    rebuilt.names = graph
+        .names
+        .iter()
+        .filter(|(id, _)| ids[id.0].is_some())
+        .map(|(id, name)| (get(id), name.clone()))
+        .collect();
+
+    (rebuilt, ids)
+}
+
+// Drops the expressions that neither outputs nor assertions depend on. Inputs are always kept, so
+// the graph's signature does not change.
+fn prune(graph: &Graph) -> (Graph, Vec<Option<ExprId>>) {
+    let roots = graph
+        .exprs
+        .iter()
+        .enumerate()
+        .filter(|(_, expr)| {
+            matches!(
+                expr.body,
+                ExprBody::Op {
+                    op: Op::Assert { .. },
+                    ..
+                }
+            )
+        })
+        .map(|(index, _)| ExprId(index))
+        .chain(graph.outputs.iter().copied())
+        .collect::<Vec<_>>();
+
+    let live = graph.region(&[], &roots);
+
+    rebuild(
+        graph,
+        |id| graph.inputs.contains(&id) || live.binary_search(&id).is_ok(),
+        |_, id| id,
+    )
+}
+
+// Follows ids through a pass and the pruning after it.
+fn compose(
+    ids: &[Option<ExprId>],
+    rewritten: &[Option<ExprId>],
+    pruned: &[Option<ExprId>],
+) -> Vec<Option<ExprId>> {
+    ids.iter().map(|&id| pruned[rewritten[id?.0]?.0]).collect()
+}
+
+// The children of `id` if it is an op `matches` accepts, with exactly `N` children.
+fn children<const N: usize>(
+    graph: &Graph,
+    id: ExprId,
+    matches: impl Fn(&Op) -> bool,
+) -> Option<[ExprId; N]> {
+    match &graph[id].body {
+        ExprBody::Op { op, children } if matches(op) => children[..].try_into().ok(),
+        _ => None,
+    }
+}
+
+fn elemwise<const N: usize>(graph: &Graph, id: ExprId, op: ElemwiseOp) -> Option<[ExprId; N]> {
+    children(
+        graph,
+        id,
+        |actual| matches!(actual, Op::Elemwise(actual) if *actual == op),
+    )
+}
+
+// The input of a reduction over only its last dimension.
+fn reduced_last(graph: &Graph, id: ExprId, op: ReduceOp) -> Option<ExprId> {
+    let [input] = children(graph, id, |actual| match actual {
+        Op::Reduce { op: actual, dims } => *actual == op && dims.len() == 1,
+        _ => false,
+    })?;
+    let ExprBody::Op {
+        op: Op::Reduce { dims, .. },
+        ..
+    } = &graph[id].body
+    else {
+        unreachable!("only reductions are matched");
+    };
+
+    (dims[0] + 1 == graph[input].layout.rank()).then_some(input)
+}
+
+// The value every element of a const shares.
+fn splat(graph: &Graph, id: ExprId) -> Option<f32> {
+    match &graph[id].body {
+        ExprBody::Const(tensor) => {
+            let first = *tensor.data.first()?;
+
+            tensor
+                .data
+                .iter()
+                .all(|&element| element == first)
+                .then_some(first)
+        }
+        _ => None,
+    }
+}
+
+// Tries both orders of the children of a commutative op.
+fn either<T>([a, b]: [ExprId; 2], f: impl Fn(ExprId, ExprId) -> Option<T>) -> Option<T> {
+    f(a, b).or_else(|| f(b, a))
+}
+
+// The input of a softmax spelled like `builder::Softmax` builds it.
+fn softmax_input(graph: &Graph, id: ExprId) -> Option<ExprId> {
+    either(elemwise(graph, id, ElemwiseOp::Mul)?, |exp, inverse| {
+        let [root, other] = elemwise(graph, inverse, ElemwiseOp::Mul)?;
+        let [sum] = elemwise(graph, root, ElemwiseOp::Rsqrt)?;
+
+        if root != other || reduced_last(graph, sum, ReduceOp::Sum)? != exp {
+            return None;
+        }
+
+        let [shifted] = elemwise(graph, exp, ElemwiseOp::Exp)?;
+
+        either(
+            elemwise(graph, shifted, ElemwiseOp::Add)?,
+            |input, negated| {
+                either(
+                    elemwise(graph, negated, ElemwiseOp::Mul)?,
+                    |max, minus_one| {
+                        (splat(graph, minus_one)? == -1.0
+                            && reduced_last(graph, max, ReduceOp::Max)? == input)
+                            .then_some(input)
+                    },
+                )
+            },
+        )
+    })
+}
+
+// The query, key, value and scale of `softmax(q @ transpose(k) * scale) @ v` at `id`, if their
+// shapes fit an attention op.
+pub(crate) fn attention_operands(
+    graph: &Graph,
+    id: ExprId,
+) -> Option<(ExprId, ExprId, ExprId, f32)> {
+    let [probabilities, value] = children(graph, id, |op| matches!(op, Op::MatMul))?;
+    let scaled = softmax_input(graph, probabilities)?;
+    let (scores, scale) = either(
+        elemwise(graph, scaled, ElemwiseOp::Mul)?,
+        |scores, scale| Some((scores, splat(graph, scale)?)),
+    )?;
+    let [query, transposed] = children(graph, scores, |op| matches!(op, Op::MatMul))?;
+    let [key] = children(graph, transposed, |op| {
+        matches!(op, Op::Movement(MovementOp::Transpose))
+    })?;
+
+    let (query_dims, key_dims, value_dims) = (
+        graph[query].layout.dims(),
+        graph[key].layout.dims(),
+        graph[value].layout.dims(),
+    );
+    let rank = query_dims.len();
+
+    (rank >= 2
+        && key_dims.len() == rank
+        && value_dims.len() == rank
+        && query_dims[..rank - 2] == key_dims[..rank - 2]
+        && key_dims[..rank - 2] == value_dims[..rank - 2]
+        && query_dims[rank - 1] == key_dims[rank - 1]
+        && key_dims[rank - 2] == value_dims[rank - 2])
+        .then_some((query, key, value, scale))
+}
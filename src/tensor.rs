@@ -1,16 +1,18 @@
 use std::{
+    error::Error,
     fmt::{self, Debug, Display, Formatter},
     iter, mem,
+    ops::Range,
 };
 
 use serde::{Deserialize, Serialize};
 
 pub(crate) type DimId = usize;
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Shape {
     pub(crate) dims: Box<[usize]>,
-    pub(crate) strides: Box<[usize]>,
+    pub(crate) strides: Box<[isize]>,
 }
 
 impl Display for Shape {
@@ -30,7 +32,7 @@ impl Shape {
     fn contiguous(dims: Box<[usize]>) -> Self {
         Self {
             strides: (1..dims.len())
-                .map(|start_dim| dims[start_dim..].iter().product())
+                .map(|start_dim| dims[start_dim..].iter().product::<usize>() as isize)
                 .chain(iter::once(1))
                 .collect(),
             dims,
@@ -49,7 +51,7 @@ impl Shape {
         self.dims.len()
     }
 
-    pub fn strides(&self) -> &[usize] {
+    pub fn strides(&self) -> &[isize] {
         &self.strides
     }
 }
@@ -90,9 +92,12 @@ impl From<Shape> for Box<[usize]> {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Layout {
     pub(crate) shape: Shape,
+    // The element the layout starts at, which negative strides count back from.
+    #[serde(default)]
+    pub(crate) offset: usize,
 }
 
 impl Display for Layout {
@@ -105,6 +110,7 @@ impl<T: Into<Shape>> From<T> for Layout {
     fn from(value: T) -> Self {
         Self {
             shape: value.into(),
+            offset: 0,
         }
     }
 }
@@ -114,6 +120,36 @@ impl Layout {
         Self::from([])
     }
 
+    /// A view of `dims` stepping `strides` elements along each dimension, from element `offset`
+    /// of its buffer. Zero strides repeat elements, and negative ones walk a dimension backwards,
+    /// which the offset has to leave room for.
+    pub fn strided(
+        dims: impl Into<Box<[usize]>>,
+        strides: impl Into<Box<[isize]>>,
+        offset: usize,
+    ) -> Self {
+        let (dims, strides) = (dims.into(), strides.into());
+
+        assert_eq!(
+            dims.len(),
+            strides.len(),
+            "a layout needs a stride for each of its dimensions"
+        );
+
+        let layout = Self {
+            shape: Shape { dims, strides },
+            offset,
+        };
+
+        assert!(
+            layout.elements() == 0 || layout.reach().0 <= offset,
+            "{layout} with strides {:?} reaches before the start of its buffer from element {offset}",
+            layout.strides()
+        );
+
+        layout
+    }
+
     pub fn shape(&self) -> &Shape {
         &self.shape
     }
@@ -126,20 +162,437 @@ impl Layout {
         self.shape().rank()
     }
 
-    pub fn strides(&self) -> &[usize] {
+    pub fn strides(&self) -> &[isize] {
         self.shape().strides()
     }
 
+    /// The element the first one in row-major order is at.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
     pub fn elements(&self) -> usize {
         self.shape().elements()
     }
 
+    /// The number of bytes of buffer the layout needs, up to and including the last element it
+    /// addresses. Overlapping views need less than one per element.
     pub fn size(&self) -> usize {
-        self.elements() * mem::size_of::<f32>()
+        if self.elements() == 0 {
+            return 0;
+        }
+
+        let (_, after) = self.reach();
+
+        (self.offset + after + 1) * mem::size_of::<f32>()
+    }
+
+    // How many elements before and after the offset the layout addresses, for a non-empty layout.
+    fn reach(&self) -> (usize, usize) {
+        self.dims()
+            .iter()
+            .zip(self.strides())
+            .fold((0, 0), |(before, after), (&dim, &stride)| {
+                let extent = (dim - 1) * stride.unsigned_abs();
+
+                if stride < 0 {
+                    (before + extent, after)
+                } else {
+                    (before, after + extent)
+                }
+            })
+    }
+
+    // The element `index` steps along `dim` away from `element`. Stepping from the offset towards
+    // any element the layout addresses never goes before the start of the buffer.
+    pub(crate) fn step(&self, element: usize, dim: DimId, index: usize) -> usize {
+        element
+            .checked_add_signed(index as isize * self.strides()[dim])
+            .expect("stepped before the start of the buffer")
+    }
+
+    // Splits `dim` into windows of `size` elements starting every `step` elements, indexed by a
+    // new last dimension. Windows overlap when `step` is smaller than `size`.
+    pub(crate) fn unfold(&self, dim: DimId, size: usize, step: usize) -> Self {
+        assert!(size > 0 && step > 0, "unfold windows must be non-empty");
+        assert!(
+            size <= self.dims()[dim],
+            "unfold window is larger than its dimension"
+        );
+
+        let mut dims = self.dims().to_vec();
+        let mut strides = self.strides().to_vec();
+        let stride = strides[dim];
+
+        dims[dim] = (dims[dim] - size) / step + 1;
+        strides[dim] = stride * step as isize;
+        dims.push(size);
+        strides.push(stride);
+
+        self.with_dims(dims, strides)
+    }
+
+    // Whether tiling by `repeats` only repeats dimensions of size one, which a zero stride can do
+    // without copying.
+    pub(crate) fn can_expand(&self, repeats: &[usize]) -> bool {
+        self.dims()
+            .iter()
+            .zip(repeats)
+            .all(|(&dim, &repeats)| dim == 1 || repeats == 1)
+    }
+
+    pub(crate) fn repeat(&self, repeats: &[usize]) -> Self {
+        assert_eq!(
+            repeats.len(),
+            self.rank(),
+            "repeat needs a count for every dimension"
+        );
+        assert!(
+            repeats.iter().all(|&repeats| repeats > 0),
+            "repeat counts must be positive"
+        );
+
+        let dims = self
+            .dims()
+            .iter()
+            .zip(repeats)
+            .map(|(dim, repeats)| dim * repeats)
+            .collect::<Vec<_>>();
+
+        if !self.can_expand(repeats) {
+            return Self::from(dims);
+        }
+
+        self.with_dims(
+            dims,
+            self.strides()
+                .iter()
+                .zip(repeats)
+                .map(|(&stride, &repeats)| if repeats == 1 { stride } else { 0 })
+                .collect(),
+        )
+    }
+
+    // The diagonal of the last two dimensions as a strided view, indexed by a new last dimension.
+    // Positive offsets start right of the main diagonal, negative ones below it.
+    pub(crate) fn diagonal_view(&self, offset: isize) -> Self {
+        let rank = self.rank();
+
+        assert!(rank >= 2, "diagonal needs at least two dimensions");
+
+        let (rows, columns) = (self.dims()[rank - 2], self.dims()[rank - 1]);
+        let (row, column) = if offset >= 0 {
+            (0, offset.unsigned_abs())
+        } else {
+            (offset.unsigned_abs(), 0)
+        };
+
+        assert!(
+            row < rows && column < columns,
+            "diagonal offset is outside the matrix"
+        );
+
+        let mut dims = self.dims()[..rank - 2].to_vec();
+        let mut strides = self.strides()[..rank - 2].to_vec();
+
+        dims.push((rows - row).min(columns - column));
+        strides.push(self.strides()[rank - 2] + self.strides()[rank - 1]);
+
+        Self {
+            offset: self.step(self.step(self.offset, rank - 2, row), rank - 1, column),
+            ..self.with_dims(dims, strides)
+        }
+    }
+
+    // Buffers on the device always start at their layout's first element, so only the main
+    // diagonal stays a view.
+    pub(crate) fn diagonal(&self, offset: isize) -> Self {
+        let view = self.diagonal_view(offset);
+
+        if offset == 0 {
+            view
+        } else {
+            view.contiguous()
+        }
+    }
+
+    /// The same elements in row-major order, viewed with the dims of `shape`. The strides are
+    /// worked out from this layout's, which can't always express the new dims, as when merging
+    /// transposed dimensions. Those reshapes need a copy.
+    pub fn reshape(&self, shape: Shape) -> Result<Self, ReshapeError> {
+        let dims = shape.dims();
+
+        if shape.elements() != self.elements() {
+            return Err(ReshapeError::ElementCount {
+                layout: self.clone(),
+                shape,
+            });
+        }
+
+        // Empty layouts and scalars address at most one element, which any strides reach.
+        if self.elements() == 0 || self.rank() == 0 {
+            return Ok(Self {
+                shape: Shape::from(dims),
+                offset: self.offset,
+            });
+        }
+
+        // Runs of dimensions that step through memory like one dimension would are split into the
+        // new dims that cover them, like PyTorch's `view` does.
+        let mut strides = vec![0; dims.len()];
+        let mut view_dim = dims.len();
+        let mut base_stride = self.strides()[self.rank() - 1];
+        let (mut elements, mut view_elements) = (1, 1);
+
+        for dim in (0..self.rank()).rev() {
+            elements *= self.dims()[dim];
+
+            let run_ends = dim == 0
+                || (self.dims()[dim - 1] != 1
+                    && self.strides()[dim - 1] != elements as isize * base_stride);
+
+            if !run_ends {
+                continue;
+            }
+
+            while view_dim > 0 && (view_elements < elements || dims[view_dim - 1] == 1) {
+                view_dim -= 1;
+                strides[view_dim] = view_elements as isize * base_stride;
+                view_elements *= dims[view_dim];
+            }
+
+            if view_elements != elements {
+                return Err(ReshapeError::NeedsCopy {
+                    layout: self.clone(),
+                    shape,
+                });
+            }
+
+            if dim > 0 {
+                base_stride = self.strides()[dim - 1];
+                elements = 1;
+                view_elements = 1;
+            }
+        }
+
+        if view_dim > 0 {
+            return Err(ReshapeError::NeedsCopy {
+                layout: self.clone(),
+                shape,
+            });
+        }
+
+        Ok(self.with_dims(dims.to_vec(), strides))
+    }
+
+    fn with_dims(&self, dims: Vec<usize>, strides: Vec<isize>) -> Self {
+        Self {
+            shape: Shape {
+                dims: dims.into_boxed_slice(),
+                strides: strides.into_boxed_slice(),
+            },
+            offset: self.offset,
+        }
+    }
+
+    /// The elements `range` of `dim`, as a view of the same buffer.
+    pub fn slice(&self, dim: DimId, range: Range<usize>) -> Self {
+        assert!(dim < self.rank(), "cannot slice dimension {dim} of {self}");
+        assert!(
+            range.start <= range.end && range.end <= self.dims()[dim],
+            "slice {range:?} is outside dimension {dim} of {self}"
+        );
+
+        let mut dims = self.dims().to_vec();
+
+        dims[dim] = range.len();
+
+        let layout = self.with_dims(dims, self.strides().to_vec());
+
+        // Empty views address nothing, so they start anywhere.
+        if layout.elements() == 0 {
+            return Self {
+                offset: 0,
+                ..layout
+            };
+        }
+
+        Self {
+            offset: self.step(self.offset, dim, range.start),
+            ..layout
+        }
+    }
+
+    /// Index `index` of `dim` as a view of the same buffer, without that dimension.
+    pub fn select(&self, dim: DimId, index: usize) -> Self {
+        assert!(
+            dim < self.rank(),
+            "cannot select from dimension {dim} of {self}"
+        );
+        assert!(
+            index < self.dims()[dim],
+            "index {index} is outside dimension {dim} of {self}"
+        );
+
+        let mut dims = self.dims().to_vec();
+        let mut strides = self.strides().to_vec();
+
+        dims.remove(dim);
+        strides.remove(dim);
+
+        let layout = self.with_dims(dims, strides);
+
+        if layout.elements() == 0 {
+            return Self {
+                offset: 0,
+                ..layout
+            };
+        }
+
+        Self {
+            offset: self.step(self.offset, dim, index),
+            ..layout
+        }
+    }
+
+    /// Reorders the dimensions, so that dimension `i` of the result is `order[i]` of this layout.
+    pub fn permute(&self, order: &[DimId]) -> Self {
+        let mut sorted = order.to_vec();
+
+        sorted.sort_unstable();
+
+        assert!(
+            sorted == (0..self.rank()).collect::<Vec<_>>(),
+            "{order:?} is not a permutation of the dimensions of {self}"
+        );
+
+        self.with_dims(
+            order.iter().map(|&dim| self.dims()[dim]).collect(),
+            order.iter().map(|&dim| self.strides()[dim]).collect(),
+        )
+    }
+
+    // Drops `dim`, which has to be of size one, or every dimension of size one if there is none.
+    pub(crate) fn squeeze(&self, dim: Option<DimId>) -> Self {
+        if let Some(dim) = dim {
+            assert!(
+                dim < self.rank() && self.dims()[dim] == 1,
+                "cannot squeeze dimension {dim} of {self}, which is not of size one"
+            );
+        }
+
+        let (dims, strides) = (0..self.rank())
+            .filter(|&index| match dim {
+                Some(dim) => index != dim,
+                None => self.dims()[index] != 1,
+            })
+            .map(|index| (self.dims()[index], self.strides()[index]))
+            .unzip();
+
+        self.with_dims(dims, strides)
+    }
+
+    // Inserts a dimension of size one before `dim`. Its stride is never stepped along, so it is
+    // the one a contiguous layout would have.
+    pub(crate) fn unsqueeze(&self, dim: DimId) -> Self {
+        assert!(
+            dim <= self.rank(),
+            "cannot insert a dimension at {dim} of {self}"
+        );
+
+        let mut dims = self.dims().to_vec();
+        let mut strides = self.strides().to_vec();
+        let stride = if dim < self.rank() {
+            self.strides()[dim] * self.dims()[dim] as isize
+        } else {
+            1
+        };
+
+        dims.insert(dim, 1);
+        strides.insert(dim, stride);
+
+        self.with_dims(dims, strides)
+    }
+
+    /// Walks each of `dims` backwards, starting from its last element.
+    pub fn flip(&self, dims: &[DimId]) -> Self {
+        let mut strides = self.strides().to_vec();
+        let mut offset = self.offset;
+
+        for (position, &dim) in dims.iter().enumerate() {
+            assert!(dim < self.rank(), "cannot flip dimension {dim} of {self}");
+            assert!(
+                !dims[..position].contains(&dim),
+                "dimension {dim} is flipped twice"
+            );
+
+            if self.dims()[dim] > 0 {
+                offset = self.step(offset, dim, self.dims()[dim] - 1);
+            }
+
+            strides[dim] = -strides[dim];
+        }
+
+        Self {
+            offset,
+            ..self.with_dims(self.dims().to_vec(), strides)
+        }
     }
 
-    pub fn reshape(&self, shape: Shape) -> Self {
-        Self { shape }
+    pub fn is_contiguous(&self) -> bool {
+        self.offset == 0 && *self.shape() == Shape::contiguous(self.dims().into())
+    }
+
+    /// Whether the layout starts at the start of its buffer and only walks forwards through it,
+    /// which graphs need of their inputs.
+    pub fn is_forward(&self) -> bool {
+        self.offset == 0 && self.strides().iter().all(|&stride| stride >= 0)
+    }
+
+    // Kernels index buffers with unsigned integers, so device code only takes strides that walk
+    // forwards.
+    pub(crate) fn forward_strides(&self) -> Vec<usize> {
+        self.strides()
+            .iter()
+            .map(|&stride| {
+                usize::try_from(stride).unwrap_or_else(|_| {
+                    panic!("{self} with strides {:?} walks backwards", self.strides())
+                })
+            })
+            .collect()
+    }
+
+    pub fn contiguous(&self) -> Self {
+        Self::from(self.dims())
+    }
+}
+
+/// How an element is stored. Complex elements are interleaved pairs of f32s, the real part first,
+/// which tensors and layouts hold along an extra last dimension of size two.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DType {
+    #[default]
+    F32,
+    Complex64,
+}
+
+impl DType {
+    /// The f32s each element is stored as.
+    pub fn components(self) -> usize {
+        match self {
+            DType::F32 => 1,
+            DType::Complex64 => 2,
+        }
+    }
+
+    /// The dims elements of this type with `shape` are stored with.
+    pub fn storage_shape(self, shape: impl Into<Shape>) -> Shape {
+        let shape = shape.into();
+
+        match self {
+            DType::F32 => shape,
+            DType::Complex64 => Shape::from([shape.dims(), &[2]].concat()),
+        }
     }
 }
 
@@ -158,9 +611,749 @@ impl Tensor {
         Self { data, layout }
     }
 
-    pub fn reshape(mut self, shape: Shape) -> Self {
-        self.layout.shape = shape;
+    /// A tensor of `shape` over `data` in row-major order.
+    pub fn from_vec(data: Vec<f32>, shape: impl Into<Shape>) -> Result<Self, ConversionError> {
+        let layout = Layout::from(shape.into());
+
+        if data.len() != layout.elements() {
+            return Err(ConversionError::ElementCount {
+                layout,
+                actual: data.len(),
+            });
+        }
+
+        Ok(Self::from_parts(data.into_boxed_slice(), layout))
+    }
+
+    /// A complex tensor of `shape` over `values` in row-major order, as `(real, imaginary)` pairs.
+    pub fn from_complex(
+        values: Vec<(f32, f32)>,
+        shape: impl Into<Shape>,
+    ) -> Result<Self, ConversionError> {
+        Self::from_vec(
+            values.into_iter().flat_map(|(re, im)| [re, im]).collect(),
+            DType::Complex64.storage_shape(shape),
+        )
+    }
+
+    /// The `(real, imaginary)` pairs of a complex tensor in row-major order.
+    pub fn to_complex(&self) -> Result<Vec<(f32, f32)>, ConversionError> {
+        if self.layout.dims().last() != Some(&DType::Complex64.components()) {
+            return Err(ConversionError::NotComplex(self.layout.clone()));
+        }
+
+        let data = offsets(&self.layout)
+            .map(|offset| self.data[offset])
+            .collect::<Vec<_>>();
+
+        Ok(data.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+    }
+
+    /// The same elements with the dims of `shape`, copying them if the strides can't express
+    /// those dims.
+    pub fn reshape(self, shape: Shape) -> Result<Self, ReshapeError> {
+        match self.layout.reshape(shape.clone()) {
+            Ok(layout) => Ok(Self { layout, ..self }),
+            Err(ReshapeError::NeedsCopy { .. }) => {
+                let copy = self.view().to_contiguous();
+
+                Ok(Self {
+                    layout: copy.layout.reshape(shape)?,
+                    ..copy
+                })
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// The value of a tensor with a single element, whatever its rank.
+    pub fn item(&self) -> Result<f32, ConversionError> {
+        if self.layout.elements() != 1 {
+            return Err(ConversionError::NotScalar(self.layout.clone()));
+        }
+
+        Ok(self.data[self.layout.offset])
+    }
+
+    /// Applies `f` to every element, giving a contiguous tensor.
+    pub fn map(&self, f: impl Fn(f32) -> f32) -> Tensor {
+        Tensor::from_parts(
+            offsets(&self.layout)
+                .map(|offset| f(self.data[offset]))
+                .collect(),
+            self.layout.contiguous(),
+        )
+    }
+
+    /// Applies `f` to each pair of matching elements of two tensors with the same dimensions,
+    /// giving a contiguous tensor.
+    pub fn zip_map(&self, other: &Tensor, f: impl Fn(f32, f32) -> f32) -> Tensor {
+        assert_eq!(
+            self.layout.dims(),
+            other.layout.dims(),
+            "cannot combine a {} tensor with a {} tensor",
+            self.layout,
+            other.layout
+        );
+
+        Tensor::from_parts(
+            offsets(&self.layout)
+                .zip(offsets(&other.layout))
+                .map(|(offset, other_offset)| f(self.data[offset], other.data[other_offset]))
+                .collect(),
+            self.layout.contiguous(),
+        )
+    }
+
+    pub fn add(&self, other: &Tensor) -> Tensor {
+        self.zip_map(other, |lhs, rhs| lhs + rhs)
+    }
+
+    pub fn mul(&self, other: &Tensor) -> Tensor {
+        self.zip_map(other, |lhs, rhs| lhs * rhs)
+    }
+
+    pub fn sum(&self) -> f32 {
+        offsets(&self.layout).map(|offset| self.data[offset]).sum()
+    }
+
+    /// The largest element, ignoring NaNs like the graph's max reduction does. Empty tensors have
+    /// a maximum of negative infinity.
+    pub fn max(&self) -> f32 {
+        offsets(&self.layout)
+            .map(|offset| self.data[offset])
+            .fold(f32::NEG_INFINITY, f32::max)
+    }
+
+    /// Whether both tensors have the same dimensions, and each element of this one is within
+    /// `atol + rtol * |expected|` of the matching element of `other`, like NumPy's `allclose`.
+    /// NaNs are never close to anything.
+    pub fn allclose(&self, other: &Tensor, rtol: f32, atol: f32) -> bool {
+        self.layout.dims() == other.layout.dims()
+            && self.view().mismatch(&other.view(), rtol, atol).is_none()
+    }
+
+    pub fn view(&self) -> TensorView<'_> {
+        TensorView {
+            data: &self.data,
+            layout: self.layout.clone(),
+        }
+    }
+
+    pub fn view_mut(&mut self) -> TensorViewMut<'_> {
+        TensorViewMut {
+            data: &mut self.data,
+            layout: self.layout.clone(),
+        }
+    }
+}
+
+impl From<f32> for Tensor {
+    fn from(value: f32) -> Self {
+        Self::from_scalar(value)
+    }
+}
+
+/// A one dimensional tensor.
+impl From<Vec<f32>> for Tensor {
+    fn from(data: Vec<f32>) -> Self {
+        let layout = Layout::from([data.len()]);
+
+        Self::from_parts(data.into_boxed_slice(), layout)
+    }
+}
+
+/// The elements in row-major order, whatever the tensor's strides.
+impl From<Tensor> for Vec<f32> {
+    fn from(tensor: Tensor) -> Self {
+        if tensor.layout.is_contiguous() {
+            let mut data = tensor.data.into_vec();
+
+            data.truncate(tensor.layout.elements());
+
+            data
+        } else {
+            tensor.view().to_contiguous().data.into_vec()
+        }
+    }
+}
+
+impl TryFrom<Tensor> for f32 {
+    type Error = ConversionError;
+
+    fn try_from(tensor: Tensor) -> Result<Self, ConversionError> {
+        tensor.item()
+    }
+}
+
+/// A matrix storing only its nonzero entries, in compressed sparse row form: the entries of row
+/// `row` are at `offsets[row]..offsets[row + 1]` of `columns` and `values`, by increasing column.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SparseTensor {
+    rows: usize,
+    cols: usize,
+    offsets: Box<[usize]>,
+    columns: Box<[usize]>,
+    values: Box<[f32]>,
+}
+
+impl SparseTensor {
+    /// A `rows` by `cols` matrix of coordinate entries, given in any order. Entries at the same
+    /// position are summed.
+    pub fn from_coo(
+        rows: usize,
+        cols: usize,
+        entries: impl IntoIterator<Item = (usize, usize, f32)>,
+    ) -> Result<Self, ConversionError> {
+        let mut entries = entries.into_iter().collect::<Vec<_>>();
+
+        if let Some(&(row, col, _)) = entries
+            .iter()
+            .find(|&&(row, col, _)| row >= rows || col >= cols)
+        {
+            return Err(ConversionError::SparseIndex {
+                index: (row, col),
+                dims: (rows, cols),
+            });
+        }
+
+        entries.sort_by_key(|&(row, col, _)| (row, col));
+
+        let mut offsets = vec![0; rows + 1];
+        let (mut columns, mut values) = (Vec::new(), Vec::<f32>::new());
+        let mut last = None;
+
+        for (row, col, value) in entries {
+            if last == Some((row, col)) {
+                *values.last_mut().unwrap() += value;
+            } else {
+                offsets[row + 1] += 1;
+                columns.push(col);
+                values.push(value);
+                last = Some((row, col));
+            }
+        }
+
+        for row in 0..rows {
+            offsets[row + 1] += offsets[row];
+        }
+
+        Ok(Self {
+            rows,
+            cols,
+            offsets: offsets.into_boxed_slice(),
+            columns: columns.into_boxed_slice(),
+            values: values.into_boxed_slice(),
+        })
+    }
+
+    /// The nonzero elements of a matrix.
+    pub fn from_dense(tensor: &Tensor) -> Self {
+        assert_eq!(
+            tensor.layout.rank(),
+            2,
+            "only matrices can be made sparse, not {}",
+            tensor.layout
+        );
+
+        let [rows, cols] = tensor.layout.dims() else {
+            unreachable!()
+        };
+        let entries = offsets(&tensor.layout)
+            .enumerate()
+            .map(|(position, offset)| (position / cols, position % cols, tensor.data[offset]))
+            .filter(|&(_, _, value)| value != 0.0);
+
+        Self::from_coo(*rows, *cols, entries).expect("dense positions are in bounds")
+    }
+
+    pub fn to_dense(&self) -> Tensor {
+        let mut data = vec![0.0; self.rows * self.cols];
+
+        for row in 0..self.rows {
+            for entry in self.offsets[row]..self.offsets[row + 1] {
+                data[row * self.cols + self.columns[entry]] = self.values[entry];
+            }
+        }
+
+        Tensor::from_parts(
+            data.into_boxed_slice(),
+            Layout::from([self.rows, self.cols]),
+        )
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    /// The number of stored entries.
+    pub fn nnz(&self) -> usize {
+        self.values.len()
+    }
+
+    /// The values, columns and row offsets as one dimensional tensors, with the indices stored as
+    /// floats. This is the order the parts of a sparse graph input are added in.
+    pub fn parts(&self) -> [Tensor; 3] {
+        let indices = |indices: &[usize]| {
+            Tensor::from(
+                indices
+                    .iter()
+                    .map(|&index| index as f32)
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        [
+            Tensor::from(self.values.to_vec()),
+            indices(&self.columns),
+            indices(&self.offsets),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConversionError {
+    /// A tensor with other than one element, where a scalar was expected.
+    NotScalar(Layout),
+    ElementCount {
+        layout: Layout,
+        actual: usize,
+    },
+    /// A tensor without the last dimension of two that complex elements are stored along.
+    NotComplex(Layout),
+    /// A sparse entry outside the dimensions of its matrix.
+    SparseIndex {
+        index: (usize, usize),
+        dims: (usize, usize),
+    },
+}
+
+impl Display for ConversionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConversionError::NotScalar(layout) => write!(
+                f,
+                "expected a tensor with one element, but it is {layout} with {} elements",
+                layout.elements()
+            ),
+            ConversionError::ElementCount { layout, actual } => write!(
+                f,
+                "a {layout} tensor has {} elements, but {actual} were given",
+                layout.elements()
+            ),
+            ConversionError::NotComplex(layout) => write!(
+                f,
+                "a {layout} tensor does not hold complex elements, which need a last dimension of 2"
+            ),
+            ConversionError::SparseIndex {
+                index: (row, col),
+                dims: (rows, cols),
+            } => write!(
+                f,
+                "entry ({row}, {col}) is outside a {rows} by {cols} sparse matrix"
+            ),
+        }
+    }
+}
+
+impl Error for ConversionError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReshapeError {
+    ElementCount {
+        layout: Layout,
+        shape: Shape,
+    },
+    /// The strides of the layout can't step through its elements in the new shape, so they have
+    /// to be copied first.
+    NeedsCopy {
+        layout: Layout,
+        shape: Shape,
+    },
+}
+
+impl Display for ReshapeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ReshapeError::ElementCount { layout, shape } => write!(
+                f,
+                "cannot reshape {layout} with {} elements into {shape} with {}",
+                layout.elements(),
+                shape.elements()
+            ),
+            ReshapeError::NeedsCopy { layout, shape } => write!(
+                f,
+                "{layout} with strides {:?} cannot be viewed as {shape} without copying",
+                layout.strides()
+            ),
+        }
+    }
+}
+
+impl Error for ReshapeError {}
+
+impl Display for Tensor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.view(), f)
+    }
+}
+
+// Tensors with more elements than this only show the first and last few entries of each
+// dimension, like NumPy does.
+const DISPLAY_THRESHOLD: usize = 1000;
+const DISPLAY_EDGE_ITEMS: usize = 3;
+
+/// A tensor over borrowed memory, so that runners can take inputs without copying them.
+#[derive(Debug, Clone)]
+pub struct TensorView<'a> {
+    pub(crate) data: &'a [f32],
+    pub(crate) layout: Layout,
+}
+
+impl<'a> TensorView<'a> {
+    /// Views the start of `data`, as much of it as `layout` spans.
+    pub fn new(data: &'a [f32], layout: Layout) -> Self {
+        assert!(
+            mem::size_of_val(data) >= layout.size(),
+            "{} elements cannot hold a {layout} tensor",
+            data.len()
+        );
+
+        Self {
+            data: &data[..layout.size() / mem::size_of::<f32>()],
+            layout,
+        }
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    pub fn to_tensor(&self) -> Tensor {
+        Tensor::from_parts(self.data.into(), self.layout.clone())
+    }
+
+    // The same elements with the data starting at the first of them, for views that walk
+    // forwards from it, which is how device buffers hold them.
+    pub(crate) fn rebased(&self) -> TensorView<'a> {
+        match rebase(&self.layout) {
+            Some(layout) => TensorView {
+                data: &self.data[self.layout.offset..],
+                layout,
+            },
+            None => self.clone(),
+        }
+    }
+
+    // The first element, in row-major order, that is not close to the matching one of `expected`,
+    // along with both values.
+    pub(crate) fn mismatch(
+        &self,
+        expected: &TensorView,
+        rtol: f32,
+        atol: f32,
+    ) -> Option<(usize, f32, f32)> {
+        offsets(&self.layout)
+            .zip(offsets(&expected.layout))
+            .map(|(offset, expected_offset)| (self.data[offset], expected.data[expected_offset]))
+            .enumerate()
+            .find(|&(_, (actual, expected))| {
+                // Equal infinities are close, even though their difference is NaN.
+                let close =
+                    actual == expected || (actual - expected).abs() <= atol + rtol * expected.abs();
+
+                !close
+            })
+            .map(|(element, (actual, expected))| (element, actual, expected))
+    }
+
+    /// Copies the elements the view addresses, in order, into a tensor of their own.
+    pub fn to_contiguous(&self) -> Tensor {
+        gather(self.data, &self.layout)
+    }
+
+    /// The elements `range` of `dim`.
+    pub fn slice(self, dim: DimId, range: Range<usize>) -> Self {
+        Self {
+            layout: self.layout.slice(dim, range),
+            ..self
+        }
+    }
+
+    /// Index `index` of `dim`, dropping the dimension.
+    pub fn select(self, dim: DimId, index: usize) -> Self {
+        Self {
+            layout: self.layout.select(dim, index),
+            ..self
+        }
+    }
+
+    pub fn permute(self, order: &[DimId]) -> Self {
+        Self {
+            layout: self.layout.permute(order),
+            ..self
+        }
+    }
+
+    /// The elements of each of `dims` in reverse order.
+    pub fn flip(self, dims: &[DimId]) -> Self {
+        Self {
+            layout: self.layout.flip(dims),
+            ..self
+        }
+    }
+}
+
+/// A tensor over borrowed memory, which runners can write their outputs into.
+#[derive(Debug)]
+pub struct TensorViewMut<'a> {
+    pub(crate) data: &'a mut [f32],
+    pub(crate) layout: Layout,
+}
+
+impl<'a> TensorViewMut<'a> {
+    pub fn new(data: &'a mut [f32], layout: Layout) -> Self {
+        assert!(
+            mem::size_of_val(data) >= layout.size(),
+            "{} elements cannot hold a {layout} tensor",
+            data.len()
+        );
+
+        Self { data, layout }
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    pub(crate) fn rebased(&mut self) -> TensorViewMut<'_> {
+        match rebase(&self.layout) {
+            Some(layout) => TensorViewMut {
+                data: &mut self.data[self.layout.offset..],
+                layout,
+            },
+            None => TensorViewMut {
+                data: self.data,
+                layout: self.layout.clone(),
+            },
+        }
+    }
+
+    pub fn as_view(&self) -> TensorView<'_> {
+        TensorView {
+            data: self.data,
+            layout: self.layout.clone(),
+        }
+    }
+
+    pub fn to_contiguous(&self) -> Tensor {
+        gather(self.data, &self.layout)
+    }
+
+    pub fn slice(self, dim: DimId, range: Range<usize>) -> Self {
+        Self {
+            layout: self.layout.slice(dim, range),
+            ..self
+        }
+    }
+
+    pub fn select(self, dim: DimId, index: usize) -> Self {
+        Self {
+            layout: self.layout.select(dim, index),
+            ..self
+        }
+    }
+
+    pub fn permute(self, order: &[DimId]) -> Self {
+        Self {
+            layout: self.layout.permute(order),
+            ..self
+        }
+    }
+
+    pub fn flip(self, dims: &[DimId]) -> Self {
+        Self {
+            layout: self.layout.flip(dims),
+            ..self
+        }
+    }
+
+    /// Copies `source` into the elements the view addresses.
+    pub fn copy_from(&mut self, source: &TensorView) {
+        assert_eq!(
+            source.layout.dims(),
+            self.layout.dims(),
+            "cannot copy a {} tensor into a {} view",
+            source.layout,
+            self.layout
+        );
+
+        for (target, offset) in offsets(&self.layout).zip(offsets(&source.layout)) {
+            self.data[target] = source.data[offset];
+        }
+    }
+}
 
-        self
+// `None` stands for the entries left out of a summarized dimension.
+fn shown_indices(size: usize, summarize: bool) -> Vec<Option<usize>> {
+    if summarize && size > 2 * DISPLAY_EDGE_ITEMS {
+        (0..DISPLAY_EDGE_ITEMS)
+            .map(Some)
+            .chain(iter::once(None))
+            .chain((size - DISPLAY_EDGE_ITEMS..size).map(Some))
+            .collect()
+    } else {
+        (0..size).map(Some).collect()
     }
 }
+
+struct DisplayOptions {
+    precision: usize,
+    summarize: bool,
+    width: usize,
+}
+
+impl TensorView<'_> {
+    fn shown_offsets(&self, dim: DimId, offset: usize, summarize: bool, offsets: &mut Vec<usize>) {
+        if dim == self.layout.rank() {
+            offsets.push(offset);
+
+            return;
+        }
+
+        for index in shown_indices(self.layout.dims()[dim], summarize)
+            .into_iter()
+            .flatten()
+        {
+            self.shown_offsets(
+                dim + 1,
+                self.layout.step(offset, dim, index),
+                summarize,
+                offsets,
+            );
+        }
+    }
+
+    fn fmt_nested(
+        &self,
+        f: &mut Formatter<'_>,
+        dim: DimId,
+        offset: usize,
+        options: &DisplayOptions,
+    ) -> fmt::Result {
+        let rank = self.layout.rank();
+
+        if dim == rank {
+            return write!(
+                f,
+                "{:>width$.precision$}",
+                self.data[offset],
+                width = options.width,
+                precision = options.precision
+            );
+        }
+
+        // Inner dimensions go on one line, and outer ones are separated by a blank line each.
+        let separator = if dim + 1 == rank {
+            String::from(", ")
+        } else {
+            format!(",{}{}", "\n".repeat(rank - dim - 1), " ".repeat(dim + 1))
+        };
+
+        f.write_str("[")?;
+
+        for (position, index) in shown_indices(self.layout.dims()[dim], options.summarize)
+            .into_iter()
+            .enumerate()
+        {
+            if position > 0 {
+                f.write_str(&separator)?;
+            }
+
+            match index {
+                Some(index) => {
+                    self.fmt_nested(f, dim + 1, self.layout.step(offset, dim, index), options)?
+                }
+                None => f.write_str("...")?,
+            }
+        }
+
+        f.write_str("]")
+    }
+}
+
+/// Prints the elements in nested brackets, one bracket per dimension, with four decimals unless
+/// the formatter asks for another precision.
+impl Display for TensorView<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let precision = f.precision().unwrap_or(4);
+        let summarize = self.layout.elements() > DISPLAY_THRESHOLD;
+        let mut offsets = Vec::new();
+
+        self.shown_offsets(0, self.layout.offset, summarize, &mut offsets);
+
+        let width = offsets
+            .iter()
+            .map(|&offset| format!("{:.precision$}", self.data[offset]).len())
+            .max()
+            .unwrap_or(0);
+
+        self.fmt_nested(
+            f,
+            0,
+            self.layout.offset,
+            &DisplayOptions {
+                precision,
+                summarize,
+                width,
+            },
+        )
+    }
+}
+
+fn rebase(layout: &Layout) -> Option<Layout> {
+    (layout.offset > 0
+        && layout.elements() > 0
+        && layout.strides().iter().all(|&stride| stride >= 0))
+    .then(|| Layout {
+        offset: 0,
+        ..layout.clone()
+    })
+}
+
+// The offsets of the elements a layout addresses, in row-major order.
+fn offsets(layout: &Layout) -> impl Iterator<Item = usize> + '_ {
+    let mut index = vec![0; layout.rank()];
+
+    (0..layout.elements()).map(move |_| {
+        let offset = index
+            .iter()
+            .enumerate()
+            .fold(layout.offset, |offset, (dim, &index)| {
+                layout.step(offset, dim, index)
+            });
+
+        for dim in (0..index.len()).rev() {
+            index[dim] += 1;
+
+            if index[dim] < layout.dims()[dim] {
+                break;
+            }
+
+            index[dim] = 0;
+        }
+
+        offset
+    })
+}
+
+fn gather(data: &[f32], layout: &Layout) -> Tensor {
+    Tensor::from_parts(
+        offsets(layout).map(|offset| data[offset]).collect(),
+        layout.contiguous(),
+    )
+}
@@ -0,0 +1,197 @@
+use crate::{graph::Graph, interp, tensor::Tensor};
+
+/// The tolerances `assert_tensors_close!` uses unless given others, loose enough for results
+/// computed in a different order in `f32`.
+pub const DEFAULT_RTOL: f32 = 1e-5;
+pub const DEFAULT_ATOL: f32 = 1e-6;
+
+/// Asserts that two tensors are close, as by `Tensor::allclose`, optionally with explicit
+/// tolerances: `assert_tensors_close!(actual, expected)` or
+/// `assert_tensors_close!(actual, expected, rtol, atol)`.
+#[macro_export]
+macro_rules! assert_tensors_close {
+    ($actual:expr, $expected:expr $(,)?) => {
+        $crate::testing::assert_close(
+            &$actual,
+            &$expected,
+            $crate::testing::DEFAULT_RTOL,
+            $crate::testing::DEFAULT_ATOL,
+        )
+    };
+    ($actual:expr, $expected:expr, $rtol:expr, $atol:expr $(,)?) => {
+        $crate::testing::assert_close(&$actual, &$expected, $rtol, $atol)
+    };
+}
+
+#[track_caller]
+pub fn assert_close(actual: &Tensor, expected: &Tensor, rtol: f32, atol: f32) {
+    assert_eq!(
+        actual.layout.dims(),
+        expected.layout.dims(),
+        "tensors have different dimensions"
+    );
+
+    if let Some((element, actual, expected)) = actual.view().mismatch(&expected.view(), rtol, atol)
+    {
+        panic!(
+            "tensors differ at element {element}: {actual} is not close to {expected} (rtol {rtol}, atol {atol})"
+        );
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GradientMismatch {
+    pub input: usize,
+    pub element: usize,
+    pub analytic: f32,
+    pub numeric: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct GradientCheck {
+    pub max_relative_error: f32,
+    pub worst: Option<GradientMismatch>,
+}
+
+fn objective(graph: &Graph, inputs: &[Tensor]) -> f64 {
+    interp::eval(graph, inputs.to_vec())
+        .iter()
+        .flat_map(|output| interp::contiguous(output).data.into_vec())
+        .map(f64::from)
+        .sum()
+}
+
+pub fn check_gradients(graph: &Graph, inputs: &[Tensor], eps: f32) -> GradientCheck {
+    let mut inputs = inputs.iter().map(interp::contiguous).collect::<Vec<_>>();
+    let analytic = interp::gradients(graph, inputs.clone());
+
+    let mut check = GradientCheck {
+        max_relative_error: 0.0,
+        worst: None,
+    };
+
+    for input in 0..inputs.len() {
+        for element in 0..inputs[input].data.len() {
+            let original = inputs[input].data[element];
+
+            inputs[input].data[element] = original + eps;
+            let plus = objective(graph, &inputs);
+
+            inputs[input].data[element] = original - eps;
+            let minus = objective(graph, &inputs);
+
+            inputs[input].data[element] = original;
+
+            let numeric = ((plus - minus) / (2.0 * f64::from(eps))) as f32;
+            let analytic = analytic[input].data[element];
+
+            let relative_error =
+                (analytic - numeric).abs() / analytic.abs().max(numeric.abs()).max(f32::EPSILON);
+
+            if relative_error > check.max_relative_error {
+                check.max_relative_error = relative_error;
+                check.worst = Some(GradientMismatch {
+                    input,
+                    element,
+                    analytic,
+                    numeric,
+                });
+            }
+        }
+    }
+
+    check
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        graph::{ElemwiseOp, Op, ReduceOp},
+        tensor::Layout,
+    };
+
+    fn tensor<const N: usize>(dims: [usize; N], data: &[f32]) -> Tensor {
+        Tensor::from_parts(data.into(), Layout::from(dims))
+    }
+
+    #[test]
+    fn gradients_match_finite_differences() {
+        let mut graph = Graph::new();
+        let x = graph.add_input(Layout::from([2, 3]));
+        let y = graph.add_input(Layout::from([3, 2]));
+        let scale = graph.add_input(Layout::from([1, 3]));
+        let scaled = graph.add_op(Op::Elemwise(ElemwiseOp::Mul), &[x, scale]);
+        let activated = graph.add_op(Op::Elemwise(ElemwiseOp::Tanh), &[scaled]);
+        let product = graph.add_op(Op::MatMul, &[activated, y]);
+        let exp = graph.add_op(Op::Elemwise(ElemwiseOp::Exp), &[product]);
+        let sin = graph.add_op(Op::Elemwise(ElemwiseOp::Sin), &[exp]);
+        let output = graph.add_op(
+            Op::Reduce {
+                op: ReduceOp::Sum,
+                dims: vec![1],
+            },
+            &[sin],
+        );
+
+        graph.add_output(output);
+
+        let check = check_gradients(
+            &graph,
+            &[
+                tensor([2, 3], &[0.1, -0.4, 0.3, 0.8, -0.2, 0.5]),
+                tensor([3, 2], &[0.3, -0.1, 0.2, 0.6, -0.5, 0.4]),
+                tensor([1, 3], &[1.5, -0.5, 0.7]),
+            ],
+            1e-3,
+        );
+
+        assert!(
+            check.max_relative_error < 1e-2,
+            "gradients differ: {:?}",
+            check.worst
+        );
+    }
+
+    #[test]
+    fn interpreter_evaluates_ops() {
+        let mut graph = Graph::new();
+        let x = graph.add_input(Layout::from([2, 2]));
+        let y = graph.add_input(Layout::from([2, 1]));
+        let sum = graph.add_op(Op::Elemwise(ElemwiseOp::Add), &[x, y]);
+        let product = graph.add_op(Op::MatMul, &[sum, x]);
+        let max = graph.add_op(
+            Op::Reduce {
+                op: ReduceOp::Max,
+                dims: vec![0],
+            },
+            &[product],
+        );
+
+        graph.add_output(product);
+        graph.add_output(max);
+
+        let outputs = interp::eval(
+            &graph,
+            vec![
+                tensor([2, 2], &[1.0, 2.0, 3.0, 4.0]),
+                tensor([2, 1], &[10.0, -1.0]),
+            ],
+        );
+
+        // [[11, 12], [2, 3]] times [[1, 2], [3, 4]].
+        crate::assert_tensors_close!(outputs[0], tensor([2, 2], &[47.0, 70.0, 11.0, 16.0]));
+        crate::assert_tensors_close!(outputs[1], tensor([1, 2], &[47.0, 70.0]));
+    }
+
+    #[test]
+    fn allclose_uses_both_tolerances() {
+        let expected = tensor([3], &[1.0, 100.0, 0.0]);
+
+        assert!(tensor([3], &[1.0005, 100.05, 0.0005]).allclose(&expected, 1e-3, 1e-3));
+        assert!(!tensor([3], &[1.0, 100.5, 0.0]).allclose(&expected, 1e-3, 1e-3));
+        assert!(!tensor([3], &[1.0, 100.0, 0.01]).allclose(&expected, 1e-3, 1e-3));
+        assert!(!tensor([3], &[1.0, f32::NAN, 0.0]).allclose(&expected, 1e-3, 1e-3));
+        assert!(!tensor([1, 3], &[1.0, 100.0, 0.0]).allclose(&expected, 1e-3, 1e-3));
+    }
+}
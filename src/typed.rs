@@ -0,0 +1,188 @@
+//! An optional layer over [`ExprId`] that tracks ranks in the type system, so that operations
+//! like transposing a scalar are rejected at compile time. Ranks are only checked at runtime
+//! where an untyped id enters the layer.
+
+use std::marker::PhantomData;
+
+use crate::{
+    builder,
+    graph::{ExprId, Graph, MovementOp, Op},
+    tensor::{DimId, Layout, Shape},
+};
+
+pub trait Rank {
+    const RANK: usize;
+}
+
+/// Ranks with at least two dimensions, which have matrices to transpose and multiply.
+pub trait AtLeast2: Rank {}
+
+pub trait Grow: Rank {
+    type Up: Rank;
+}
+
+pub trait Shrink: Rank {
+    type Down: Rank;
+}
+
+macro_rules! ranks {
+    ($($rank:ident = $value:literal),*) => {
+        $(
+            #[derive(Copy, Clone, Debug)]
+            pub struct $rank;
+
+            impl Rank for $rank {
+                const RANK: usize = $value;
+            }
+        )*
+    };
+}
+
+ranks!(Rank0 = 0, Rank1 = 1, Rank2 = 2, Rank3 = 3, Rank4 = 4);
+
+impl AtLeast2 for Rank2 {}
+impl AtLeast2 for Rank3 {}
+impl AtLeast2 for Rank4 {}
+
+macro_rules! steps {
+    ($(($lower:ident, $higher:ident)),*) => {
+        $(
+            impl Grow for $lower {
+                type Up = $higher;
+            }
+
+            impl Shrink for $higher {
+                type Down = $lower;
+            }
+        )*
+    };
+}
+
+steps!(
+    (Rank0, Rank1),
+    (Rank1, Rank2),
+    (Rank2, Rank3),
+    (Rank3, Rank4)
+);
+
+pub struct Expr<R: Rank> {
+    id: ExprId,
+    rank: PhantomData<R>,
+}
+
+// Derives would require `R: Copy`, which the markers don't need to be.
+impl<R: Rank> Clone for Expr<R> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<R: Rank> Copy for Expr<R> {}
+
+impl<R: Rank> Expr<R> {
+    /// Panics if the layout doesn't have rank `R`.
+    pub fn input(graph: &mut Graph, layout: Layout) -> Self {
+        assert_eq!(
+            layout.rank(),
+            R::RANK,
+            "input does not have the expected rank"
+        );
+
+        Self::unchecked(graph.add_input(layout))
+    }
+
+    /// Types an existing expression. Panics if it doesn't have rank `R`.
+    pub fn new(graph: &Graph, id: ExprId) -> Self {
+        assert_eq!(
+            graph[id].layout.rank(),
+            R::RANK,
+            "expression does not have the expected rank"
+        );
+
+        Self::unchecked(id)
+    }
+
+    fn unchecked(id: ExprId) -> Self {
+        Self {
+            id,
+            rank: PhantomData,
+        }
+    }
+
+    pub fn id(self) -> ExprId {
+        self.id
+    }
+
+    pub fn add(self, graph: &mut Graph, other: Self) -> Self {
+        Self::unchecked(builder::Add::new(self.id, other.id).build(graph))
+    }
+
+    pub fn mul(self, graph: &mut Graph, other: Self) -> Self {
+        Self::unchecked(builder::Mul::new(self.id, other.id).build(graph))
+    }
+
+    pub fn flip(self, graph: &mut Graph, dims: &[DimId]) -> Self {
+        Self::unchecked(builder::Flip::new(self.id, dims).build(graph))
+    }
+
+    /// Sums over `dims`, keeping them with size one.
+    pub fn sum(self, graph: &mut Graph, dims: &[DimId]) -> Self {
+        Self::unchecked(builder::Sum::new(self.id, dims).build(graph))
+    }
+
+    pub fn sum_all(self, graph: &mut Graph) -> Expr<Rank0> {
+        Expr::unchecked(builder::Sum::all(self.id).build(graph))
+    }
+
+    /// Panics if `shape` doesn't have rank `S`.
+    pub fn reshape<S: Rank>(self, graph: &mut Graph, shape: impl Into<Shape>) -> Expr<S> {
+        let shape = shape.into();
+
+        assert_eq!(
+            shape.rank(),
+            S::RANK,
+            "reshape does not have the expected rank"
+        );
+
+        Expr::unchecked(graph.add_op(Op::Movement(MovementOp::Reshape(shape)), &[self.id]))
+    }
+
+    pub fn unsqueeze(self, graph: &mut Graph, dim: DimId) -> Expr<R::Up>
+    where
+        R: Grow,
+    {
+        Expr::unchecked(graph.add_op(Op::Movement(MovementOp::Unsqueeze(dim)), &[self.id]))
+    }
+
+    pub fn squeeze(self, graph: &mut Graph, dim: DimId) -> Expr<R::Down>
+    where
+        R: Shrink,
+    {
+        Expr::unchecked(graph.add_op(Op::Movement(MovementOp::Squeeze(Some(dim))), &[self.id]))
+    }
+}
+
+impl<R: AtLeast2> Expr<R> {
+    pub fn transpose(self, graph: &mut Graph) -> Self {
+        Self::unchecked(graph.add_op(Op::Movement(MovementOp::Transpose), &[self.id]))
+    }
+
+    pub fn matmul(self, graph: &mut Graph, other: Self) -> Self {
+        Self::unchecked(builder::MatMul::new(self.id, other.id).build(graph))
+    }
+
+    pub fn diagonal(self, graph: &mut Graph, offset: isize) -> Expr<R::Down>
+    where
+        R: Shrink,
+    {
+        Expr::unchecked(builder::Diagonal::new(self.id).offset(offset).build(graph))
+    }
+
+    pub fn trace(self, graph: &mut Graph) -> Expr<<R::Down as Shrink>::Down>
+    where
+        R: Shrink,
+        R::Down: Shrink,
+    {
+        Expr::unchecked(builder::Trace::new(self.id).build(graph))
+    }
+}
@@ -0,0 +1,212 @@
+use std::{collections::HashMap, sync::Arc};
+
+use wgpu::{Buffer, BufferDescriptor, BufferUsages, Device};
+
+use super::arena::ARENA_ALIGNMENT;
+
+pub struct Allocation {
+    pub buffer: Arc<Buffer>,
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct AllocatorStats {
+    pub buffers_created: usize,
+    pub allocations: usize,
+    pub reuses: usize,
+    /// Allocations not yet freed, which may share buffers depending on the strategy.
+    pub live_allocations: usize,
+    pub live_bytes: u64,
+    pub peak_bytes: u64,
+}
+
+impl AllocatorStats {
+    fn allocated(&mut self, size: u64) {
+        self.allocations += 1;
+        self.live_allocations += 1;
+        self.live_bytes += size;
+        self.peak_bytes = self.peak_bytes.max(self.live_bytes);
+    }
+
+    fn freed(&mut self, size: u64) {
+        self.live_allocations -= 1;
+        self.live_bytes -= size;
+    }
+}
+
+pub trait AllocatorStrategy: Send {
+    fn allocate(&mut self, device: &Device, size: u64) -> Allocation;
+
+    fn free(&mut self, allocation: Allocation);
+
+    /// Called before each run, once every allocation of the previous run has been freed.
+    fn reset(&mut self) {}
+
+    fn stats(&self) -> AllocatorStats;
+}
+
+fn create_buffer(device: &Device, size: u64) -> Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: Some("tensor"),
+        size,
+        // Any buffer may hold the workgroup counts of an indirect dispatch.
+        usage: BufferUsages::COPY_DST
+            | BufferUsages::COPY_SRC
+            | BufferUsages::STORAGE
+            | BufferUsages::INDIRECT,
+        mapped_at_creation: false,
+    })
+}
+
+#[derive(Default)]
+pub struct DeviceAllocator {
+    stats: AllocatorStats,
+}
+
+impl AllocatorStrategy for DeviceAllocator {
+    fn allocate(&mut self, device: &Device, size: u64) -> Allocation {
+        self.stats.buffers_created += 1;
+        self.stats.allocated(size);
+
+        Allocation {
+            buffer: Arc::new(create_buffer(device, size)),
+            offset: 0,
+            size,
+        }
+    }
+
+    fn free(&mut self, allocation: Allocation) {
+        self.stats.freed(allocation.size);
+    }
+
+    fn stats(&self) -> AllocatorStats {
+        self.stats
+    }
+}
+
+#[derive(Default)]
+pub struct PooledAllocator {
+    pool: HashMap<u64, Vec<Arc<Buffer>>>,
+    stats: AllocatorStats,
+}
+
+impl AllocatorStrategy for PooledAllocator {
+    fn allocate(&mut self, device: &Device, size: u64) -> Allocation {
+        let class = size.next_power_of_two().max(ARENA_ALIGNMENT as u64);
+
+        let buffer = match self.pool.get_mut(&class).and_then(Vec::pop) {
+            Some(buffer) => {
+                self.stats.reuses += 1;
+                buffer
+            }
+            None => {
+                self.stats.buffers_created += 1;
+                Arc::new(create_buffer(device, class))
+            }
+        };
+
+        self.stats.allocated(size);
+
+        Allocation {
+            buffer,
+            offset: 0,
+            size,
+        }
+    }
+
+    fn free(&mut self, allocation: Allocation) {
+        self.stats.freed(allocation.size);
+
+        self.pool
+            .entry(allocation.buffer.size())
+            .or_default()
+            .push(allocation.buffer);
+    }
+
+    // Runs of the same plan then take the same buffers in the same order, which lets them reuse
+    // their bind groups.
+    fn reset(&mut self) {
+        for buffers in self.pool.values_mut() {
+            buffers.sort_by_key(|buffer| buffer.global_id());
+        }
+    }
+
+    fn stats(&self) -> AllocatorStats {
+        self.stats
+    }
+}
+
+pub struct BumpAllocator {
+    chunk_size: u64,
+    chunks: Vec<Arc<Buffer>>,
+    chunk: usize,
+    offset: u64,
+    stats: AllocatorStats,
+}
+
+impl BumpAllocator {
+    pub fn new(chunk_size: u64) -> Self {
+        Self {
+            chunk_size,
+            chunks: Vec::new(),
+            chunk: 0,
+            offset: 0,
+            stats: AllocatorStats::default(),
+        }
+    }
+}
+
+impl AllocatorStrategy for BumpAllocator {
+    fn allocate(&mut self, device: &Device, size: u64) -> Allocation {
+        let aligned_size = size.next_multiple_of(ARENA_ALIGNMENT as u64);
+
+        self.stats.allocated(size);
+
+        if aligned_size > self.chunk_size {
+            self.stats.buffers_created += 1;
+
+            return Allocation {
+                buffer: Arc::new(create_buffer(device, aligned_size)),
+                offset: 0,
+                size,
+            };
+        }
+
+        if self.chunk < self.chunks.len() && self.offset + aligned_size > self.chunk_size {
+            self.chunk += 1;
+            self.offset = 0;
+        }
+
+        if self.chunk == self.chunks.len() {
+            self.stats.buffers_created += 1;
+            self.chunks
+                .push(Arc::new(create_buffer(device, self.chunk_size)));
+        } else {
+            self.stats.reuses += 1;
+        }
+
+        let offset = self.offset;
+
+        self.offset += aligned_size;
+
+        Allocation {
+            buffer: self.chunks[self.chunk].clone(),
+            offset,
+            size,
+        }
+    }
+
+    fn free(&mut self, allocation: Allocation) {
+        self.stats.freed(allocation.size);
+    }
+
+    fn reset(&mut self) {
+        self.chunk = 0;
+        self.offset = 0;
+    }
+
+    fn stats(&self) -> AllocatorStats {
+        self.stats
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{graph::ExprId, ir::Pool};
+
+use super::compiler::WgpuStep;
+
+pub(crate) const ARENA_ALIGNMENT: usize = 256;
+
+fn conflicts(steps: &[WgpuStep]) -> HashMap<ExprId, HashSet<ExprId>> {
+    let mut conflicts: HashMap<ExprId, HashSet<ExprId>> = HashMap::new();
+
+    add_conflicts(steps, &mut conflicts);
+
+    conflicts
+}
+
+fn add_conflicts(steps: &[WgpuStep], conflicts: &mut HashMap<ExprId, HashSet<ExprId>>) {
+    // Kernels binding a slice bind the buffer it is part of.
+    let mut slices = HashMap::new();
+
+    for step in steps {
+        match step {
+            WgpuStep::Slice { id, source, .. } => {
+                slices.insert(*id, *source);
+            }
+            WgpuStep::Execute {
+                inputs, workgroups, ..
+            } => {
+                let buffer = |id: &ExprId| slices.get(id).copied().unwrap_or(*id);
+                let output = buffer(&inputs[0]);
+
+                for input in inputs[1..]
+                    .iter()
+                    .chain(workgroups.arguments().as_ref())
+                    .map(buffer)
+                    .filter(|&input| input != output)
+                {
+                    conflicts.entry(output).or_default().insert(input);
+                    conflicts.entry(input).or_default().insert(output);
+                }
+            }
+            WgpuStep::Branch {
+                then_steps,
+                else_steps,
+                ..
+            } => {
+                add_conflicts(then_steps, conflicts);
+                add_conflicts(else_steps, conflicts);
+            }
+            _ => {}
+        }
+    }
+}
+
+// A kernel may not bind one buffer as both writable and read-only, so a buffer never shares an
+// arena with the buffers it is computed from. Buffers reserved inside branches are left to the
+// runner's allocator, since which of them exist is only known at run time.
+pub(crate) fn plan(steps: Vec<WgpuStep>) -> (Vec<WgpuStep>, Vec<usize>) {
+    let conflicts = conflicts(&steps);
+
+    let mut arenas: Vec<Pool> = Vec::new();
+    let mut placements = HashMap::new();
+
+    let steps = steps
+        .into_iter()
+        .map(|step| match step {
+            WgpuStep::Reserve { id, size } => {
+                let taken = conflicts
+                    .get(&id)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|other| placements.get(other))
+                    .map(|&(arena, _)| arena)
+                    .collect::<HashSet<_>>();
+
+                let arena = (0..)
+                    .find(|arena| !taken.contains(arena))
+                    .expect("ran out of arenas");
+
+                if arena == arenas.len() {
+                    arenas.push(Pool::new(ARENA_ALIGNMENT));
+                }
+
+                let offset = arenas[arena].place(size);
+
+                placements.insert(id, (arena, offset));
+
+                WgpuStep::Place {
+                    id,
+                    arena,
+                    offset,
+                    size,
+                }
+            }
+            WgpuStep::Deallocate(id) => {
+                if let Some(&(arena, offset)) = placements.get(&id) {
+                    arenas[arena].free(offset);
+                }
+
+                WgpuStep::Deallocate(id)
+            }
+            step => step,
+        })
+        .collect();
+
+    (steps, arenas.into_iter().map(|arena| arena.size).collect())
+}
@@ -0,0 +1,114 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+/// How many kernels a cache holds unless told otherwise.
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub entries: usize,
+    pub evictions: usize,
+}
+
+struct Kernels {
+    // Each source, by its full key, with the time it was last used.
+    sources: HashMap<String, (String, u64)>,
+    time: u64,
+    capacity: usize,
+    stats: CacheStats,
+}
+
+pub struct KernelCache {
+    kernels: Mutex<Kernels>,
+}
+
+impl Default for KernelCache {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+}
+
+impl KernelCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A cache holding at most `capacity` kernels, evicting the least recently used one to make
+    /// room for another.
+    pub fn with_capacity(capacity: usize) -> Self {
+        assert!(capacity > 0, "a kernel cache must hold at least one kernel");
+
+        Self {
+            kernels: Mutex::new(Kernels {
+                sources: HashMap::new(),
+                time: 0,
+                capacity,
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    pub fn global() -> Arc<KernelCache> {
+        static GLOBAL: OnceLock<Arc<KernelCache>> = OnceLock::new();
+
+        GLOBAL.get_or_init(|| Arc::new(KernelCache::new())).clone()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.kernels
+            .lock()
+            .expect("kernel cache was poisoned")
+            .stats
+    }
+
+    pub fn clear(&self) {
+        let mut kernels = self.kernels.lock().expect("kernel cache was poisoned");
+
+        kernels.sources.clear();
+        kernels.stats = CacheStats::default();
+    }
+
+    pub(crate) fn get_or_render(&self, key: &str, render: impl FnOnce() -> String) -> String {
+        let mut kernels = self.kernels.lock().expect("kernel cache was poisoned");
+        let Kernels {
+            sources,
+            time,
+            capacity,
+            stats,
+        } = &mut *kernels;
+
+        *time += 1;
+
+        if let Some((source, used)) = sources.get_mut(key) {
+            stats.hits += 1;
+            *used = *time;
+
+            return source.clone();
+        }
+
+        let source = render();
+
+        stats.misses += 1;
+
+        if sources.len() == *capacity {
+            let oldest = sources
+                .iter()
+                .min_by_key(|(_, &(_, used))| used)
+                .map(|(key, _)| key.clone())
+                .expect("a full cache has kernels");
+
+            sources.remove(&oldest);
+            stats.evictions += 1;
+        } else {
+            stats.entries += 1;
+        }
+
+        sources.insert(key.to_owned(), (source.clone(), *time));
+
+        source
+    }
+}
@@ -0,0 +1,68 @@
+use std::mem::size_of;
+
+use serde::{Deserialize, Serialize};
+
+use crate::graph::ExprId;
+
+use super::{compiler::WgpuStep, kernel};
+
+const CHECKSUM_SIZE: usize = 2 * size_of::<u32>();
+
+#[derive(Clone, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Checksums(pub Vec<(ExprId, u64)>);
+
+impl Checksums {
+    pub fn first_divergence(&self, baseline: &Checksums) -> Option<ExprId> {
+        self.0
+            .iter()
+            .zip(baseline.0.iter())
+            .find(|(current, baseline)| current != baseline)
+            .map(|((id, _), _)| *id)
+    }
+}
+
+pub(crate) fn add_checksums(
+    steps: Vec<WgpuStep>,
+    workgroup_size: [u32; 3],
+    max_workgroups: u32,
+    first_id: usize,
+) -> (Vec<WgpuStep>, Vec<(ExprId, ExprId)>) {
+    let mut checksums = Vec::new();
+    let mut result = Vec::with_capacity(steps.len());
+
+    for step in steps {
+        let WgpuStep::Execute {
+            output,
+            inputs,
+            inputs_layout,
+            ..
+        } = &step
+        else {
+            result.push(step);
+            continue;
+        };
+
+        let id = ExprId(first_id + checksums.len());
+        let (target, (size, _)) = (inputs[0], inputs_layout[0]);
+        let elements = size / size_of::<u32>();
+        let [x, y, z] = workgroup_size;
+
+        checksums.push((*output, id));
+
+        result.push(step);
+        result.push(WgpuStep::Reserve {
+            id,
+            size: CHECKSUM_SIZE,
+        });
+        result.push(WgpuStep::Execute {
+            output: id,
+            source: kernel::checksum(workgroup_size, elements),
+            workgroups: kernel::dispatch((elements as u32).div_ceil(x * y * z), max_workgroups)
+                .into(),
+            inputs: vec![id, target],
+            inputs_layout: vec![(CHECKSUM_SIZE, false), (size, true)],
+        });
+    }
+
+    (result, checksums)
+}
@@ -1,128 +1,2779 @@
-use std::iter;
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    error::Error,
+    fmt::{self, Display, Formatter},
+    iter,
+    sync::Arc,
+};
 
 use serde::{Deserialize, Serialize};
+use tracing::{debug, debug_span, info_span, warn};
+use wgpu::Limits;
 
 use crate::{
     compiler::Compiler,
-    graph::{ElemwiseOp, ExprBody, ExprId, Graph, Op},
-    tensor::{Layout, Tensor},
+    graph::{
+        AttentionGeometry, Children, ElemwiseOp, ExprBody, ExprId, Graph, MatMulGeometry, Mode,
+        MovementOp, Op, OpKind, Predicate, ReduceOp,
+    },
+    ir,
+    rewrite::{self, Rewrite, Rewriter},
+    tensor::{DimId, Layout, Tensor},
 };
 
 use super::{
+    arena,
+    cache::KernelCache,
+    checksum,
     expr::{WgpuExpr, WgpuOp},
-    kernel,
+    kernel::{self, HalfStorage, Packing},
+    repeat::{self, Iteration},
 };
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) enum WgpuStep {
     Allocate {
         id: ExprId,
         tensor: Tensor,
     },
     Deallocate(ExprId),
+    Reserve {
+        id: ExprId,
+        size: usize,
+    },
+    Place {
+        id: ExprId,
+        arena: usize,
+        offset: usize,
+        size: usize,
+    },
+    // Binds `size` bytes of `source`'s buffer from `offset` under `id`, so that kernels can work
+    // on part of a buffer too large to bind whole. Slices own no memory and are never
+    // deallocated.
+    Slice {
+        id: ExprId,
+        source: ExprId,
+        offset: usize,
+        size: usize,
+    },
     Execute {
         output: ExprId,
         source: String,
-        workgroups: [u32; 3],
+        workgroups: Workgroups,
         inputs: Vec<ExprId>,
         inputs_layout: Vec<(usize, bool)>,
     },
+    Repeat {
+        body: Vec<WgpuStep>,
+        iterations: Vec<Iteration>,
+    },
+    // Reads the condition on the host and runs one of the bodies. Both leave the result of the
+    // `if` in the buffer reserved for it, and free everything else they allocate.
+    Branch {
+        condition: ExprId,
+        predicate: Predicate,
+        then_steps: Vec<WgpuStep>,
+        else_steps: Vec<WgpuStep>,
+    },
+}
+
+/// How many workgroups a kernel is dispatched with.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Workgroups {
+    Fixed([u32; 3]),
+    /// Read from the first three words of a buffer that an earlier kernel writes, so that the
+    /// work can depend on the data without a round trip through the host.
+    Indirect(ExprId),
+}
+
+impl Workgroups {
+    /// The buffer holding the counts of an indirect dispatch.
+    pub fn arguments(self) -> Option<ExprId> {
+        match self {
+            Workgroups::Fixed(_) => None,
+            Workgroups::Indirect(arguments) => Some(arguments),
+        }
+    }
+}
+
+impl From<[u32; 3]> for Workgroups {
+    fn from(workgroups: [u32; 3]) -> Self {
+        Workgroups::Fixed(workgroups)
+    }
+}
+
+struct Lowering {
+    steps: Vec<WgpuStep>,
+    aliases: Vec<ExprId>,
+    layouts: Vec<Arc<Layout>>,
+    assertions: Vec<(ExprId, String)>,
+    // The number of buffer ids the steps may use.
+    ids: usize,
+    // The expression whose kernel computes each elementwise op fused into it.
+    roots: HashMap<ExprId, ExprId>,
 }
 
+/// An environment variable naming a directory that every compiled plan writes its kernels to.
+pub const DUMP_KERNELS_VAR: &str = "MOMENTUM_DUMP_KERNELS";
+
 #[derive(Serialize, Deserialize)]
 pub struct WgpuPlan {
     pub(crate) inputs: Vec<ExprId>,
+    pub(crate) input_layouts: Vec<Layout>,
     pub(crate) steps: Vec<WgpuStep>,
     pub(crate) outputs: Vec<ExprId>,
     pub(crate) output_layouts: Vec<Layout>,
+    pub(crate) assertions: Vec<(ExprId, String)>,
+    pub(crate) arenas: Vec<usize>,
+    pub(crate) checksums: Vec<(ExprId, ExprId)>,
+    pub(crate) readbacks: Vec<Readback>,
+    // Inputs read from, and outputs written to, the runner's persistent buffers of these names.
+    pub(crate) persistent_inputs: Vec<(ExprId, String, Layout)>,
+    pub(crate) persistent_outputs: Vec<(ExprId, String, Layout)>,
+    // The work of each expression, attributed to the first step computing it.
+    pub(crate) flops: HashMap<ExprId, usize>,
+    // Debug labels for the steps computing named expressions.
+    pub(crate) labels: HashMap<ExprId, String>,
+    // The layout of each of the graph's expressions, logged with the steps computing them.
+    pub(crate) layouts: Vec<Arc<Layout>>,
+    pub(crate) deterministic: bool,
+    // The fused graph without its const data, and the compiler lowering it, for simulating the
+    // plan with inputs of other layouts.
+    #[serde(skip)]
+    pub(crate) source: Option<Arc<(WgpuCompiler, Graph)>>,
+}
+
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub enum Accumulation {
+    #[default]
+    Single,
+    TwoFloat,
+}
+
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Readback {
+    #[default]
+    F32,
+    F16,
+    Bf16,
+}
+
+/// What happens to NaNs and denormals in outputs before they are read back.
+#[derive(Copy, Clone, Default, PartialEq, Debug)]
+pub enum FloatPolicy {
+    #[default]
+    Unchecked,
+    /// Flushes denormals to zero.
+    FlushToZero,
+    /// Replaces NaNs with a finite value, and flushes denormals to zero.
+    ReplaceNan(f32),
 }
 
+#[derive(Clone)]
 pub struct WgpuCompiler {
     pub workgroup_size_x: u32,
+    /// Workgroup sizes for kinds of ops, which must be powers of two along every dimension.
+    pub workgroup_overrides: HashMap<OpKind, [u32; 3]>,
+    pub mean_accumulation: Accumulation,
+    pub sum_accumulation: Accumulation,
+    pub assertions: bool,
+    pub in_place: bool,
+    pub checksums: bool,
+    pub readback: HashMap<ExprId, Readback>,
+    /// Names of inputs and outputs kept on the device between runs. A persistent input reads the
+    /// runner's buffer of its name instead of being passed to `run`, and a persistent output is
+    /// stored under its name instead of being read back.
+    pub persistent: HashMap<ExprId, String>,
+    pub float_policy: FloatPolicy,
+    pub cache: Option<Arc<KernelCache>>,
+    pub fold_repeats: bool,
+    /// Passes elementwise shapes through a parameters buffer, so that one pipeline serves every
+    /// shape of the same rank.
+    pub dynamic_shapes: bool,
+    /// Loads and stores four elements per invocation in elementwise kernels over contiguous
+    /// layouts whose element count is a multiple of four.
+    pub vectorize: bool,
+    /// Stores elementwise results that are only read by other elementwise ops as f16, halving
+    /// their memory traffic. Results the graph keeps in f32 are left alone. Pairs are packed into
+    /// words, as the WGSL front end of this wgpu version has no f16 type, even on devices with
+    /// `Features::SHADER_F16`, so this works on any device.
+    pub f16_storage: bool,
+    /// Inlines single-element consts into elementwise kernels as literals instead of binding them
+    /// as buffers.
+    pub inline_literals: bool,
+    /// Guarantees bit-identical results across runs on the same device. The built-in kernels
+    /// already reduce in a fixed order and only use integer atomics, so this rejects custom ops
+    /// that do not declare themselves deterministic and disables the runner's shader hot reload.
+    pub deterministic: bool,
+    /// The limits of the device plans run on, as given by `WgpuRunner::limits`. Workgroups are
+    /// shrunk and dispatches split to fit them, and graphs with larger buffers than a kernel may
+    /// bind are rejected.
+    pub limits: Limits,
+    /// Splits elementwise ops and reductions over more memory than `limits` lets a kernel bind
+    /// into several dispatches, each binding a slice of the buffers. Elementwise ops are split
+    /// when every input is contiguous like the output or a scalar, and reductions when they
+    /// reduce the trailing dimensions of a contiguous input.
+    pub chunked: bool,
+    /// Runs attention spelled out as a softmax of scaled query-key products times values, as
+    /// `Rewrite::fuse_attention` matches it, through the fused attention kernel.
+    pub fuse_attention: bool,
 }
 
 impl Default for WgpuCompiler {
     fn default() -> Self {
         Self {
             workgroup_size_x: 256,
+            workgroup_overrides: HashMap::new(),
+            mean_accumulation: Accumulation::default(),
+            sum_accumulation: Accumulation::default(),
+            assertions: cfg!(debug_assertions),
+            in_place: true,
+            checksums: false,
+            readback: HashMap::new(),
+            persistent: HashMap::new(),
+            float_policy: FloatPolicy::default(),
+            cache: Some(KernelCache::global()),
+            fold_repeats: true,
+            dynamic_shapes: false,
+            vectorize: true,
+            f16_storage: false,
+            inline_literals: true,
+            deterministic: false,
+            limits: Limits::default(),
+            chunked: false,
+            fuse_attention: true,
+        }
+    }
+}
+
+impl WgpuCompiler {
+    fn workgroup_size(&self, kind: OpKind) -> [u32; 3] {
+        let mut size = self
+            .workgroup_overrides
+            .get(&kind)
+            .copied()
+            .unwrap_or(match kind {
+                OpKind::Movement => kernel::TRANSPOSE_WORKGROUP_SIZE,
+                OpKind::Attention => kernel::ATTENTION_WORKGROUP_SIZE,
+                _ => [self.workgroup_size_x, 1, 1],
+            });
+        let max = [
+            self.limits.max_compute_workgroup_size_x,
+            self.limits.max_compute_workgroup_size_y,
+            self.limits.max_compute_workgroup_size_z,
+        ];
+
+        // Workgroups the device can't run are halved along the offending dimension, or the widest
+        // one if there are too many invocations, which keeps power of two sizes tiling evenly.
+        loop {
+            let dim = match (0..3).find(|&dim| size[dim] > max[dim]) {
+                Some(dim) => dim,
+                None if size.iter().product::<u32>()
+                    > self.limits.max_compute_invocations_per_workgroup =>
+                {
+                    (0..3).max_by_key(|&dim| size[dim]).unwrap()
+                }
+                None => return size,
+            };
+
+            assert!(size[dim] > 1, "the device cannot run any workgroup");
+            size[dim] /= 2;
+        }
+    }
+
+    // The most rows of `row` elements a chunk of a split op binds, keeping every chunk's offset
+    // aligned as the device requires.
+    fn chunk_rows(&self, row: usize) -> usize {
+        let alignment = self.limits.min_storage_buffer_offset_alignment as usize / size_of::<f32>();
+        let rows = self.limits.max_storage_buffer_binding_size as usize / (row * size_of::<f32>());
+
+        rows / alignment * alignment
+    }
+
+    fn dispatch(&self, groups: u32) -> Workgroups {
+        kernel::dispatch(groups, self.limits.max_compute_workgroups_per_dimension).into()
+    }
+
+    fn kernel(&self, key: String, render: impl FnOnce() -> String) -> String {
+        match &self.cache {
+            Some(cache) => cache.get_or_render(&key, render),
+            None => render(),
+        }
+    }
+
+    fn elemwise_workgroups(&self, kind: OpKind, layout: &Layout) -> Workgroups {
+        let [x, y, z] = self.workgroup_size(kind);
+
+        self.dispatch((layout.elements() as u32).div_ceil(x * y * z))
+    }
+}
+
+fn inputs_note(children: &[ExprId], layouts: &[Arc<Layout>]) -> String {
+    format!(
+        "inputs: {}",
+        children
+            .iter()
+            .map(|child| format!("{child:?}: {}", layouts[child.0]))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+/// A graph that the limits of the compiler's device, or its options, rule out.
+#[derive(Clone, PartialEq, Debug)]
+pub enum LimitError {
+    /// A workgroup size override with a zero or non power of two dimension, which could neither
+    /// run nor be halved to fit the device.
+    WorkgroupSize { kind: OpKind, size: [u32; 3] },
+    /// An expression with more elements than the kernels computing or using it address, which is
+    /// any past 32-bit indices except for views, chunked elementwise ops and reductions.
+    Elements { expr: ExprId, elements: usize },
+    /// An expression larger than the largest storage buffer the device binds, which only chunked
+    /// compilation splits.
+    Buffer { expr: ExprId, size: usize, max: u32 },
+    /// A kernel dispatching more workgroups along a dimension than the device allows.
+    Workgroups {
+        expr: ExprId,
+        workgroups: [u32; 3],
+        max: u32,
+    },
+    /// A kernel binding a buffer larger than the device allows.
+    Binding {
+        expr: ExprId,
+        sizes: Vec<usize>,
+        max: u32,
+    },
+    /// A `FloatPolicy::ReplaceNan` replacement that is not finite itself.
+    NanReplacement(f32),
+    /// A custom op that does not declare itself deterministic, compiled in deterministic mode.
+    Nondeterministic { expr: ExprId, op: String },
+}
+
+impl Display for LimitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitError::WorkgroupSize { kind, size } => write!(
+                f,
+                "{kind:?} workgroups of {size:?} must have power of two sizes along every dimension"
+            ),
+            LimitError::Elements { expr, elements } => write!(
+                f,
+                "{expr:?} has {elements} elements, which cannot be addressed by 32-bit kernel indices"
+            ),
+            LimitError::Buffer { expr, size, max } => write!(
+                f,
+                "{expr:?} takes {size} bytes, but the device only binds storage buffers of up to {max}"
+            ),
+            LimitError::Workgroups {
+                expr,
+                workgroups,
+                max,
+            } => write!(
+                f,
+                "{expr:?} dispatches {workgroups:?} workgroups, but the device allows at most {max} per dimension"
+            ),
+            LimitError::Binding { expr, sizes, max } => write!(
+                f,
+                "{expr:?} binds {sizes:?} bytes, but the device only binds storage buffers of up to {max}"
+            ),
+            LimitError::NanReplacement(value) => {
+                write!(f, "NaNs must be replaced by a finite value, not {value}")
+            }
+            LimitError::Nondeterministic { expr, op } => write!(
+                f,
+                "{expr:?} is custom op {op}, which is not deterministic, in deterministic mode"
+            ),
         }
     }
 }
 
+impl Error for LimitError {}
+
+// Expressions of branches and scan bodies are checked too, as they are lowered like the graph.
+fn check_exprs(
+    graph: &Graph,
+    limits: &Limits,
+    chunked: bool,
+    deterministic: bool,
+) -> Result<(), LimitError> {
+    for (id, expr) in (0..).map(ExprId).zip(graph.exprs.iter()) {
+        let max = limits.max_storage_buffer_binding_size;
+
+        if let ExprBody::Op { op, children } = &expr.body {
+            let wide = |child: &ExprId| wide_operand(graph, chunked, &expr.layout, op, *child);
+
+            for child in children {
+                let elements = graph.exprs[child.0].layout.elements();
+
+                if u32::try_from(elements).is_ok() || wide(child) {
+                    continue;
+                }
+
+                return Err(LimitError::Elements {
+                    expr: *child,
+                    elements,
+                });
+            }
+
+            // Only views and chunked elementwise ops write more elements than their kernels count,
+            // and only when every operand is addressed the same way, so that broadcasting small
+            // operands into a large output is caught too.
+            let elements = expr.layout.elements();
+            let wide_output = matches!(op, Op::Movement(_) | Op::Repeat { .. } | Op::Elemwise(_))
+                && children.iter().all(wide);
+
+            if u32::try_from(elements).is_err() && !wide_output {
+                return Err(LimitError::Elements { expr: id, elements });
+            }
+        }
+
+        if !chunked && expr.layout.size() as u64 > u64::from(max) {
+            return Err(LimitError::Buffer {
+                expr: id,
+                size: expr.layout.size(),
+                max,
+            });
+        }
+
+        if let ExprBody::Op { op, .. } = &expr.body {
+            match op {
+                Op::If {
+                    then_graph,
+                    else_graph,
+                    ..
+                } => {
+                    check_exprs(then_graph, limits, chunked, deterministic)?;
+                    check_exprs(else_graph, limits, chunked, deterministic)?;
+                }
+                Op::Scan { body, .. } => check_exprs(body, limits, chunked, deterministic)?,
+                Op::Custom(op) if deterministic && !op.deterministic() => {
+                    return Err(LimitError::Nondeterministic {
+                        expr: id,
+                        op: op.name().to_owned(),
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Whether the kernels of `op` address `child` even with more elements than a u32 counts. Views
+// only alias it, passing it on to their own users, unlike flips, copying reshapes and repeats
+// that can't be expanded. Elementwise ops only do when chunked, over slices of operands laid out
+// like their output, or scalars. Reductions switch to emulated 64-bit positions, as long as their
+// threads can still be counted and positions needn't be written out.
+fn wide_operand(graph: &Graph, chunked: bool, output: &Layout, op: &Op, child: ExprId) -> bool {
+    let input = &graph.exprs[child.0].layout;
+
+    match op {
+        Op::Movement(MovementOp::Flip(_)) => false,
+        Op::Movement(MovementOp::Reshape(shape)) => input.reshape(shape.clone()).is_ok(),
+        Op::Movement(_) => true,
+        Op::Repeat { repeats } => input.can_expand(repeats),
+        Op::Elemwise(_) => {
+            chunked
+                && (input.elements() == 1
+                    || (input.is_contiguous() && input.dims() == output.dims()))
+        }
+        &Op::Reduce { op, ref dims } => {
+            let reduced_elements = dims.iter().map(|&dim| input.dims()[dim]).product::<usize>();
+            let chunk_size = kernel::reduce_chunk_size(op, reduced_elements);
+            let threads = (input.elements() / reduced_elements.max(1))
+                .checked_mul(reduced_elements.div_ceil(chunk_size));
+
+            !matches!(op, ReduceOp::ArgMax | ReduceOp::ArgMin)
+                && u32::try_from(chunk_size).is_ok()
+                && threads.is_some_and(|threads| u32::try_from(threads).is_ok())
+        }
+        _ => false,
+    }
+}
+
+// Tiled kernels and custom ops can't wrap their dispatches into another dimension, so they must
+// fit as they are.
+fn check_limits(steps: &[WgpuStep], limits: &Limits) -> Result<(), LimitError> {
+    let max = limits.max_compute_workgroups_per_dimension;
+    let binding = limits.max_storage_buffer_binding_size;
+
+    for step in steps {
+        match step {
+            WgpuStep::Execute {
+                output,
+                workgroups,
+                inputs_layout,
+                ..
+            } => {
+                if let &Workgroups::Fixed(workgroups) = workgroups {
+                    if workgroups.iter().any(|&groups| groups > max) {
+                        return Err(LimitError::Workgroups {
+                            expr: *output,
+                            workgroups,
+                            max,
+                        });
+                    }
+                }
+
+                if inputs_layout
+                    .iter()
+                    .any(|&(size, _)| size as u64 > u64::from(binding))
+                {
+                    return Err(LimitError::Binding {
+                        expr: *output,
+                        sizes: inputs_layout.iter().map(|&(size, _)| size).collect(),
+                        max: binding,
+                    });
+                }
+            }
+            WgpuStep::Repeat { body, .. } => check_limits(body, limits)?,
+            WgpuStep::Branch {
+                then_steps,
+                else_steps,
+                ..
+            } => {
+                check_limits(then_steps, limits)?;
+                check_limits(else_steps, limits)?;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn wgpu_op(op: ElemwiseOp) -> WgpuOp {
+    match op {
+        ElemwiseOp::Add => WgpuOp::Add,
+        ElemwiseOp::Mul => WgpuOp::Mul,
+        ElemwiseOp::Sin => WgpuOp::Sin,
+        ElemwiseOp::Rsqrt => WgpuOp::Rsqrt,
+        ElemwiseOp::Fma => WgpuOp::Fma,
+        ElemwiseOp::Exp => WgpuOp::Exp,
+        ElemwiseOp::Log => WgpuOp::Log,
+        ElemwiseOp::Tanh => WgpuOp::Tanh,
+        ElemwiseOp::Floor => WgpuOp::Floor,
+        ElemwiseOp::Ceil => WgpuOp::Ceil,
+        ElemwiseOp::Clamp => WgpuOp::Clamp,
+    }
+}
+
+// The expression of an elementwise op with the ops fused into it inlined, and its other children
+// given by `leaf`.
+fn fused_expr(
+    op: ElemwiseOp,
+    children: &[ExprId],
+    fused: &HashMap<ExprId, (ElemwiseOp, Children)>,
+    leaf: &impl Fn(ExprId) -> WgpuExpr,
+) -> WgpuExpr {
+    WgpuExpr::new(
+        wgpu_op(op),
+        children
+            .iter()
+            .map(|&child| match fused.get(&child) {
+                Some((op, children)) => fused_expr(*op, children, fused, leaf),
+                None => leaf(child),
+            })
+            .collect(),
+    )
+}
+
+fn fresh_id(next_id: &mut usize) -> ExprId {
+    *next_id += 1;
+
+    ExprId(*next_id - 1)
+}
+
+fn annotate(source: String, notes: &[String]) -> String {
+    notes
+        .iter()
+        .map(|note| format!("// {note}\n"))
+        .chain(iter::once(source))
+        .collect()
+}
+
 impl Compiler for WgpuCompiler {
     type CompileResult = WgpuPlan;
 
     fn compile(&self, graph: Graph) -> Self::CompileResult {
+        self.try_compile(graph)
+            .unwrap_or_else(|error| panic!("{error}"))
+    }
+}
+
+impl WgpuCompiler {
+    /// Compiles `graph`, or reports the first part of it that the device's limits rule out.
+    pub fn try_compile(&self, graph: Graph) -> Result<WgpuPlan, LimitError> {
+        let _span = info_span!("compile", exprs = graph.exprs.len()).entered();
+
+        if let Some((&kind, &size)) = self
+            .workgroup_overrides
+            .iter()
+            .find(|(_, size)| !size.iter().all(|dim| dim.is_power_of_two()))
+        {
+            return Err(LimitError::WorkgroupSize { kind, size });
+        }
+
+        if let FloatPolicy::ReplaceNan(value) = self.float_policy {
+            if !value.is_finite() {
+                return Err(LimitError::NanReplacement(value));
+            }
+        }
+
+        let (graph, persistent, readback) = self.fuse(graph);
+
+        // Lowering the fused graph again, as simulations do, must not fuse it twice.
+        let compiler = WgpuCompiler {
+            persistent,
+            readback,
+            fuse_attention: false,
+            ..self.clone()
+        };
+        let source = graph.without_const_data();
+        let mut plan = compiler.plan(graph)?;
+
+        plan.source = Some(Arc::new((compiler, source)));
+
+        if let Some(dir) = env::var_os(DUMP_KERNELS_VAR) {
+            if let Err(error) = plan.dump_kernels(&dir) {
+                warn!(dir = ?dir, "could not dump kernels: {error}");
+            }
+        }
+
+        Ok(plan)
+    }
+
+    // Lowers a fused graph into a plan.
+    pub(crate) fn plan(&self, graph: Graph) -> Result<WgpuPlan, LimitError> {
+        let (persistent, readback) = (&self.persistent, &self.readback);
+
+        check_exprs(&graph, &self.limits, self.chunked, self.deterministic)?;
+
+        let (persistent_inputs, inputs): (Vec<ExprId>, Vec<_>) = graph
+            .inputs
+            .iter()
+            .partition(|id| persistent.contains_key(id));
+        let (persistent_outputs, graph_outputs): (Vec<ExprId>, Vec<_>) = graph
+            .outputs
+            .iter()
+            .partition(|id| persistent.contains_key(id));
+        let keep_f32 = graph.keep_f32.clone();
+        let mut flops: HashMap<_, _> = (0..graph.exprs.len())
+            .map(ExprId)
+            .map(|id| (id, graph.flops(id)))
+            .filter(|&(_, flops)| flops > 0)
+            .collect();
+        let labels = graph
+            .names
+            .iter()
+            .map(|(id, name)| (*id, format!("{name} {id:?}")))
+            .collect();
+
+        let Lowering {
+            mut steps,
+            aliases,
+            layouts,
+            assertions,
+            ids,
+            roots,
+        } = debug_span!("lower").in_scope(|| self.lower(graph, false));
+
+        for (fused, root) in roots {
+            if let Some(fused) = flops.remove(&fused) {
+                *flops.entry(root).or_default() += fused;
+            }
+        }
+
+        let persistent_inputs = persistent_inputs
+            .into_iter()
+            .map(|id| (id, persistent[&id].clone(), Layout::clone(&layouts[id.0])))
+            .collect();
+        let persistent_outputs = persistent_outputs
+            .into_iter()
+            .map(|id| {
+                (
+                    aliases[id.0],
+                    persistent[&id].clone(),
+                    Layout::clone(&layouts[id.0]),
+                )
+            })
+            .collect();
+
+        let mut outputs = graph_outputs
+            .iter()
+            .map(|id| aliases[id.0])
+            .collect::<Vec<_>>();
+        let mut output_layouts = graph_outputs
+            .iter()
+            .map(|id| Layout::clone(&layouts[id.0]))
+            .collect::<Vec<_>>();
+        let mut readbacks = Vec::with_capacity(outputs.len());
+        let mut next_id = ids;
+
+        if self.float_policy != FloatPolicy::Unchecked {
+            let workgroup_size = self.workgroup_size(OpKind::Elemwise);
+
+            for (index, output) in graph_outputs.iter().enumerate() {
+                let id = ExprId(next_id);
+                let layout = &output_layouts[index];
+
+                next_id += 1;
+
+                steps.push(WgpuStep::Reserve {
+                    id,
+                    size: layout.contiguous().size(),
+                });
+                steps.push(WgpuStep::Execute {
+                    output: *output,
+                    source: annotate(
+                        kernel::sanitize(workgroup_size, layout, self.float_policy),
+                        &[format!("{output:?}: {:?}", self.float_policy)],
+                    ),
+                    workgroups: self.elemwise_workgroups(OpKind::Elemwise, layout),
+                    inputs: vec![id, outputs[index]],
+                    inputs_layout: vec![(layout.contiguous().size(), false), (layout.size(), true)],
+                });
+
+                outputs[index] = id;
+                output_layouts[index] = layout.contiguous();
+            }
+        }
+
+        for (index, output) in graph_outputs.iter().enumerate() {
+            // Precision-sensitive outputs are never downcast on the way back.
+            let readback = if keep_f32.contains(output) {
+                Readback::F32
+            } else {
+                readback.get(output).copied().unwrap_or_default()
+            };
+
+            readbacks.push(readback);
+
+            if readback == Readback::F32 {
+                continue;
+            }
+
+            let id = ExprId(next_id);
+            let layout = &output_layouts[index];
+            let size = layout.elements().div_ceil(2) * size_of::<u32>();
+
+            next_id += 1;
+
+            steps.push(WgpuStep::Reserve { id, size });
+            steps.push(WgpuStep::Execute {
+                output: *output,
+                source: annotate(
+                    kernel::convert(self.workgroup_size(OpKind::Elemwise), layout, readback),
+                    &[format!("{output:?}: convert to {readback:?}")],
+                ),
+                workgroups: self.dispatch(
+                    (layout.elements().div_ceil(2) as u32)
+                        .div_ceil(self.workgroup_size(OpKind::Elemwise).iter().product()),
+                ),
+                inputs: vec![id, outputs[index]],
+                inputs_layout: vec![(size, false), (layout.size(), true)],
+            });
+
+            outputs[index] = id;
+            output_layouts[index] = layout.contiguous();
+        }
+
+        let (steps, checksums) = if self.checksums {
+            debug_span!("checksums").in_scope(|| {
+                checksum::add_checksums(
+                    steps,
+                    self.workgroup_size(OpKind::Elemwise),
+                    self.limits.max_compute_workgroups_per_dimension,
+                    next_id,
+                )
+            })
+        } else {
+            (steps, Vec::new())
+        };
+
+        let (mut steps, arenas) = debug_span!("arenas").in_scope(|| arena::plan(steps));
+
+        if self.fold_repeats {
+            steps = debug_span!("fold repeats").in_scope(|| repeat::fold(steps));
+        }
+
+        check_limits(&steps, &self.limits)?;
+
+        debug!(steps = steps.len(), arenas = arenas.len(), "compiled plan");
+
+        Ok(WgpuPlan {
+            input_layouts: inputs
+                .iter()
+                .map(|id| Layout::clone(&layouts[id.0]))
+                .collect(),
+            inputs,
+            steps,
+            output_layouts,
+            outputs,
+            assertions,
+            arenas,
+            checksums,
+            readbacks,
+            persistent_inputs,
+            persistent_outputs,
+            flops,
+            labels,
+            layouts,
+            deterministic: self.deterministic,
+            source: None,
+        })
+    }
+
+    // Fuses the graph's attention subgraphs, along with the options naming its expressions, whose
+    // ids the rewrite changes.
+    fn fuse(&self, graph: Graph) -> (Graph, HashMap<ExprId, String>, HashMap<ExprId, Readback>) {
+        let fusable = self.fuse_attention
+            && (0..graph.exprs.len())
+                .any(|id| rewrite::attention_operands(&graph, ExprId(id)).is_some());
+
+        if !fusable {
+            return (graph, self.persistent.clone(), self.readback.clone());
+        }
+
+        let (graph, ids) = Rewriter::new()
+            .rule(Rewrite::fuse_attention())
+            .rewrite_mapped(&graph);
+
+        debug!(exprs = graph.exprs.len(), "fused attention");
+
+        let persistent = self
+            .persistent
+            .iter()
+            .filter_map(|(id, name)| Some((ids.get(id.0).copied()??, name.clone())))
+            .collect();
+        let readback = self
+            .readback
+            .iter()
+            .filter_map(|(id, readback)| Some((ids.get(id.0).copied()??, *readback)))
+            .collect();
+
+        (graph, persistent, readback)
+    }
+
+    // Turns the graph's expressions into steps. Inputs are only deallocated by the caller when
+    // `keep_inputs` is set, which is the case for branches since they read their parent's buffers.
+    fn lower(&self, graph: Graph, keep_inputs: bool) -> Lowering {
         let last_usages = graph.last_usages();
 
+        let mut uses = vec![0; graph.exprs.len()];
+
+        for expr in graph.exprs.iter() {
+            if let ExprBody::Op { children, .. } = &expr.body {
+                for child in children {
+                    uses[child.0] += 1;
+                }
+            }
+        }
+
+        let mut packings = HashMap::new();
+
+        for (id, expr) in (0..).map(ExprId).zip(graph.exprs.iter()) {
+            if let ExprBody::Op {
+                op: Op::Concat { dim },
+                children,
+            } = &expr.body
+            {
+                let mut offset = 0;
+
+                for &child in children {
+                    if matches!(
+                        graph[child].body,
+                        ExprBody::Op {
+                            op: Op::Elemwise(_),
+                            ..
+                        }
+                    ) && uses[child.0] == 1
+                        && !graph.outputs.contains(&child)
+                    {
+                        packings.insert(
+                            child,
+                            (
+                                id,
+                                Packing {
+                                    offset: offset * expr.layout.forward_strides()[*dim],
+                                    strides: expr.layout.forward_strides(),
+                                },
+                            ),
+                        );
+                    }
+
+                    offset += graph[child].layout.dims()[*dim];
+                }
+            }
+        }
+
+        // Elementwise results whose every use is another elementwise op, and so can be stored as
+        // f16 without the other kernels knowing, unless they are precision-sensitive.
+        let mut halves = HashSet::new();
+
+        if self.f16_storage {
+            halves.extend(
+                (0..)
+                    .map(ExprId)
+                    .zip(graph.exprs.iter())
+                    .filter_map(|(id, expr)| {
+                        (matches!(
+                            expr.body,
+                            ExprBody::Op {
+                                op: Op::Elemwise(_),
+                                ..
+                            }
+                        ) && uses[id.0] > 0
+                            && !packings.contains_key(&id)
+                            && !graph.outputs.contains(&id)
+                            && !graph.keep_f32.contains(&id))
+                        .then_some(id)
+                    }),
+            );
+
+            for expr in graph.exprs.iter() {
+                if let ExprBody::Op { op, children } = &expr.body {
+                    if !matches!(op, Op::Elemwise(_)) {
+                        for child in children {
+                            halves.remove(child);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Single-element consts, which elementwise kernels inline. Those that only elementwise ops
+        // read are never allocated.
+        let mut literals = HashMap::new();
+        let mut unallocated = HashSet::new();
+
+        if self.inline_literals {
+            literals.extend(
+                (0..)
+                    .map(ExprId)
+                    .zip(graph.exprs.iter())
+                    .filter_map(|(id, expr)| match &expr.body {
+                        ExprBody::Const(tensor) if expr.layout.elements() == 1 => {
+                            Some((id, tensor.data[0]))
+                        }
+                        _ => None,
+                    }),
+            );
+            unallocated.extend(
+                literals
+                    .keys()
+                    .copied()
+                    .filter(|id| !graph.outputs.contains(id)),
+            );
+
+            for expr in graph.exprs.iter() {
+                if let ExprBody::Op { op, children } = &expr.body {
+                    if !matches!(op, Op::Elemwise(_)) {
+                        for child in children {
+                            unallocated.remove(child);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Chains of elementwise ops are fused as the shared lowering groups them, into the kernel
+        // of their last op, which reads the operands of the whole chain. Ops with uses the
+        // lowering skips, being dead, are left to kernels of their own.
+        let mut fused = HashMap::new();
+        let mut roots = HashMap::new();
+        let mut operands = HashMap::new();
+
+        for kernel in ir::lower(&graph, 1).kernels {
+            if kernel.fused.len() == 1 {
+                continue;
+            }
+
+            let mut pending = vec![kernel.output];
+            let mut members = Vec::new();
+            let mut kernel_operands = Children::new();
+
+            while let Some(id) = pending.pop() {
+                let ExprBody::Op { children, .. } = &graph[id].body else {
+                    unreachable!("fused expressions are ops");
+                };
+
+                for &child in children {
+                    match &graph[child].body {
+                        ExprBody::Op {
+                            op: Op::Elemwise(op),
+                            children,
+                        } if kernel.fused.contains(&child) && uses[child.0] == 1 => {
+                            members.push((child, (*op, children.clone())));
+                            pending.push(child);
+                        }
+                        _ if !kernel_operands.contains(&child) => kernel_operands.push(child),
+                        _ => {}
+                    }
+                }
+            }
+
+            // Besides its operands, the kernel binds its output and, when dynamic, its parameters.
+            let bound = kernel_operands
+                .iter()
+                .filter(|child| !literals.contains_key(child))
+                .count()
+                + 2;
+
+            if bound > self.limits.max_storage_buffers_per_shader_stage as usize {
+                continue;
+            }
+
+            for (member, body) in members {
+                fused.insert(member, body);
+                roots.insert(member, kernel.output);
+            }
+
+            operands.insert(kernel.output, kernel_operands);
+        }
+
         let mut steps = Vec::with_capacity(graph.exprs.len());
-        let mut layouts = Vec::with_capacity(graph.exprs.len());
+        let mut layouts: Vec<Arc<Layout>> = Vec::with_capacity(graph.exprs.len());
 
         let mut aliases: Vec<ExprId> = Vec::with_capacity(graph.exprs.len());
+        let mut buffer_last_usages = last_usages.clone();
+        let mut reserved = HashSet::new();
+        let mut assertions = Vec::new();
+
+        // Operands read by a fused op live until the kernel it is fused into.
+        for (member, (_, children)) in &fused {
+            for child in children {
+                buffer_last_usages[child.0] = buffer_last_usages[child.0].max(roots[member]);
+            }
+        }
+
+        for output in graph.outputs.iter() {
+            buffer_last_usages[output.0] = ExprId(usize::MAX);
+        }
+
+        if keep_inputs {
+            for input in graph.inputs.iter() {
+                buffer_last_usages[input.0] = ExprId(usize::MAX);
+            }
+        }
+
+        let sizes = (0..)
+            .map(ExprId)
+            .zip(graph.exprs.iter())
+            .map(|(id, expr)| {
+                if halves.contains(&id) {
+                    expr.layout.elements().div_ceil(2) * size_of::<u32>()
+                } else {
+                    expr.layout.size()
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mode = graph.mode;
+        let mut next_id = graph.exprs.len();
 
         for (id, expr) in (0..).map(ExprId).zip(graph.exprs) {
-            match expr.body {
+            let provenance = format!("{id:?}: {} = {:?};", expr.layout, expr.body);
+
+            let (buffer, layout) = match expr.body {
+                // Computed by the kernel they are fused into, without a buffer of their own.
+                ExprBody::Op { .. } if fused.contains_key(&id) => (id, expr.layout.clone()),
                 ExprBody::Op { op, children } => {
-                    steps.push(WgpuStep::Execute {
-                        output: id,
-                        source: match op {
-                            Op::Elemwise(op) => kernel::elemwise(
-                                self.workgroup_size_x,
-                                &expr.layout,
-                                children.iter().map(|id| (*id, &layouts[id.0])).collect(),
-                                WgpuExpr::new(
-                                    match op {
-                                        ElemwiseOp::Add => WgpuOp::Add,
-                                        ElemwiseOp::Mul => WgpuOp::Mul,
-                                        ElemwiseOp::Sin => WgpuOp::Sin,
+                    let expr_children = children;
+                    let children = operands
+                        .remove(&id)
+                        .unwrap_or_else(|| expr_children.clone());
+
+                    // Ops binding more memory than the device allows, split into dispatches over
+                    // slices of their buffers.
+                    let binding = self.limits.max_storage_buffer_binding_size as usize;
+                    let chunked = self.chunked
+                        && !packings.contains_key(&id)
+                        && !halves.contains(&id)
+                        && expr.layout.is_contiguous()
+                        && match &op {
+                            Op::Elemwise(_) => {
+                                expr.layout.size() > binding
+                                    && children.iter().all(|child| {
+                                        let layout = &*layouts[child.0];
+
+                                        literals.contains_key(child)
+                                            || (!halves.contains(child)
+                                                && (layout.elements() == 1
+                                                    || (layout.is_contiguous()
+                                                        && layout.dims() == expr.layout.dims())))
+                                    })
+                            }
+                            Op::Reduce { dims, .. } => {
+                                let input = &*layouts[children[0].0];
+                                let mut dims = dims.clone();
+
+                                dims.sort();
+
+                                input.size() > binding
+                                    && input.is_contiguous()
+                                    && dims
+                                        .iter()
+                                        .copied()
+                                        .eq(input.rank() - dims.len()..input.rank())
+                                    && self.chunk_rows(
+                                        dims.iter().map(|&dim| input.dims()[dim]).product(),
+                                    ) > 0
+                            }
+                            _ => false,
+                        };
+
+                    let in_place = children
+                        .iter()
+                        .copied()
+                        .find(|child| {
+                            let buffer = aliases[child.0];
+
+                            self.in_place
+                                && matches!(op, Op::Elemwise(_))
+                                && !chunked
+                                && !literals.contains_key(child)
+                                && !packings.contains_key(&id)
+                                && !packings.contains_key(child)
+                                && !halves.contains(&id)
+                                && !halves.contains(child)
+                                && buffer_last_usages[buffer.0] == id
+                                && sizes[buffer.0] == expr.layout.size()
+                                && layouts[child.0] == expr.layout
+                                && children
+                                    .iter()
+                                    .all(|other| other == child || aliases[other.0] != buffer)
+                        })
+                        .map(|child| aliases[child.0]);
+
+                    let buffer = match (packings.get(&id), in_place) {
+                        (Some((concat, _)), _) => *concat,
+                        (None, Some(buffer)) => {
+                            buffer_last_usages[buffer.0] = buffer_last_usages[id.0];
+
+                            buffer
+                        }
+                        (None, None) => id,
+                    };
+
+                    // Ops that only alias their input's buffer.
+                    let view = match &op {
+                        Op::Movement(MovementOp::Flip(_)) => false,
+                        Op::Movement(MovementOp::Reshape(shape)) => {
+                            layouts[children[0].0].reshape(shape.clone()).is_ok()
+                        }
+                        Op::Movement(_) | Op::Assert { .. } => true,
+                        Op::Dropout { .. } => mode == Mode::Inference,
+                        Op::Repeat { repeats } => layouts[children[0].0].can_expand(repeats),
+                        Op::Diagonal { offset } => *offset == 0,
+                        _ => false,
+                    };
+
+                    if !reserved.contains(&buffer) && in_place.is_none() && !view {
+                        reserved.insert(buffer);
+
+                        steps.push(WgpuStep::Reserve {
+                            id: buffer,
+                            size: sizes[buffer.0],
+                        });
+                    }
+
+                    let layout = match op {
+                        Op::Elemwise(op) if chunked => {
+                            let workgroup_size = self.workgroup_size(OpKind::Elemwise);
+                            let elements = expr.layout.elements();
+                            let rows = self.chunk_rows(1);
+
+                            let mut unique_children = children
+                                .iter()
+                                .copied()
+                                .filter(|child| !literals.contains_key(child))
+                                .collect::<Vec<_>>();
+
+                            unique_children.sort();
+                            unique_children.dedup();
+
+                            let position = |child: &ExprId| {
+                                ExprId(unique_children.binary_search(child).unwrap())
+                            };
+
+                            let leaf = |child: ExprId| match literals.get(&child) {
+                                Some(&value) => WgpuExpr::new_literal(value),
+                                None => {
+                                    WgpuExpr::new_var(format!("elem_input_{}", position(&child).0))
+                                }
+                            };
+                            let wgpu_expr = fused_expr(op, &expr_children, &fused, &leaf);
+                            let scalar = Layout::from([1]);
+
+                            for start in (0..elements).step_by(rows) {
+                                let chunk = Layout::from([rows.min(elements - start)]);
+                                let inputs = unique_children
+                                    .iter()
+                                    .map(|child| {
+                                        let layout = if layouts[child.0].elements() == 1 {
+                                            &scalar
+                                        } else {
+                                            &chunk
+                                        };
+
+                                        (position(child), layout)
+                                    })
+                                    .collect::<Vec<_>>();
+                                let half = HalfStorage::default();
+
+                                let source = self.kernel(
+                                    format!(
+                                        "elemwise {workgroup_size:?} {chunk:?} {inputs:?} {wgpu_expr} None None {half:?}"
+                                    ),
+                                    || {
+                                        kernel::elemwise(
+                                            workgroup_size,
+                                            &chunk,
+                                            &inputs,
+                                            wgpu_expr.clone(),
+                                            None,
+                                            None,
+                                            false,
+                                            &half,
+                                        )
                                     },
-                                    children
+                                );
+
+                                let mut bound = Vec::new();
+
+                                // Scalars are bound whole, and everything else sliced like the
+                                // output.
+                                for (source, read_only) in iter::once((buffer, false))
+                                    .chain(unique_children.iter().map(|child| (*child, true)))
+                                {
+                                    if read_only && layouts[source.0].elements() == 1 {
+                                        bound.push((
+                                            aliases[source.0],
+                                            (layouts[source.0].size(), true),
+                                        ));
+                                        continue;
+                                    }
+
+                                    let slice = fresh_id(&mut next_id);
+
+                                    steps.push(WgpuStep::Slice {
+                                        id: slice,
+                                        source: if read_only { aliases[source.0] } else { source },
+                                        offset: start * size_of::<f32>(),
+                                        size: chunk.size(),
+                                    });
+                                    bound.push((slice, (chunk.size(), read_only)));
+                                }
+
+                                steps.push(WgpuStep::Execute {
+                                    output: id,
+                                    source: annotate(
+                                        source,
+                                        &[
+                                            provenance.clone(),
+                                            inputs_note(&children, &layouts),
+                                            format!(
+                                                "chunk of elements {start}..{}",
+                                                start + chunk.elements()
+                                            ),
+                                        ],
+                                    ),
+                                    workgroups: self.elemwise_workgroups(OpKind::Elemwise, &chunk),
+                                    inputs: bound.iter().map(|&(id, _)| id).collect(),
+                                    inputs_layout: bound
                                         .iter()
-                                        .map(|id| WgpuExpr::new_var(format!("elem_input_{}", id.0)))
+                                        .map(|&(_, layout)| layout)
                                         .collect(),
+                                });
+                            }
+
+                            expr.layout.clone()
+                        }
+                        Op::Reduce { op, dims } if chunked => {
+                            let input = &*layouts[children[0].0];
+                            let row = dims.iter().map(|&dim| input.dims()[dim]).product::<usize>();
+                            let outputs = expr.layout.elements();
+                            let rows = self.chunk_rows(row);
+
+                            for start in (0..outputs).step_by(rows) {
+                                let count = rows.min(outputs - start);
+                                let (chunk_input, chunk_output) =
+                                    (Layout::from([count, row]), Layout::from([count, 1]));
+                                let (input_slice, output_slice) =
+                                    (fresh_id(&mut next_id), fresh_id(&mut next_id));
+
+                                steps.push(WgpuStep::Slice {
+                                    id: input_slice,
+                                    source: aliases[children[0].0],
+                                    offset: start * row * size_of::<f32>(),
+                                    size: chunk_input.size(),
+                                });
+                                steps.push(WgpuStep::Slice {
+                                    id: output_slice,
+                                    source: buffer,
+                                    offset: start * size_of::<f32>(),
+                                    size: chunk_output.size(),
+                                });
+
+                                let chunk_steps = self.lower_reduce(
+                                    OpKind::Reduce,
+                                    op,
+                                    (input_slice, &chunk_input),
+                                    (output_slice, &chunk_output, chunk_output.size()),
+                                    &[1],
+                                    &mut next_id,
+                                    &[
+                                        provenance.clone(),
+                                        inputs_note(&children, &layouts),
+                                        format!("chunk of rows {start}..{}", start + count),
+                                    ],
+                                );
+
+                                // The last pass writes the slice, but is named after the
+                                // expression like any other kernel writing it.
+                                steps.extend(chunk_steps.into_iter().map(|step| match step {
+                                    WgpuStep::Execute {
+                                        output,
+                                        source,
+                                        workgroups,
+                                        inputs,
+                                        inputs_layout,
+                                    } if output == output_slice => WgpuStep::Execute {
+                                        output: id,
+                                        source,
+                                        workgroups,
+                                        inputs,
+                                        inputs_layout,
+                                    },
+                                    step => step,
+                                }));
+                            }
+
+                            expr.layout.clone()
+                        }
+                        Op::Elemwise(op) => {
+                            let mut notes = vec![provenance, inputs_note(&children, &layouts)];
+
+                            if let Some((concat, packing)) = packings.get(&id) {
+                                notes.push(format!(
+                                    "packed into {concat:?} at offset {}",
+                                    packing.offset
+                                ));
+                            }
+
+                            if let Some(buffer) = in_place {
+                                notes.push(format!("in place in {buffer:?}"));
+                            }
+
+                            let mut unique_children = children
+                                .iter()
+                                .copied()
+                                .filter(|child| !literals.contains_key(child))
+                                .collect::<Vec<_>>();
+
+                            unique_children.sort();
+                            unique_children.dedup();
+
+                            let in_place_child = unique_children
+                                .iter()
+                                .copied()
+                                .find(|child| Some(aliases[child.0]) == in_place);
+
+                            let bound_children = unique_children
+                                .iter()
+                                .copied()
+                                .filter(|&child| Some(child) != in_place_child)
+                                .collect::<Vec<_>>();
+
+                            // Kernels name their inputs by position so that structurally
+                            // identical expressions render to identical, cacheable sources.
+                            let position = |child: &ExprId| {
+                                ExprId(unique_children.binary_search(child).unwrap())
+                            };
+
+                            let workgroup_size = self.workgroup_size(OpKind::Elemwise);
+                            let inputs = unique_children
+                                .iter()
+                                .map(|child| (position(child), &*layouts[child.0]))
+                                .collect::<Vec<_>>();
+                            let packing = packings.get(&id).map(|(_, packing)| packing);
+                            let in_place_position = in_place_child.as_ref().map(position);
+
+                            // Inputs either match the output element for element or are
+                            // splatted scalars.
+                            let half = HalfStorage {
+                                output: halves.contains(&id),
+                                inputs: unique_children
+                                    .iter()
+                                    .filter(|child| halves.contains(child))
+                                    .map(position)
+                                    .collect(),
+                            };
+                            let vectorized = self.vectorize
+                                && !self.dynamic_shapes
+                                && packing.is_none()
+                                && !half.output
+                                && half.inputs.is_empty()
+                                && expr.layout.elements().is_multiple_of(4)
+                                && expr.layout.is_contiguous()
+                                && inputs.iter().all(|(_, layout)| {
+                                    layout.elements() == 1
+                                        || (layout.is_contiguous()
+                                            && layout.dims() == expr.layout.dims())
+                                });
+
+                            let wgpu_expr = fused_expr(op, &expr_children, &fused, &|child| {
+                                match literals.get(&child) {
+                                    // Vectorized kernels compute on vec4s, which functions
+                                    // such as fma do not mix with scalars.
+                                    Some(&value) if vectorized => WgpuExpr::new(
+                                        WgpuOp::Vec4,
+                                        vec![WgpuExpr::new_literal(value)],
+                                    ),
+                                    Some(&value) => WgpuExpr::new_literal(value),
+                                    None => WgpuExpr::new_var(format!(
+                                        "elem_input_{}",
+                                        position(&child).0
+                                    )),
+                                }
+                            });
+
+                            // Dynamic kernels are keyed by rank alone, as the shapes are only
+                            // known to the parameters buffer.
+                            let key = if vectorized {
+                                format!(
+                                    "elemwise vec4 {workgroup_size:?} {} {:?} {wgpu_expr} {in_place_position:?}",
+                                    expr.layout.elements(),
+                                    inputs
+                                        .iter()
+                                        .map(|(id, layout)| (id, layout.elements() == 1))
+                                        .collect::<Vec<_>>()
+                                )
+                            } else if self.dynamic_shapes {
+                                format!(
+                                    "elemwise dynamic {workgroup_size:?} {} {} {wgpu_expr} {} {in_place_position:?} {half:?}",
+                                    expr.layout.rank(),
+                                    inputs.len(),
+                                    packing.is_some()
+                                )
+                            } else {
+                                format!(
+                                    "elemwise {workgroup_size:?} {:?} {inputs:?} {wgpu_expr} {packing:?} {in_place_position:?} {half:?}",
+                                    expr.layout
+                                )
+                            };
+                            let source = self.kernel(key, || {
+                                if vectorized {
+                                    kernel::elemwise_vec4(
+                                        workgroup_size,
+                                        expr.layout.elements(),
+                                        &inputs,
+                                        wgpu_expr,
+                                        in_place_position,
+                                    )
+                                } else {
+                                    kernel::elemwise(
+                                        workgroup_size,
+                                        &expr.layout,
+                                        &inputs,
+                                        wgpu_expr,
+                                        packing,
+                                        in_place_position,
+                                        self.dynamic_shapes,
+                                        &half,
+                                    )
+                                }
+                            });
+
+                            let mut bound = iter::once((buffer, (sizes[buffer.0], false)))
+                                .chain(bound_children.iter().map(|id| {
+                                    let size = if halves.contains(id) {
+                                        sizes[id.0]
+                                    } else {
+                                        layouts[id.0].size()
+                                    };
+
+                                    (aliases[id.0], (size, true))
+                                }))
+                                .collect::<Vec<_>>();
+                            let parameters = self.dynamic_shapes.then(|| {
+                                let values =
+                                    kernel::elemwise_parameters(&expr.layout, &inputs, packing);
+                                let parameters = fresh_id(&mut next_id);
+
+                                bound.push((parameters, (4 * values.len(), true)));
+                                steps.push(WgpuStep::Allocate {
+                                    id: parameters,
+                                    tensor: Tensor::from_parts(
+                                        values.iter().copied().map(f32::from_bits).collect(),
+                                        Layout::from([values.len()]),
+                                    ),
+                                });
+
+                                parameters
+                            });
+
+                            steps.push(WgpuStep::Execute {
+                                output: id,
+                                source: annotate(source, &notes),
+                                workgroups: if vectorized {
+                                    self.dispatch(
+                                        (expr.layout.elements() as u32 / 4)
+                                            .div_ceil(workgroup_size.iter().product()),
+                                    )
+                                } else if half.output {
+                                    self.dispatch(
+                                        (expr.layout.elements().div_ceil(2) as u32)
+                                            .div_ceil(workgroup_size.iter().product()),
+                                    )
+                                } else {
+                                    self.elemwise_workgroups(OpKind::Elemwise, &expr.layout)
+                                },
+                                inputs: bound.iter().map(|&(id, _)| id).collect(),
+                                inputs_layout: bound.iter().map(|&(_, layout)| layout).collect(),
+                            });
+                            steps.extend(parameters.map(WgpuStep::Deallocate));
+
+                            expr.layout.clone()
+                        }
+                        Op::Reduce { op, dims } => {
+                            steps.extend(self.lower_reduce(
+                                OpKind::Reduce,
+                                op,
+                                (aliases[children[0].0], &*layouts[children[0].0]),
+                                (buffer, &expr.layout, sizes[buffer.0]),
+                                &dims,
+                                &mut next_id,
+                                &[provenance, inputs_note(&children, &layouts)],
+                            ));
+
+                            expr.layout.clone()
+                        }
+                        Op::Movement(MovementOp::Transpose)
+                            if graph.outputs.contains(&id)
+                                && layouts[children[0].0].is_contiguous() =>
+                        {
+                            let dims = layouts[children[0].0].dims();
+
+                            let rows = dims[dims.len() - 2];
+                            let cols = dims[dims.len() - 1];
+
+                            let layout = expr.layout.contiguous();
+                            let workgroup_size = self.workgroup_size(OpKind::Movement);
+
+                            steps.push(WgpuStep::Reserve {
+                                id,
+                                size: layout.size(),
+                            });
+
+                            steps.push(WgpuStep::Execute {
+                                output: id,
+                                source: annotate(
+                                    self.kernel(
+                                        format!("transpose {workgroup_size:?} {rows} {cols}"),
+                                        || kernel::transpose(workgroup_size, rows, cols),
+                                    ),
+                                    &[
+                                        provenance.clone(),
+                                        inputs_note(&children, &layouts),
+                                        format!("materialized as {layout}"),
+                                    ],
                                 ),
-                            ),
-                            Op::Reduce { .. } => todo!(),
-                            Op::Movement(_) => {
-                                aliases.push(children[0]);
-                                layouts.push(expr.layout);
+                                workgroups: Workgroups::Fixed([
+                                    (cols as u32).div_ceil(workgroup_size[0]),
+                                    (rows as u32).div_ceil(workgroup_size[0]),
+                                    (expr.layout.elements() / (rows * cols)) as u32,
+                                ]),
+                                inputs: vec![id, aliases[children[0].0]],
+                                inputs_layout: vec![
+                                    (layout.size(), false),
+                                    (layouts[children[0].0].size(), true),
+                                ],
+                            });
+
+                            Arc::new(layout)
+                        }
+                        Op::Assert { predicate, message } => {
+                            let buffer = aliases[children[0].0];
+                            let input = &*layouts[children[0].0];
+
+                            buffer_last_usages[buffer.0] =
+                                buffer_last_usages[buffer.0].max(buffer_last_usages[id.0]);
+
+                            if self.assertions {
+                                let workgroup_size = self.workgroup_size(OpKind::Assert);
+
+                                steps.push(WgpuStep::Reserve {
+                                    id,
+                                    size: size_of::<u32>(),
+                                });
 
-                                continue;
+                                steps.push(WgpuStep::Execute {
+                                    output: id,
+                                    source: annotate(
+                                        self.kernel(
+                                            format!(
+                                                "assert {workgroup_size:?} {input:?} {predicate:?}"
+                                            ),
+                                            || kernel::assert(workgroup_size, input, predicate),
+                                        ),
+                                        &[provenance, inputs_note(&children, &layouts)],
+                                    ),
+                                    workgroups: self.elemwise_workgroups(OpKind::Assert, input),
+                                    inputs: vec![id, buffer],
+                                    inputs_layout: vec![
+                                        (size_of::<u32>(), false),
+                                        (input.size(), true),
+                                    ],
+                                });
+
+                                assertions.push((id, message));
                             }
-                        },
-                        workgroups: [
-                            (expr.layout.elements() as u32).div_ceil(self.workgroup_size_x),
-                            1,
-                            1,
-                        ],
-                        inputs: iter::once(id).chain(children.iter().copied()).collect(),
-                        inputs_layout: iter::once((expr.layout.size(), false))
-                            .chain(children.iter().map(|id| (layouts[id.0].size(), true)))
-                            .collect(),
-                    });
 
-                    for &child in children.iter().filter(|child| last_usages[child.0] == id) {
-                        steps.push(WgpuStep::Deallocate(child));
+                            aliases.push(buffer);
+                            layouts.push(expr.layout.clone());
+
+                            continue;
+                        }
+                        Op::Dropout { .. } if mode == Mode::Inference => {
+                            let buffer = aliases[children[0].0];
+
+                            buffer_last_usages[buffer.0] =
+                                buffer_last_usages[buffer.0].max(buffer_last_usages[id.0]);
+
+                            aliases.push(buffer);
+                            layouts.push(layouts[children[0].0].clone());
+
+                            continue;
+                        }
+                        // A gather of the input's elements in row-major order, which is the order
+                        // of the reshaped ones too.
+                        Op::Movement(MovementOp::Reshape(_)) if !view => {
+                            let input = &*layouts[children[0].0];
+                            let output = input.contiguous();
+                            let workgroup_size = self.workgroup_size(OpKind::Movement);
+
+                            steps.push(WgpuStep::Execute {
+                                output: id,
+                                source: annotate(
+                                    self.kernel(
+                                        format!("repeat {workgroup_size:?} {input:?} {output:?}"),
+                                        || kernel::repeat(workgroup_size, input, &output),
+                                    ),
+                                    &[provenance, inputs_note(&children, &layouts)],
+                                ),
+                                workgroups: self
+                                    .elemwise_workgroups(OpKind::Movement, &expr.layout),
+                                inputs: vec![buffer, aliases[children[0].0]],
+                                inputs_layout: vec![(sizes[buffer.0], false), (input.size(), true)],
+                            });
+
+                            expr.layout.clone()
+                        }
+                        Op::Movement(MovementOp::Flip(dims)) => {
+                            let input = &*layouts[children[0].0];
+                            let workgroup_size = self.workgroup_size(OpKind::Movement);
+
+                            steps.push(WgpuStep::Execute {
+                                output: id,
+                                source: annotate(
+                                    self.kernel(
+                                        format!("flip {workgroup_size:?} {input:?} {dims:?}"),
+                                        || kernel::flip(workgroup_size, input, &dims),
+                                    ),
+                                    &[provenance, inputs_note(&children, &layouts)],
+                                ),
+                                workgroups: self
+                                    .elemwise_workgroups(OpKind::Movement, &expr.layout),
+                                inputs: vec![buffer, aliases[children[0].0]],
+                                inputs_layout: vec![(sizes[buffer.0], false), (input.size(), true)],
+                            });
+
+                            expr.layout.clone()
+                        }
+                        Op::Movement(_) => {
+                            let buffer = aliases[children[0].0];
+
+                            buffer_last_usages[buffer.0] =
+                                buffer_last_usages[buffer.0].max(buffer_last_usages[id.0]);
+
+                            aliases.push(buffer);
+                            layouts.push(expr.layout.clone());
+
+                            continue;
+                        }
+                        Op::Repeat { repeats } if view => {
+                            let buffer = aliases[children[0].0];
+
+                            buffer_last_usages[buffer.0] =
+                                buffer_last_usages[buffer.0].max(buffer_last_usages[id.0]);
+
+                            aliases.push(buffer);
+                            layouts.push(Arc::new(layouts[children[0].0].repeat(&repeats)));
+
+                            continue;
+                        }
+                        Op::Repeat { .. } => {
+                            let input = &*layouts[children[0].0];
+                            let workgroup_size = self.workgroup_size(OpKind::Repeat);
+
+                            steps.push(WgpuStep::Execute {
+                                output: id,
+                                source: annotate(
+                                    self.kernel(
+                                        format!(
+                                            "repeat {workgroup_size:?} {input:?} {:?}",
+                                            expr.layout
+                                        ),
+                                        || kernel::repeat(workgroup_size, input, &expr.layout),
+                                    ),
+                                    &[provenance, inputs_note(&children, &layouts)],
+                                ),
+                                workgroups: self.elemwise_workgroups(OpKind::Repeat, &expr.layout),
+                                inputs: vec![buffer, aliases[children[0].0]],
+                                inputs_layout: vec![(sizes[buffer.0], false), (input.size(), true)],
+                            });
+
+                            expr.layout.clone()
+                        }
+                        Op::Diagonal { offset } if view => {
+                            let buffer = aliases[children[0].0];
+
+                            buffer_last_usages[buffer.0] =
+                                buffer_last_usages[buffer.0].max(buffer_last_usages[id.0]);
+
+                            aliases.push(buffer);
+                            layouts.push(Arc::new(layouts[children[0].0].diagonal(offset)));
+
+                            continue;
+                        }
+                        Op::Diagonal { offset } => {
+                            let input = &*layouts[children[0].0];
+                            let workgroup_size = self.workgroup_size(OpKind::Diagonal);
+
+                            steps.push(WgpuStep::Execute {
+                                output: id,
+                                source: annotate(
+                                    self.kernel(
+                                        format!("diagonal {workgroup_size:?} {input:?} {offset}"),
+                                        || kernel::diagonal(workgroup_size, input, offset),
+                                    ),
+                                    &[provenance, inputs_note(&children, &layouts)],
+                                ),
+                                workgroups: self
+                                    .elemwise_workgroups(OpKind::Diagonal, &expr.layout),
+                                inputs: vec![buffer, aliases[children[0].0]],
+                                inputs_layout: vec![(sizes[buffer.0], false), (input.size(), true)],
+                            });
+
+                            expr.layout.clone()
+                        }
+                        // A sum over the last dimension of the main diagonal's view.
+                        Op::Trace => {
+                            let input = layouts[children[0].0].diagonal_view(0);
+                            let output = Layout::from([expr.layout.dims(), &[1]].concat());
+
+                            steps.extend(self.lower_reduce(
+                                OpKind::Trace,
+                                ReduceOp::Sum,
+                                (aliases[children[0].0], &input),
+                                (buffer, &output, sizes[buffer.0]),
+                                &[input.rank() - 1],
+                                &mut next_id,
+                                &[provenance, inputs_note(&children, &layouts)],
+                            ));
+
+                            expr.layout.clone()
+                        }
+                        Op::Concat { dim } => {
+                            let mut offset = 0;
+
+                            for &child in children.iter() {
+                                let child_layout = &*layouts[child.0];
+
+                                if !packings.contains_key(&child) {
+                                    let packing = Packing {
+                                        offset: offset * expr.layout.forward_strides()[dim],
+                                        strides: expr.layout.forward_strides(),
+                                    };
+
+                                    let workgroup_size = self.workgroup_size(OpKind::Concat);
+
+                                    let source = self.kernel(
+                                        format!(
+                                            "copy {workgroup_size:?} {child_layout:?} {packing:?}"
+                                        ),
+                                        || {
+                                            kernel::elemwise(
+                                                workgroup_size,
+                                                child_layout,
+                                                &[(ExprId(0), child_layout)],
+                                                WgpuExpr::new_var(String::from("elem_input_0")),
+                                                Some(&packing),
+                                                None,
+                                                false,
+                                                &HalfStorage::default(),
+                                            )
+                                        },
+                                    );
+
+                                    steps.push(WgpuStep::Execute {
+                                        output: id,
+                                        source: annotate(
+                                            source,
+                                            &[
+                                                provenance.clone(),
+                                                inputs_note(&[child], &layouts),
+                                                format!("copy at offset {}", packing.offset),
+                                            ],
+                                        ),
+                                        workgroups: self
+                                            .elemwise_workgroups(OpKind::Concat, child_layout),
+                                        inputs: vec![id, aliases[child.0]],
+                                        inputs_layout: vec![
+                                            (expr.layout.size(), false),
+                                            (child_layout.size(), true),
+                                        ],
+                                    });
+                                }
+
+                                offset += child_layout.dims()[dim];
+                            }
+
+                            expr.layout.clone()
+                        }
+                        Op::MatMul | Op::MaskedMatMul { .. } => {
+                            let (lhs, rhs) = (&*layouts[children[0].0], &*layouts[children[1].0]);
+                            let workgroup_size = self.workgroup_size(OpKind::MatMul);
+                            let mask = match &op {
+                                Op::MaskedMatMul { mask } => Some(mask),
+                                _ => None,
+                            };
+
+                            steps.push(WgpuStep::Execute {
+                                output: id,
+                                source: annotate(
+                                    self.kernel(
+                                        format!(
+                                            "matmul {workgroup_size:?} {lhs:?} {rhs:?} {mask:?}"
+                                        ),
+                                        || {
+                                            kernel::matmul(
+                                                workgroup_size,
+                                                &MatMulGeometry::new(lhs, rhs),
+                                                mask,
+                                            )
+                                        },
+                                    ),
+                                    &[provenance, inputs_note(&children, &layouts)],
+                                ),
+                                workgroups: self.elemwise_workgroups(OpKind::MatMul, &expr.layout),
+                                inputs: vec![
+                                    buffer,
+                                    aliases[children[0].0],
+                                    aliases[children[1].0],
+                                ],
+                                inputs_layout: vec![
+                                    (sizes[buffer.0], false),
+                                    (lhs.size(), true),
+                                    (rhs.size(), true),
+                                ],
+                            });
+
+                            expr.layout.clone()
+                        }
+                        Op::Attention { scale } => {
+                            let inputs = children
+                                .iter()
+                                .map(|child| &*layouts[child.0])
+                                .collect::<Vec<_>>();
+                            let geometry = AttentionGeometry::new(inputs[0], inputs[1], inputs[2]);
+                            let workgroup_size = self.workgroup_size(OpKind::Attention);
+
+                            steps.push(WgpuStep::Execute {
+                                output: id,
+                                source: annotate(
+                                    self.kernel(
+                                        format!(
+                                            "attention {workgroup_size:?} {inputs:?} {scale:?}"
+                                        ),
+                                        || kernel::attention(workgroup_size, &geometry, scale),
+                                    ),
+                                    &[provenance, inputs_note(&children, &layouts)],
+                                ),
+                                workgroups: kernel::attention_workgroups(workgroup_size, &geometry)
+                                    .into(),
+                                inputs: iter::once(buffer)
+                                    .chain(children.iter().map(|child| aliases[child.0]))
+                                    .collect(),
+                                inputs_layout: iter::once((sizes[buffer.0], false))
+                                    .chain(inputs.iter().map(|layout| (layout.size(), true)))
+                                    .collect(),
+                            });
+
+                            expr.layout.clone()
+                        }
+                        Op::Random {
+                            distribution, seed, ..
+                        } => {
+                            let workgroup_size = self.workgroup_size(OpKind::Random);
+                            let elements = expr.layout.elements();
+
+                            steps.push(WgpuStep::Execute {
+                                output: id,
+                                source: annotate(
+                                    self.kernel(
+                                        format!(
+                                            "random {workgroup_size:?} {elements} {distribution:?} {seed}"
+                                        ),
+                                        || {
+                                            kernel::random(
+                                                workgroup_size,
+                                                elements,
+                                                distribution,
+                                                seed,
+                                            )
+                                        },
+                                    ),
+                                    &[provenance],
+                                ),
+                                workgroups: self
+                                    .elemwise_workgroups(OpKind::Random, &expr.layout),
+                                inputs: vec![buffer],
+                                inputs_layout: vec![(sizes[buffer.0], false)],
+                            });
+
+                            expr.layout.clone()
+                        }
+                        Op::Dropout { probability, seed } => {
+                            let input = &*layouts[children[0].0];
+                            let workgroup_size = self.workgroup_size(OpKind::Dropout);
+
+                            steps.push(WgpuStep::Execute {
+                                output: id,
+                                source: annotate(
+                                    self.kernel(
+                                        format!(
+                                            "dropout {workgroup_size:?} {input:?} {probability:?} {seed}"
+                                        ),
+                                        || kernel::dropout(workgroup_size, input, probability, seed),
+                                    ),
+                                    &[provenance, inputs_note(&children, &layouts)],
+                                ),
+                                workgroups: self.elemwise_workgroups(OpKind::Dropout, input),
+                                inputs: vec![buffer, aliases[children[0].0]],
+                                inputs_layout: vec![
+                                    (sizes[buffer.0], false),
+                                    (input.size(), true),
+                                ],
+                            });
+
+                            expr.layout.clone()
+                        }
+                        Op::If {
+                            predicate,
+                            then_graph,
+                            else_graph,
+                        } => {
+                            let inputs = children[1..]
+                                .iter()
+                                .map(|child| (aliases[child.0], &*layouts[child.0]))
+                                .collect::<Vec<_>>();
+                            let [then_steps, else_steps] = [then_graph, else_graph].map(|branch| {
+                                self.lower_branch(
+                                    &branch,
+                                    mode,
+                                    &inputs,
+                                    buffer,
+                                    &mut next_id,
+                                    &mut assertions,
+                                )
+                            });
+
+                            steps.push(WgpuStep::Branch {
+                                condition: aliases[children[0].0],
+                                predicate,
+                                then_steps,
+                                else_steps,
+                            });
+
+                            expr.layout.clone()
+                        }
+                        Op::Scan { body, dim } => {
+                            steps.extend(self.lower_scan(
+                                &body,
+                                dim,
+                                mode,
+                                (aliases[children[0].0], &*layouts[children[0].0]),
+                                (aliases[children[1].0], &*layouts[children[1].0]),
+                                (buffer, &expr.layout),
+                                &mut next_id,
+                                &mut assertions,
+                            ));
+
+                            expr.layout.clone()
+                        }
+                        Op::Nonzero | Op::MaskedSelect => {
+                            let (mask, values) = match op {
+                                Op::Nonzero => (children[0], None),
+                                _ => (children[1], Some(children[0])),
+                            };
+
+                            steps.extend(self.lower_compact(
+                                (aliases[mask.0], &*layouts[mask.0]),
+                                values.map(|values| (aliases[values.0], &*layouts[values.0])),
+                                (buffer, sizes[buffer.0]),
+                                &mut next_id,
+                                &[provenance, inputs_note(&children, &layouts)],
+                            ));
+
+                            expr.layout.clone()
+                        }
+                        Op::Complex(op) => {
+                            let inputs = children
+                                .iter()
+                                .map(|child| &*layouts[child.0])
+                                .collect::<Vec<_>>();
+                            let workgroup_size = self.workgroup_size(OpKind::Complex);
+
+                            steps.push(WgpuStep::Execute {
+                                output: id,
+                                source: annotate(
+                                    self.kernel(
+                                        format!(
+                                            "complex {workgroup_size:?} {op} {:?} {inputs:?}",
+                                            expr.layout
+                                        ),
+                                        || {
+                                            kernel::complex(
+                                                workgroup_size,
+                                                op,
+                                                &expr.layout,
+                                                &inputs,
+                                            )
+                                        },
+                                    ),
+                                    &[provenance, inputs_note(&children, &layouts)],
+                                ),
+                                workgroups: self.elemwise_workgroups(
+                                    OpKind::Complex,
+                                    &Layout::from([expr.layout.elements() / 2]),
+                                ),
+                                inputs: iter::once(buffer)
+                                    .chain(children.iter().map(|child| aliases[child.0]))
+                                    .collect(),
+                                inputs_layout: iter::once((sizes[buffer.0], false))
+                                    .chain(inputs.iter().map(|layout| (layout.size(), true)))
+                                    .collect(),
+                            });
+
+                            expr.layout.clone()
+                        }
+                        Op::Fft { dim, inverse } => {
+                            steps.extend(self.lower_fft(
+                                (aliases[children[0].0], &*layouts[children[0].0]),
+                                (buffer, sizes[buffer.0]),
+                                dim,
+                                inverse,
+                                &mut next_id,
+                                &[provenance, inputs_note(&children, &layouts)],
+                            ));
+
+                            expr.layout.clone()
+                        }
+                        Op::SpMM | Op::SpMV => {
+                            let [values, columns, offsets, dense] =
+                                [0, 1, 2, 3].map(|child| &*layouts[children[child].0]);
+                            let workgroup_size = self.workgroup_size(op.kind());
+
+                            steps.push(WgpuStep::Execute {
+                                output: id,
+                                source: annotate(
+                                    self.kernel(
+                                        format!(
+                                            "spmm {workgroup_size:?} {values:?} {columns:?} {offsets:?} {dense:?}"
+                                        ),
+                                        || {
+                                            kernel::sparse_matmul(
+                                                workgroup_size,
+                                                values,
+                                                columns,
+                                                offsets,
+                                                dense,
+                                            )
+                                        },
+                                    ),
+                                    &[provenance, inputs_note(&children, &layouts)],
+                                ),
+                                workgroups: self.elemwise_workgroups(op.kind(), &expr.layout),
+                                inputs: iter::once(buffer)
+                                    .chain(children.iter().map(|child| aliases[child.0]))
+                                    .collect(),
+                                inputs_layout: iter::once((sizes[buffer.0], false))
+                                    .chain([values, columns, offsets, dense].map(|layout| (layout.size(), true)))
+                                    .collect(),
+                            });
+
+                            expr.layout.clone()
+                        }
+                        Op::Custom(op) => {
+                            let inputs = children
+                                .iter()
+                                .map(|child| &*layouts[child.0])
+                                .collect::<Vec<_>>();
+                            let notes = [provenance, inputs_note(&children, &layouts)];
+
+                            // The workgroups are counted by a kernel of their own, run first.
+                            let arguments = op.indirect_wgsl(&expr.layout, &inputs).map(|source| {
+                                let arguments = fresh_id(&mut next_id);
+
+                                steps.push(WgpuStep::Reserve {
+                                    id: arguments,
+                                    size: 3 * size_of::<u32>(),
+                                });
+                                steps.push(WgpuStep::Execute {
+                                    output: arguments,
+                                    source: annotate(source, &notes),
+                                    workgroups: Workgroups::Fixed([1, 1, 1]),
+                                    inputs: iter::once(arguments)
+                                        .chain(children.iter().map(|child| aliases[child.0]))
+                                        .collect(),
+                                    inputs_layout: iter::once((3 * size_of::<u32>(), false))
+                                        .chain(inputs.iter().map(|layout| (layout.size(), true)))
+                                        .collect(),
+                                });
+
+                                arguments
+                            });
+
+                            steps.push(WgpuStep::Execute {
+                                output: id,
+                                source: annotate(op.wgsl(&expr.layout, &inputs), &notes),
+                                workgroups: match arguments {
+                                    Some(arguments) => Workgroups::Indirect(arguments),
+                                    None => op.workgroups(&expr.layout).into(),
+                                },
+                                inputs: iter::once(buffer)
+                                    .chain(children.iter().map(|child| aliases[child.0]))
+                                    .collect(),
+                                inputs_layout: iter::once((sizes[buffer.0], false))
+                                    .chain(inputs.iter().map(|layout| (layout.size(), true)))
+                                    .collect(),
+                            });
+                            steps.extend(arguments.map(WgpuStep::Deallocate));
+
+                            expr.layout.clone()
+                        }
+                    };
+
+                    let mut buffers = children
+                        .iter()
+                        .filter(|child| {
+                            !packings.contains_key(child) && !unallocated.contains(child)
+                        })
+                        .map(|child| aliases[child.0])
+                        .filter(|buffer| buffer_last_usages[buffer.0] == id)
+                        .collect::<Vec<_>>();
+
+                    buffers.sort();
+                    buffers.dedup();
+
+                    steps.extend(buffers.into_iter().map(WgpuStep::Deallocate));
+
+                    (buffer, layout)
+                }
+                ExprBody::Input(_) => (id, expr.layout.clone()),
+                ExprBody::Const(tensor) => {
+                    if !unallocated.contains(&id) {
+                        steps.push(WgpuStep::Allocate { id, tensor });
                     }
+
+                    (id, expr.layout.clone())
                 }
-                ExprBody::Input(_) => {}
-                ExprBody::Const(tensor) => steps.push(WgpuStep::Allocate { id, tensor }),
-            }
+            };
 
-            aliases.push(id);
-            layouts.push(expr.layout);
+            aliases.push(buffer);
+            layouts.push(layout);
         }
 
-        WgpuPlan {
-            inputs: graph.inputs,
+        Lowering {
             steps,
-            output_layouts: graph
-                .outputs
-                .iter()
-                .rev()
-                .map(|id| layouts.remove(id.0))
-                .collect(),
-            outputs: graph.outputs.iter().map(|id| aliases[id.0]).collect(),
+            aliases,
+            layouts,
+            assertions,
+            ids: next_id,
+            roots,
+        }
+    }
+
+    // Lowers a branch of an `if` so that it reads the parent's `inputs` buffers and copies its
+    // result into `output`. Its own buffers get ids from `next_id` on.
+    #[allow(clippy::too_many_arguments)]
+    fn lower_branch(
+        &self,
+        branch: &Graph,
+        mode: Mode,
+        inputs: &[(ExprId, &Layout)],
+        output: ExprId,
+        next_id: &mut usize,
+        assertions: &mut Vec<(ExprId, String)>,
+    ) -> Vec<WgpuStep> {
+        // The branch is rebuilt on top of the actual input layouts, which may be views.
+        let mut graph = Graph::new();
+
+        graph.set_mode(mode);
+
+        let ids = inputs
+            .iter()
+            .map(|(_, layout)| graph.add_input((*layout).clone()))
+            .collect::<Vec<_>>();
+        let result = graph.inline(branch, &ids)[0];
+
+        graph.add_output(result);
+
+        let lowering = self.lower(graph, true);
+        let base = *next_id;
+        let rename = |id: ExprId| match inputs.get(id.0) {
+            Some(&(buffer, _)) => buffer,
+            None => ExprId(base + id.0),
+        };
+
+        *next_id += lowering.ids;
+
+        assertions.extend(
+            lowering
+                .assertions
+                .into_iter()
+                .map(|(id, message)| (rename(id), message)),
+        );
+
+        let source = rename(lowering.aliases[result.0]);
+        let layout = &lowering.layouts[result.0];
+
+        let mut steps = lowering
+            .steps
+            .iter()
+            .map(|step| repeat::rename(step, rename))
+            .collect::<Vec<_>>();
+
+        steps.push(self.copy(
+            OpKind::If,
+            layout,
+            output,
+            source,
+            format!("{output:?}: branch result {source:?}"),
+        ));
+
+        if inputs.iter().all(|&(buffer, _)| buffer != source) {
+            steps.push(WgpuStep::Deallocate(source));
+        }
+
+        steps
+    }
+
+    // Reduces `input` over `dims` into `output`. Reductions of more than `REDUCE_CHUNK` elements are
+    // split across threads, whose partial results are reduced again until one thread per output
+    // remains.
+    #[allow(clippy::too_many_arguments)]
+    fn lower_reduce(
+        &self,
+        kind: OpKind,
+        op: ReduceOp,
+        (mut source, input): (ExprId, &Layout),
+        (output, layout, size): (ExprId, &Layout, usize),
+        dims: &[DimId],
+        next_id: &mut usize,
+        notes: &[String],
+    ) -> Vec<WgpuStep> {
+        let workgroup_size = self.workgroup_size(kind);
+        let two_float = match op {
+            ReduceOp::Sum => self.sum_accumulation,
+            ReduceOp::Mean => self.mean_accumulation,
+            ReduceOp::Max | ReduceOp::ArgMax | ReduceOp::ArgMin => Accumulation::Single,
+        } == Accumulation::TwoFloat;
+        let count = dims.iter().map(|&dim| input.dims()[dim]).product::<usize>();
+        let outputs = layout.elements();
+
+        let buffer = source;
+        let (mut input, mut reduced, mut dims) = (input.clone(), layout.clone(), dims.to_vec());
+        let mut steps = Vec::new();
+
+        loop {
+            let reduced_elements = dims.iter().map(|&dim| input.dims()[dim]).product::<usize>();
+            let chunks = reduced_elements
+                .div_ceil(kernel::reduce_chunk_size(op, reduced_elements))
+                .max(1);
+            let partials = Layout::from([outputs, chunks]);
+
+            // Partial means are sums, which the last pass divides by the full count.
+            let (target, target_size, pass_op) = if chunks == 1 {
+                (output, size, op)
+            } else {
+                let partial = fresh_id(next_id);
+
+                steps.push(WgpuStep::Reserve {
+                    id: partial,
+                    size: partials.size(),
+                });
+
+                let pass_op = match op {
+                    ReduceOp::Mean => ReduceOp::Sum,
+                    op => op,
+                };
+
+                (partial, partials.size(), pass_op)
+            };
+
+            let mut notes = notes.to_vec();
+
+            if chunks > 1 {
+                notes.push(format!("partial pass over {chunks} chunks"));
+            }
+
+            steps.push(WgpuStep::Execute {
+                output: target,
+                source: annotate(
+                    self.kernel(
+                        format!(
+                            "reduce {workgroup_size:?} {pass_op} {input:?} {reduced:?} {dims:?} {two_float} {count}"
+                        ),
+                        || {
+                            kernel::reduce(
+                                workgroup_size,
+                                pass_op,
+                                &input,
+                                &reduced,
+                                &dims,
+                                two_float,
+                                count,
+                            )
+                        },
+                    ),
+                    &notes,
+                ),
+                workgroups: self.elemwise_workgroups(kind, &partials),
+                inputs: vec![target, source],
+                inputs_layout: vec![(target_size, false), (input.size(), true)],
+            });
+
+            if source != buffer {
+                steps.push(WgpuStep::Deallocate(source));
+            }
+
+            if chunks == 1 {
+                break;
+            }
+
+            source = target;
+            input = partials;
+            reduced = Layout::from([outputs, 1]);
+            dims = vec![1];
+        }
+
+        steps
+    }
+
+    // Compacts the elements `mask` selects to the start of `output`, padding the rest. A first pass
+    // counts the selected elements of each chunk of the mask, a single invocation scans the counts
+    // into positions, and a second pass writes each chunk from its position on.
+    fn lower_compact(
+        &self,
+        (mask, mask_layout): (ExprId, &Layout),
+        values: Option<(ExprId, &Layout)>,
+        (output, size): (ExprId, usize),
+        next_id: &mut usize,
+        notes: &[String],
+    ) -> Vec<WgpuStep> {
+        let (kind, pad) = match values {
+            Some(_) => (OpKind::MaskedSelect, 0.0),
+            None => (OpKind::Nonzero, -1.0),
+        };
+        let workgroup_size = self.workgroup_size(kind);
+        let elements = mask_layout.elements();
+        let chunks = elements.div_ceil(kernel::COMPACT_CHUNK_SIZE);
+        let counts = fresh_id(next_id);
+        let counts_size = (chunks + 1) * size_of::<u32>();
+        let values_layout = values.map(|(_, layout)| layout);
+
+        let [x, y, z] = workgroup_size;
+        let chunk_workgroups = self.dispatch((chunks as u32).div_ceil(x * y * z));
+
+        let compact = |counting: bool| {
+            annotate(
+                self.kernel(
+                    format!(
+                        "compact {workgroup_size:?} {counting} {mask_layout:?} {values_layout:?}"
+                    ),
+                    || kernel::compact(workgroup_size, mask_layout, values_layout, counting),
+                ),
+                notes,
+            )
+        };
+
+        vec![
+            WgpuStep::Reserve {
+                id: counts,
+                size: counts_size,
+            },
+            WgpuStep::Execute {
+                output: counts,
+                source: compact(true),
+                workgroups: chunk_workgroups,
+                inputs: vec![counts, mask],
+                inputs_layout: vec![(counts_size, false), (mask_layout.size(), true)],
+            },
+            WgpuStep::Execute {
+                output: counts,
+                source: annotate(
+                    self.kernel(format!("compact scan {chunks}"), || {
+                        kernel::compact_scan(chunks)
+                    }),
+                    notes,
+                ),
+                workgroups: Workgroups::Fixed([1, 1, 1]),
+                inputs: vec![counts],
+                inputs_layout: vec![(counts_size, false)],
+            },
+            WgpuStep::Execute {
+                output,
+                source: compact(false),
+                workgroups: chunk_workgroups,
+                inputs: iter::once(output)
+                    .chain(iter::once(mask))
+                    .chain(values.map(|(values, _)| values))
+                    .chain(iter::once(counts))
+                    .collect(),
+                inputs_layout: iter::once((size, false))
+                    .chain(iter::once((mask_layout.size(), true)))
+                    .chain(values.map(|(_, layout)| (layout.size(), true)))
+                    .chain(iter::once((counts_size, true)))
+                    .collect(),
+            },
+            WgpuStep::Execute {
+                output,
+                source: annotate(
+                    self.kernel(
+                        format!("compact pad {workgroup_size:?} {elements} {chunks} {pad:?}"),
+                        || kernel::compact_pad(workgroup_size, elements, chunks, pad),
+                    ),
+                    notes,
+                ),
+                workgroups: self.elemwise_workgroups(kind, &Layout::from([elements])),
+                inputs: vec![output, counts],
+                inputs_layout: vec![(size, false), (counts_size, true)],
+            },
+            WgpuStep::Deallocate(counts),
+        ]
+    }
+
+    // Transforms `input` along `dim` in radix-4 passes, with a radix-2 pass last when the size is
+    // an odd power of two. The passes alternate between `output` and a scratch buffer, ending on
+    // `output`.
+    #[allow(clippy::too_many_arguments)]
+    fn lower_fft(
+        &self,
+        (source, input): (ExprId, &Layout),
+        (output, size): (ExprId, usize),
+        dim: DimId,
+        inverse: bool,
+        next_id: &mut usize,
+        notes: &[String],
+    ) -> Vec<WgpuStep> {
+        let workgroup_size = self.workgroup_size(OpKind::Fft);
+        let length = input.dims()[dim];
+        let layout = input.contiguous();
+        let lines = layout.elements() / 2 / length;
+
+        let mut radices = vec![4; length.trailing_zeros() as usize / 2];
+
+        if length.trailing_zeros() % 2 == 1 {
+            radices.push(2);
+        }
+
+        // Transforms of size one copy their input.
+        if radices.is_empty() {
+            radices.push(1);
+        }
+
+        let scratch = (radices.len() > 1).then(|| fresh_id(next_id));
+        let mut steps = Vec::new();
+
+        steps.extend(scratch.map(|id| WgpuStep::Reserve {
+            id,
+            size: layout.size(),
+        }));
+
+        let (mut source, mut input, mut span) = (source, input.clone(), 1);
+
+        for (pass, &radix) in radices.iter().enumerate() {
+            let remaining = radices.len() - 1 - pass;
+            let (target, target_size) = match scratch {
+                Some(scratch) if remaining % 2 == 1 => (scratch, layout.size()),
+                _ => (output, size),
+            };
+            let scale = if inverse && remaining == 0 {
+                1.0 / length as f32
+            } else {
+                1.0
+            };
+
+            let mut notes = notes.to_vec();
+
+            if radices.len() > 1 {
+                notes.push(format!(
+                    "pass {} of {} with radix {radix}",
+                    pass + 1,
+                    radices.len()
+                ));
+            }
+
+            steps.push(WgpuStep::Execute {
+                output: target,
+                source: annotate(
+                    self.kernel(
+                        format!(
+                            "fft {workgroup_size:?} {input:?} {layout:?} {dim} {radix} {span} {inverse} {scale:?}"
+                        ),
+                        || {
+                            kernel::fft(
+                                workgroup_size,
+                                &input,
+                                &layout,
+                                dim,
+                                radix,
+                                span,
+                                inverse,
+                                scale,
+                            )
+                        },
+                    ),
+                    &notes,
+                ),
+                workgroups: self.elemwise_workgroups(
+                    OpKind::Fft,
+                    &Layout::from([lines * length / radix]),
+                ),
+                inputs: vec![target, source],
+                inputs_layout: vec![(target_size, false), (input.size(), true)],
+            });
+
+            source = target;
+            input = layout.clone();
+            span *= radix;
+        }
+
+        steps.extend(scratch.map(WgpuStep::Deallocate));
+
+        steps
+    }
+
+    // Lowers a scan into one run of the body per position along `dim`. The runs only differ in
+    // their buffers, with the position read from a one-element buffer rather than baked into the
+    // kernels, so folding repeats turns them into a single repeated body.
+    #[allow(clippy::too_many_arguments)]
+    fn lower_scan(
+        &self,
+        body: &Graph,
+        dim: DimId,
+        mode: Mode,
+        (state, state_layout): (ExprId, &Layout),
+        (sequence, sequence_layout): (ExprId, &Layout),
+        (output, output_layout): (ExprId, &Layout),
+        next_id: &mut usize,
+        assertions: &mut Vec<(ExprId, String)>,
+    ) -> Vec<WgpuStep> {
+        let carry_layout = state_layout.contiguous();
+        let mut slice_dims = sequence_layout.dims().to_vec();
+
+        slice_dims[dim] = 1;
+
+        let slice_layout = Layout::from(slice_dims);
+
+        let mut graph = Graph::new();
+
+        graph.set_mode(mode);
+
+        let ids = [
+            graph.add_input(carry_layout.clone()),
+            graph.add_input(slice_layout.clone()),
+        ];
+        let result = graph.inline(body, &ids)[0];
+
+        graph.add_output(result);
+
+        let lowering = self.lower(graph, true);
+        let result_layout = &lowering.layouts[result.0];
+        // Carries are kept contiguous and owned by the run that computed them.
+        let copy_result =
+            !result_layout.is_contiguous() || lowering.aliases[result.0].0 < ids.len();
+
+        let workgroup_size = self.workgroup_size(OpKind::Scan);
+        let slice_source = self.kernel(
+            format!("scan slice {workgroup_size:?} {sequence_layout:?} {dim}"),
+            || kernel::scan_slice(workgroup_size, sequence_layout, dim),
+        );
+        let stack_source = self.kernel(
+            format!("scan stack {workgroup_size:?} {}", carry_layout.elements()),
+            || kernel::scan_stack(workgroup_size, carry_layout.elements()),
+        );
+
+        let mut steps = Vec::new();
+        let mut carry = state;
+
+        if !state_layout.is_contiguous() {
+            carry = fresh_id(next_id);
+
+            steps.push(WgpuStep::Reserve {
+                id: carry,
+                size: carry_layout.size(),
+            });
+            steps.push(self.copy(
+                OpKind::Scan,
+                state_layout,
+                carry,
+                state,
+                format!("{output:?}: initial state {state:?}"),
+            ));
+        }
+
+        for position in 0..sequence_layout.dims()[dim] {
+            let (position_id, slice) = (fresh_id(next_id), fresh_id(next_id));
+
+            steps.push(WgpuStep::Allocate {
+                id: position_id,
+                tensor: Tensor::from_parts(Box::new([position as f32]), Layout::from([1])),
+            });
+            steps.push(WgpuStep::Reserve {
+                id: slice,
+                size: slice_layout.size(),
+            });
+            steps.push(WgpuStep::Execute {
+                output: slice,
+                source: annotate(
+                    slice_source.clone(),
+                    &[format!("{output:?}: position {position} of {sequence:?}")],
+                ),
+                workgroups: self.elemwise_workgroups(OpKind::Scan, &slice_layout),
+                inputs: vec![slice, sequence, position_id],
+                inputs_layout: vec![
+                    (slice_layout.size(), false),
+                    (sequence_layout.size(), true),
+                    (size_of::<f32>(), true),
+                ],
+            });
+
+            let base = *next_id;
+            let rename = |id: ExprId| match id.0 {
+                0 => carry,
+                1 => slice,
+                _ => ExprId(base + id.0),
+            };
+
+            *next_id += lowering.ids;
+
+            steps.extend(
+                lowering
+                    .steps
+                    .iter()
+                    .map(|step| repeat::rename(step, rename)),
+            );
+            assertions.extend(
+                lowering
+                    .assertions
+                    .iter()
+                    .map(|(id, message)| (rename(*id), message.clone())),
+            );
+
+            let mut next_carry = rename(lowering.aliases[result.0]);
+
+            if copy_result {
+                let copy = fresh_id(next_id);
+
+                steps.push(WgpuStep::Reserve {
+                    id: copy,
+                    size: carry_layout.size(),
+                });
+                steps.push(self.copy(
+                    OpKind::Scan,
+                    result_layout,
+                    copy,
+                    next_carry,
+                    format!("{output:?}: carry at position {position}"),
+                ));
+
+                if lowering.aliases[result.0].0 >= ids.len() {
+                    steps.push(WgpuStep::Deallocate(next_carry));
+                }
+
+                next_carry = copy;
+            }
+
+            steps.push(WgpuStep::Execute {
+                output,
+                source: annotate(
+                    stack_source.clone(),
+                    &[format!("{output:?}: output at position {position}")],
+                ),
+                workgroups: self.elemwise_workgroups(OpKind::Scan, &carry_layout),
+                inputs: vec![output, next_carry, position_id],
+                inputs_layout: vec![
+                    (output_layout.size(), false),
+                    (carry_layout.size(), true),
+                    (size_of::<f32>(), true),
+                ],
+            });
+
+            steps.push(WgpuStep::Deallocate(position_id));
+            steps.push(WgpuStep::Deallocate(slice));
+
+            if carry != state {
+                steps.push(WgpuStep::Deallocate(carry));
+            }
+
+            carry = next_carry;
+        }
+
+        if carry != state {
+            steps.push(WgpuStep::Deallocate(carry));
+        }
+
+        steps
+    }
+
+    // Copies `source`, laid out as `layout`, into the contiguous buffer `output`.
+    fn copy(
+        &self,
+        kind: OpKind,
+        layout: &Layout,
+        output: ExprId,
+        source: ExprId,
+        note: String,
+    ) -> WgpuStep {
+        let workgroup_size = self.workgroup_size(kind);
+
+        WgpuStep::Execute {
+            output,
+            source: annotate(
+                self.kernel(format!("contiguous {workgroup_size:?} {layout:?}"), || {
+                    kernel::elemwise(
+                        workgroup_size,
+                        &layout.contiguous(),
+                        &[(ExprId(0), layout)],
+                        WgpuExpr::new_var(String::from("elem_input_0")),
+                        None,
+                        None,
+                        false,
+                        &HalfStorage::default(),
+                    )
+                }),
+                &[note],
+            ),
+            workgroups: self.elemwise_workgroups(kind, layout),
+            inputs: vec![output, source],
+            inputs_layout: vec![(layout.contiguous().size(), false), (layout.size(), true)],
         }
     }
 }
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+
+use crate::graph::ExprId;
+
+use super::{
+    compiler::{WgpuPlan, WgpuStep},
+    repeat,
+};
+
+/// The throughput of a device, for estimating how long plans take on it.
+#[derive(Clone, Debug)]
+pub struct DeviceProfile {
+    pub flops_per_second: f64,
+    pub bytes_per_second: f64,
+    /// The fixed cost of each dispatch, in seconds.
+    pub dispatch_seconds: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct StepCost {
+    pub step: usize,
+    pub output: ExprId,
+    pub flops: usize,
+    pub bytes: usize,
+    pub seconds: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct CostEstimate {
+    pub steps: Vec<StepCost>,
+    pub flops: usize,
+    pub bytes: usize,
+    pub seconds: f64,
+}
+
+// Adds up the cost of the kernels in `steps`, taking the more expensive side of each branch.
+fn estimate_steps(
+    steps: &[WgpuStep],
+    profile: &DeviceProfile,
+    flops: &mut HashMap<ExprId, usize>,
+    costs: &mut Vec<StepCost>,
+) {
+    for (index, step) in steps.iter().enumerate() {
+        match step {
+            WgpuStep::Execute {
+                output,
+                inputs_layout,
+                ..
+            } => {
+                let (flops, bytes) = (
+                    flops.remove(output).unwrap_or(0),
+                    inputs_layout.iter().map(|&(size, _)| size).sum::<usize>(),
+                );
+
+                // A roofline: kernels are bound by either arithmetic or memory.
+                costs.push(StepCost {
+                    step: index,
+                    output: *output,
+                    flops,
+                    bytes,
+                    seconds: (flops as f64 / profile.flops_per_second)
+                        .max(bytes as f64 / profile.bytes_per_second)
+                        + profile.dispatch_seconds,
+                });
+            }
+            WgpuStep::Branch {
+                condition,
+                then_steps,
+                else_steps,
+                ..
+            } => {
+                let sides = [then_steps, else_steps].map(|steps| {
+                    let mut costs = Vec::new();
+
+                    estimate_steps(steps, profile, &mut flops.clone(), &mut costs);
+
+                    costs
+                });
+                let seconds = |costs: &[StepCost]| costs.iter().map(|cost| cost.seconds).sum();
+                let [then_costs, else_costs] = &sides;
+                let taken = if seconds(then_costs) >= seconds(else_costs) {
+                    then_costs
+                } else {
+                    else_costs
+                };
+
+                costs.push(StepCost {
+                    step: index,
+                    output: *condition,
+                    flops: taken.iter().map(|cost| cost.flops).sum(),
+                    bytes: taken.iter().map(|cost| cost.bytes).sum(),
+                    seconds: seconds(taken),
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+impl WgpuPlan {
+    /// Estimates the work of each kernel and how long it takes on a device with `profile`,
+    /// without running anything. Steps are numbered as in the unrolled plan.
+    pub fn estimate(&self, profile: &DeviceProfile) -> CostEstimate {
+        let mut steps = Vec::new();
+
+        estimate_steps(
+            &repeat::unroll(&self.steps),
+            profile,
+            &mut self.flops.clone(),
+            &mut steps,
+        );
+
+        CostEstimate {
+            flops: steps.iter().map(|cost| cost.flops).sum(),
+            bytes: steps.iter().map(|cost| cost.bytes).sum(),
+            seconds: steps.iter().map(|cost| cost.seconds).sum(),
+            steps,
+        }
+    }
+}
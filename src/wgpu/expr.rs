@@ -2,26 +2,68 @@ use std::fmt::Display;
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum WgpuOp {
     Add,
     Mul,
     Sin,
+    Rsqrt,
+    Fma,
+    Exp,
+    Log,
+    Tanh,
+    Floor,
+    Ceil,
+    Clamp,
+    /// Splats a scalar across a `vec4`.
+    Vec4,
+    Literal(f32),
     Var(String),
 }
 
+impl WgpuOp {
+    // Binding strength of infix operators, higher binding tighter. Functions and variables are
+    // atoms.
+    fn precedence(&self) -> Option<u8> {
+        match self {
+            WgpuOp::Add => Some(1),
+            WgpuOp::Mul => Some(2),
+            _ => None,
+        }
+    }
+}
+
 impl Display for WgpuOp {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let WgpuOp::Literal(value) = self {
+            // WGSL has no literals for infinities and NaNs, so those are spelled by their bits.
+            return if value.is_finite() {
+                write!(f, "{value:?}f")
+            } else {
+                write!(f, "bitcast<f32>({}u)", value.to_bits())
+            };
+        }
+
         f.write_str(match self {
             WgpuOp::Add => "+",
             WgpuOp::Mul => "*",
             WgpuOp::Sin => "sin",
+            WgpuOp::Rsqrt => "inverseSqrt",
+            WgpuOp::Fma => "fma",
+            WgpuOp::Exp => "exp",
+            WgpuOp::Log => "log",
+            WgpuOp::Tanh => "tanh",
+            WgpuOp::Floor => "floor",
+            WgpuOp::Ceil => "ceil",
+            WgpuOp::Clamp => "clamp",
+            WgpuOp::Vec4 => "vec4",
+            WgpuOp::Literal(_) => unreachable!("literals are formatted above"),
             WgpuOp::Var(variable) => variable.as_str(),
         })
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct WgpuExpr {
     op: WgpuOp,
     children: Vec<WgpuExpr>,
@@ -38,19 +80,43 @@ impl WgpuExpr {
             children: vec![],
         }
     }
+
+    pub fn new_literal(value: f32) -> Self {
+        Self {
+            op: WgpuOp::Literal(value),
+            children: vec![],
+        }
+    }
+
+    // Writes an operand of an infix operator of the given precedence. Float addition and
+    // multiplication are not associative, so a right operand of equal precedence keeps its
+    // parentheses to preserve the evaluation order.
+    fn fmt_operand(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        precedence: u8,
+        right: bool,
+    ) -> std::fmt::Result {
+        match (&self.op, self.op.precedence()) {
+            (_, Some(own)) if own < precedence || (right && own == precedence) => {
+                write!(f, "({self})")
+            }
+            (WgpuOp::Literal(value), _) if value.is_sign_negative() => write!(f, "({self})"),
+            _ => write!(f, "{self}"),
+        }
+    }
 }
 
 impl Display for WgpuExpr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match &self.op {
-            WgpuOp::Add | WgpuOp::Mul => {
-                write!(
-                    f,
-                    "({}) {} ({})",
-                    &self.children[0], self.op, &self.children[1]
-                )
+        match (&self.op, self.op.precedence()) {
+            (WgpuOp::Var(_) | WgpuOp::Literal(_), _) => write!(f, "{}", self.op),
+            (_, Some(precedence)) => {
+                self.children[0].fmt_operand(f, precedence, false)?;
+                write!(f, " {} ", self.op)?;
+                self.children[1].fmt_operand(f, precedence, true)
             }
-            WgpuOp::Sin => write!(
+            (_, None) => write!(
                 f,
                 "{}({})",
                 self.op,
@@ -60,7 +126,6 @@ impl Display for WgpuExpr {
                     .collect::<Vec<_>>()
                     .join(", ")
             ),
-            WgpuOp::Var(variable) => f.write_str(variable),
         }
     }
 }
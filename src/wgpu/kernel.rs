@@ -3,12 +3,57 @@ use std::{collections::HashMap, iter, sync::OnceLock};
 use serde::{Deserialize, Serialize};
 use tera::{Context, Tera};
 
-use crate::{graph::ExprId, tensor::Layout};
+use crate::{
+    graph::{
+        AttentionGeometry, ComplexOp, Distribution, ExprId, MatMulGeometry, MatMulMask, Predicate,
+        ReduceOp, PHILOX_MULTIPLIERS, PHILOX_WEYL,
+    },
+    tensor::{DimId, Layout},
+};
 
-use super::expr::WgpuExpr;
+use super::{
+    compiler::{FloatPolicy, Readback},
+    expr::WgpuExpr,
+};
 
 const ELEMWISE: &str = "elemwise";
+const ELEMWISE_VEC4: &str = "elemwise_vec4";
 const REDUCE: &str = "reduce";
+const TRANSPOSE: &str = "transpose";
+const ASSERT: &str = "assert";
+const CHECKSUM: &str = "checksum";
+const CONVERT: &str = "convert";
+const SANITIZE: &str = "sanitize";
+const MATMUL: &str = "matmul";
+const ATTENTION: &str = "attention";
+const RANDOM: &str = "random";
+const DROPOUT: &str = "dropout";
+const REPEAT: &str = "repeat";
+const DIAGONAL: &str = "diagonal";
+const FLIP: &str = "flip";
+const SCAN_SLICE: &str = "scan_slice";
+const SCAN_STACK: &str = "scan_stack";
+const COMPACT: &str = "compact";
+const COMPACT_SCAN: &str = "compact_scan";
+const COMPACT_PAD: &str = "compact_pad";
+const SPMM: &str = "spmm";
+const COMPLEX: &str = "complex";
+const FFT: &str = "fft";
+
+pub(crate) const TRANSPOSE_WORKGROUP_SIZE: [u32; 3] = [32, 8, 1];
+pub(crate) const ATTENTION_WORKGROUP_SIZE: [u32; 3] = [64, 1, 1];
+const ATTENTION_TILE_ELEMENTS: usize = 4096;
+pub(crate) const COMPACT_CHUNK_SIZE: usize = 256;
+
+// Kernels that index elements linearly number their workgroups row by row across the x-y grid,
+// so that dispatches too large for a single dimension can wrap into the next one.
+pub(crate) fn dispatch(groups: u32, max_per_dimension: u32) -> [u32; 3] {
+    if groups <= max_per_dimension {
+        [groups, 1, 1]
+    } else {
+        [max_per_dimension, groups.div_ceil(max_per_dimension), 1]
+    }
+}
 
 fn tera() -> &'static Tera {
     static TERA: OnceLock<Tera> = OnceLock::new();
@@ -19,7 +64,45 @@ fn tera() -> &'static Tera {
         tera.add_template_files([
             ("./src/wgpu/templates/common.wgsl.tera", Some("common")),
             ("./src/wgpu/templates/elemwise.wgsl.tera", Some(ELEMWISE)),
+            (
+                "./src/wgpu/templates/elemwise_vec4.wgsl.tera",
+                Some(ELEMWISE_VEC4),
+            ),
             ("./src/wgpu/templates/reduce.wgsl.tera", Some(REDUCE)),
+            ("./src/wgpu/templates/transpose.wgsl.tera", Some(TRANSPOSE)),
+            ("./src/wgpu/templates/assert.wgsl.tera", Some(ASSERT)),
+            ("./src/wgpu/templates/checksum.wgsl.tera", Some(CHECKSUM)),
+            ("./src/wgpu/templates/convert.wgsl.tera", Some(CONVERT)),
+            ("./src/wgpu/templates/sanitize.wgsl.tera", Some(SANITIZE)),
+            ("./src/wgpu/templates/matmul.wgsl.tera", Some(MATMUL)),
+            ("./src/wgpu/templates/attention.wgsl.tera", Some(ATTENTION)),
+            ("./src/wgpu/templates/wide.wgsl.tera", Some("wide")),
+            ("./src/wgpu/templates/philox.wgsl.tera", Some("philox")),
+            ("./src/wgpu/templates/random.wgsl.tera", Some(RANDOM)),
+            ("./src/wgpu/templates/dropout.wgsl.tera", Some(DROPOUT)),
+            ("./src/wgpu/templates/repeat.wgsl.tera", Some(REPEAT)),
+            ("./src/wgpu/templates/diagonal.wgsl.tera", Some(DIAGONAL)),
+            ("./src/wgpu/templates/flip.wgsl.tera", Some(FLIP)),
+            (
+                "./src/wgpu/templates/scan_slice.wgsl.tera",
+                Some(SCAN_SLICE),
+            ),
+            (
+                "./src/wgpu/templates/scan_stack.wgsl.tera",
+                Some(SCAN_STACK),
+            ),
+            ("./src/wgpu/templates/compact.wgsl.tera", Some(COMPACT)),
+            (
+                "./src/wgpu/templates/compact_scan.wgsl.tera",
+                Some(COMPACT_SCAN),
+            ),
+            (
+                "./src/wgpu/templates/compact_pad.wgsl.tera",
+                Some(COMPACT_PAD),
+            ),
+            ("./src/wgpu/templates/spmm.wgsl.tera", Some(SPMM)),
+            ("./src/wgpu/templates/complex.wgsl.tera", Some(COMPLEX)),
+            ("./src/wgpu/templates/fft.wgsl.tera", Some(FFT)),
         ])
         .expect("could not create templates");
 
@@ -38,61 +121,897 @@ impl LayoutInfo {
     fn new(layout: &Layout) -> Self {
         Self {
             elements: layout.elements(),
-            strides: layout.strides().to_vec(),
+            strides: layout.forward_strides(),
             dims: layout.dims().to_vec(),
         }
     }
+
+    fn broadcast(layout: &Layout) -> Self {
+        Self {
+            strides: layout
+                .dims()
+                .iter()
+                .zip(layout.forward_strides())
+                .map(|(&dim, stride)| if dim == 1 { 0 } else { stride })
+                .collect(),
+            ..Self::new(layout)
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Packing {
+    pub(crate) offset: usize,
+    pub(crate) strides: Vec<usize>,
 }
 
+/// The values of the parameters buffer read by a dynamic elementwise kernel: the output's element
+/// count and strides, the broadcast strides of each input, then the packing offset and strides.
+pub(crate) fn elemwise_parameters(
+    output_layout: &Layout,
+    inputs: &[(ExprId, &Layout)],
+    packing: Option<&Packing>,
+) -> Vec<u32> {
+    let output = LayoutInfo::new(output_layout);
+
+    iter::once(output.elements)
+        .chain(output.strides)
+        .chain(
+            inputs
+                .iter()
+                .flat_map(|(_, layout)| LayoutInfo::broadcast(layout).strides),
+        )
+        .chain(
+            packing
+                .into_iter()
+                .flat_map(|packing| iter::once(packing.offset).chain(packing.strides.clone())),
+        )
+        .map(|value| value.try_into().expect("parameter does not fit in 32 bits"))
+        .collect()
+}
+
+// Replaces the element counts, strides and offsets of a dynamic kernel with their positions in
+// the parameters buffer, in the order `elemwise_parameters` writes them.
+fn parameter_positions(
+    layouts: &mut HashMap<String, LayoutInfo>,
+    inputs: &[(ExprId, &Layout)],
+    packing: Option<&Packing>,
+) -> Option<Packing> {
+    let mut next = 0..;
+    let output = layouts.get_mut("output").unwrap();
+
+    output.elements = next.next().unwrap();
+    output
+        .strides
+        .iter_mut()
+        .for_each(|stride| *stride = next.next().unwrap());
+
+    for (id, _) in inputs {
+        let input = layouts.get_mut(&format!("input_{}", id.0)).unwrap();
+
+        input
+            .strides
+            .iter_mut()
+            .for_each(|stride| *stride = next.next().unwrap());
+    }
+
+    packing.map(|packing| Packing {
+        offset: next.next().unwrap(),
+        strides: packing
+            .strides
+            .iter()
+            .map(|_| next.next().unwrap())
+            .collect(),
+    })
+}
+
+/// Which of an elementwise kernel's buffers hold pairs of f16 packed into words, rather than f32.
+#[derive(Default, Debug)]
+pub(crate) struct HalfStorage {
+    pub(crate) output: bool,
+    pub(crate) inputs: Vec<ExprId>,
+}
+
+/// Renders an elementwise kernel. When `dynamic`, the shapes are read from a trailing parameters
+/// buffer instead of being baked in, so the source only depends on the rank of the layouts.
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn elemwise(
-    workgroup_size_x: u32,
+    workgroup_size: [u32; 3],
     output_layout: &Layout,
-    layouts: HashMap<ExprId, &Layout>,
+    inputs: &[(ExprId, &Layout)],
     expr: WgpuExpr,
+    packing: Option<&Packing>,
+    in_place: Option<ExprId>,
+    dynamic: bool,
+    half: &HalfStorage,
 ) -> String {
     let mut context = Context::new();
+    let mut layouts = inputs
+        .iter()
+        .map(|(id, layout)| (format!("input_{}", id.0), LayoutInfo::broadcast(layout)))
+        .chain(iter::once((
+            String::from("output"),
+            LayoutInfo::new(output_layout),
+        )))
+        .collect::<HashMap<_, _>>();
+    let packing = if dynamic {
+        parameter_positions(&mut layouts, inputs, packing)
+    } else {
+        packing.cloned()
+    };
 
-    context.insert("workgroup_size_x", &workgroup_size_x);
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("layouts", &layouts);
+    context.insert("dynamic", &dynamic);
     context.insert(
-        "layouts",
-        &layouts
+        "inputs",
+        &inputs
             .iter()
-            .map(|(id, layout)| (format!("input_{}", id.0), *layout))
-            .chain(iter::once((String::from("output"), output_layout)))
-            .map(|(id, layout)| (id, LayoutInfo::new(layout)))
-            .collect::<HashMap<_, _>>(),
+            .map(|(id, _)| format!("input_{}", id.0))
+            .collect::<Vec<_>>(),
     );
     context.insert(
-        "inputs",
-        &layouts
-            .keys()
-            .map(|id| format!("input_{}", id.0))
+        "bindings",
+        &inputs
+            .iter()
+            .filter(|(id, _)| Some(*id) != in_place)
+            .map(|(id, _)| format!("input_{}", id.0))
             .collect::<Vec<_>>(),
     );
+    context.insert("in_place", &in_place.map(|id| format!("input_{}", id.0)));
     context.insert("expr", &expr.to_string());
+    context.insert("packing", &packing);
+    context.insert("half_output", &half.output);
+    context.insert(
+        "half_inputs",
+        &half
+            .inputs
+            .iter()
+            .map(|id| format!("input_{}", id.0))
+            .collect::<Vec<_>>(),
+    );
 
     tera()
         .render(ELEMWISE, &context)
         .expect("template execution failed")
 }
 
-// pub(crate) fn reduce(
-//     workgroup_size_x: u32,
-//     op: ReduceOp,
-//     input: &Layout,
-//     output: &Layout,
-//     dims: &[DimId],
-// ) -> String {
-//     let mut context = Context::new();
-//
-//     context.insert("workgroup_size_x", &workgroup_size_x);
-//     context.insert("strides", &input.strides());
-//     context.insert("new_strides", &output.strides());
-//     context.insert("reduce_dims", dims);
-//     context.insert("input_dims", input.dims());
-//     context.insert("op", &op.to_string());
-//
-//     tera()
-//         .render(REDUCE, &context)
-//         .expect("template execution failed")
-// }
+/// Renders an elementwise kernel over contiguous layouts whose element count is a multiple of
+/// four, with each invocation loading and storing a `vec4`. Inputs with a single element are
+/// splatted instead.
+pub(crate) fn elemwise_vec4(
+    workgroup_size: [u32; 3],
+    elements: usize,
+    inputs: &[(ExprId, &Layout)],
+    expr: WgpuExpr,
+    in_place: Option<ExprId>,
+) -> String {
+    assert!(
+        elements.is_multiple_of(4),
+        "vectorized kernels need a multiple of four elements"
+    );
+
+    let mut context = Context::new();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("quads", &(elements / 4));
+    context.insert(
+        "inputs",
+        &inputs
+            .iter()
+            .map(|(id, _)| format!("input_{}", id.0))
+            .collect::<Vec<_>>(),
+    );
+    context.insert(
+        "scalars",
+        &inputs
+            .iter()
+            .filter(|(_, layout)| layout.elements() == 1)
+            .map(|(id, _)| format!("input_{}", id.0))
+            .collect::<Vec<_>>(),
+    );
+    context.insert(
+        "bindings",
+        &inputs
+            .iter()
+            .filter(|(id, _)| Some(*id) != in_place)
+            .map(|(id, _)| format!("input_{}", id.0))
+            .collect::<Vec<_>>(),
+    );
+    context.insert("in_place", &in_place.map(|id| format!("input_{}", id.0)));
+    context.insert("expr", &expr.to_string());
+
+    tera()
+        .render(ELEMWISE_VEC4, &context)
+        .expect("template execution failed")
+}
+
+pub(crate) fn transpose(workgroup_size: [u32; 3], rows: usize, cols: usize) -> String {
+    assert!(
+        workgroup_size[0].is_multiple_of(workgroup_size[1]) && workgroup_size[2] == 1,
+        "transpose workgroups must be a single tile whose width is a multiple of its rows"
+    );
+
+    let mut context = Context::new();
+
+    context.insert("tile_dim", &workgroup_size[0]);
+    context.insert("block_rows", &workgroup_size[1]);
+    context.insert("rows", &rows);
+    context.insert("cols", &cols);
+
+    tera()
+        .render(TRANSPOSE, &context)
+        .expect("template execution failed")
+}
+
+// The most elements one thread of a reduction folds. Larger reductions split across threads
+// whose partial results are reduced again in another pass.
+pub(crate) const REDUCE_CHUNK: usize = 1024;
+
+// The most threads reducing one output. Reductions past it, which only those with wide positions
+// reach, lengthen their chunks instead.
+pub(crate) const MAX_REDUCE_CHUNKS: usize = 1 << 22;
+
+// Positions can't be combined from partial results without carrying the values along, so they
+// are found in a single pass.
+pub(crate) fn reduce_chunk_size(op: ReduceOp, reduced_elements: usize) -> usize {
+    match op {
+        ReduceOp::ArgMax | ReduceOp::ArgMin => reduced_elements.max(1),
+        _ => REDUCE_CHUNK.max(reduced_elements.div_ceil(MAX_REDUCE_CHUNKS)),
+    }
+}
+
+// The high and low words of `value`, as wide kernel constants spell it.
+fn hi_lo(value: usize) -> [u32; 2] {
+    let value = value as u64;
+
+    [(value >> 32) as u32, value as u32]
+}
+
+// Reduces `input` over `dims`, or produces the partial results of a pass over `REDUCE_CHUNK`
+// chunks of them when there are more. Means divide by `count`, the elements the original
+// reduction covered. Reductions over more elements than a u32 counts, as of broadcasts, address
+// them with emulated 64-bit positions.
+pub(crate) fn reduce(
+    workgroup_size: [u32; 3],
+    op: ReduceOp,
+    input: &Layout,
+    output: &Layout,
+    dims: &[DimId],
+    two_float: bool,
+    count: usize,
+) -> String {
+    let reduced_dims = dims
+        .iter()
+        .map(|&dim| input.dims()[dim])
+        .collect::<Vec<_>>();
+    let reduced_elements = reduced_dims.iter().product::<usize>();
+    let reduced_strides = Layout::from(reduced_dims.clone()).strides().to_vec();
+    let wide = u32::try_from(reduced_elements).is_err();
+
+    let mut context = Context::new();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("op", &op.to_string());
+    context.insert("output_elements", &output.elements());
+    context.insert("output_strides", output.strides());
+    context.insert("input_strides", &input.forward_strides());
+    context.insert("reduce_dims", dims);
+    context.insert("wide", &wide);
+
+    if wide {
+        context.insert("reduced_elements", &hi_lo(reduced_elements));
+        context.insert(
+            "reduced_strides",
+            &reduced_strides
+                .iter()
+                .map(|&stride| hi_lo(stride as usize))
+                .collect::<Vec<_>>(),
+        );
+        context.insert(
+            "reduced_dims",
+            &reduced_dims
+                .iter()
+                .enumerate()
+                .map(|(position, &dim)| {
+                    let [hi, lo] = hi_lo(dim);
+
+                    (position, hi, lo)
+                })
+                .collect::<Vec<_>>(),
+        );
+    } else {
+        context.insert("reduced_elements", &reduced_elements);
+        context.insert("reduced_strides", &reduced_strides);
+    }
+
+    let chunk_size = reduce_chunk_size(op, reduced_elements);
+
+    context.insert("chunks", &reduced_elements.div_ceil(chunk_size).max(1));
+    context.insert("chunk_size", &chunk_size);
+    context.insert("two_float", &two_float);
+    context.insert("count", &count);
+
+    tera()
+        .render(REDUCE, &context)
+        .expect("template execution failed")
+}
+
+pub(crate) fn assert(workgroup_size: [u32; 3], input: &Layout, predicate: Predicate) -> String {
+    let mut context = Context::new();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("elements", &input.elements());
+    context.insert("output_strides", input.contiguous().strides());
+    context.insert("input_strides", &input.forward_strides());
+    context.insert(
+        "condition",
+        &match predicate {
+            Predicate::Finite => String::from("value == value && abs(value) <= 3.40282347e+38f"),
+            Predicate::InRange { min, max } => {
+                format!("value >= {} && value <= {}", literal(min), literal(max))
+            }
+        },
+    );
+
+    tera()
+        .render(ASSERT, &context)
+        .expect("template execution failed")
+}
+
+// Spells a float the way kernel expressions do, which also covers infinities and NaNs.
+fn literal(value: f32) -> String {
+    WgpuExpr::new_literal(value).to_string()
+}
+
+fn insert_philox(context: &mut Context, seed: u64) {
+    context.insert("key", &[seed as u32, (seed >> 32) as u32]);
+    context.insert("multipliers", &PHILOX_MULTIPLIERS);
+    context.insert("weyl", &PHILOX_WEYL);
+}
+
+pub(crate) fn random(
+    workgroup_size: [u32; 3],
+    elements: usize,
+    distribution: Distribution,
+    seed: u64,
+) -> String {
+    let mut context = Context::new();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("elements", &elements);
+    insert_philox(&mut context, seed);
+    context.insert(
+        "sample",
+        &match distribution {
+            Distribution::Uniform { low, high } => {
+                let (low, high) = (literal(low), literal(high));
+
+                format!("{low} + ({high} - {low}) * first")
+            }
+            Distribution::Normal { mean, std } => format!(
+                "{} + {} * sqrt(-2.0 * log(1.0 - first)) * cos(6.28318530717958647692 * second)",
+                literal(mean),
+                literal(std)
+            ),
+        },
+    );
+
+    tera()
+        .render(RANDOM, &context)
+        .expect("template execution failed")
+}
+
+pub(crate) fn dropout(
+    workgroup_size: [u32; 3],
+    input: &Layout,
+    probability: f32,
+    seed: u64,
+) -> String {
+    let mut context = Context::new();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("elements", &input.elements());
+    context.insert("output_strides", input.contiguous().strides());
+    context.insert("input_strides", &input.forward_strides());
+    context.insert("probability", &format!("{probability:?}"));
+    context.insert("scale", &format!("{:?}", 1.0 / (1.0 - probability)));
+    insert_philox(&mut context, seed);
+
+    tera()
+        .render(DROPOUT, &context)
+        .expect("template execution failed")
+}
+
+pub(crate) fn repeat(workgroup_size: [u32; 3], input: &Layout, output: &Layout) -> String {
+    let mut context = Context::new();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("elements", &output.elements());
+    context.insert(
+        "dims",
+        &output
+            .strides()
+            .iter()
+            .zip(input.dims())
+            .zip(input.forward_strides())
+            .map(|((&output_stride, &dim), stride)| [output_stride as usize, dim, stride])
+            .collect::<Vec<_>>(),
+    );
+
+    tera()
+        .render(REPEAT, &context)
+        .expect("template execution failed")
+}
+
+pub(crate) fn diagonal(workgroup_size: [u32; 3], input: &Layout, offset: isize) -> String {
+    let diagonal = input.diagonal_view(offset);
+    let mut context = Context::new();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("elements", &diagonal.elements());
+    context.insert("output_strides", diagonal.contiguous().strides());
+    context.insert("input_strides", &diagonal.forward_strides());
+    context.insert("start", &diagonal.offset());
+
+    tera()
+        .render(DIAGONAL, &context)
+        .expect("template execution failed")
+}
+
+pub(crate) fn flip(workgroup_size: [u32; 3], input: &Layout, dims: &[DimId]) -> String {
+    let mut context = Context::new();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("elements", &input.elements());
+    context.insert("input_offset", &input.offset());
+    context.insert(
+        "dims",
+        &input
+            .contiguous()
+            .forward_strides()
+            .into_iter()
+            .zip(input.dims())
+            .zip(input.forward_strides())
+            .enumerate()
+            .map(|(dim, ((output_stride, &size), stride))| {
+                [output_stride, size, stride, dims.contains(&dim) as usize]
+            })
+            .collect::<Vec<_>>(),
+    );
+
+    tera()
+        .render(FLIP, &context)
+        .expect("template execution failed")
+}
+
+pub(crate) fn scan_slice(workgroup_size: [u32; 3], sequence: &Layout, dim: DimId) -> String {
+    let mut slice = sequence.dims().to_vec();
+
+    slice[dim] = 1;
+
+    let slice = Layout::from(slice);
+    let mut context = Context::new();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("elements", &slice.elements());
+    context.insert("output_strides", slice.strides());
+    context.insert("input_strides", &sequence.forward_strides());
+    context.insert("position_stride", &sequence.forward_strides()[dim]);
+
+    tera()
+        .render(SCAN_SLICE, &context)
+        .expect("template execution failed")
+}
+
+pub(crate) fn scan_stack(workgroup_size: [u32; 3], elements: usize) -> String {
+    let mut context = Context::new();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("elements", &elements);
+
+    tera()
+        .render(SCAN_STACK, &context)
+        .expect("template execution failed")
+}
+
+/// Renders a pass over the mask in chunks of `COMPACT_CHUNK_SIZE` elements, which either counts
+/// each chunk's nonzero elements, or writes the values they select from the positions the
+/// counts were scanned into. Without values, the selected elements' flat positions are written.
+pub(crate) fn compact(
+    workgroup_size: [u32; 3],
+    mask: &Layout,
+    values: Option<&Layout>,
+    counting: bool,
+) -> String {
+    let mut context = Context::new();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("elements", &mask.elements());
+    context.insert("chunks", &mask.elements().div_ceil(COMPACT_CHUNK_SIZE));
+    context.insert("chunk_size", &COMPACT_CHUNK_SIZE);
+    context.insert("counting", &counting);
+    context.insert("selecting", &values.is_some());
+    context.insert("output_strides", mask.contiguous().strides());
+    context.insert("mask_strides", &mask.forward_strides());
+    context.insert("mask_offset", &mask.offset());
+
+    if let Some(values) = values {
+        context.insert("values_strides", &values.forward_strides());
+        context.insert("values_offset", &values.offset());
+    }
+
+    tera()
+        .render(COMPACT, &context)
+        .expect("template execution failed")
+}
+
+pub(crate) fn compact_scan(chunks: usize) -> String {
+    let mut context = Context::new();
+
+    context.insert("chunks", &chunks);
+
+    tera()
+        .render(COMPACT_SCAN, &context)
+        .expect("template execution failed")
+}
+
+pub(crate) fn compact_pad(
+    workgroup_size: [u32; 3],
+    elements: usize,
+    chunks: usize,
+    pad: f32,
+) -> String {
+    let mut context = Context::new();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("elements", &elements);
+    context.insert("chunks", &chunks);
+    context.insert("pad", &format!("{pad:?}"));
+
+    tera()
+        .render(COMPACT_PAD, &context)
+        .expect("template execution failed")
+}
+
+#[derive(Serialize)]
+struct SparsePart {
+    offset: usize,
+    stride: usize,
+}
+
+impl SparsePart {
+    fn new(layout: &Layout) -> Self {
+        Self {
+            offset: layout.offset(),
+            stride: layout.forward_strides()[0],
+        }
+    }
+}
+
+/// Renders a product of the sparse matrix in `values`, `columns` and `offsets` with a dense matrix,
+/// or a vector when `dense` is one dimensional. Each invocation computes one output element.
+pub(crate) fn sparse_matmul(
+    workgroup_size: [u32; 3],
+    values: &Layout,
+    columns: &Layout,
+    offsets: &Layout,
+    dense: &Layout,
+) -> String {
+    let mut context = Context::new();
+    let dense_strides = dense.forward_strides();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("rows", &(offsets.elements() - 1));
+    context.insert("n", &dense.dims().get(1).copied().unwrap_or(1));
+    context.insert("values", &SparsePart::new(values));
+    context.insert("columns", &SparsePart::new(columns));
+    context.insert("offsets", &SparsePart::new(offsets));
+    context.insert("dense_offset", &dense.offset());
+    context.insert("dense_row_stride", &dense_strides[0]);
+    context.insert(
+        "dense_col_stride",
+        &dense_strides.get(1).copied().unwrap_or(0),
+    );
+
+    tera()
+        .render(SPMM, &context)
+        .expect("template execution failed")
+}
+
+#[derive(Serialize)]
+struct ComplexInput {
+    offset: usize,
+    strides: Vec<usize>,
+    part_stride: usize,
+}
+
+/// Renders a complex op with one invocation per complex element of the contiguous `output`. The
+/// last dimension of each layout holds the parts of its elements.
+pub(crate) fn complex(
+    workgroup_size: [u32; 3],
+    op: ComplexOp,
+    output: &Layout,
+    inputs: &[&Layout],
+) -> String {
+    let rank = output.rank() - 1;
+    let elements = output.elements() / 2;
+    let mut context = Context::new();
+
+    // A leading dimension covering every element keeps the strides nonempty for scalars.
+    let output_strides = iter::once(elements.max(1))
+        .chain(Layout::from(&output.dims()[..rank]).forward_strides())
+        .collect::<Vec<_>>();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("op", &op.to_string());
+    context.insert("elements", &elements);
+    context.insert("output_strides", &output_strides);
+    context.insert(
+        "inputs",
+        &inputs
+            .iter()
+            .map(|layout| {
+                let strides = LayoutInfo::broadcast(layout).strides;
+
+                ComplexInput {
+                    offset: layout.offset(),
+                    strides: iter::once(0)
+                        .chain(strides[..rank].iter().copied())
+                        .collect(),
+                    part_stride: strides[rank],
+                }
+            })
+            .collect::<Vec<_>>(),
+    );
+
+    tera()
+        .render(COMPLEX, &context)
+        .expect("template execution failed")
+}
+
+#[derive(Serialize)]
+struct FftBuffer {
+    offset: usize,
+    line_strides: Vec<usize>,
+    position_stride: usize,
+    part_stride: usize,
+}
+
+impl FftBuffer {
+    // A leading stride of zero matches the leading line stride `fft` adds.
+    fn new(layout: &Layout, dim: DimId) -> Self {
+        let strides = layout.forward_strides();
+        let rank = layout.rank() - 1;
+
+        Self {
+            offset: layout.offset(),
+            line_strides: iter::once(0)
+                .chain((0..rank).filter(|&d| d != dim).map(|d| strides[d]))
+                .collect(),
+            position_stride: strides[dim],
+            part_stride: strides[rank],
+        }
+    }
+}
+
+/// Renders one pass of a radix-2 or radix-4 transform along `dim` of a complex tensor, combining
+/// transforms of size `span`. Each invocation computes one butterfly of one line, and the results
+/// are multiplied by `scale`. A radix of one copies the input.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn fft(
+    workgroup_size: [u32; 3],
+    input: &Layout,
+    output: &Layout,
+    dim: DimId,
+    radix: usize,
+    span: usize,
+    inverse: bool,
+    scale: f32,
+) -> String {
+    let size = input.dims()[dim];
+    let lines = input.elements() / 2 / size;
+    let line_dims = (0..input.rank() - 1)
+        .filter(|&d| d != dim)
+        .map(|d| input.dims()[d])
+        .collect::<Vec<_>>();
+    let mut context = Context::new();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("size", &size);
+    context.insert("lines", &lines);
+    context.insert("radix", &radix);
+    context.insert("span", &span);
+    context.insert("sign", if inverse { "1.0" } else { "-1.0" });
+    context.insert("scale", &format!("{scale:?}"));
+    // A leading dimension covering every line keeps the strides nonempty for single lines.
+    context.insert(
+        "line_strides",
+        &iter::once(lines.max(1))
+            .chain(Layout::from(line_dims).forward_strides())
+            .collect::<Vec<_>>(),
+    );
+    context.insert("input", &FftBuffer::new(input, dim));
+    context.insert("output", &FftBuffer::new(output, dim));
+
+    tera()
+        .render(FFT, &context)
+        .expect("template execution failed")
+}
+
+pub(crate) fn checksum(workgroup_size: [u32; 3], elements: usize) -> String {
+    let mut context = Context::new();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("elements", &elements);
+
+    tera()
+        .render(CHECKSUM, &context)
+        .expect("template execution failed")
+}
+
+pub(crate) fn convert(workgroup_size: [u32; 3], input: &Layout, readback: Readback) -> String {
+    let mut context = Context::new();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("elements", &input.elements());
+    context.insert("words", &input.elements().div_ceil(2));
+    context.insert("output_strides", input.contiguous().strides());
+    context.insert("input_strides", &input.forward_strides());
+    context.insert(
+        "format",
+        match readback {
+            Readback::F32 => unreachable!("f32 outputs are read back directly"),
+            Readback::F16 => "f16",
+            Readback::Bf16 => "bf16",
+        },
+    );
+
+    tera()
+        .render(CONVERT, &context)
+        .expect("template execution failed")
+}
+
+pub(crate) fn sanitize(workgroup_size: [u32; 3], input: &Layout, policy: FloatPolicy) -> String {
+    let mut context = Context::new();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("elements", &input.elements());
+    context.insert("output_strides", input.contiguous().strides());
+    context.insert("input_strides", &input.forward_strides());
+    context.insert(
+        "replacement",
+        &match policy {
+            FloatPolicy::Unchecked => unreachable!("unchecked outputs are read back directly"),
+            FloatPolicy::FlushToZero => None,
+            FloatPolicy::ReplaceNan(value) => Some(literal(value)),
+        },
+    );
+
+    tera()
+        .render(SANITIZE, &context)
+        .expect("template execution failed")
+}
+
+#[derive(Serialize)]
+struct MatMulBatchDim {
+    size: usize,
+    lhs_stride: usize,
+    rhs_stride: usize,
+}
+
+pub(crate) fn matmul(
+    workgroup_size: [u32; 3],
+    geometry: &MatMulGeometry,
+    mask: Option<&MatMulMask>,
+) -> String {
+    let mut context = Context::new();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert(
+        "elements",
+        &(geometry.batch.iter().product::<usize>() * geometry.m * geometry.n),
+    );
+    let rank = geometry.batch.len();
+
+    context.insert(
+        "batch",
+        &(0..rank)
+            .rev()
+            .map(|dim| MatMulBatchDim {
+                size: geometry.batch[dim],
+                lhs_stride: geometry.lhs_strides[dim],
+                rhs_stride: geometry.rhs_strides[dim],
+            })
+            .collect::<Vec<_>>(),
+    );
+    context.insert("m", &geometry.m);
+    context.insert("k", &geometry.k);
+    context.insert("n", &geometry.n);
+    context.insert("lhs_m_stride", &geometry.lhs_strides[rank]);
+    context.insert("lhs_k_stride", &geometry.lhs_strides[rank + 1]);
+    context.insert("rhs_k_stride", &geometry.rhs_strides[rank]);
+    context.insert("rhs_n_stride", &geometry.rhs_strides[rank + 1]);
+
+    match mask {
+        Some(MatMulMask::Lower) => context.insert("mask", "lower"),
+        Some(MatMulMask::Upper) => context.insert("mask", "upper"),
+        Some(MatMulMask::Blocks { size, tiles }) => {
+            context.insert("mask", "blocks");
+            context.insert("tile_size", size);
+            context.insert("tile_columns", &geometry.n.div_ceil(*size));
+            context.insert("tiles", tiles);
+        }
+        None => context.insert("mask", &false),
+    }
+
+    tera()
+        .render(MATMUL, &context)
+        .expect("template execution failed")
+}
+
+#[derive(Serialize)]
+struct AttentionBatchDim {
+    size: usize,
+    query_stride: usize,
+    key_stride: usize,
+    value_stride: usize,
+}
+
+pub(crate) fn attention_workgroups(
+    workgroup_size: [u32; 3],
+    geometry: &AttentionGeometry,
+) -> [u32; 3] {
+    let rows = workgroup_size.iter().product::<u32>();
+
+    [
+        geometry.batch.iter().product::<usize>() as u32 * (geometry.queries as u32).div_ceil(rows),
+        1,
+        1,
+    ]
+}
+
+pub(crate) fn attention(
+    workgroup_size: [u32; 3],
+    geometry: &AttentionGeometry,
+    scale: f32,
+) -> String {
+    let mut context = Context::new();
+    let rows = workgroup_size.iter().product::<u32>() as usize;
+    let rank = geometry.batch.len();
+
+    context.insert("workgroup_size", &workgroup_size);
+    context.insert("rows", &rows);
+    context.insert("row_groups", &geometry.queries.div_ceil(rows));
+    context.insert(
+        "tile",
+        &(ATTENTION_TILE_ELEMENTS / (geometry.head_dim + geometry.value_dim)).clamp(1, rows),
+    );
+    context.insert(
+        "batch",
+        &(0..rank)
+            .rev()
+            .map(|dim| AttentionBatchDim {
+                size: geometry.batch[dim],
+                query_stride: geometry.query_strides[dim],
+                key_stride: geometry.key_strides[dim],
+                value_stride: geometry.value_strides[dim],
+            })
+            .collect::<Vec<_>>(),
+    );
+    context.insert("queries", &geometry.queries);
+    context.insert("keys", &geometry.keys);
+    context.insert("head_dim", &geometry.head_dim);
+    context.insert("value_dim", &geometry.value_dim);
+    context.insert("query_row_stride", &geometry.query_strides[rank]);
+    context.insert("query_dim_stride", &geometry.query_strides[rank + 1]);
+    context.insert("key_row_stride", &geometry.key_strides[rank]);
+    context.insert("key_dim_stride", &geometry.key_strides[rank + 1]);
+    context.insert("value_row_stride", &geometry.value_strides[rank]);
+    context.insert("value_dim_stride", &geometry.value_strides[rank + 1]);
+    context.insert("scale", &format!("{scale:?}"));
+
+    tera()
+        .render(ATTENTION, &context)
+        .expect("template execution failed")
+}
@@ -0,0 +1,279 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
+
+use crate::{graph::ExprId, tensor::Layout};
+
+use super::{
+    compiler::{LimitError, WgpuPlan, WgpuStep},
+    repeat,
+    runner::InputError,
+};
+
+#[derive(Clone, Debug)]
+pub struct BufferLifetime {
+    pub id: ExprId,
+    pub size: usize,
+    pub allocated_at: usize,
+    pub freed_at: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MemoryReport {
+    pub buffers: Vec<BufferLifetime>,
+    pub live_bytes: Vec<usize>,
+    pub peak_bytes: usize,
+    pub peak_step: usize,
+    pub arena_bytes: Vec<usize>,
+}
+
+// Branches free everything they allocate, so only the peak of their own buffers is reported, at
+// the step of the branch itself.
+fn branch_peak(steps: &[WgpuStep]) -> usize {
+    let mut sizes = HashMap::new();
+    let (mut current, mut peak) = (0, 0);
+
+    for step in steps {
+        match step {
+            WgpuStep::Allocate { id, tensor } => {
+                sizes.insert(*id, tensor.layout.size());
+                current += tensor.layout.size();
+            }
+            WgpuStep::Reserve { id, size } | WgpuStep::Place { id, size, .. } => {
+                sizes.insert(*id, *size);
+                current += size;
+            }
+            WgpuStep::Deallocate(id) => current -= sizes.remove(id).unwrap_or(0),
+            WgpuStep::Branch {
+                then_steps,
+                else_steps,
+                ..
+            } => peak = peak.max(current + branch_peak(then_steps).max(branch_peak(else_steps))),
+            WgpuStep::Slice { .. } | WgpuStep::Execute { .. } | WgpuStep::Repeat { .. } => {}
+        }
+
+        peak = peak.max(current);
+    }
+
+    peak
+}
+
+impl WgpuPlan {
+    /// Replays the plan's allocations without a device.
+    pub fn memory_report(&self) -> MemoryReport {
+        let mut buffers = self
+            .inputs
+            .iter()
+            .zip(self.input_layouts.iter())
+            .chain(
+                self.persistent_inputs
+                    .iter()
+                    .map(|(id, _, layout)| (id, layout)),
+            )
+            .map(|(&id, layout)| BufferLifetime {
+                id,
+                size: layout.size(),
+                allocated_at: 0,
+                freed_at: None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut live = buffers
+            .iter()
+            .enumerate()
+            .map(|(index, buffer)| (buffer.id, index))
+            .collect::<HashMap<_, _>>();
+
+        let steps = repeat::unroll(&self.steps);
+
+        let mut live_bytes = Vec::with_capacity(steps.len());
+        let mut current = buffers.iter().map(|buffer| buffer.size).sum::<usize>();
+
+        for (index, step) in steps.iter().enumerate() {
+            match step {
+                WgpuStep::Allocate { id, tensor } => {
+                    live.insert(*id, buffers.len());
+                    current += tensor.layout.size();
+
+                    buffers.push(BufferLifetime {
+                        id: *id,
+                        size: tensor.layout.size(),
+                        allocated_at: index,
+                        freed_at: None,
+                    });
+                }
+                WgpuStep::Reserve { id, size } | WgpuStep::Place { id, size, .. } => {
+                    live.insert(*id, buffers.len());
+                    current += size;
+
+                    buffers.push(BufferLifetime {
+                        id: *id,
+                        size: *size,
+                        allocated_at: index,
+                        freed_at: None,
+                    });
+                }
+                WgpuStep::Deallocate(id) => {
+                    let buffer = &mut buffers[live.remove(id).expect("buffer was not allocated")];
+
+                    buffer.freed_at = Some(index);
+                    current -= buffer.size;
+                }
+                WgpuStep::Slice { .. } | WgpuStep::Execute { .. } => {}
+                WgpuStep::Repeat { .. } => unreachable!("repeated steps were unrolled"),
+                WgpuStep::Branch {
+                    then_steps,
+                    else_steps,
+                    ..
+                } => {
+                    live_bytes.push(current + branch_peak(then_steps).max(branch_peak(else_steps)));
+
+                    continue;
+                }
+            }
+
+            live_bytes.push(current);
+        }
+
+        let (peak_step, peak_bytes) = live_bytes
+            .iter()
+            .copied()
+            .enumerate()
+            .max_by_key(|&(step, bytes)| (bytes, usize::MAX - step))
+            .unwrap_or((0, current));
+
+        MemoryReport {
+            buffers,
+            live_bytes,
+            peak_bytes,
+            peak_step,
+            arena_bytes: self.arenas.clone(),
+        }
+    }
+
+    /// Replays the allocations of the plan compiled for inputs of other layouts, such as with
+    /// other sizes along dynamic dimensions, without compiling it for a device. Inputs keep their
+    /// rank. Plans read from bytes no longer have the graph they were compiled from, so they only
+    /// simulate the layouts they were compiled for.
+    ///
+    /// Panics for layouts the graph's ops reject, as building the graph with them would.
+    pub fn simulate_memory(
+        &self,
+        input_layouts: &[Layout],
+    ) -> Result<MemoryReport, SimulationError> {
+        if input_layouts.len() != self.input_layouts.len() {
+            return Err(InputError::Count {
+                expected: self.input_layouts.len(),
+                actual: input_layouts.len(),
+            }
+            .into());
+        }
+
+        let mismatch = |rejected: fn(&Layout, &Layout) -> bool| {
+            self.input_layouts
+                .iter()
+                .zip(input_layouts)
+                .enumerate()
+                .find(|(_, (expected, actual))| rejected(expected, actual))
+                .map(|(index, (expected, actual))| InputError::Layout {
+                    index,
+                    expected: expected.clone(),
+                    actual: actual.clone(),
+                })
+        };
+
+        if let Some(error) =
+            mismatch(|expected, actual| expected.rank() != actual.rank() || !actual.is_forward())
+        {
+            return Err(error.into());
+        }
+
+        let Some(source) = &self.source else {
+            return match mismatch(|expected, actual| expected != actual) {
+                Some(error) => Err(error.into()),
+                None => Ok(self.memory_report()),
+            };
+        };
+        let (compiler, graph) = &**source;
+
+        // Persistent inputs keep the layouts of their buffers.
+        let mut input_layouts = input_layouts.iter();
+        let layouts = graph
+            .inputs
+            .iter()
+            .map(|id| {
+                if compiler.persistent.contains_key(id) {
+                    Layout::clone(&graph[*id].layout)
+                } else {
+                    input_layouts.next().unwrap().clone()
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Ok(compiler
+            .plan(graph.with_input_layouts(&layouts))?
+            .memory_report())
+    }
+}
+
+/// Why a plan could not be simulated with inputs of some layouts.
+#[derive(Debug)]
+pub enum SimulationError {
+    Input(InputError),
+    /// The plan the layouts need breaks the limits of the device it was compiled for.
+    Limit(LimitError),
+}
+
+impl Display for SimulationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SimulationError::Input(error) => error.fmt(f),
+            SimulationError::Limit(error) => error.fmt(f),
+        }
+    }
+}
+
+impl Error for SimulationError {}
+
+impl From<InputError> for SimulationError {
+    fn from(error: InputError) -> Self {
+        SimulationError::Input(error)
+    }
+}
+
+impl From<LimitError> for SimulationError {
+    fn from(error: LimitError) -> Self {
+        SimulationError::Limit(error)
+    }
+}
+
+impl Display for MemoryReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "peak: {} bytes at step {}",
+            self.peak_bytes, self.peak_step
+        )?;
+
+        for (index, bytes) in self.arena_bytes.iter().enumerate() {
+            writeln!(f, "arena {index}: {bytes} bytes")?;
+        }
+
+        for buffer in self.buffers.iter() {
+            write!(
+                f,
+                "{:?}: {} bytes, steps {}..",
+                buffer.id, buffer.size, buffer.allocated_at
+            )?;
+
+            match buffer.freed_at {
+                Some(freed_at) => writeln!(f, "{freed_at}")?,
+                None => writeln!(f)?,
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,4 +1,15 @@
+pub mod allocator;
+mod arena;
+pub mod cache;
+pub mod checksum;
 pub mod compiler;
+pub mod cost;
 mod expr;
 mod kernel;
+pub mod memory;
+pub mod registry;
+mod repeat;
+pub mod replay;
+mod report;
 pub mod runner;
+pub mod view;
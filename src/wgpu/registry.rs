@@ -0,0 +1,115 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
+
+use crate::{
+    compiler::{Compiler, Runner},
+    graph::Graph,
+    tensor::{Tensor, TensorView},
+};
+
+use super::{
+    compiler::{WgpuCompiler, WgpuPlan},
+    memory::MemoryReport,
+    runner::{ConcreteWgpuPlan, InputError, WgpuRunner},
+};
+
+struct Model {
+    plan: ConcreteWgpuPlan,
+    memory: MemoryReport,
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    UnknownModel(String),
+    Input(InputError),
+}
+
+impl Display for RegistryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::UnknownModel(name) => write!(f, "no model named {name:?} is loaded"),
+            RegistryError::Input(error) => error.fmt(f),
+        }
+    }
+}
+
+impl Error for RegistryError {}
+
+impl From<InputError> for RegistryError {
+    fn from(error: InputError) -> Self {
+        RegistryError::Input(error)
+    }
+}
+
+/// Named plans sharing one runner, and so one device, its pipelines and its buffers.
+pub struct ModelRegistry {
+    runner: WgpuRunner,
+    compiler: WgpuCompiler,
+    models: HashMap<String, Model>,
+}
+
+impl ModelRegistry {
+    pub fn new(runner: WgpuRunner) -> Self {
+        Self {
+            runner,
+            compiler: WgpuCompiler::default(),
+            models: HashMap::new(),
+        }
+    }
+
+    /// The compiler graphs loaded from now on are compiled with.
+    pub fn compiler(mut self, compiler: WgpuCompiler) -> Self {
+        self.compiler = compiler;
+        self
+    }
+
+    /// Compiles `graph` and loads it under `name`, replacing any model already loaded there.
+    pub fn load(&mut self, name: impl Into<String>, graph: Graph) {
+        let plan = self.compiler.compile(graph);
+
+        self.load_plan(name, plan);
+    }
+
+    pub fn load_plan(&mut self, name: impl Into<String>, plan: WgpuPlan) {
+        let memory = plan.memory_report();
+        let plan = self.runner.preprocess(plan);
+
+        self.models.insert(name.into(), Model { plan, memory });
+    }
+
+    /// Returns whether a model was loaded under `name`.
+    pub fn unload(&mut self, name: &str) -> bool {
+        self.models.remove(name).is_some()
+    }
+
+    pub fn models(&self) -> impl Iterator<Item = &str> {
+        self.models.keys().map(String::as_str)
+    }
+
+    /// The planned memory use of the model loaded under `name`.
+    pub fn memory(&self, name: &str) -> Option<&MemoryReport> {
+        self.models.get(name).map(|model| &model.memory)
+    }
+
+    pub fn run(&self, name: &str, inputs: &[TensorView]) -> Result<Vec<Tensor>, RegistryError> {
+        let plan = self
+            .models
+            .get(name)
+            .ok_or_else(|| RegistryError::UnknownModel(name.to_owned()))?
+            .plan
+            .clone();
+
+        Ok(self.runner.try_run(plan, inputs)?)
+    }
+
+    pub fn runner(&self) -> &WgpuRunner {
+        &self.runner
+    }
+
+    pub fn runner_mut(&mut self) -> &mut WgpuRunner {
+        &mut self.runner
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{graph::ExprId, tensor::Tensor};
+
+use super::compiler::{WgpuStep, Workgroups};
+
+// Ids in a repeated body are slots, which each iteration binds to its own buffers. Consts and
+// placements are listed in the order their steps appear in the body.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Iteration {
+    pub(crate) ids: Vec<ExprId>,
+    pub(crate) consts: Vec<Tensor>,
+    pub(crate) places: Vec<(usize, usize)>,
+}
+
+fn key(step: &WgpuStep) -> String {
+    match step {
+        WgpuStep::Allocate { tensor, .. } => format!("allocate {:?}", tensor.layout),
+        WgpuStep::Deallocate(_) => String::from("deallocate"),
+        WgpuStep::Reserve { size, .. } => format!("reserve {size}"),
+        WgpuStep::Place { size, .. } => format!("place {size}"),
+        WgpuStep::Slice { offset, size, .. } => format!("slice {offset} {size}"),
+        WgpuStep::Execute {
+            source,
+            workgroups,
+            inputs_layout,
+            ..
+        } => format!(
+            "execute {} {inputs_layout:?} {}",
+            match workgroups {
+                Workgroups::Fixed(workgroups) => format!("{workgroups:?}"),
+                Workgroups::Indirect(_) => String::from("indirect"),
+            },
+            source
+                .lines()
+                .filter(|line| !line.starts_with("//"))
+                .collect::<String>()
+        ),
+        WgpuStep::Repeat { .. } => unreachable!("repeated steps cannot be nested"),
+        WgpuStep::Branch { .. } => unreachable!("branches are never folded"),
+    }
+}
+
+fn ids(step: &WgpuStep) -> Vec<ExprId> {
+    match step {
+        WgpuStep::Allocate { id, .. }
+        | WgpuStep::Deallocate(id)
+        | WgpuStep::Reserve { id, .. }
+        | WgpuStep::Place { id, .. } => vec![*id],
+        WgpuStep::Slice { id, source, .. } => vec![*id, *source],
+        WgpuStep::Execute {
+            output,
+            inputs,
+            workgroups,
+            ..
+        } => [&[*output], inputs.as_slice()]
+            .concat()
+            .into_iter()
+            .chain(workgroups.arguments())
+            .collect(),
+        WgpuStep::Repeat { .. } => unreachable!("repeated steps cannot be nested"),
+        WgpuStep::Branch { .. } => unreachable!("branches are never folded"),
+    }
+}
+
+fn slots(steps: &[WgpuStep]) -> (Vec<ExprId>, Vec<usize>) {
+    let mut ids_to_slots = HashMap::new();
+    let mut slot_ids = Vec::new();
+
+    let pattern = steps
+        .iter()
+        .flat_map(ids)
+        .map(|id| {
+            *ids_to_slots.entry(id).or_insert_with(|| {
+                slot_ids.push(id);
+
+                slot_ids.len() - 1
+            })
+        })
+        .collect();
+
+    (slot_ids, pattern)
+}
+
+fn iteration(steps: &[WgpuStep]) -> Iteration {
+    Iteration {
+        ids: slots(steps).0,
+        consts: steps
+            .iter()
+            .filter_map(|step| match step {
+                WgpuStep::Allocate { tensor, .. } => Some(tensor.clone()),
+                _ => None,
+            })
+            .collect(),
+        places: steps
+            .iter()
+            .filter_map(|step| match *step {
+                WgpuStep::Place { arena, offset, .. } => Some((arena, offset)),
+                _ => None,
+            })
+            .collect(),
+    }
+}
+
+pub(crate) fn rename(step: &WgpuStep, id: impl Fn(ExprId) -> ExprId + Copy) -> WgpuStep {
+    match step {
+        WgpuStep::Allocate { id: old, tensor } => WgpuStep::Allocate {
+            id: id(*old),
+            tensor: tensor.clone(),
+        },
+        WgpuStep::Deallocate(old) => WgpuStep::Deallocate(id(*old)),
+        WgpuStep::Reserve { id: old, size } => WgpuStep::Reserve {
+            id: id(*old),
+            size: *size,
+        },
+        WgpuStep::Place {
+            id: old,
+            arena,
+            offset,
+            size,
+        } => WgpuStep::Place {
+            id: id(*old),
+            arena: *arena,
+            offset: *offset,
+            size: *size,
+        },
+        WgpuStep::Slice {
+            id: old,
+            source,
+            offset,
+            size,
+        } => WgpuStep::Slice {
+            id: id(*old),
+            source: id(*source),
+            offset: *offset,
+            size: *size,
+        },
+        WgpuStep::Execute {
+            output,
+            source,
+            workgroups,
+            inputs,
+            inputs_layout,
+        } => WgpuStep::Execute {
+            output: id(*output),
+            source: source.clone(),
+            workgroups: match workgroups {
+                Workgroups::Indirect(arguments) => Workgroups::Indirect(id(*arguments)),
+                workgroups => *workgroups,
+            },
+            inputs: inputs.iter().map(|&input| id(input)).collect(),
+            inputs_layout: inputs_layout.clone(),
+        },
+        WgpuStep::Repeat { .. } => unreachable!("repeated steps cannot be nested"),
+        WgpuStep::Branch {
+            condition,
+            predicate,
+            then_steps,
+            else_steps,
+        } => WgpuStep::Branch {
+            condition: id(*condition),
+            predicate: *predicate,
+            then_steps: then_steps.iter().map(|step| rename(step, id)).collect(),
+            else_steps: else_steps.iter().map(|step| rename(step, id)).collect(),
+        },
+    }
+}
+
+pub(crate) fn substitute(body: &[WgpuStep], iteration: &Iteration) -> Vec<WgpuStep> {
+    let mut consts = iteration.consts.iter();
+    let mut places = iteration.places.iter();
+
+    body.iter()
+        .map(|step| {
+            let mut step = rename(step, |slot| iteration.ids[slot.0]);
+
+            match &mut step {
+                WgpuStep::Allocate { tensor, .. } => {
+                    *tensor = consts.next().expect("iteration is missing a const").clone();
+                }
+                WgpuStep::Place { arena, offset, .. } => {
+                    (*arena, *offset) = *places.next().expect("iteration is missing a placement");
+                }
+                _ => {}
+            }
+
+            step
+        })
+        .collect()
+}
+
+pub(crate) fn unroll(steps: &[WgpuStep]) -> Vec<WgpuStep> {
+    steps
+        .iter()
+        .flat_map(|step| match step {
+            WgpuStep::Repeat { body, iterations } => iterations
+                .iter()
+                .flat_map(|iteration| substitute(body, iteration))
+                .collect(),
+            step => vec![step.clone()],
+        })
+        .collect()
+}
+
+pub(crate) fn fold(steps: Vec<WgpuStep>) -> Vec<WgpuStep> {
+    let mut interned = HashMap::new();
+    let keys = steps
+        .iter()
+        .enumerate()
+        .map(|(index, step)| {
+            let next = interned.len();
+            // Each branch gets a key of its own so that no repeat contains one.
+            let key = match step {
+                WgpuStep::Branch { .. } => format!("branch {index}"),
+                step => key(step),
+            };
+
+            *interned.entry(key).or_insert(next)
+        })
+        .collect::<Vec<_>>();
+
+    let matches = |first: usize, second: usize, period: usize| {
+        keys[first..first + period] == keys[second..second + period]
+            && slots(&steps[first..first + period]).1 == slots(&steps[second..second + period]).1
+    };
+
+    let mut repeats = Vec::new();
+    let mut start = 0;
+
+    while start < steps.len() {
+        let mut best: Option<(usize, usize)> = None;
+
+        for period in 1..=(steps.len() - start) / 2 {
+            if keys[start] != keys[start + period]
+                || !steps[start..start + period]
+                    .iter()
+                    .any(|step| matches!(step, WgpuStep::Execute { .. }))
+            {
+                continue;
+            }
+
+            let mut count = 1;
+
+            while start + (count + 1) * period <= steps.len()
+                && matches(start, start + count * period, period)
+            {
+                count += 1;
+            }
+
+            if count > 1 && best.is_none_or(|(best, repeats)| period * count > best * repeats) {
+                best = Some((period, count));
+            }
+        }
+
+        match best {
+            Some((period, count)) => {
+                repeats.push((start, period, count));
+                start += period * count;
+            }
+            None => start += 1,
+        }
+    }
+
+    let mut result = Vec::with_capacity(steps.len());
+    let mut position = 0;
+
+    for (start, period, count) in repeats {
+        result.extend_from_slice(&steps[position..start]);
+
+        let body = &steps[start..start + period];
+        let slot_of = slots(body)
+            .0
+            .into_iter()
+            .enumerate()
+            .map(|(slot, id)| (id, ExprId(slot)))
+            .collect::<HashMap<_, _>>();
+
+        result.push(WgpuStep::Repeat {
+            body: body
+                .iter()
+                .map(|step| rename(step, |id| slot_of[&id]))
+                .collect(),
+            iterations: (0..count)
+                .map(|index| iteration(&steps[start + index * period..][..period]))
+                .collect(),
+        });
+
+        position = start + period * count;
+    }
+
+    result.extend_from_slice(&steps[position..]);
+
+    result
+}
@@ -0,0 +1,129 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{compiler::Runner, tensor::Tensor};
+
+use super::{compiler::WgpuPlan, runner::WgpuRunner};
+
+const PLAN_FILE: &str = "plan.bin";
+const INPUTS_FILE: &str = "inputs.bin";
+const ADAPTER_FILE: &str = "adapter.txt";
+const STEPS_DIR: &str = "steps";
+
+#[derive(Clone, Debug)]
+pub struct StepDifference {
+    pub step: usize,
+    pub max_difference: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Replay {
+    pub recorded_adapter: String,
+    pub outputs: Vec<Tensor>,
+    pub differences: Vec<StepDifference>,
+}
+
+fn step_file(path: &Path, index: usize) -> PathBuf {
+    path.join(STEPS_DIR).join(format!("step_{index}.bin"))
+}
+
+impl WgpuRunner {
+    pub fn record(
+        &mut self,
+        plan: WgpuPlan,
+        inputs: Vec<Tensor>,
+        path: impl AsRef<Path>,
+    ) -> Vec<Tensor> {
+        let path = path.as_ref();
+
+        fs::create_dir_all(path.join(STEPS_DIR)).expect("could not create replay bundle");
+
+        fs::write(
+            path.join(PLAN_FILE),
+            bincode::serialize(&plan).expect("could not encode plan"),
+        )
+        .expect("could not write plan");
+        fs::write(
+            path.join(INPUTS_FILE),
+            bincode::serialize(&inputs).expect("could not encode inputs"),
+        )
+        .expect("could not write inputs");
+        fs::write(
+            path.join(ADAPTER_FILE),
+            format!("{:#?}\n", self.adapter_info()),
+        )
+        .expect("could not write adapter info");
+
+        let runnable = self.preprocess(plan);
+
+        self.run_with(
+            runnable,
+            &inputs.iter().map(Tensor::view).collect::<Vec<_>>(),
+            |runner, index, buffer| {
+                let data = runner.read_buffer(buffer, runner.buffer_size(buffer));
+
+                fs::write(
+                    step_file(path, index),
+                    bincode::serialize(&data).expect("could not encode step output"),
+                )
+                .expect("could not write step output");
+            },
+        )
+        .unwrap_or_else(|error| panic!("{error}"))
+    }
+
+    pub fn replay(&mut self, path: impl AsRef<Path>) -> Replay {
+        let path = path.as_ref();
+
+        let plan: WgpuPlan =
+            bincode::deserialize(&fs::read(path.join(PLAN_FILE)).expect("could not read plan"))
+                .expect("could not decode plan");
+        let inputs: Vec<Tensor> =
+            bincode::deserialize(&fs::read(path.join(INPUTS_FILE)).expect("could not read inputs"))
+                .expect("could not decode inputs");
+
+        let runnable = self.preprocess(plan);
+        let mut differences = Vec::new();
+
+        let outputs = self
+            .run_with(
+                runnable,
+                &inputs.iter().map(Tensor::view).collect::<Vec<_>>(),
+                |runner, index, buffer| {
+                    let recorded: Vec<f32> = bincode::deserialize(
+                        &fs::read(step_file(path, index)).expect("could not read step output"),
+                    )
+                    .expect("could not decode step output");
+
+                    let data = runner.read_buffer(buffer, runner.buffer_size(buffer));
+
+                    differences.push(StepDifference {
+                        step: index,
+                        max_difference: recorded
+                            .iter()
+                            .zip(data)
+                            .map(|(recorded, value)| {
+                                if recorded.to_bits() == value.to_bits() {
+                                    0.0
+                                } else if recorded.is_nan() || value.is_nan() {
+                                    f32::INFINITY
+                                } else {
+                                    (recorded - value).abs()
+                                }
+                            })
+                            .fold(0.0, f32::max),
+                    });
+                },
+            )
+            .unwrap_or_else(|error| panic!("{error}"));
+
+        Replay {
+            recorded_adapter: fs::read_to_string(path.join(ADAPTER_FILE))
+                .unwrap_or_else(|_| String::from("unknown")),
+            outputs,
+            differences,
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use std::{collections::HashMap, fmt::Write};
+
+use super::{
+    compiler::{WgpuPlan, WgpuStep, Workgroups},
+    repeat,
+};
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; font-size: 0.9em; }
+td, th { padding: 2px 8px; text-align: left; border-bottom: 1px solid #eee; }
+tr.execute { cursor: pointer; }
+tr.execute:hover, tr.selected { background: #e8f0fe; }
+td.bytes { width: 200px; }
+div.bar { background: #8ab4f8; height: 10px; }
+#panes { display: flex; gap: 2em; align-items: flex-start; }
+#viewer { position: sticky; top: 1em; flex: 1; min-width: 0; }
+#viewer pre { background: #f6f8fa; padding: 1em; overflow: auto; max-height: 80vh; }
+svg rect:hover { fill: #1a73e8; }
+";
+
+const SCRIPT: &str = "
+function show(kernel, row) {
+    document.querySelectorAll('#viewer pre').forEach(pre => pre.hidden = pre.id !== 'kernel-' + kernel);
+    document.querySelectorAll('tr.selected').forEach(tr => tr.classList.remove('selected'));
+    row.classList.add('selected');
+    document.getElementById('viewer-title').textContent = 'Kernel ' + kernel;
+}
+";
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Numbers every distinct kernel source, including those inside branches, in order of first use.
+fn number_kernels<'a>(steps: &'a [WgpuStep], kernels: &mut HashMap<&'a str, usize>) {
+    for step in steps {
+        match step {
+            WgpuStep::Execute { source, .. } => {
+                let next = kernels.len();
+
+                kernels.entry(source).or_insert(next);
+            }
+            WgpuStep::Repeat { body, .. } => number_kernels(body, kernels),
+            WgpuStep::Branch {
+                then_steps,
+                else_steps,
+                ..
+            } => {
+                number_kernels(then_steps, kernels);
+                number_kernels(else_steps, kernels);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl WgpuPlan {
+    /// A standalone HTML page describing the plan: a timeline of its steps, the lifetime of each
+    /// buffer, and the WGSL of each kernel, shown by clicking the steps that run it.
+    pub fn to_html_report(&self) -> String {
+        let report = self.memory_report();
+        let steps = repeat::unroll(&self.steps);
+
+        let mut kernels = HashMap::new();
+
+        number_kernels(&steps, &mut kernels);
+
+        let mut sources = kernels
+            .iter()
+            .map(|(&source, &index)| (index, source))
+            .collect::<Vec<_>>();
+
+        sources.sort();
+
+        let mut html = String::new();
+
+        writeln!(
+            html,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Execution plan</title>\n<style>{STYLE}</style>\n</head>\n<body>"
+        )
+        .unwrap();
+        writeln!(
+            html,
+            "<h1>Execution plan</h1>\n<p>{} steps, {} kernels, {} arenas. Peak of {} bytes at step {}.</p>",
+            steps.len(),
+            sources.len(),
+            self.arenas.len(),
+            report.peak_bytes,
+            report.peak_step
+        )
+        .unwrap();
+
+        html.push_str("<h2>Timeline</h2>\n<div id=\"panes\">\n<table>\n");
+        html.push_str("<tr><th>step</th><th>kind</th><th>buffer</th><th>details</th><th>live bytes</th></tr>\n");
+
+        for (index, step) in steps.iter().enumerate() {
+            let (kind, buffer, details) = match step {
+                WgpuStep::Allocate { id, tensor } => (
+                    "allocate",
+                    format!("{id:?}"),
+                    format!("{} bytes", tensor.layout.size()),
+                ),
+                WgpuStep::Deallocate(id) => ("deallocate", format!("{id:?}"), String::new()),
+                WgpuStep::Reserve { id, size } => {
+                    ("reserve", format!("{id:?}"), format!("{size} bytes"))
+                }
+                WgpuStep::Place {
+                    id,
+                    arena,
+                    offset,
+                    size,
+                } => (
+                    "place",
+                    format!("{id:?}"),
+                    format!("{size} bytes at offset {offset} of arena {arena}"),
+                ),
+                WgpuStep::Slice {
+                    id,
+                    source,
+                    offset,
+                    size,
+                } => (
+                    "slice",
+                    format!("{id:?}"),
+                    format!("{size} bytes at offset {offset} of {source:?}"),
+                ),
+                WgpuStep::Execute {
+                    output,
+                    source,
+                    workgroups,
+                    inputs,
+                    ..
+                } => (
+                    "execute",
+                    format!("{output:?}"),
+                    format!(
+                        "kernel {}, {} workgroups, reads {:?}",
+                        kernels[source.as_str()],
+                        match workgroups {
+                            Workgroups::Fixed(workgroups) => format!("{workgroups:?}"),
+                            Workgroups::Indirect(arguments) => format!("{arguments:?}'s"),
+                        },
+                        &inputs[1..]
+                    ),
+                ),
+                WgpuStep::Branch {
+                    condition,
+                    predicate,
+                    then_steps,
+                    else_steps,
+                } => (
+                    "branch",
+                    format!("{condition:?}"),
+                    format!(
+                        "{} steps if {predicate:?} holds, {} otherwise",
+                        then_steps.len(),
+                        else_steps.len()
+                    ),
+                ),
+                WgpuStep::Repeat { .. } => unreachable!("repeated steps were unrolled"),
+            };
+
+            let width = report.live_bytes[index] * 100 / report.peak_bytes.max(1);
+
+            match step {
+                WgpuStep::Execute { source, .. } => write!(
+                    html,
+                    "<tr class=\"execute\" onclick=\"show({}, this)\">",
+                    kernels[source.as_str()]
+                ),
+                _ => write!(html, "<tr>"),
+            }
+            .unwrap();
+            writeln!(
+                html,
+                "<td>{index}</td><td>{kind}</td><td>{}</td><td>{}</td><td class=\"bytes\" title=\"{} bytes\"><div class=\"bar\" style=\"width: {width}%\"></div></td></tr>",
+                escape(&buffer),
+                escape(&details),
+                report.live_bytes[index]
+            )
+            .unwrap();
+        }
+
+        html.push_str("</table>\n<div id=\"viewer\">\n<h3 id=\"viewer-title\">Click a step to see its kernel</h3>\n");
+
+        for (index, source) in &sources {
+            writeln!(
+                html,
+                "<pre id=\"kernel-{index}\" hidden>{}</pre>",
+                escape(source)
+            )
+            .unwrap();
+        }
+
+        html.push_str("</div>\n</div>\n");
+
+        // One row per buffer, spanning the steps it is live for.
+        let (row_height, step_width) = (14, 800.0 / steps.len().max(1) as f64);
+
+        writeln!(
+            html,
+            "<h2>Buffer lifetimes</h2>\n<svg width=\"900\" height=\"{}\">",
+            report.buffers.len() * row_height + 10
+        )
+        .unwrap();
+
+        for (row, buffer) in report.buffers.iter().enumerate() {
+            let end = buffer.freed_at.unwrap_or(steps.len());
+
+            writeln!(
+                html,
+                "<text x=\"0\" y=\"{}\" font-size=\"10\">{}</text><rect x=\"{:.1}\" y=\"{}\" width=\"{:.1}\" height=\"{}\" fill=\"#8ab4f8\"><title>{}: {} bytes, steps {}..{end}</title></rect>",
+                row * row_height + 10,
+                escape(&format!("{:?}", buffer.id)),
+                100.0 + buffer.allocated_at as f64 * step_width,
+                row * row_height,
+                ((end - buffer.allocated_at) as f64 * step_width).max(1.0),
+                row_height - 2,
+                escape(&format!("{:?}", buffer.id)),
+                buffer.size,
+                buffer.allocated_at
+            )
+            .unwrap();
+        }
+
+        writeln!(html, "</svg>\n<script>{SCRIPT}</script>\n</body>\n</html>").unwrap();
+
+        html
+    }
+}
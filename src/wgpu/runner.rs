@@ -1,64 +1,488 @@
-use std::{borrow::Cow, collections::HashMap, num::NonZeroU64};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, HashMap},
+    error::Error,
+    fmt::{self, Display, Formatter},
+    fs,
+    hash::{Hash, Hasher},
+    io, mem,
+    num::NonZeroU64,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
 
 use pollster::FutureExt;
+use tracing::{debug, field, info_span, warn};
 use wgpu::{
-    util::{BufferInitDescriptor, DeviceExt},
-    Adapter, BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
-    BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType, Buffer, BufferBindingType,
-    BufferDescriptor, BufferUsages, CommandEncoder, ComputePipeline, ComputePipelineDescriptor,
-    Device, Instance, InstanceDescriptor, Maintain, MapMode, PipelineLayoutDescriptor, Queue,
-    RequestAdapterOptions, ShaderModule, ShaderModuleDescriptor, ShaderSource, ShaderStages,
-    SubmissionIndex,
+    Adapter, AdapterInfo, Backends, BindGroup, BindGroupDescriptor, BindGroupEntry,
+    BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType,
+    Buffer, BufferBinding, BufferBindingType, BufferDescriptor, BufferUsages, CommandEncoder,
+    ComputePassDescriptor, ComputePipeline, ComputePipelineDescriptor, Device, DeviceDescriptor,
+    ErrorFilter, Features, Id, Instance, InstanceDescriptor, Limits, Maintain, MapMode,
+    PipelineLayoutDescriptor, PowerPreference, Queue, RequestAdapterOptions, ShaderModule,
+    ShaderModuleDescriptor, ShaderSource, ShaderStages, SubmissionIndex, COPY_BUFFER_ALIGNMENT,
 };
 
 use crate::{
     compiler::Runner,
-    graph::ExprId,
-    tensor::{Layout, Tensor},
+    graph::{ExprId, Predicate},
+    tensor::{Layout, Tensor, TensorView, TensorViewMut},
 };
 
-use super::compiler::{WgpuCompiler, WgpuPlan, WgpuStep};
+use super::{
+    allocator::{Allocation, AllocatorStats, AllocatorStrategy, DeviceAllocator},
+    checksum::Checksums,
+    compiler::{Readback, WgpuCompiler, WgpuPlan, WgpuStep, Workgroups},
+    repeat::Iteration,
+};
 
-#[derive(Debug)]
+// Enough for an upload to be written while the two before it are still being copied.
+const UPLOAD_BUFFERS: usize = 3;
+
+fn f16_to_f32(half: u16) -> f32 {
+    let sign = u32::from(half >> 15) << 31;
+    let exponent = u32::from((half >> 10) & 0x1f);
+    let mantissa = u32::from(half & 0x3ff);
+
+    match exponent {
+        0 => f32::from_bits(sign | (mantissa as f32 * 2f32.powi(-24)).to_bits()),
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | (mantissa << 13)),
+        _ => f32::from_bits(sign | ((exponent + 127 - 15) << 23) | (mantissa << 13)),
+    }
+}
+
+#[derive(Clone, Debug)]
 pub(crate) enum ConcreteWgpuStep {
     Allocate {
         id: ExprId,
         tensor: Tensor,
     },
     Deallocate(ExprId),
+    Reserve {
+        id: ExprId,
+        size: u64,
+    },
+    Place {
+        id: ExprId,
+        arena: usize,
+        offset: u64,
+        size: u64,
+    },
+    Slice {
+        id: ExprId,
+        source: ExprId,
+        offset: u64,
+        size: u64,
+    },
     Execute {
+        compute_pipeline: Arc<ComputePipeline>,
+        bind_group_layout: Arc<BindGroupLayout>,
+        workgroups: Workgroups,
         output: ExprId,
-        output_size: u64,
-        compute_pipeline: ComputePipeline,
-        bind_group_layout: BindGroupLayout,
-        workgroups: [u32; 3],
         inputs: Vec<ExprId>,
+        watched: Option<Arc<Mutex<WatchedShader>>>,
+        label: Option<Arc<str>>,
+        layout: Option<Arc<Layout>>,
+    },
+    Repeat {
+        body: Vec<ConcreteWgpuStep>,
+        iterations: Vec<Iteration>,
     },
+    Branch {
+        condition: ExprId,
+        predicate: Predicate,
+        then_steps: Vec<ConcreteWgpuStep>,
+        else_steps: Vec<ConcreteWgpuStep>,
+    },
+}
+
+// The values of an output read back as pairs of halves, which may end with padding.
+fn unpack_halves(words: &[f32], readback: Readback) -> impl Iterator<Item = f32> + '_ {
+    words
+        .iter()
+        .flat_map(|word| {
+            let word = word.to_bits();
+
+            [word as u16, (word >> 16) as u16]
+        })
+        .map(move |half| match readback {
+            Readback::F16 => f16_to_f32(half),
+            _ => f32::from_bits(u32::from(half) << 16),
+        })
+}
+
+fn readback_size(layout: &Layout, readback: Readback) -> u64 {
+    match readback {
+        Readback::F32 => layout.size() as u64,
+        Readback::F16 | Readback::Bf16 => (layout.elements().div_ceil(2) * size_of::<u32>()) as u64,
+    }
+}
+
+fn create_staging_buffer(device: &Device, size: u64) -> Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: Some("staging"),
+        size,
+        usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    })
+}
+
+impl ConcreteWgpuStep {
+    // Whether the step may write the buffer of `id`. Repeats are assumed to write every buffer
+    // one of their iterations uses. Kernels writing a slice of an expression's buffer still name
+    // the expression as their output.
+    fn writes(&self, id: ExprId) -> bool {
+        match self {
+            ConcreteWgpuStep::Execute { inputs, output, .. } => inputs[0] == id || *output == id,
+            ConcreteWgpuStep::Repeat { iterations, .. } => iterations
+                .iter()
+                .any(|iteration| iteration.ids.contains(&id)),
+            ConcreteWgpuStep::Branch {
+                then_steps,
+                else_steps,
+                ..
+            } => then_steps
+                .iter()
+                .chain(else_steps)
+                .any(|step| step.writes(id)),
+            _ => false,
+        }
+    }
+
+    fn substitute(body: &[ConcreteWgpuStep], iteration: &Iteration) -> Vec<ConcreteWgpuStep> {
+        let id = |slot: ExprId| iteration.ids[slot.0];
+        let mut consts = iteration.consts.iter();
+        let mut places = iteration.places.iter();
+
+        body.iter()
+            .map(|step| match step.clone() {
+                ConcreteWgpuStep::Allocate { id: slot, .. } => ConcreteWgpuStep::Allocate {
+                    id: id(slot),
+                    tensor: consts.next().expect("iteration is missing a const").clone(),
+                },
+                ConcreteWgpuStep::Deallocate(slot) => ConcreteWgpuStep::Deallocate(id(slot)),
+                ConcreteWgpuStep::Reserve { id: slot, size } => {
+                    ConcreteWgpuStep::Reserve { id: id(slot), size }
+                }
+                ConcreteWgpuStep::Place { id: slot, size, .. } => {
+                    let &(arena, offset) = places.next().expect("iteration is missing a placement");
+
+                    ConcreteWgpuStep::Place {
+                        id: id(slot),
+                        arena,
+                        offset: offset as u64,
+                        size,
+                    }
+                }
+                ConcreteWgpuStep::Slice {
+                    id: slot,
+                    source,
+                    offset,
+                    size,
+                } => ConcreteWgpuStep::Slice {
+                    id: id(slot),
+                    source: id(source),
+                    offset,
+                    size,
+                },
+                ConcreteWgpuStep::Execute {
+                    compute_pipeline,
+                    bind_group_layout,
+                    workgroups,
+                    output,
+                    inputs,
+                    watched,
+                    label,
+                    layout,
+                } => ConcreteWgpuStep::Execute {
+                    compute_pipeline,
+                    bind_group_layout,
+                    workgroups: match workgroups {
+                        Workgroups::Indirect(arguments) => Workgroups::Indirect(id(arguments)),
+                        workgroups => workgroups,
+                    },
+                    output: id(output),
+                    inputs: inputs.into_iter().map(id).collect(),
+                    watched,
+                    label,
+                    layout,
+                },
+                ConcreteWgpuStep::Repeat { .. } => {
+                    unreachable!("repeated steps cannot be nested")
+                }
+                ConcreteWgpuStep::Branch { .. } => unreachable!("branches are never folded"),
+            })
+            .collect()
+    }
 }
 
+// A kernel exported to the shader directory, with the pipeline of its file as last modified.
+// Shared by clones of the plan, so that a reload carries over to their later runs.
 #[derive(Debug)]
+pub(crate) struct WatchedShader {
+    path: PathBuf,
+    modified: SystemTime,
+    compute_pipeline: Arc<ComputePipeline>,
+}
+
+#[derive(Clone, Debug)]
 pub struct ConcreteWgpuPlan {
     pub(crate) inputs: Vec<ExprId>,
+    pub(crate) input_layouts: Vec<Layout>,
     pub(crate) steps: Vec<ConcreteWgpuStep>,
     pub(crate) outputs: Vec<ExprId>,
     pub(crate) output_layouts: Vec<Layout>,
+    pub(crate) assertions: Vec<(ExprId, String)>,
+    pub(crate) arenas: Vec<u64>,
+    pub(crate) checksums: Vec<(ExprId, ExprId)>,
+    pub(crate) readbacks: Vec<Readback>,
+    pub(crate) persistent_inputs: Vec<(ExprId, String, Layout)>,
+    pub(crate) persistent_outputs: Vec<(ExprId, String, Layout)>,
+    pub(crate) bind_groups: BindGroupCache,
+}
+
+type BindGroupKey = (
+    Id<BindGroupLayout>,
+    Vec<(Id<Buffer>, u64, Option<NonZeroU64>)>,
+);
+
+// With whether a run used it since the last pruning.
+type CachedBindGroup = (Arc<BindGroup>, bool);
+
+// The bind groups a plan's runs created, by layout and bound ranges, shared by clones of the
+// plan. Runs reuse them for as long as the allocator hands them the same buffers. Those a run
+// didn't use are dropped after it, as they keep their buffers alive.
+#[derive(Clone, Default)]
+pub(crate) struct BindGroupCache(Arc<Mutex<HashMap<BindGroupKey, CachedBindGroup>>>);
+
+impl BindGroupCache {
+    fn get_or_create(
+        &self,
+        key: BindGroupKey,
+        create: impl FnOnce() -> BindGroup,
+    ) -> Arc<BindGroup> {
+        let mut bind_groups = self.0.lock().unwrap();
+        let (bind_group, used) = bind_groups
+            .entry(key)
+            .or_insert_with(|| (Arc::new(create()), false));
+
+        *used = true;
+
+        bind_group.clone()
+    }
+
+    fn retain_used(&self) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|_, (_, used)| mem::take(used));
+    }
 }
 
+impl fmt::Debug for BindGroupCache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "BindGroupCache({})", self.0.lock().unwrap().len())
+    }
+}
+
+type Pipeline = (Arc<ComputePipeline>, Arc<BindGroupLayout>);
+
+// Outputs copied into a staging buffer by a submission that may still be running, with each
+// output's byte range in it.
+struct PendingReadback {
+    staging_buffer: Buffer,
+    submission: SubmissionIndex,
+    outputs: Vec<(Layout, Readback, Range<u64>)>,
+    // Set once the staging buffer is mapped, which only happens while the device is polled.
+    mapped: Arc<AtomicBool>,
+}
+
+/// A snapshot of what a runner holds on its device, for health checks.
+#[derive(Clone, Debug)]
+pub struct RunnerStats {
+    /// The allocations, arenas, upload buffers and persistent buffers the runner holds.
+    pub live_buffers: usize,
+    /// The bytes of every buffer the runner holds, including arenas and upload buffers.
+    pub resident_bytes: u64,
+    pub cached_pipelines: usize,
+    pub adapter_info: Option<AdapterInfo>,
+}
+
+// The allocator is shared by every run in flight, and only reset when none are.
+struct SharedAllocator {
+    strategy: Box<dyn AllocatorStrategy>,
+    runs: usize,
+}
+
+/// The buffers of a single run. Runners keep idle contexts around, so that later runs reuse their
+/// arenas and upload buffers, and create more when runs overlap.
+pub(crate) struct RunContext {
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    allocator: Arc<Mutex<SharedAllocator>>,
+    buffers: HashMap<ExprId, Allocation>,
+    arenas: Vec<Buffer>,
+    placements: HashMap<ExprId, (usize, u64, u64)>,
+    // The buffer each slice is part of, with its offset and size in it.
+    slices: HashMap<ExprId, (ExprId, u64, u64)>,
+    // The running plan's.
+    bind_groups: BindGroupCache,
+    // A ring of write-mapped staging buffers, each with the submission copying out of it, which
+    // must finish before it is rewritten.
+    upload_buffers: Vec<Option<(Buffer, SubmissionIndex)>>,
+    next_upload: usize,
+}
+
+/// Runs plans on one device. Everything a run writes lives in a context of its own, so a runner
+/// can be shared between threads, running several plans at once.
 pub struct WgpuRunner {
-    device: Device,
-    queue: Queue,
-    buffers: HashMap<ExprId, Buffer>,
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    allocator: Arc<Mutex<SharedAllocator>>,
+    contexts: Mutex<Vec<RunContext>>,
+    shader_dir: Option<PathBuf>,
+    adapter_info: Option<AdapterInfo>,
+    checksums: Mutex<Checksums>,
+    // Keyed by the kernel source without its notes, and which of its bindings are read only.
+    pipelines: HashMap<(String, Vec<bool>), Pipeline>,
+    // Buffers that outlive runs, by name, with the layout of their contents.
+    persistent: Mutex<HashMap<String, (Buffer, Layout)>>,
+}
+
+#[derive(Default)]
+pub struct WgpuRunnerBuilder {
+    backends: Option<Backends>,
+    power_preference: PowerPreference,
+    adapter_name: Option<String>,
+    required_features: Features,
+    // The adapter's own limits when unset.
+    required_limits: Option<Limits>,
+    device: Option<(Arc<Device>, Arc<Queue>)>,
+    shader_dir: Option<PathBuf>,
+    allocator: Option<Box<dyn AllocatorStrategy>>,
+}
+
+impl WgpuRunnerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn backends(mut self, backends: Backends) -> Self {
+        self.backends = Some(backends);
+        self
+    }
+
+    pub fn power_preference(mut self, power_preference: PowerPreference) -> Self {
+        self.power_preference = power_preference;
+        self
+    }
+
+    pub fn adapter_name(mut self, name: impl Into<String>) -> Self {
+        self.adapter_name = Some(name.into());
+        self
+    }
+
+    pub fn required_features(mut self, features: Features) -> Self {
+        self.required_features = features;
+        self
+    }
+
+    /// Requests `limits` instead of every limit the adapter supports.
+    pub fn required_limits(mut self, limits: Limits) -> Self {
+        self.required_limits = Some(limits);
+        self
+    }
+
+    pub fn device(mut self, device: Arc<Device>, queue: Arc<Queue>) -> Self {
+        self.device = Some((device, queue));
+        self
+    }
+
+    pub fn shader_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.shader_dir = Some(path.into());
+        self
+    }
+
+    pub fn allocator(mut self, allocator: Box<dyn AllocatorStrategy>) -> Self {
+        self.allocator = Some(allocator);
+        self
+    }
+
+    pub fn build(self) -> WgpuRunner {
+        self.build_async().block_on()
+    }
+
+    pub async fn build_async(self) -> WgpuRunner {
+        let runner = match self.device {
+            Some((device, queue)) => WgpuRunner::new_with_device(device, queue),
+            None => self.request_runner().await,
+        };
+
+        let mut runner = WgpuRunner {
+            shader_dir: self.shader_dir,
+            ..runner
+        };
+
+        if let Some(allocator) = self.allocator {
+            runner.set_allocator(allocator);
+        }
+
+        runner
+    }
+
+    async fn request_runner(&self) -> WgpuRunner {
+        let backends = self.backends.unwrap_or(Backends::all());
+        let instance = Instance::new(InstanceDescriptor {
+            backends,
+            ..Default::default()
+        });
+
+        let adapter = match &self.adapter_name {
+            Some(name) => instance
+                .enumerate_adapters(backends)
+                .into_iter()
+                .find(|adapter| adapter.get_info().name.contains(name.as_str())),
+            None => {
+                instance
+                    .request_adapter(&RequestAdapterOptions {
+                        power_preference: self.power_preference,
+                        ..Default::default()
+                    })
+                    .await
+            }
+        }
+        .expect("could not find adapter");
+
+        let (device, queue) = adapter
+            .request_device(
+                &DeviceDescriptor {
+                    label: None,
+                    required_features: self.required_features,
+                    required_limits: self
+                        .required_limits
+                        .clone()
+                        .unwrap_or_else(|| adapter.limits()),
+                },
+                None,
+            )
+            .await
+            .expect("could not get device");
+
+        WgpuRunner {
+            adapter_info: Some(adapter.get_info()),
+            ..WgpuRunner::new_with_device(Arc::new(device), Arc::new(queue))
+        }
+    }
 }
 
 impl Default for WgpuRunner {
     fn default() -> Self {
-        let instance = Instance::new(InstanceDescriptor::default());
-        let adapter = instance
-            .request_adapter(&RequestAdapterOptions::default())
-            .block_on()
-            .expect("could not find adapter");
-
-        Self::new_with_adapter(adapter).block_on()
+        Self::builder().build()
     }
 }
 
@@ -67,45 +491,124 @@ impl WgpuRunner {
         Self::default()
     }
 
+    pub fn builder() -> WgpuRunnerBuilder {
+        WgpuRunnerBuilder::new()
+    }
+
     pub async fn new_with_adapter(adapter: Adapter) -> Self {
         let (device, queue) = adapter
             .request_device(&Default::default(), None)
             .await
             .expect("could not get device");
 
+        Self {
+            adapter_info: Some(adapter.get_info()),
+            ..Self::new_with_device(Arc::new(device), Arc::new(queue))
+        }
+    }
+
+    pub fn new_with_device(device: Arc<Device>, queue: Arc<Queue>) -> Self {
         Self {
             device,
             queue,
-            buffers: HashMap::new(),
+            allocator: Arc::new(Mutex::new(SharedAllocator {
+                strategy: Box::new(DeviceAllocator::default()),
+                runs: 0,
+            })),
+            contexts: Mutex::new(Vec::new()),
+            shader_dir: None,
+            adapter_info: None,
+            checksums: Mutex::new(Checksums::default()),
+            pipelines: HashMap::new(),
+            persistent: Mutex::new(HashMap::new()),
         }
     }
 
-    fn track(&mut self, id: ExprId, buffer: Buffer) {
-        self.buffers.insert(id, buffer);
+    pub fn set_shader_dir(&mut self, path: Option<PathBuf>) {
+        self.shader_dir = path;
     }
 
-    fn allocate(&mut self, id: ExprId, tensor: &Tensor) {
-        self.track(
-            id,
-            self.device.create_buffer_init(&BufferInitDescriptor {
-                label: None,
-                contents: bytemuck::cast_slice(&tensor.data),
-                usage: BufferUsages::COPY_DST | BufferUsages::COPY_SRC | BufferUsages::STORAGE,
-            }),
-        );
+    pub fn adapter_info(&self) -> Option<&AdapterInfo> {
+        self.adapter_info.as_ref()
     }
 
-    fn deallocate(&mut self, id: ExprId) {
-        self.buffers.remove(&id);
+    /// The limits of the device, which compilers should be given to fit plans to it.
+    pub fn limits(&self) -> Limits {
+        self.device.limits()
+    }
+
+    /// The checksums of the last run to finish.
+    pub fn checksums(&self) -> Checksums {
+        self.checksums.lock().unwrap().clone()
+    }
+
+    pub fn set_allocator(&mut self, allocator: Box<dyn AllocatorStrategy>) {
+        // Runs free their buffers before returning, so no allocation outlives the old strategy.
+        self.allocator.lock().unwrap().strategy = allocator;
+    }
+
+    pub fn allocator_stats(&self) -> AllocatorStats {
+        self.allocator.lock().unwrap().strategy.stats()
+    }
+
+    /// Runs in flight on other threads are not counted, besides their allocations.
+    pub fn stats(&self) -> RunnerStats {
+        let contexts = self.contexts.lock().unwrap();
+        let persistent = self.persistent.lock().unwrap();
+        let allocator = self.allocator_stats();
+        let context_buffers = contexts.iter().flat_map(|context| {
+            context.arenas.iter().chain(
+                context
+                    .upload_buffers
+                    .iter()
+                    .flatten()
+                    .map(|(buffer, _)| buffer),
+            )
+        });
+
+        RunnerStats {
+            live_buffers: allocator.live_allocations
+                + context_buffers.clone().count()
+                + persistent.len(),
+            resident_bytes: allocator.live_bytes
+                + context_buffers.map(Buffer::size).sum::<u64>()
+                + persistent
+                    .values()
+                    .map(|(buffer, _)| buffer.size())
+                    .sum::<u64>(),
+            cached_pipelines: self.pipelines.len(),
+            adapter_info: self.adapter_info.clone(),
+        }
+    }
+
+    /// Waits for everything submitted to the device to finish, then frees the runner's buffers.
+    pub fn shutdown(mut self) {
+        self.device.poll(Maintain::Wait);
+        self.contexts.get_mut().unwrap().clear();
+        self.pipelines.clear();
+        self.persistent.get_mut().unwrap().clear();
     }
 
-    fn retrieve(&self, id: ExprId, layout: Layout) -> Tensor {
-        let buffer = &self.buffers[&id];
-        let staging_buffer = self.create_staging_buffer(&layout);
+    /// Stores a tensor on the device under a name, for plans with persistent inputs of that name.
+    pub fn persist(&self, name: impl Into<String>, tensor: &Tensor) {
+        let name = name.into();
+        let tensor = tensor.view().rebased();
+        let mut persistent = self.persistent.lock().unwrap();
 
-        let mut encoder = self.device.create_command_encoder(&Default::default());
+        self.reserve_persistent(&mut persistent, &name, tensor.layout.clone());
+        self.queue
+            .write_buffer(&persistent[&name].0, 0, bytemuck::cast_slice(tensor.data));
+    }
+
+    /// Reads a persistent buffer back to the host.
+    pub fn read_persistent(&self, name: &str) -> Option<Tensor> {
+        let persistent = self.persistent.lock().unwrap();
+        let (buffer, layout) = persistent.get(name)?;
+        let staging_buffer = create_staging_buffer(&self.device, buffer.size());
 
-        encoder.copy_buffer_to_buffer(buffer, 0, &staging_buffer, 0, layout.size() as u64);
+        let mut encoder = self.create_command_encoder();
+
+        encoder.copy_buffer_to_buffer(buffer, 0, &staging_buffer, 0, buffer.size());
 
         let copy_submission = self.queue.submit(Some(encoder.finish()));
 
@@ -115,229 +618,1452 @@ impl WgpuRunner {
         self.device
             .poll(Maintain::WaitForSubmissionIndex(copy_submission));
 
-        let data = buffer_slice.get_mapped_range();
-        let tensor = Tensor {
-            data: bytemuck::cast_slice(&data).to_vec().into_boxed_slice(),
-            layout,
-        };
+        let data = bytemuck::cast_slice::<_, f32>(&buffer_slice.get_mapped_range()).to_vec();
 
-        drop(data);
         staging_buffer.unmap();
 
-        tensor
-    }
-
-    fn create_shader_module(&self, contents: &str) -> ShaderModule {
-        self.device.create_shader_module(ShaderModuleDescriptor {
-            label: None,
-            source: ShaderSource::Wgsl(Cow::Borrowed(contents)),
+        Some(Tensor {
+            data: data.into_boxed_slice(),
+            layout: layout.clone(),
         })
     }
 
-    fn create_staging_buffer(&self, layout: &Layout) -> Buffer {
-        self.device.create_buffer(&BufferDescriptor {
-            label: None,
-            size: layout.size() as u64,
-            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
-            mapped_at_creation: false,
-        })
+    /// Frees a persistent buffer, returning whether it existed.
+    pub fn remove_persistent(&self, name: &str) -> bool {
+        self.persistent.lock().unwrap().remove(name).is_some()
     }
 
-    fn create_output_buffer(&mut self, id: ExprId, size: u64) {
-        self.track(
-            id,
-            self.device.create_buffer(&BufferDescriptor {
-                label: None,
+    // Buffers are reused while they keep their size, so iterating a plan does not reallocate.
+    fn reserve_persistent(
+        &self,
+        persistent: &mut HashMap<String, (Buffer, Layout)>,
+        name: &str,
+        layout: Layout,
+    ) {
+        let size = layout.size() as u64;
+
+        if persistent
+            .get(name)
+            .is_none_or(|(buffer, _)| buffer.size() != size)
+        {
+            let buffer = self.device.create_buffer(&BufferDescriptor {
+                label: Some(name),
                 size,
-                usage: BufferUsages::COPY_SRC | BufferUsages::STORAGE,
+                usage: BufferUsages::COPY_DST | BufferUsages::COPY_SRC | BufferUsages::STORAGE,
                 mapped_at_creation: false,
-            }),
-        );
-    }
+            });
 
-    fn create_compute_pipeline(
-        &self,
-        module: &ShaderModule,
-        entry_point: &str,
-        bind_group_layout: &BindGroupLayout,
-    ) -> ComputePipeline {
-        self.device
-            .create_compute_pipeline(&ComputePipelineDescriptor {
-                label: None,
-                layout: Some(
-                    &self
-                        .device
-                        .create_pipeline_layout(&PipelineLayoutDescriptor {
-                            label: None,
-                            bind_group_layouts: &[bind_group_layout],
-                            push_constant_ranges: &[],
-                        }),
-                ),
-                module,
-                entry_point,
-            })
+            persistent.insert(name.to_string(), (buffer, layout));
+        } else {
+            persistent.get_mut(name).unwrap().1 = layout;
+        }
     }
 
-    fn create_bind_group_layout(&self, inputs_layout: &[(usize, bool)]) -> BindGroupLayout {
-        self.device
-            .create_bind_group_layout(&BindGroupLayoutDescriptor {
-                label: None,
-                entries: &inputs_layout
-                    .iter()
-                    .enumerate()
-                    .map(|(index, &(size, read_only))| BindGroupLayoutEntry {
-                        binding: index as u32,
-                        visibility: ShaderStages::COMPUTE,
-                        ty: BindingType::Buffer {
-                            ty: BufferBindingType::Storage { read_only },
-                            has_dynamic_offset: false,
-                            min_binding_size: Some(NonZeroU64::new(size as u64).unwrap()),
-                        },
-                        count: None,
-                    })
-                    .collect::<Vec<_>>(),
-            })
+    // Copies persistent buffers into the run's own, since kernels may overwrite their inputs.
+    fn load_persistent(&self, context: &mut RunContext, inputs: &[(ExprId, String, Layout)]) {
+        let persistent = self.persistent.lock().unwrap();
+        let mut encoder = self.create_command_encoder();
+
+        for (id, name, layout) in inputs {
+            let size = layout.size() as u64;
+
+            let allocation = context.track(*id, size);
+            let (buffer, _) = &persistent[name];
+
+            encoder.copy_buffer_to_buffer(buffer, 0, &allocation.buffer, allocation.offset, size);
+        }
+
+        self.queue.submit(Some(encoder.finish()));
     }
 
-    fn create_bind_group(&self, layout: &BindGroupLayout, buffers: &[&Buffer]) -> BindGroup {
-        self.device.create_bind_group(&BindGroupDescriptor {
-            label: None,
-            layout,
-            entries: buffers
-                .iter()
-                .enumerate()
-                .map(|(index, buffer)| BindGroupEntry {
-                    binding: index as u32,
-                    resource: buffer.as_entire_binding(),
-                })
-                .collect::<Vec<_>>()
-                .as_slice(),
-        })
+    fn store_persistent(&self, context: &RunContext, outputs: Vec<(ExprId, String, Layout)>) {
+        let mut persistent = self.persistent.lock().unwrap();
+
+        for (id, name, layout) in outputs {
+            let size = layout.size() as u64;
+
+            self.reserve_persistent(&mut persistent, &name, layout);
+
+            let mut encoder = self.create_command_encoder();
+            let source = context.binding(id);
+
+            encoder.copy_buffer_to_buffer(
+                source.buffer,
+                source.offset,
+                &persistent[&name].0,
+                0,
+                size,
+            );
+
+            self.queue.submit(Some(encoder.finish()));
+        }
     }
 
     fn create_command_encoder(&self) -> CommandEncoder {
         self.device.create_command_encoder(&Default::default())
     }
+}
 
-    fn start_compute_pass(
-        &self,
-        mut encoder: CommandEncoder,
-        compute_pipeline: &ComputePipeline,
-        bind_group: &BindGroup,
-        workgroups: [u32; 3],
-    ) -> SubmissionIndex {
-        {
-            let mut compute_pass = encoder.begin_compute_pass(&Default::default());
+impl RunContext {
+    fn new(runner: &WgpuRunner) -> Self {
+        Self {
+            device: runner.device.clone(),
+            queue: runner.queue.clone(),
+            allocator: runner.allocator.clone(),
+            buffers: HashMap::new(),
+            arenas: Vec::new(),
+            placements: HashMap::new(),
+            slices: HashMap::new(),
+            bind_groups: BindGroupCache::default(),
+            upload_buffers: Vec::new(),
+            next_upload: 0,
+        }
+    }
 
-            compute_pass.set_pipeline(compute_pipeline);
-            compute_pass.set_bind_group(0, bind_group, &[]);
-            compute_pass.dispatch_workgroups(workgroups[0], workgroups[1], workgroups[2]);
+    fn track(&mut self, id: ExprId, size: u64) -> &Allocation {
+        let mut allocator = self.allocator.lock().unwrap();
+        let allocation = allocator.strategy.allocate(&self.device, size);
+
+        if let Some(previous) = self.buffers.insert(id, allocation) {
+            allocator.strategy.free(previous);
         }
 
-        self.queue.submit(Some(encoder.finish()))
+        &self.buffers[&id]
     }
 
-    fn execute_pipeline(
-        &self,
-        compute_pipeline: &ComputePipeline,
-        workgroups: [u32; 3],
-        bind_group_layout: &BindGroupLayout,
-        buffers: &[ExprId],
-    ) {
-        let buffers = buffers
-            .iter()
-            .map(|id| &self.buffers[&id])
-            .collect::<Vec<_>>();
-
-        let bind_group = self.create_bind_group(bind_group_layout, &buffers);
+    fn release_all(&mut self) {
+        let mut allocator = self.allocator.lock().unwrap();
 
-        let encoder = self.create_command_encoder();
+        for (_, allocation) in self.buffers.drain() {
+            allocator.strategy.free(allocation);
+        }
 
-        self.start_compute_pass(encoder, compute_pipeline, &bind_group, workgroups);
+        self.placements.clear();
+        self.slices.clear();
     }
-}
 
-impl Runner for WgpuRunner {
-    type Compiler = WgpuCompiler;
+    fn allocate(&mut self, id: ExprId, tensor: &Tensor) {
+        let queue = self.queue.clone();
+        let allocation = self.track(id, tensor.layout.size() as u64);
 
-    type Runnable = ConcreteWgpuPlan;
+        queue.write_buffer(
+            &allocation.buffer,
+            allocation.offset,
+            bytemuck::cast_slice(&tensor.data),
+        );
+    }
 
-    fn preprocess(&mut self, plan: WgpuPlan) -> ConcreteWgpuPlan {
-        ConcreteWgpuPlan {
-            inputs: plan.inputs,
-            steps: plan
-                .steps
-                .into_iter()
-                .map(|step| match step {
-                    WgpuStep::Allocate { id, tensor } => ConcreteWgpuStep::Allocate { id, tensor },
-                    WgpuStep::Deallocate(id) => ConcreteWgpuStep::Deallocate(id),
-                    WgpuStep::Execute {
-                        output,
-                        source,
-                        workgroups,
-                        inputs,
-                        inputs_layout,
-                    } => {
-                        let module = self.create_shader_module(&source);
-                        let bind_group_layout = self.create_bind_group_layout(&inputs_layout);
+    fn upload(&mut self, ids: &[ExprId], tensors: &[TensorView]) {
+        let size = tensors
+            .iter()
+            .map(|tensor| tensor.layout.size() as u64)
+            .sum::<u64>();
 
-                        ConcreteWgpuStep::Execute {
-                            output,
-                            output_size: inputs_layout[0].0 as u64,
-                            compute_pipeline: self.create_compute_pipeline(
-                                &module,
-                                "main",
-                                &bind_group_layout,
-                            ),
-                            bind_group_layout,
-                            workgroups,
-                            inputs,
-                        }
-                    }
+        if size == 0 {
+            return;
+        }
+
+        // Only the upload that last used this slot of the ring is waited for, so uploads overlap
+        // both compute and the copies of the uploads before them.
+        let slot = self.next_upload;
+        self.next_upload = (slot + 1) % UPLOAD_BUFFERS;
+
+        if slot == self.upload_buffers.len() {
+            self.upload_buffers.push(None);
+        }
+
+        let upload_buffer = match self.upload_buffers[slot].take() {
+            Some((buffer, submission)) => {
+                self.device
+                    .poll(Maintain::WaitForSubmissionIndex(submission));
+
+                (buffer.size() >= size).then_some(buffer)
+            }
+            None => None,
+        };
+        let upload_buffer = upload_buffer.unwrap_or_else(|| {
+            self.device.create_buffer(&BufferDescriptor {
+                label: Some("upload"),
+                size: size.next_power_of_two(),
+                usage: BufferUsages::MAP_WRITE | BufferUsages::COPY_SRC,
+                mapped_at_creation: true,
+            })
+        });
+
+        {
+            let mut view = upload_buffer.slice(..size).get_mapped_range_mut();
+            let mut offset = 0;
+
+            for tensor in tensors {
+                let data = bytemuck::cast_slice(tensor.data);
+
+                view[offset..offset + data.len()].copy_from_slice(data);
+                offset += data.len();
+            }
+        }
+
+        upload_buffer.unmap();
+
+        let mut encoder = self.create_command_encoder();
+        let mut offset = 0;
+
+        for (&id, tensor) in ids.iter().zip(tensors) {
+            let size = tensor.layout.size() as u64;
+            let allocation = self.track(id, size);
+
+            encoder.copy_buffer_to_buffer(
+                &upload_buffer,
+                offset,
+                &allocation.buffer,
+                allocation.offset,
+                size,
+            );
+            offset += size;
+        }
+
+        let submission = self.queue.submit(Some(encoder.finish()));
+
+        upload_buffer.slice(..).map_async(MapMode::Write, |_| {});
+
+        self.upload_buffers[slot] = Some((upload_buffer, submission));
+    }
+
+    fn reserve_arenas(&mut self, sizes: &[u64]) {
+        for (index, &size) in sizes.iter().enumerate() {
+            if self
+                .arenas
+                .get(index)
+                .is_some_and(|arena| arena.size() >= size)
+            {
+                continue;
+            }
+
+            let arena = self.device.create_buffer(&BufferDescriptor {
+                label: Some("arena"),
+                size,
+                usage: BufferUsages::COPY_DST
+                    | BufferUsages::COPY_SRC
+                    | BufferUsages::STORAGE
+                    | BufferUsages::INDIRECT,
+                mapped_at_creation: false,
+            });
+
+            if index < self.arenas.len() {
+                self.arenas[index] = arena;
+            } else {
+                self.arenas.push(arena);
+            }
+        }
+    }
+
+    fn place(&mut self, id: ExprId, arena: usize, offset: u64, size: u64) {
+        let mut encoder = self.create_command_encoder();
+
+        encoder.clear_buffer(&self.arenas[arena], offset, Some(size));
+
+        self.queue.submit(Some(encoder.finish()));
+        self.placements.insert(id, (arena, offset, size));
+    }
+
+    fn deallocate(&mut self, id: ExprId) {
+        if let Some(allocation) = self.buffers.remove(&id) {
+            self.allocator.lock().unwrap().strategy.free(allocation);
+        }
+
+        self.placements.remove(&id);
+        self.slices.remove(&id);
+    }
+
+    fn binding(&self, id: ExprId) -> BufferBinding<'_> {
+        if let Some(&(source, offset, size)) = self.slices.get(&id) {
+            let binding = self.binding(source);
+
+            return BufferBinding {
+                buffer: binding.buffer,
+                offset: binding.offset + offset,
+                size: NonZeroU64::new(size),
+            };
+        }
+
+        match self.placements.get(&id) {
+            Some(&(arena, offset, size)) => BufferBinding {
+                buffer: &self.arenas[arena],
+                offset,
+                size: NonZeroU64::new(size),
+            },
+            None => {
+                let allocation = &self.buffers[&id];
+
+                BufferBinding {
+                    buffer: &allocation.buffer,
+                    offset: allocation.offset,
+                    size: NonZeroU64::new(allocation.size),
+                }
+            }
+        }
+    }
+
+    pub(super) fn read_buffer(&self, id: ExprId, size: u64) -> Vec<f32> {
+        let binding = self.binding(id);
+        let staging_buffer = create_staging_buffer(&self.device, size);
+
+        let mut encoder = self.create_command_encoder();
+
+        encoder.copy_buffer_to_buffer(binding.buffer, binding.offset, &staging_buffer, 0, size);
+
+        let copy_submission = self.queue.submit(Some(encoder.finish()));
+
+        let buffer_slice = staging_buffer.slice(..);
+        buffer_slice.map_async(MapMode::Read, |_| {});
+
+        self.device
+            .poll(Maintain::WaitForSubmissionIndex(copy_submission));
+
+        let mapped = buffer_slice.get_mapped_range();
+        let data = bytemuck::cast_slice(&mapped).to_vec();
+
+        drop(mapped);
+        staging_buffer.unmap();
+
+        data
+    }
+
+    pub(super) fn buffer_size(&self, id: ExprId) -> u64 {
+        match (self.slices.get(&id), self.placements.get(&id)) {
+            (Some(&(_, _, size)), _) | (None, Some(&(_, _, size))) => size,
+            (None, None) => self.buffers[&id].size,
+        }
+    }
+
+    // Copies every output into one staging buffer, without waiting for the copy.
+    fn start_readback(&self, outputs: Vec<(ExprId, Layout, Readback)>) -> PendingReadback {
+        let sizes = outputs
+            .iter()
+            .map(|(_, layout, readback)| readback_size(layout, *readback))
+            .collect::<Vec<_>>();
+        let staging_buffer = create_staging_buffer(
+            &self.device,
+            sizes.iter().sum::<u64>().max(COPY_BUFFER_ALIGNMENT),
+        );
+
+        let mut encoder = self.create_command_encoder();
+        let mut offset = 0;
+
+        let outputs = outputs
+            .into_iter()
+            .zip(sizes)
+            .map(|((id, layout, readback), size)| {
+                let binding = self.binding(id);
+
+                encoder.copy_buffer_to_buffer(
+                    binding.buffer,
+                    binding.offset,
+                    &staging_buffer,
+                    offset,
+                    size,
+                );
+                offset += size;
+
+                (layout, readback, offset - size..offset)
+            })
+            .collect();
+
+        let submission = self.queue.submit(Some(encoder.finish()));
+
+        let mapped = Arc::new(AtomicBool::new(false));
+
+        staging_buffer.slice(..).map_async(MapMode::Read, {
+            let mapped = mapped.clone();
+
+            move |_| mapped.store(true, Ordering::Release)
+        });
+
+        PendingReadback {
+            staging_buffer,
+            submission,
+            outputs,
+            mapped,
+        }
+    }
+
+    fn create_output_buffer(&mut self, id: ExprId, size: u64) {
+        let mut encoder = self.create_command_encoder();
+        let allocation = self.track(id, size);
+
+        encoder.clear_buffer(&allocation.buffer, allocation.offset, Some(size));
+
+        self.queue.submit(Some(encoder.finish()));
+    }
+
+    fn create_bind_group(
+        &self,
+        layout: &BindGroupLayout,
+        buffers: &[ExprId],
+        label: Option<&str>,
+    ) -> BindGroup {
+        self.device.create_bind_group(&BindGroupDescriptor {
+            label,
+            layout,
+            entries: buffers
+                .iter()
+                .enumerate()
+                .map(|(index, &id)| BindGroupEntry {
+                    binding: index as u32,
+                    resource: BindingResource::Buffer(self.binding(id)),
+                })
+                .collect::<Vec<_>>()
+                .as_slice(),
+        })
+    }
+
+    fn start_compute_pass(
+        &self,
+        mut encoder: CommandEncoder,
+        compute_pipeline: &ComputePipeline,
+        bind_group: &BindGroup,
+        workgroups: Workgroups,
+        label: Option<&str>,
+    ) -> SubmissionIndex {
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label,
+                timestamp_writes: None,
+            });
+
+            compute_pass.set_pipeline(compute_pipeline);
+            compute_pass.set_bind_group(0, bind_group, &[]);
+            match workgroups {
+                Workgroups::Fixed([x, y, z]) => compute_pass.dispatch_workgroups(x, y, z),
+                Workgroups::Indirect(arguments) => {
+                    let binding = self.binding(arguments);
+
+                    compute_pass.dispatch_workgroups_indirect(binding.buffer, binding.offset);
+                }
+            }
+        }
+
+        self.queue.submit(Some(encoder.finish()))
+    }
+
+    fn create_command_encoder(&self) -> CommandEncoder {
+        self.device.create_command_encoder(&Default::default())
+    }
+
+    fn execute_pipeline(
+        &self,
+        compute_pipeline: &ComputePipeline,
+        workgroups: Workgroups,
+        bind_group_layout: &BindGroupLayout,
+        buffers: &[ExprId],
+        label: Option<&str>,
+    ) {
+        let key = (
+            bind_group_layout.global_id(),
+            buffers
+                .iter()
+                .map(|&id| {
+                    let binding = self.binding(id);
+
+                    (binding.buffer.global_id(), binding.offset, binding.size)
                 })
                 .collect(),
-            outputs: plan.outputs,
-            output_layouts: plan.output_layouts,
+        );
+        let bind_group = self.bind_groups.get_or_create(key, || {
+            self.create_bind_group(bind_group_layout, buffers, label)
+        });
+
+        let encoder = self.create_command_encoder();
+
+        self.start_compute_pass(encoder, compute_pipeline, &bind_group, workgroups, label);
+    }
+}
+
+impl WgpuRunner {
+    fn finish_readback(&self, pending: PendingReadback) -> Vec<Tensor> {
+        self.device
+            .poll(Maintain::WaitForSubmissionIndex(pending.submission));
+
+        let mapped = pending.staging_buffer.slice(..).get_mapped_range();
+
+        let outputs = pending
+            .outputs
+            .into_iter()
+            .map(|(layout, readback, range)| {
+                let words: &[f32] =
+                    bytemuck::cast_slice(&mapped[range.start as usize..range.end as usize]);
+
+                let data = match readback {
+                    Readback::F32 => words.to_vec(),
+                    Readback::F16 | Readback::Bf16 => unpack_halves(words, readback)
+                        .take(layout.elements())
+                        .collect(),
+                };
+
+                Tensor {
+                    data: data.into_boxed_slice(),
+                    layout,
+                }
+            })
+            .collect();
+
+        drop(mapped);
+        pending.staging_buffer.unmap();
+
+        outputs
+    }
+
+    // Like `finish_readback`, but decoding straight from the staging buffer into `targets`.
+    fn finish_readback_into(&self, pending: PendingReadback, targets: &mut [TensorViewMut]) {
+        self.device
+            .poll(Maintain::WaitForSubmissionIndex(pending.submission));
+
+        let mapped = pending.staging_buffer.slice(..).get_mapped_range();
+
+        for ((_, readback, range), target) in pending.outputs.into_iter().zip(targets) {
+            let words: &[f32] =
+                bytemuck::cast_slice(&mapped[range.start as usize..range.end as usize]);
+
+            match readback {
+                Readback::F32 => target.data[..words.len()].copy_from_slice(words),
+                Readback::F16 | Readback::Bf16 => {
+                    for (value, unpacked) in target.data[..target.layout.elements()]
+                        .iter_mut()
+                        .zip(unpack_halves(words, readback))
+                    {
+                        *value = unpacked;
+                    }
+                }
+            }
+        }
+
+        drop(mapped);
+        pending.staging_buffer.unmap();
+    }
+
+    fn read_shader(path: &Path) -> io::Result<(String, SystemTime)> {
+        let modified = fs::metadata(path).and_then(|metadata| metadata.modified())?;
+
+        Ok((fs::read_to_string(path)?, modified))
+    }
+
+    // Exports `source` to the shader directory, unless an earlier run already did, and reads back
+    // what the file holds now. Files are named after a hash of the generated source, so kernels of
+    // other plans, or since changed, never stand in for it. Kernels that can't be exported or read
+    // run as generated.
+    fn watch_shader(
+        &self,
+        index: usize,
+        output: ExprId,
+        source: String,
+    ) -> (String, Option<(PathBuf, SystemTime)>) {
+        let Some(shader_dir) = &self.shader_dir else {
+            return (source, None);
+        };
+
+        let mut hasher = DefaultHasher::new();
+
+        source.hash(&mut hasher);
+
+        let path = shader_dir.join(format!(
+            "step_{index}_expr_{}_{:016x}.wgsl",
+            output.0,
+            hasher.finish()
+        ));
+        let exported = if path.exists() {
+            Ok(())
+        } else {
+            fs::create_dir_all(shader_dir).and_then(|()| fs::write(&path, &source))
+        };
+
+        match exported.and_then(|()| Self::read_shader(&path)) {
+            Ok((source, modified)) => (source, Some((path, modified))),
+            Err(error) => {
+                warn!(path = %path.display(), "could not watch shader: {error}");
+
+                (source, None)
+            }
         }
     }
 
-    fn run(&mut self, plan: ConcreteWgpuPlan, inputs: Vec<Tensor>) -> Vec<Tensor> {
-        for (index, input) in inputs.iter().enumerate() {
-            self.allocate(plan.inputs[index], input);
+    // Rebuilds the pipeline of a watched shader whose file changed since it was last read. An edit
+    // that doesn't compile leaves the kernel as it was, and is only reported once.
+    fn reload_shader(
+        &self,
+        watched: &Mutex<WatchedShader>,
+        step: usize,
+        output: ExprId,
+        bind_group_layout: &BindGroupLayout,
+        label: Option<&str>,
+    ) -> Arc<ComputePipeline> {
+        let mut watched = watched.lock().unwrap();
+        let modified = fs::metadata(&watched.path).and_then(|metadata| metadata.modified());
+
+        if let Ok(modified) = modified {
+            if modified != watched.modified {
+                watched.modified = modified;
+
+                match fs::read_to_string(&watched.path)
+                    .map_err(|error| error.to_string())
+                    .and_then(|source| self.try_create_pipeline(&source, bind_group_layout, label))
+                {
+                    Ok(pipeline) => watched.compute_pipeline = Arc::new(pipeline),
+                    Err(message) => {
+                        warn!(step, expr = ?output, "could not reload shader: {message}")
+                    }
+                }
+            }
+        }
+
+        watched.compute_pipeline.clone()
+    }
+
+    fn create_shader_module(&self, contents: &str, label: Option<&str>) -> ShaderModule {
+        self.device.create_shader_module(ShaderModuleDescriptor {
+            label,
+            source: ShaderSource::Wgsl(Cow::Borrowed(contents)),
+        })
+    }
+
+    fn create_compute_pipeline(
+        &self,
+        module: &ShaderModule,
+        entry_point: &str,
+        bind_group_layout: &BindGroupLayout,
+        label: Option<&str>,
+    ) -> ComputePipeline {
+        self.device
+            .create_compute_pipeline(&ComputePipelineDescriptor {
+                label,
+                layout: Some(
+                    &self
+                        .device
+                        .create_pipeline_layout(&PipelineLayoutDescriptor {
+                            label,
+                            bind_group_layouts: &[bind_group_layout],
+                            push_constant_ranges: &[],
+                        }),
+                ),
+                module,
+                entry_point,
+            })
+    }
+
+    // Creates the pipeline inside an error scope, so that wgpu reports a shader it rejects
+    // instead of panicking.
+    fn try_create_pipeline(
+        &self,
+        source: &str,
+        bind_group_layout: &BindGroupLayout,
+        label: Option<&str>,
+    ) -> Result<ComputePipeline, String> {
+        self.device.push_error_scope(ErrorFilter::Validation);
+
+        let module = self.create_shader_module(source, label);
+        let pipeline = self.create_compute_pipeline(&module, "main", bind_group_layout, label);
+
+        match self.device.pop_error_scope().block_on() {
+            Some(error) => Err(error.to_string()),
+            None => Ok(pipeline),
         }
+    }
+
+    fn create_bind_group_layout(&self, inputs_layout: &[(usize, bool)]) -> BindGroupLayout {
+        self.device
+            .create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: None,
+                entries: &inputs_layout
+                    .iter()
+                    .enumerate()
+                    .map(|(index, &(_, read_only))| BindGroupLayoutEntry {
+                        binding: index as u32,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only },
+                            has_dynamic_offset: false,
+                            // Left unset so that layouts, and the pipelines using them, can be
+                            // shared by kernels reading their shapes from a parameters buffer.
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    })
+                    .collect::<Vec<_>>(),
+            })
+    }
+
+    fn concretize(
+        &mut self,
+        index: &mut usize,
+        step: WgpuStep,
+        labels: &HashMap<ExprId, String>,
+        layouts: &[Arc<Layout>],
+    ) -> Result<ConcreteWgpuStep, CompileError> {
+        *index += 1;
+
+        Ok(match step {
+            WgpuStep::Allocate { id, tensor } => ConcreteWgpuStep::Allocate { id, tensor },
+            WgpuStep::Deallocate(id) => ConcreteWgpuStep::Deallocate(id),
+            WgpuStep::Reserve { id, size } => ConcreteWgpuStep::Reserve {
+                id,
+                size: size as u64,
+            },
+            WgpuStep::Place {
+                id,
+                arena,
+                offset,
+                size,
+            } => ConcreteWgpuStep::Place {
+                id,
+                arena,
+                offset: offset as u64,
+                size: size as u64,
+            },
+            WgpuStep::Slice {
+                id,
+                source,
+                offset,
+                size,
+            } => ConcreteWgpuStep::Slice {
+                id,
+                source,
+                offset: offset as u64,
+                size: size as u64,
+            },
+            WgpuStep::Execute {
+                output,
+                source,
+                workgroups,
+                inputs,
+                inputs_layout,
+            } => {
+                let (source, source_file) = self.watch_shader(*index - 1, output, source);
+                let label = labels.get(&output).map(|label| Arc::from(label.as_str()));
+                let key = (
+                    source
+                        .lines()
+                        .skip_while(|line| line.starts_with("// "))
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                    inputs_layout
+                        .iter()
+                        .map(|&(_, read_only)| read_only)
+                        .collect(),
+                );
+
+                // Watched shaders may be edited independently, so they never share pipelines.
+                // Shared pipelines keep the label of the expression they were created for.
+                let (compute_pipeline, bind_group_layout) = match self.pipelines.get(&key) {
+                    Some(pipeline) if source_file.is_none() => pipeline.clone(),
+                    _ => {
+                        debug!(expr = ?output, "creating pipeline");
+
+                        let bind_group_layout = self.create_bind_group_layout(&inputs_layout);
+                        let compute_pipeline = self
+                            .try_create_pipeline(&source, &bind_group_layout, label.as_deref())
+                            .map_err(|message| CompileError {
+                                step: *index - 1,
+                                expr: output,
+                                label: label.as_deref().map(str::to_owned),
+                                source: source.clone(),
+                                message,
+                            })?;
+                        let pipeline = (Arc::new(compute_pipeline), Arc::new(bind_group_layout));
+
+                        if source_file.is_none() {
+                            self.pipelines.insert(key, pipeline.clone());
+                        }
+
+                        pipeline
+                    }
+                };
 
-        for step in plan.steps {
+                let watched = source_file.map(|(path, modified)| {
+                    Arc::new(Mutex::new(WatchedShader {
+                        path,
+                        modified,
+                        compute_pipeline: compute_pipeline.clone(),
+                    }))
+                });
+
+                ConcreteWgpuStep::Execute {
+                    compute_pipeline,
+                    bind_group_layout,
+                    workgroups,
+                    output,
+                    inputs,
+                    watched,
+                    label,
+                    // Steps of branches and repeated bodies compute expressions of no known layout.
+                    layout: layouts.get(output.0).cloned(),
+                }
+            }
+            // The ids of repeated bodies are slots, which iterations bind to different
+            // expressions.
+            WgpuStep::Repeat { body, iterations } => ConcreteWgpuStep::Repeat {
+                body: body
+                    .into_iter()
+                    .map(|step| self.concretize(index, step, labels, &[]))
+                    .collect::<Result<_, _>>()?,
+                iterations,
+            },
+            WgpuStep::Branch {
+                condition,
+                predicate,
+                then_steps,
+                else_steps,
+            } => ConcreteWgpuStep::Branch {
+                condition,
+                predicate,
+                then_steps: then_steps
+                    .into_iter()
+                    .map(|step| self.concretize(index, step, labels, layouts))
+                    .collect::<Result<_, _>>()?,
+                else_steps: else_steps
+                    .into_iter()
+                    .map(|step| self.concretize(index, step, labels, layouts))
+                    .collect::<Result<_, _>>()?,
+            },
+        })
+    }
+
+    fn run_steps(
+        &self,
+        context: &mut RunContext,
+        steps: Vec<ConcreteWgpuStep>,
+        index: &mut usize,
+        on_execute: &mut impl FnMut(&RunContext, usize, ExprId),
+    ) {
+        for step in steps {
             match step {
                 ConcreteWgpuStep::Allocate { id, tensor } => {
-                    self.allocate(id, &tensor);
+                    context.allocate(id, &tensor);
                 }
                 ConcreteWgpuStep::Deallocate(id) => {
-                    self.deallocate(id);
+                    context.deallocate(id);
+                }
+                ConcreteWgpuStep::Reserve { id, size } => {
+                    context.create_output_buffer(id, size);
+                }
+                ConcreteWgpuStep::Place {
+                    id,
+                    arena,
+                    offset,
+                    size,
+                } => {
+                    context.place(id, arena, offset, size);
+                }
+                ConcreteWgpuStep::Slice {
+                    id,
+                    source,
+                    offset,
+                    size,
+                } => {
+                    context.slices.insert(id, (source, offset, size));
                 }
                 ConcreteWgpuStep::Execute {
-                    output,
-                    output_size,
-                    compute_pipeline,
+                    mut compute_pipeline,
                     bind_group_layout,
                     workgroups,
+                    output,
                     inputs,
+                    watched,
+                    label,
+                    layout,
                 } => {
-                    self.create_output_buffer(output, output_size);
+                    debug!(
+                        step = *index,
+                        expr = ?output,
+                        layout = layout.as_ref().map(field::display),
+                        buffer = ?inputs[0],
+                        ?workgroups,
+                        "dispatch"
+                    );
 
-                    self.execute_pipeline(
+                    if let Some(watched) = &watched {
+                        compute_pipeline = self.reload_shader(
+                            watched,
+                            *index,
+                            output,
+                            &bind_group_layout,
+                            label.as_deref(),
+                        );
+                    }
+
+                    context.execute_pipeline(
                         &compute_pipeline,
                         workgroups,
                         &bind_group_layout,
                         &inputs,
+                        label.as_deref(),
                     );
+
+                    on_execute(context, *index, inputs[0]);
+                }
+                ConcreteWgpuStep::Repeat { body, iterations } => {
+                    for iteration in iterations.iter() {
+                        self.run_steps(
+                            context,
+                            ConcreteWgpuStep::substitute(&body, iteration),
+                            index,
+                            on_execute,
+                        );
+                    }
+
+                    continue;
                 }
+                ConcreteWgpuStep::Branch {
+                    condition,
+                    predicate,
+                    then_steps,
+                    else_steps,
+                } => {
+                    // Reading the condition waits for everything submitted so far.
+                    let condition = context.read_buffer(condition, size_of::<f32>() as u64)[0];
+
+                    debug!(
+                        step = *index,
+                        condition,
+                        taken = predicate.holds(condition),
+                        "branch"
+                    );
+
+                    self.run_steps(
+                        context,
+                        if predicate.holds(condition) {
+                            then_steps
+                        } else {
+                            else_steps
+                        },
+                        index,
+                        on_execute,
+                    );
+
+                    continue;
+                }
+            }
+
+            *index += 1;
+        }
+    }
+
+    // Runs the top level steps one at a time, copying out each output after the last of them
+    // writing it, and handing over the outputs whose copies finished before the next one.
+    fn stream_steps(
+        &self,
+        context: &mut RunContext,
+        steps: Vec<ConcreteWgpuStep>,
+        outputs: Vec<(ExprId, Layout, Readback)>,
+        on_execute: &mut impl FnMut(&RunContext, usize, ExprId),
+        on_output: &mut dyn FnMut(usize, Tensor),
+    ) {
+        // Folded repeats are unrolled, since their iterations may write outputs.
+        let steps = steps
+            .into_iter()
+            .flat_map(|step| match step {
+                ConcreteWgpuStep::Repeat { body, iterations } => iterations
+                    .iter()
+                    .flat_map(|iteration| ConcreteWgpuStep::substitute(&body, iteration))
+                    .collect(),
+                step => vec![step],
+            })
+            .collect::<Vec<_>>();
+
+        // Counted in steps run, so outputs no step writes, such as inputs, are ready from the start.
+        let ready_after = outputs
+            .iter()
+            .map(|(id, _, _)| {
+                steps
+                    .iter()
+                    .rposition(|step| step.writes(*id))
+                    .map_or(0, |position| position + 1)
+            })
+            .collect::<Vec<_>>();
+
+        let mut pending: Vec<(Vec<usize>, PendingReadback)> = Vec::new();
+        let mut index = 0;
+        let mut steps = steps.into_iter();
+
+        for position in 0..=steps.len() {
+            if position > 0 {
+                self.run_steps(
+                    context,
+                    steps.next().into_iter().collect(),
+                    &mut index,
+                    on_execute,
+                );
+            }
+
+            let ready = (0..outputs.len())
+                .filter(|&output| ready_after[output] == position)
+                .collect::<Vec<_>>();
+
+            if !ready.is_empty() {
+                let readback = context.start_readback(
+                    ready
+                        .iter()
+                        .map(|&output| outputs[output].clone())
+                        .collect(),
+                );
+
+                pending.push((ready, readback));
+            }
+
+            self.device.poll(Maintain::Poll);
+
+            for (ready, readback) in
+                pending.extract_if(.., |(_, readback)| readback.mapped.load(Ordering::Acquire))
+            {
+                for (output, tensor) in ready.into_iter().zip(self.finish_readback(readback)) {
+                    on_output(output, tensor);
+                }
+            }
+        }
+
+        for (ready, readback) in pending {
+            for (output, tensor) in ready.into_iter().zip(self.finish_readback(readback)) {
+                on_output(output, tensor);
+            }
+        }
+    }
+}
+
+impl Runner for WgpuRunner {
+    type Compiler = WgpuCompiler;
+
+    type Runnable = ConcreteWgpuPlan;
+
+    fn preprocess(&mut self, plan: WgpuPlan) -> ConcreteWgpuPlan {
+        self.try_preprocess(plan)
+            .unwrap_or_else(|error| panic!("{error}"))
+    }
+
+    fn run(&mut self, plan: ConcreteWgpuPlan, inputs: &[TensorView]) -> Vec<Tensor> {
+        self.try_run(plan, inputs)
+            .unwrap_or_else(|error| panic!("{error}"))
+    }
+}
+
+#[derive(Debug)]
+pub enum InputError {
+    Count {
+        expected: usize,
+        actual: usize,
+    },
+    Layout {
+        index: usize,
+        expected: Layout,
+        actual: Layout,
+    },
+    /// A persistent input whose buffer does not exist, or holds a different layout.
+    Persistent {
+        name: String,
+        expected: Layout,
+        actual: Option<Layout>,
+    },
+    OutputCount {
+        expected: usize,
+        actual: usize,
+    },
+    /// An output view with a different layout than the plan's output, so that the data read back
+    /// would not line up with it.
+    OutputLayout {
+        index: usize,
+        expected: Layout,
+        actual: Layout,
+    },
+    /// The messages of the assertions that failed during the run, with how many elements failed
+    /// each.
+    Assertion(Vec<String>),
+}
+
+impl Display for InputError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            InputError::Count { expected, actual } => {
+                write!(f, "plan takes {expected} inputs, but {actual} were given")
+            }
+            InputError::Layout {
+                index,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "input {index} should be {expected} with strides {:?}, but is {actual} with strides {:?}",
+                expected.strides(),
+                actual.strides()
+            ),
+            InputError::Persistent {
+                name,
+                expected,
+                actual: None,
+            } => write!(f, "persistent input {name} ({expected}) was never stored"),
+            InputError::Persistent {
+                name,
+                expected,
+                actual: Some(actual),
+            } => write!(
+                f,
+                "persistent input {name} should be {expected} with strides {:?}, but is {actual} with strides {:?}",
+                expected.strides(),
+                actual.strides()
+            ),
+            InputError::OutputCount { expected, actual } => write!(
+                f,
+                "plan has {expected} outputs, but {actual} output views were given"
+            ),
+            InputError::OutputLayout {
+                index,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "output view {index} should be {expected} with strides {:?}, but is {actual} with strides {:?}",
+                expected.strides(),
+                actual.strides()
+            ),
+            InputError::Assertion(failures) => {
+                write!(f, "assertion failed: {}", failures.join("; "))
             }
         }
+    }
+}
+
+impl Error for InputError {}
+
+/// A kernel wgpu would not create a pipeline for.
+#[derive(Debug)]
+pub struct CompileError {
+    /// The index of the plan step running the kernel, as in exported shader names.
+    pub step: usize,
+    pub expr: ExprId,
+    pub label: Option<String>,
+    /// The generated WGSL, which starts with comments on the op it computes.
+    pub source: String,
+    pub message: String,
+}
+
+impl CompileError {
+    // The comments the compiler leaves at the top of a kernel.
+    fn notes(&self) -> impl Iterator<Item = &str> {
+        self.source
+            .lines()
+            .map_while(|line| line.strip_prefix("// "))
+    }
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "could not compile the kernel of step {} for {:?}",
+            self.step, self.expr
+        )?;
+
+        if let Some(label) = &self.label {
+            write!(f, " ({label})")?;
+        }
+
+        for note in self.notes() {
+            write!(f, "\n  {note}")?;
+        }
+
+        write!(f, "\n{}", self.message)
+    }
+}
+
+impl Error for CompileError {}
+
+impl WgpuRunner {
+    /// Creates the pipelines of `plan`'s kernels, or reports the first kernel wgpu rejects, along
+    /// with its generated source.
+    pub fn try_preprocess(&mut self, plan: WgpuPlan) -> Result<ConcreteWgpuPlan, CompileError> {
+        // Hot reloaded shaders could change between runs of a deterministic plan.
+        let shader_dir = if plan.deterministic {
+            self.shader_dir.take()
+        } else {
+            None
+        };
+
+        let steps = {
+            let mut index = 0;
+
+            plan.steps
+                .into_iter()
+                .map(|step| self.concretize(&mut index, step, &plan.labels, &plan.layouts))
+                .collect::<Result<_, _>>()
+        };
+
+        if shader_dir.is_some() {
+            self.shader_dir = shader_dir;
+        }
+
+        Ok(ConcreteWgpuPlan {
+            inputs: plan.inputs,
+            input_layouts: plan.input_layouts,
+            steps: steps?,
+            outputs: plan.outputs,
+            output_layouts: plan.output_layouts,
+            assertions: plan.assertions,
+            arenas: plan.arenas.into_iter().map(|size| size as u64).collect(),
+            checksums: plan.checksums,
+            readbacks: plan.readbacks,
+            persistent_inputs: plan.persistent_inputs,
+            persistent_outputs: plan.persistent_outputs,
+            bind_groups: BindGroupCache::default(),
+        })
+    }
+
+    pub fn try_run(
+        &self,
+        plan: ConcreteWgpuPlan,
+        inputs: &[TensorView],
+    ) -> Result<Vec<Tensor>, InputError> {
+        self.run_with(plan, inputs, |_, _, _| {})
+    }
+
+    /// Runs `plan` on each set of inputs in turn, reading back the outputs of a run only once the
+    /// next one is submitted, so that the readback and the next upload overlap the device's
+    /// compute. wgpu exposes a single queue per device, so rather than a separate transfer queue,
+    /// the overlap comes from never waiting on more than the submission whose results are needed.
+    /// Plans with assertions or checksums still wait for each run to finish, to check them.
+    pub fn run_pipelined<'a>(
+        &self,
+        plan: &ConcreteWgpuPlan,
+        batches: impl IntoIterator<Item = Vec<TensorView<'a>>>,
+    ) -> Result<Vec<Vec<Tensor>>, InputError> {
+        let mut outputs = Vec::new();
+        let mut pending = None;
+
+        for inputs in batches {
+            let next = self.start_run(plan.clone(), &inputs, |_, _, _| {}, None)?;
+
+            if let Some(previous) = pending.replace(next) {
+                outputs.push(self.finish_readback(previous));
+            }
+        }
+
+        outputs.extend(pending.map(|pending| self.finish_readback(pending)));
+
+        Ok(outputs)
+    }
+
+    pub(super) fn run_with(
+        &self,
+        plan: ConcreteWgpuPlan,
+        inputs: &[TensorView],
+        on_execute: impl FnMut(&RunContext, usize, ExprId),
+    ) -> Result<Vec<Tensor>, InputError> {
+        let pending = self.start_run(plan, inputs, on_execute, None)?;
+
+        Ok(self.finish_readback(pending))
+    }
+
+    /// Runs `plan`, writing its outputs into memory owned by the caller rather than allocating
+    /// tensors for them. Each view must have the layout of its output.
+    pub fn run_into(
+        &self,
+        plan: ConcreteWgpuPlan,
+        inputs: &[TensorView],
+        outputs: &mut [TensorViewMut],
+    ) -> Result<(), InputError> {
+        let outputs = &mut outputs
+            .iter_mut()
+            .map(TensorViewMut::rebased)
+            .collect::<Vec<_>>();
+
+        if outputs.len() != plan.output_layouts.len() {
+            return Err(InputError::OutputCount {
+                expected: plan.output_layouts.len(),
+                actual: outputs.len(),
+            });
+        }
+
+        if let Some((index, (expected, output))) = plan
+            .output_layouts
+            .iter()
+            .zip(outputs.iter())
+            .enumerate()
+            .find(|(_, (expected, output))| **expected != output.layout)
+        {
+            return Err(InputError::OutputLayout {
+                index,
+                expected: expected.clone(),
+                actual: output.layout.clone(),
+            });
+        }
+
+        let pending = self.start_run(plan, inputs, |_, _, _| {}, None)?;
 
-        plan.outputs
+        self.finish_readback_into(pending, outputs);
+
+        Ok(())
+    }
+
+    /// Runs `plan`, handing each output to `on_output` along with its index as soon as it is read
+    /// back, rather than once the whole plan has run. An output is copied out once the last step
+    /// writing it is submitted, and handed over once that copy finishes, while later steps still
+    /// run. Outputs that are sanitized or downcast on the way back are only ready at the end.
+    /// Assertions are checked once the plan has run, so outputs handed over by then may come from a
+    /// run that fails them.
+    pub fn run_streamed(
+        &self,
+        plan: ConcreteWgpuPlan,
+        inputs: &[TensorView],
+        mut on_output: impl FnMut(usize, Tensor),
+    ) -> Result<(), InputError> {
+        let pending = self.start_run(plan, inputs, |_, _, _| {}, Some(&mut on_output))?;
+
+        self.finish_readback(pending);
+
+        Ok(())
+    }
+
+    // Everything but reading back the outputs, unless they are streamed.
+    fn start_run(
+        &self,
+        plan: ConcreteWgpuPlan,
+        inputs: &[TensorView],
+        mut on_execute: impl FnMut(&RunContext, usize, ExprId),
+        on_output: Option<&mut dyn FnMut(usize, Tensor)>,
+    ) -> Result<PendingReadback, InputError> {
+        let inputs = &inputs.iter().map(TensorView::rebased).collect::<Vec<_>>();
+
+        if inputs.len() != plan.input_layouts.len() {
+            return Err(InputError::Count {
+                expected: plan.input_layouts.len(),
+                actual: inputs.len(),
+            });
+        }
+
+        if let Some((index, (expected, input))) = plan
+            .input_layouts
+            .iter()
+            .zip(inputs.iter())
+            .enumerate()
+            .find(|(_, (expected, input))| **expected != input.layout)
+        {
+            return Err(InputError::Layout {
+                index,
+                expected: expected.clone(),
+                actual: input.layout.clone(),
+            });
+        }
+
+        {
+            let persistent = self.persistent.lock().unwrap();
+
+            if let Some((_, name, expected)) =
+                plan.persistent_inputs.iter().find(|(_, name, expected)| {
+                    persistent
+                        .get(name)
+                        .is_none_or(|(_, layout)| layout != expected)
+                })
+            {
+                return Err(InputError::Persistent {
+                    name: name.clone(),
+                    expected: expected.clone(),
+                    actual: persistent.get(name).map(|(_, layout)| layout.clone()),
+                });
+            }
+        }
+
+        let _span = info_span!("run", inputs = inputs.len()).entered();
+
+        let mut context = self
+            .contexts
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| RunContext::new(self));
+
+        {
+            let mut allocator = self.allocator.lock().unwrap();
+
+            if allocator.runs == 0 {
+                allocator.strategy.reset();
+            }
+
+            allocator.runs += 1;
+        }
+
+        context.bind_groups = plan.bind_groups.clone();
+        context.upload(&plan.inputs, inputs);
+        self.load_persistent(&mut context, &plan.persistent_inputs);
+        context.reserve_arenas(&plan.arenas);
+
+        let outputs = plan
+            .outputs
             .into_iter()
             .zip(plan.output_layouts)
-            .map(|(id, layout)| self.retrieve(id, layout))
-            .collect()
+            .zip(plan.readbacks)
+            .map(|((id, layout), readback)| (id, layout, readback))
+            .collect::<Vec<_>>();
+
+        let outputs = match on_output {
+            Some(on_output) => {
+                self.stream_steps(
+                    &mut context,
+                    plan.steps,
+                    outputs,
+                    &mut on_execute,
+                    on_output,
+                );
+
+                Vec::new()
+            }
+            None => {
+                self.run_steps(&mut context, plan.steps, &mut 0, &mut on_execute);
+
+                outputs
+            }
+        };
+
+        self.store_persistent(&context, plan.persistent_outputs);
+
+        let pending = context.start_readback(outputs);
+
+        let checksums = Checksums(
+            plan.checksums
+                .into_iter()
+                .map(|(expr, id)| {
+                    let data = context.read_buffer(id, 2 * size_of::<u32>() as u64);
+
+                    context.deallocate(id);
+
+                    (
+                        expr,
+                        (u64::from(data[0].to_bits()) << 32) | u64::from(data[1].to_bits()),
+                    )
+                })
+                .collect(),
+        );
+
+        let failures = plan
+            .assertions
+            .into_iter()
+            .filter_map(|(id, message)| {
+                // Assertions inside branches that were not taken never ran.
+                if !context.buffers.contains_key(&id) && !context.placements.contains_key(&id) {
+                    return None;
+                }
+
+                let failures = context.read_buffer(id, size_of::<u32>() as u64)[0].to_bits();
+
+                context.deallocate(id);
+
+                (failures > 0).then(|| format!("{message} ({failures} elements)"))
+            })
+            .collect::<Vec<_>>();
+
+        plan.bind_groups.retain_used();
+        context.release_all();
+        self.allocator.lock().unwrap().runs -= 1;
+        self.contexts.lock().unwrap().push(context);
+        *self.checksums.lock().unwrap() = checksums;
+
+        if !failures.is_empty() {
+            return Err(InputError::Assertion(failures));
+        }
+
+        Ok(pending)
     }
 }
@@ -0,0 +1,494 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    path::Path,
+};
+
+use wgpu::{BindGroupLayout, ComputePipeline};
+
+use crate::{
+    graph::{ExprId, Predicate},
+    tensor::{Layout, Tensor},
+};
+
+use super::{
+    compiler::{Readback, WgpuPlan, WgpuStep, Workgroups},
+    repeat::Iteration,
+    runner::{ConcreteWgpuPlan, ConcreteWgpuStep},
+};
+
+#[derive(Copy, Clone, Debug)]
+pub enum Step<'a> {
+    Allocate {
+        id: ExprId,
+        tensor: &'a Tensor,
+    },
+    Deallocate {
+        id: ExprId,
+    },
+    Reserve {
+        id: ExprId,
+        size: usize,
+    },
+    Place {
+        id: ExprId,
+        arena: usize,
+        offset: usize,
+        size: usize,
+    },
+    /// Part of another buffer, bound under its own id.
+    Slice {
+        id: ExprId,
+        source: ExprId,
+        offset: usize,
+        size: usize,
+    },
+    Execute(Kernel<'a>),
+    Repeat(Repeat<'a>),
+    Branch(Branch<'a>),
+}
+
+impl<'a> Step<'a> {
+    fn new(step: &'a WgpuStep) -> Self {
+        match step {
+            WgpuStep::Allocate { id, tensor } => Step::Allocate { id: *id, tensor },
+            WgpuStep::Deallocate(id) => Step::Deallocate { id: *id },
+            WgpuStep::Reserve { id, size } => Step::Reserve {
+                id: *id,
+                size: *size,
+            },
+            WgpuStep::Place {
+                id,
+                arena,
+                offset,
+                size,
+            } => Step::Place {
+                id: *id,
+                arena: *arena,
+                offset: *offset,
+                size: *size,
+            },
+            WgpuStep::Slice {
+                id,
+                source,
+                offset,
+                size,
+            } => Step::Slice {
+                id: *id,
+                source: *source,
+                offset: *offset,
+                size: *size,
+            },
+            WgpuStep::Execute {
+                output,
+                source,
+                workgroups,
+                inputs,
+                inputs_layout,
+            } => Step::Execute(Kernel {
+                output: *output,
+                source,
+                workgroups: *workgroups,
+                buffers: inputs,
+                bindings: inputs_layout,
+            }),
+            WgpuStep::Repeat { body, iterations } => Step::Repeat(Repeat { body, iterations }),
+            WgpuStep::Branch {
+                condition,
+                predicate,
+                then_steps,
+                else_steps,
+            } => Step::Branch(Branch {
+                condition: *condition,
+                predicate: *predicate,
+                then_steps,
+                else_steps,
+            }),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Kernel<'a> {
+    output: ExprId,
+    source: &'a str,
+    workgroups: Workgroups,
+    buffers: &'a [ExprId],
+    bindings: &'a [(usize, bool)],
+}
+
+impl<'a> Kernel<'a> {
+    pub fn output(&self) -> ExprId {
+        self.output
+    }
+
+    pub fn source(&self) -> &'a str {
+        self.source
+    }
+
+    pub fn workgroups(&self) -> Workgroups {
+        self.workgroups
+    }
+
+    /// The buffer bound at each binding. Binding 0 is the buffer the kernel writes.
+    pub fn buffers(&self) -> &'a [ExprId] {
+        self.buffers
+    }
+
+    /// The minimum size of each binding and whether it is bound read-only.
+    pub fn bindings(&self) -> &'a [(usize, bool)] {
+        self.bindings
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Iterations<'a> {
+    iterations: &'a [Iteration],
+}
+
+impl<'a> Iterations<'a> {
+    pub fn len(&self) -> usize {
+        self.iterations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iterations.is_empty()
+    }
+
+    /// The buffers each slot of the body is bound to in `iteration`. Ids in the body are slot
+    /// indices into this list.
+    pub fn slots(&self, iteration: usize) -> &'a [ExprId] {
+        &self.iterations[iteration].ids
+    }
+
+    /// The tensors of the body's allocations in `iteration`, in body order.
+    pub fn consts(&self, iteration: usize) -> &'a [Tensor] {
+        &self.iterations[iteration].consts
+    }
+
+    /// The arena and offset of the body's placements in `iteration`, in body order.
+    pub fn placements(&self, iteration: usize) -> &'a [(usize, usize)] {
+        &self.iterations[iteration].places
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Repeat<'a> {
+    body: &'a [WgpuStep],
+    iterations: &'a [Iteration],
+}
+
+impl<'a> Repeat<'a> {
+    pub fn body(&self) -> impl Iterator<Item = Step<'a>> {
+        self.body.iter().map(Step::new)
+    }
+
+    pub fn iterations(&self) -> Iterations<'a> {
+        Iterations {
+            iterations: self.iterations,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Branch<'a> {
+    condition: ExprId,
+    predicate: Predicate,
+    then_steps: &'a [WgpuStep],
+    else_steps: &'a [WgpuStep],
+}
+
+impl<'a> Branch<'a> {
+    /// The buffer holding the scalar the predicate is checked against.
+    pub fn condition(&self) -> ExprId {
+        self.condition
+    }
+
+    pub fn predicate(&self) -> Predicate {
+        self.predicate
+    }
+
+    pub fn then_steps(&self) -> impl Iterator<Item = Step<'a>> {
+        self.then_steps.iter().map(Step::new)
+    }
+
+    pub fn else_steps(&self) -> impl Iterator<Item = Step<'a>> {
+        self.else_steps.iter().map(Step::new)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ConcreteRepeat<'a> {
+    body: &'a [ConcreteWgpuStep],
+    iterations: &'a [Iteration],
+}
+
+impl<'a> ConcreteRepeat<'a> {
+    pub fn body(&self) -> impl Iterator<Item = ConcreteStep<'a>> {
+        self.body.iter().map(ConcreteStep::new)
+    }
+
+    pub fn iterations(&self) -> Iterations<'a> {
+        Iterations {
+            iterations: self.iterations,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum ConcreteStep<'a> {
+    Allocate {
+        id: ExprId,
+        tensor: &'a Tensor,
+    },
+    Deallocate {
+        id: ExprId,
+    },
+    Reserve {
+        id: ExprId,
+        size: u64,
+    },
+    Place {
+        id: ExprId,
+        arena: usize,
+        offset: u64,
+        size: u64,
+    },
+    Slice {
+        id: ExprId,
+        source: ExprId,
+        offset: u64,
+        size: u64,
+    },
+    Execute {
+        compute_pipeline: &'a ComputePipeline,
+        bind_group_layout: &'a BindGroupLayout,
+        workgroups: Workgroups,
+        buffers: &'a [ExprId],
+    },
+    Repeat(ConcreteRepeat<'a>),
+    Branch(ConcreteBranch<'a>),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ConcreteBranch<'a> {
+    condition: ExprId,
+    predicate: Predicate,
+    then_steps: &'a [ConcreteWgpuStep],
+    else_steps: &'a [ConcreteWgpuStep],
+}
+
+impl<'a> ConcreteBranch<'a> {
+    pub fn condition(&self) -> ExprId {
+        self.condition
+    }
+
+    pub fn predicate(&self) -> Predicate {
+        self.predicate
+    }
+
+    pub fn then_steps(&self) -> impl Iterator<Item = ConcreteStep<'a>> {
+        self.then_steps.iter().map(ConcreteStep::new)
+    }
+
+    pub fn else_steps(&self) -> impl Iterator<Item = ConcreteStep<'a>> {
+        self.else_steps.iter().map(ConcreteStep::new)
+    }
+}
+
+impl<'a> ConcreteStep<'a> {
+    fn new(step: &'a ConcreteWgpuStep) -> Self {
+        match step {
+            ConcreteWgpuStep::Allocate { id, tensor } => ConcreteStep::Allocate { id: *id, tensor },
+            ConcreteWgpuStep::Deallocate(id) => ConcreteStep::Deallocate { id: *id },
+            ConcreteWgpuStep::Reserve { id, size } => ConcreteStep::Reserve {
+                id: *id,
+                size: *size,
+            },
+            ConcreteWgpuStep::Place {
+                id,
+                arena,
+                offset,
+                size,
+            } => ConcreteStep::Place {
+                id: *id,
+                arena: *arena,
+                offset: *offset,
+                size: *size,
+            },
+            ConcreteWgpuStep::Slice {
+                id,
+                source,
+                offset,
+                size,
+            } => ConcreteStep::Slice {
+                id: *id,
+                source: *source,
+                offset: *offset,
+                size: *size,
+            },
+            ConcreteWgpuStep::Execute {
+                compute_pipeline,
+                bind_group_layout,
+                workgroups,
+                inputs,
+                ..
+            } => ConcreteStep::Execute {
+                compute_pipeline,
+                bind_group_layout,
+                workgroups: *workgroups,
+                buffers: inputs,
+            },
+            ConcreteWgpuStep::Repeat { body, iterations } => {
+                ConcreteStep::Repeat(ConcreteRepeat { body, iterations })
+            }
+            ConcreteWgpuStep::Branch {
+                condition,
+                predicate,
+                then_steps,
+                else_steps,
+            } => ConcreteStep::Branch(ConcreteBranch {
+                condition: *condition,
+                predicate: *predicate,
+                then_steps,
+                else_steps,
+            }),
+        }
+    }
+}
+
+// Collects the sources of every execute step, including those in repeated bodies and branches.
+fn collect_kernels<'a>(steps: &'a [WgpuStep], kernels: &mut Vec<(ExprId, &'a str)>) {
+    for step in steps {
+        match step {
+            WgpuStep::Execute { output, source, .. } => kernels.push((*output, source)),
+            WgpuStep::Repeat { body, .. } => collect_kernels(body, kernels),
+            WgpuStep::Branch {
+                then_steps,
+                else_steps,
+                ..
+            } => {
+                collect_kernels(then_steps, kernels);
+                collect_kernels(else_steps, kernels);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl WgpuPlan {
+    pub fn steps(&self) -> impl Iterator<Item = Step<'_>> {
+        self.steps.iter().map(Step::new)
+    }
+
+    /// The WGSL of each execute step in order, with the expression it computes. Repeated bodies
+    /// are listed once, and both sides of branches are included.
+    pub fn kernels(&self) -> Vec<(ExprId, &str)> {
+        let mut kernels = Vec::new();
+
+        collect_kernels(&self.steps, &mut kernels);
+
+        kernels
+    }
+
+    /// Writes each of `kernels()` to `plan_{hash}_kernel_{index}_expr_{id}.wgsl` in `dir`,
+    /// creating it if needed. The hash is of the plan's kernels, so that the dumps of different
+    /// plans sharing a directory don't overwrite each other.
+    pub fn dump_kernels(&self, dir: impl AsRef<Path>) -> io::Result<()> {
+        let dir = dir.as_ref();
+        let kernels = self.kernels();
+        let mut hasher = DefaultHasher::new();
+
+        kernels.hash(&mut hasher);
+
+        let hash = hasher.finish();
+
+        fs::create_dir_all(dir)?;
+
+        for (index, (output, source)) in kernels.into_iter().enumerate() {
+            fs::write(
+                dir.join(format!(
+                    "plan_{hash:016x}_kernel_{index}_expr_{}.wgsl",
+                    output.0
+                )),
+                source,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn inputs(&self) -> impl Iterator<Item = (ExprId, &Layout)> {
+        self.inputs.iter().copied().zip(self.input_layouts.iter())
+    }
+
+    pub fn outputs(&self) -> impl Iterator<Item = (ExprId, &Layout, Readback)> {
+        self.outputs
+            .iter()
+            .copied()
+            .zip(self.output_layouts.iter())
+            .zip(self.readbacks.iter().copied())
+            .map(|((id, layout), readback)| (id, layout, readback))
+    }
+
+    /// The inputs read from the runner's persistent buffers, with the names of those buffers.
+    pub fn persistent_inputs(&self) -> impl Iterator<Item = (ExprId, &str, &Layout)> {
+        self.persistent_inputs
+            .iter()
+            .map(|(id, name, layout)| (*id, name.as_str(), layout))
+    }
+
+    /// The outputs stored in the runner's persistent buffers instead of being read back.
+    pub fn persistent_outputs(&self) -> impl Iterator<Item = (ExprId, &str, &Layout)> {
+        self.persistent_outputs
+            .iter()
+            .map(|(id, name, layout)| (*id, name.as_str(), layout))
+    }
+
+    pub fn arenas(&self) -> &[usize] {
+        &self.arenas
+    }
+
+    pub fn assertions(&self) -> impl Iterator<Item = (ExprId, &str)> {
+        self.assertions
+            .iter()
+            .map(|(id, message)| (*id, message.as_str()))
+    }
+}
+
+impl ConcreteWgpuPlan {
+    pub fn steps(&self) -> impl Iterator<Item = ConcreteStep<'_>> {
+        self.steps.iter().map(ConcreteStep::new)
+    }
+
+    pub fn inputs(&self) -> impl Iterator<Item = (ExprId, &Layout)> {
+        self.inputs.iter().copied().zip(self.input_layouts.iter())
+    }
+
+    pub fn outputs(&self) -> impl Iterator<Item = (ExprId, &Layout, Readback)> {
+        self.outputs
+            .iter()
+            .copied()
+            .zip(self.output_layouts.iter())
+            .zip(self.readbacks.iter().copied())
+            .map(|((id, layout), readback)| (id, layout, readback))
+    }
+
+    pub fn persistent_inputs(&self) -> impl Iterator<Item = (ExprId, &str, &Layout)> {
+        self.persistent_inputs
+            .iter()
+            .map(|(id, name, layout)| (*id, name.as_str(), layout))
+    }
+
+    pub fn persistent_outputs(&self) -> impl Iterator<Item = (ExprId, &str, &Layout)> {
+        self.persistent_outputs
+            .iter()
+            .map(|(id, name, layout)| (*id, name.as_str(), layout))
+    }
+
+    pub fn arenas(&self) -> &[u64] {
+        &self.arenas
+    }
+}